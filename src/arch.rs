@@ -0,0 +1,135 @@
+//! The architecture of the installed system, which is always the host's, since pacstrap installs
+//! the packages of the host's architecture. aarch64 systems are built on Arch Linux ARM hosts
+//! with its packages, and boot with GRUB's arm64 EFI loader, which the firmware of servers and
+//! U-Boot on single board computers load (see `--board`).
+
+use crate::args::Kernel;
+use crate::constants::{ROOT_AARCH64_PARTITION_TYPE, ROOT_X86_64_PARTITION_TYPE};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Arch {
+    X86_64,
+    Aarch64,
+}
+
+/// Arch Linux ARM kernels install /boot/Image, while GRUB's 10_linux and the mkinitcpio presets of
+/// ALMA expect /boot/vmlinuz-<package>
+pub const KERNEL_IMAGE_HOOK: &str = "80-alma-kernel-image.hook";
+pub const KERNEL_IMAGE_SCRIPT: &str = "kernel-image.sh";
+
+impl Arch {
+    pub const ALL: [Arch; 2] = [Arch::X86_64, Arch::Aarch64];
+
+    /// The architecture of the host, none if ALMA cannot build systems for it
+    pub fn host() -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|arch| arch.name() == env::consts::ARCH)
+    }
+
+    /// The architecture of the systems ALMA builds. `alma create` refuses unsupported hosts
+    /// up front, so they do not get this far.
+    pub fn target() -> Self {
+        Self::host().unwrap_or(Arch::X86_64)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Arch::X86_64 => "x86_64",
+            Arch::Aarch64 => "aarch64",
+        }
+    }
+
+    /// The `--target` of grub-install for UEFI
+    pub fn grub_efi_target(self) -> &'static str {
+        match self {
+            Arch::X86_64 => "x86_64-efi",
+            Arch::Aarch64 => "arm64-efi",
+        }
+    }
+
+    /// The root partition type of the Discoverable Partitions Specification
+    pub fn root_partition_type(self) -> &'static str {
+        match self {
+            Arch::X86_64 => ROOT_X86_64_PARTITION_TYPE,
+            Arch::Aarch64 => ROOT_AARCH64_PARTITION_TYPE,
+        }
+    }
+
+    /// Packages installed on top of the base packages, which only exist for this architecture
+    pub fn packages(self) -> &'static [&'static str] {
+        match self {
+            Arch::X86_64 => &["intel-ucode", "amd-ucode", "broadcom-wl"],
+            Arch::Aarch64 => &[],
+        }
+    }
+
+    pub fn default_kernel(self) -> Kernel {
+        match self {
+            Arch::X86_64 => Kernel::Linux,
+            Arch::Aarch64 => Kernel::LinuxAarch64,
+        }
+    }
+
+    /// Shim is only signed for x86_64 in shim-signed
+    pub fn has_shim(self) -> bool {
+        self == Arch::X86_64
+    }
+}
+
+impl fmt::Display for Arch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// The pacman hook keeping /boot/vmlinuz-linux-aarch64 a copy of the /boot/Image of the kernel
+pub fn kernel_image_hook(scripts_dir: &str) -> String {
+    format!(
+        "[Trigger]
+Type = Path
+Operation = Install
+Operation = Upgrade
+Target = boot/Image
+
+[Action]
+Description = Copying the kernel image for GRUB (ALMA)...
+When = PostTransaction
+Exec = /{scripts_dir}/{KERNEL_IMAGE_SCRIPT}
+"
+    )
+}
+
+pub fn kernel_image_script() -> String {
+    format!(
+        "#!/bin/sh
+set -e
+if [ -f /boot/Image ]; then
+  install -Dm644 /boot/Image /boot/vmlinuz-{}
+fi
+",
+        Kernel::LinuxAarch64.package_name()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arch() {
+        assert_eq!(Arch::X86_64.grub_efi_target(), "x86_64-efi");
+        assert_eq!(Arch::Aarch64.grub_efi_target(), "arm64-efi");
+        assert_eq!(
+            serde_json::to_string(&Arch::ALL).unwrap(),
+            r#"["x86_64","aarch64"]"#
+        );
+        assert!(Arch::Aarch64.packages().is_empty());
+        assert!(kernel_image_script().contains("/boot/vmlinuz-linux-aarch64"));
+        assert!(kernel_image_hook("usr/share/alma/hooks").contains("Target = boot/Image\n"));
+    }
+}
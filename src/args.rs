@@ -1,12 +1,24 @@
+use super::arch::Arch;
 use super::aur::AurHelper;
-use anyhow::anyhow;
+use super::board::BoardProfile;
+use anyhow::{Context, anyhow};
 use byte_unit::Byte;
-use std::{fmt, path::PathBuf, str::FromStr};
+use log::debug;
+use std::{
+    collections::BTreeMap, env, ffi::OsString, fmt, fs, path::Path, path::PathBuf, str::FromStr,
+};
 
-use clap::{Parser, ValueEnum};
+use clap::parser::ValueSource;
+use clap::{ArgGroup, ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum};
 use serde::{Deserialize, Serialize};
 
+use super::constants::{DEFAULT_BOOT_LABEL, DEFAULT_ROOT_LABEL, DEFAULT_SWAP_MB};
+use super::fault::FailPoint;
+use super::initcpio::{Compression, InitcpioOptions};
+use super::interactive::UserSettings;
 use super::presets::PresetsPath;
+use super::sudo::{SudoLecture, SudoOptions};
+use super::tool::UsbId;
 
 /// Parse size argument as bytes e.g. 10GB, 10GiB, etc.
 /// If a raw number is given, it is treated as MiB.
@@ -29,6 +41,31 @@ fn parse_presets_path(path: &str) -> anyhow::Result<PresetsPath> {
     PresetsPath::from_str(path).map_err(|e| anyhow!("{}", e))
 }
 
+/// Replaces each `@FILE` argument with the packages listed in that file: whitespace-separated
+/// names, with comments starting with `#`.
+fn expand_package_lists(packages: &[String]) -> anyhow::Result<Vec<String>> {
+    let mut expanded = Vec::new();
+    for package in packages {
+        let Some(path) = package.strip_prefix('@') else {
+            expanded.push(package.clone());
+            continue;
+        };
+        let list = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read the package list {path}"))?;
+        expanded.extend(
+            list.lines()
+                .flat_map(|line| {
+                    line.split('#')
+                        .next()
+                        .unwrap_or_default()
+                        .split_whitespace()
+                })
+                .map(String::from),
+        );
+    }
+    Ok(expanded)
+}
+
 #[derive(Parser, Debug, Clone)]
 #[clap(name = "alma", about = "Arch Linux Mobile Appliance", version, author)]
 pub struct App {
@@ -40,7 +77,40 @@ pub struct App {
     pub cmd: Command,
 }
 
+impl App {
+    /// Parses the command line and fills in any flags not given there from the user
+    /// configuration file.
+    pub fn parse_with_config() -> anyhow::Result<Self> {
+        let matches = Self::command().get_matches();
+        let app = Self::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+        app.with_config(&matches)
+    }
+
+    /// Like [`App::parse_with_config`], for command lines ALMA builds itself
+    pub fn try_parse_with_config<I, T>(args: I) -> anyhow::Result<Self>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let matches = Self::command().try_get_matches_from(args)?;
+        let app = Self::from_arg_matches(&matches)?;
+        app.with_config(&matches)
+    }
+
+    fn with_config(mut self, matches: &ArgMatches) -> anyhow::Result<Self> {
+        if let (Command::Create(command), Some((_, sub_matches))) =
+            (&mut self.cmd, matches.subcommand())
+        {
+            Config::load(command.config.as_deref())?.apply_to_create(command, sub_matches)?;
+            command.extra_packages = expand_package_lists(&command.extra_packages)?;
+            command.aur_packages = expand_package_lists(&command.aur_packages)?;
+        }
+        Ok(self)
+    }
+}
+
 #[derive(Parser, Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
 pub enum Command {
     #[clap(name = "create", about = "Create a new Arch Linux bootable system")]
     Create(CreateCommand),
@@ -50,6 +120,86 @@ pub enum Command {
     Chroot(ChrootCommand),
     #[clap(name = "qemu", about = "Boot the ALMA system with Qemu")]
     Qemu(QemuCommand),
+    #[clap(
+        name = "doctor",
+        about = "Check that the host has everything ALMA needs"
+    )]
+    Doctor(DoctorCommand),
+    #[clap(
+        name = "list-devices",
+        about = "List the disks ALMA can install to, for scripts choosing a target"
+    )]
+    ListDevices(ListDevicesCommand),
+    #[clap(
+        name = "resize",
+        about = "Grow an image file and the root filesystem it contains"
+    )]
+    Resize(ResizeCommand),
+    #[clap(
+        name = "fix-gpt",
+        about = "Repair the GPT of an image written to a larger device, optionally growing the root partition"
+    )]
+    FixGpt(FixGptCommand),
+    #[clap(
+        name = "restore-gpt",
+        about = "Restore a partition table saved with `alma create --backup-table`"
+    )]
+    RestoreGpt(RestoreGptCommand),
+    #[clap(
+        name = "cleanup",
+        about = "Unmount and detach whatever crashed ALMA runs left behind"
+    )]
+    Cleanup(CleanupCommand),
+    #[clap(
+        name = "gc",
+        about = "Remove the sources baked into the running ALMA system to free their space"
+    )]
+    Gc(GcCommand),
+    #[clap(
+        name = "schedule",
+        about = "Install a systemd timer which periodically rebuilds an image"
+    )]
+    Schedule(ScheduleCommand),
+    #[clap(
+        name = "seed",
+        about = "Turn the btrfs root filesystem of a master image into a read-only seed"
+    )]
+    Seed(SeedCommand),
+    #[clap(
+        name = "sprout",
+        about = "Create a writable copy of a seed image on another device"
+    )]
+    Sprout(SproutCommand),
+    #[clap(
+        name = "clone",
+        about = "Copy an ALMA system to another drive, giving the copy its own identifiers"
+    )]
+    Clone(CloneCommand),
+    #[clap(
+        name = "update",
+        about = "Upgrade the packages of an ALMA system, then regenerate its initramfs and bootloader"
+    )]
+    Update(UpdateCommand),
+    #[clap(
+        name = "info",
+        about = "Show how an ALMA system was built, from the manifest it carries"
+    )]
+    Info(InfoCommand),
+    #[clap(
+        name = "backup",
+        about = "Capture an ALMA device into an image file, optionally shrunk and compressed"
+    )]
+    Backup(BackupCommand),
+    #[clap(
+        name = "flash",
+        about = "Write an image file to a device and verify it by reading it back"
+    )]
+    Flash(FlashCommand),
+    #[clap(
+        name = "export",
+        about = "Package an image for other tools, like a Vagrant box"
+    )]
+    Export(ExportCommand),
 }
 
 #[derive(ValueEnum, Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
@@ -81,7 +231,261 @@ pub enum RootFilesystemType {
     Btrfs,
 }
 
+impl fmt::Display for RootFilesystemType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                RootFilesystemType::Ext4 => "ext4",
+                RootFilesystemType::Btrfs => "btrfs",
+            }
+        )
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Kernel {
+    #[default]
+    Linux,
+    LinuxLts,
+    LinuxZen,
+    LinuxHardened,
+    /// The mainline kernel of Arch Linux ARM
+    LinuxAarch64,
+}
+
+impl Kernel {
+    /// The kernel package name, which is also the name of its mkinitcpio preset
+    pub fn package_name(&self) -> &'static str {
+        match self {
+            Kernel::Linux => "linux",
+            Kernel::LinuxLts => "linux-lts",
+            Kernel::LinuxZen => "linux-zen",
+            Kernel::LinuxHardened => "linux-hardened",
+            Kernel::LinuxAarch64 => "linux-aarch64",
+        }
+    }
+
+    pub fn arch(&self) -> Arch {
+        match self {
+            Kernel::LinuxAarch64 => Arch::Aarch64,
+            _ => Arch::X86_64,
+        }
+    }
+}
+
+impl fmt::Display for Kernel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.package_name())
+    }
+}
+
+fn default_kernels() -> Vec<Kernel> {
+    vec![Arch::target().default_kernel()]
+}
+
+fn default_root_label() -> String {
+    String::from(DEFAULT_ROOT_LABEL)
+}
+
+fn default_boot_label() -> String {
+    String::from(DEFAULT_BOOT_LABEL)
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum InitramfsGenerator {
+    #[default]
+    Mkinitcpio,
+    Dracut,
+}
+
+impl fmt::Display for InitramfsGenerator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                InitramfsGenerator::Mkinitcpio => "mkinitcpio",
+                InitramfsGenerator::Dracut => "dracut",
+            }
+        )
+    }
+}
+
+/// Whether the package databases and the keyring are refreshed before building
+#[derive(ValueEnum, Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RefreshPolicy {
+    /// Never start a refresh, for air-gapped builds
+    Never,
+    /// Warn when the host's databases are stale, pacstrap syncs those of the target as usual
+    #[default]
+    Auto,
+    /// Update the host's databases and keyring, and download every database of the target again
+    Force,
+}
+
+/// The tool which partitions the disk
+#[derive(ValueEnum, Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Partitioner {
+    #[default]
+    Sgdisk,
+    /// systemd-repart, whose definitions are also installed so the root partition grows to
+    /// fill a larger disk on the first boot
+    Repart,
+}
+
+/// The platforms GRUB is installed for
+#[derive(ValueEnum, Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum GrubTargets {
+    /// UEFI only, as the removable x86_64-efi (or arm64-efi) loader on the boot partition
+    Efi,
+    /// Legacy BIOS only, as the i386-pc loader in the BIOS boot partition
+    Bios,
+    #[default]
+    Both,
+}
+
+impl GrubTargets {
+    pub fn efi(self) -> bool {
+        self != GrubTargets::Bios
+    }
+
+    pub fn bios(self) -> bool {
+        self != GrubTargets::Efi
+    }
+}
+
+/// aarch64 machines have no BIOS
+fn default_grub_targets() -> GrubTargets {
+    match Arch::target() {
+        Arch::X86_64 => GrubTargets::Both,
+        Arch::Aarch64 => GrubTargets::Efi,
+    }
+}
+
+/// How the space of a new image file is allocated
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Preallocation {
+    /// Create a sparse file, only allocating space as it is written
+    #[default]
+    None,
+    /// Reserve all the space up front with fallocate, without writing to it
+    Falloc,
+    /// Write zeroes to the whole file
+    Full,
+}
+
+/// How the steps of a dry run are printed
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PlanFormat {
+    /// As shell commands
+    #[default]
+    Shell,
+    /// As one JSON object per line
+    Json,
+}
+
+/// Compiler caches for the AUR builds
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompilerCache {
+    /// Caches C and C++ compilations, through makepkg's ccache option
+    Ccache,
+    /// Caches Rust compilations, as the wrapper of rustc
+    Sccache,
+}
+
+impl CompilerCache {
+    /// The name of the package and of the cache directory
+    pub fn name(self) -> &'static str {
+        match self {
+            CompilerCache::Ccache => "ccache",
+            CompilerCache::Sccache => "sccache",
+        }
+    }
+}
+
+/// Compressors of the finished image
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageCompression {
+    /// Fast to compress and decompress
+    Zstd,
+    /// Smaller, but much slower to compress
+    Xz,
+}
+
+impl ImageCompression {
+    /// The name of the compressor binary
+    pub fn name(self) -> &'static str {
+        match self {
+            ImageCompression::Zstd => "zstd",
+            ImageCompression::Xz => "xz",
+        }
+    }
+
+    /// The extension the compressor appends to the file name
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageCompression::Zstd => "zst",
+            ImageCompression::Xz => "xz",
+        }
+    }
+}
+
+/// Virtual machine disk formats qemu-img converts the raw image to
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiskFormat {
+    /// QEMU/KVM and libvirt
+    Qcow2,
+    /// VMware
+    Vmdk,
+    /// VirtualBox
+    Vdi,
+}
+
+impl DiskFormat {
+    /// The format name of qemu-img, which is also the usual file extension
+    pub fn name(self) -> &'static str {
+        match self {
+            DiskFormat::Qcow2 => "qcow2",
+            DiskFormat::Vmdk => "vmdk",
+            DiskFormat::Vdi => "vdi",
+        }
+    }
+}
+
+/// The phases of `alma create`, in the order they run
+#[derive(ValueEnum, Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    /// Wipe, partition and format the device (and set up encryption)
+    Partition,
+    /// Install the base system with pacstrap
+    Bootstrap,
+    /// Copy the presets and sources into the image
+    Bake,
+    /// Apply the settings from the interactive setup
+    Setup,
+    /// Install the AUR helper and AUR packages
+    Aur,
+    /// Run the preset scripts
+    Presets,
+    /// Configure services, the initramfs and the bootloader
+    Bootloader,
+    /// Install Omarchy
+    Omarchy,
+    /// Write the installation manifest
+    Manifest,
+}
+
 #[derive(Parser, Debug, Clone)]
+// --dryrun-script implies --dryrun, so either makes a dry run
+#[clap(group(ArgGroup::new("dryrun_mode").args(["dryrun", "dryrun_script"]).multiple(true)))]
 pub struct CreateCommand {
     /// Path to a block device or a non-existing file if --image is specified
     #[clap(value_name = "BLOCK_DEVICE | IMAGE")]
@@ -95,6 +499,26 @@ pub struct CreateCommand {
     #[clap(long, value_enum, default_value_t = RootFilesystemType::Ext4)]
     pub filesystem: RootFilesystemType,
 
+    /// Do not patch the Omarchy install scripts to run in a chroot
+    #[clap(long = "omarchy-no-patch")]
+    pub omarchy_no_patch: bool,
+
+    /// Additional Omarchy patch rules, in the format of the built-in omarchy_patches.toml
+    #[clap(
+        long = "omarchy-patches",
+        value_name = "PATH",
+        conflicts_with = "omarchy_no_patch"
+    )]
+    pub omarchy_patches: Option<PathBuf>,
+
+    /// Bake the presets without their git history, to save space
+    #[clap(long = "bake-shallow")]
+    pub bake_shallow: bool,
+
+    /// The kernel(s) to install. Can be given multiple times to install several kernels.
+    #[clap(long = "kernel", value_enum, default_values_t = default_kernels())]
+    pub kernels: Vec<Kernel>,
+
     /// Path to a partition to use as the target root partition - this will reformat the partition.
     /// Should be used when you do not want to repartition and wipe the entire disk (e.g. dual-booting).
     /// If it is not set, then the entire disk will be repartitioned and wiped.
@@ -113,18 +537,94 @@ pub struct CreateCommand {
     #[clap(short = 'c', long = "pacman-conf", value_name = "PACMAN_CONF")]
     pub pacman_conf: Option<PathBuf>,
 
-    /// Additional packages to install from Pacman repos
+    /// Do not copy the host's mirrorlist into the image, use the default one of the
+    /// pacman-mirrorlist package instead
+    #[clap(long = "no-host-mirrorlist")]
+    pub no_host_mirrorlist: bool,
+
+    /// Bootstrap from the best mirrors of these countries, e.g. DE,FR, ranked by the Arch Linux
+    /// mirror status, and install them as the mirrorlist of the image
+    #[clap(
+        long = "mirrors-country",
+        value_name = "COUNTRIES",
+        value_delimiter = ',',
+        value_parser = crate::mirrors::parse_country,
+        conflicts_with = "no_host_mirrorlist"
+    )]
+    pub mirrors_country: Vec<String>,
+
+    /// Do not copy the host's pacman keyring into the image, populate a new one from
+    /// archlinux-keyring while bootstrapping instead. This is slower.
+    #[clap(long = "no-host-keyring", conflicts_with = "fresh_keyring")]
+    pub no_host_keyring: bool,
+
+    /// Bootstrap with the host's pacman keyring, then replace it with a freshly generated one,
+    /// so the image does not share the host's local signing key
+    #[clap(long = "fresh-keyring")]
+    pub fresh_keyring: bool,
+
+    /// When to refresh the package databases and the pacman keyring, on the host and in the
+    /// target
+    #[clap(long = "refresh", value_enum, default_value_t = RefreshPolicy::Auto)]
+    pub refresh: RefreshPolicy,
+
+    /// Additional packages to install from Pacman repos. @FILE reads them from a file, one or
+    /// more per line, with # comments
     #[clap(short = 'p', long = "extra-packages", value_name = "PACKAGE")]
     pub extra_packages: Vec<String>,
 
-    /// Additional packages to install from the AUR
+    /// Additional packages to install from the AUR. @FILE reads them from a file, like
+    /// --extra-packages
     #[clap(long = "aur-packages", value_name = "AUR_PACKAGE")]
     pub aur_packages: Vec<String>,
 
+    /// Keep going when the AUR packages of a preset (or of --aur-packages) fail to install. The
+    /// failures are recorded in the manifest
+    #[clap(long = "continue-on-aur-failure")]
+    pub continue_on_aur_failure: bool,
+
     /// Boot partition size. Raw numbers are treated as MiB. [default: 300MiB]
     #[clap(long = "boot-size", value_name = "SIZE_WITH_UNIT", value_parser = parse_bytes)]
     pub boot_size: Option<Byte>,
 
+    /// Leave this much unallocated space at the end of the disk, e.g. for SSD over-provisioning
+    /// or a later data partition. Raw numbers are treated as MiB
+    #[clap(
+        long = "leave-free",
+        value_name = "SIZE_WITH_UNIT",
+        value_parser = parse_bytes,
+        conflicts_with = "root_partition"
+    )]
+    pub leave_free: Option<Byte>,
+
+    /// Create an exFAT partition of this size at the end of the disk, mounted at /data, for files
+    /// shared with Windows and macOS machines. Raw numbers are treated as MiB
+    #[clap(
+        long = "data-partition",
+        value_name = "SIZE_WITH_UNIT",
+        value_parser = parse_bytes,
+        conflicts_with = "root_partition"
+    )]
+    pub data_partition: Option<Byte>,
+
+    /// Mirror the root partition onto this second block device with an mdadm RAID-1 array. It is
+    /// wiped and partitioned like the first one, and gets a copy of the boot partition
+    #[clap(
+        long = "raid1",
+        value_name = "MIRROR_DEVICE",
+        conflicts_with_all = ["image", "root_partition", "data_partition"]
+    )]
+    pub raid1: Option<PathBuf>,
+
+    /// The tool used to partition the disk
+    #[clap(
+        long = "partitioner",
+        value_enum,
+        default_value_t = Partitioner::Sgdisk,
+        conflicts_with_all = ["root_partition", "raid1"]
+    )]
+    pub partitioner: Partitioner,
+
     /// Enter interactive chroot before unmounting the drive
     #[clap(short = 'i', long = "interactive")]
     pub interactive: bool,
@@ -133,14 +633,82 @@ pub struct CreateCommand {
     #[clap(short = 'e', long = "encrypted-root")]
     pub encrypted_root: bool,
 
+    /// Console keymap of the installed system, e.g. "de-latin1", also used by the initramfs to
+    /// read the passphrase of an encrypted root (default: us)
+    #[clap(long = "keymap", value_name = "KEYMAP")]
+    pub keymap: Option<String>,
+
+    /// Locale to generate in the installed system, e.g. "de_DE.UTF-8". Give it multiple times
+    /// for several locales, the first one sets LANG [default: en_US.UTF-8]
+    #[clap(long = "locale", value_name = "LOCALE")]
+    pub locales: Vec<String>,
+
     /// Paths to preset files/dirs (local, http(s) zip/tar.gz, or git repo)
     #[clap(long = "presets", value_name = "PRESETS_PATH", value_parser = parse_presets_path)]
     pub presets: Vec<PresetsPath>,
 
+    /// Read the defaults of flags not given on the command line from this file instead of
+    /// ~/.config/alma/config.toml and /etc/alma/config.toml
+    #[clap(long = "config", value_name = "CONFIG_FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Build a live ISO booting from a squashfs with a tmpfs overlay, instead of installing to
+    /// a block device. The system is built in a sparse ISO_FILE.rootfs.img first, which is
+    /// removed afterwards
+    #[clap(
+        long = "iso",
+        value_name = "ISO_FILE",
+        conflicts_with_all = [
+            "path", "image", "root_partition", "raid1", "encrypted_root", "systemd_initramfs",
+            "volatile_root", "hibernate", "swapfile", "data_partition", "partitioner",
+            "grub_password", "grub_targets", "board",
+        ]
+    )]
+    pub iso: Option<PathBuf>,
+
     /// Create a raw image file instead of using a block device
     #[clap(long = "image", value_name = "SIZE_WITH_UNIT", requires = "path", value_parser = parse_bytes)]
     pub image: Option<Byte>,
 
+    /// Write this finished image file to the block device instead of building a system on it,
+    /// verify it, then grow its root partition to fill the device. The image must be raw, like
+    /// one built with --image
+    #[clap(
+        long = "from-image",
+        value_name = "IMAGE",
+        requires = "path",
+        conflicts_with_all = ["image", "iso", "root_partition", "raid1", "dryrun"]
+    )]
+    pub from_image: Option<PathBuf>,
+
+    /// How to allocate the space of the image file
+    #[clap(long = "preallocate", value_enum, default_value_t = Preallocation::None, requires = "image")]
+    pub preallocate: Preallocation,
+
+    /// Convert the finished image to a virtual machine disk with qemu-img, written next to it
+    /// with the extension of the format
+    #[clap(
+        long = "convert",
+        value_name = "FORMAT",
+        value_enum,
+        requires = "image"
+    )]
+    pub convert: Option<DiskFormat>,
+
+    /// Remove the raw image once it has been converted
+    #[clap(long = "remove-raw", requires = "convert")]
+    pub remove_raw: bool,
+
+    /// Compress the finished image (or the disk of --convert) next to it, e.g. to alma.img.zst,
+    /// with its checksum in alma.img.zst.sha256
+    #[clap(
+        long = "compress",
+        value_name = "FORMAT",
+        value_enum,
+        requires = "image"
+    )]
+    pub compress: Option<ImageCompression>,
+
     /// Overwrite existing image files. Use with caution!
     #[clap(long = "overwrite")]
     pub overwrite: bool,
@@ -153,13 +721,358 @@ pub struct CreateCommand {
     #[clap(long = "aur-helper", value_enum, default_value_t = AurHelper::Paru, ignore_case = true)]
     pub aur_helper: AurHelper,
 
-    /// Do not ask for confirmation (not supported for Omarchy or encryption)
+    /// Install the AUR helper from its upstream release binary, verified against the checksum in
+    /// its -bin AUR package, instead of building it with makepkg inside the installation
+    #[clap(long = "aur-helper-prebuilt")]
+    pub aur_helper_prebuilt: bool,
+
+    /// Build the AUR packages with a compiler cache kept in ~/.cache/alma across builds, which
+    /// speeds up rebuilding large packages. It is removed from the installation afterwards
+    #[clap(long = "compiler-cache", value_name = "CACHE", value_enum)]
+    pub compiler_cache: Option<CompilerCache>,
+
+    /// Do not install shim-signed from the AUR, producing a plain GRUB EFI install.
+    /// No AUR helper is installed unless other AUR packages are requested.
+    #[clap(long = "no-shim")]
+    pub no_shim: bool,
+
+    /// GRUB menu timeout in seconds
+    #[clap(long = "grub-timeout", value_name = "SECONDS")]
+    pub grub_timeout: Option<u32>,
+
+    /// Default GRUB menu entry (index, entry title or "saved")
+    #[clap(long = "grub-default", value_name = "ENTRY")]
+    pub grub_default: Option<String>,
+
+    /// Protect GRUB with a password for the "root" superuser: the menu entries still boot
+    /// without it, but editing them or opening the GRUB shell asks for it. The password is
+    /// prompted for, or read from $ALMA_GRUB_PASSWORD
+    #[clap(long = "grub-password")]
+    pub grub_password: bool,
+
+    /// Platforms to install GRUB for. Skipping BIOS avoids the i386-pc install, which fails on
+    /// some loop device setups, and skipping EFI also skips shim
+    #[clap(long = "grub-targets", value_enum, default_value_t = default_grub_targets())]
+    pub grub_targets: GrubTargets,
+
+    /// Extra kernel parameters for normal boots, e.g. "quiet splash"
+    #[clap(long = "kernel-cmdline", value_name = "PARAMETERS")]
+    pub kernel_cmdline: Option<String>,
+
+    /// TOML profile of the single board computer the disk boots, naming the U-Boot/SPL images to
+    /// write at raw offsets or to the eMMC boot areas and the device tree to load
+    #[clap(long = "board", value_name = "PROFILE", value_parser = crate::board::load)]
+    pub board: Option<BoardProfile>,
+
+    /// Include the autodetect hook in the initramfs. This shrinks the initramfs and speeds up
+    /// booting, but the system will only boot on hardware similar to the host running ALMA.
+    /// Only use this for installs targeting a single machine.
+    #[clap(long = "initramfs-autodetect")]
+    pub initramfs_autodetect: bool,
+
+    /// The tool used to generate the initramfs images
+    #[clap(long = "initramfs", value_enum, default_value_t = InitramfsGenerator::Mkinitcpio)]
+    pub initramfs: InitramfsGenerator,
+
+    /// Use the systemd based mkinitcpio hooks (systemd, sd-vconsole, sd-encrypt) instead of the
+    /// busybox based ones. Required for unlocking an encrypted root with a TPM or FIDO2 token.
+    #[clap(long = "systemd-initramfs")]
+    pub systemd_initramfs: bool,
+
+    /// Create a swap file and resume from it on boot, enabling hibernation (suspend-to-disk)
+    #[clap(long = "hibernate")]
+    pub hibernate: bool,
+
+    /// Boot with the root filesystem as the read-only lower layer of an overlay, whose upper
+    /// layer is a tmpfs, so every change to / is lost on reboot. Needs --systemd-initramfs with
+    /// mkinitcpio
+    #[clap(
+        long = "volatile-root",
+        conflicts_with_all = ["hibernate", "swapfile"]
+    )]
+    pub volatile_root: bool,
+
+    /// Install cloud-init and sshd, so the image sets up its users, ssh keys and hostname from
+    /// the metadata of the cloud it boots on
+    #[clap(long = "cloud-init")]
+    pub cloud_init: bool,
+
+    /// Embed a NoCloud seed, a directory with user-data, meta-data and optionally network-config,
+    /// so the image configures itself when booted outside of a cloud
+    #[clap(long = "cloud-init-seed", value_name = "DIR", requires = "cloud_init")]
+    pub cloud_init_seed: Option<PathBuf>,
+
+    /// Disable copy-on-write (chattr +C) for files created in this directory, e.g. for VM images
+    /// or databases. Only applies to btrfs. Can be given multiple times
+    #[clap(long = "nodatacow", value_name = "DIR")]
+    pub nodatacow: Vec<PathBuf>,
+
+    /// Label of the root filesystem (at most 16 bytes on ext4)
+    #[clap(long = "root-label", value_name = "LABEL", default_value = DEFAULT_ROOT_LABEL)]
+    pub root_label: String,
+
+    /// Label of the boot filesystem, which is also the EFI system partition (at most 11 ASCII
+    /// characters)
+    #[clap(long = "boot-label", value_name = "LABEL", default_value = DEFAULT_BOOT_LABEL)]
+    pub boot_label: String,
+
+    /// Refer to the filesystems by label instead of UUID in fstab, so it keeps working when the
+    /// filesystems are recreated with new UUIDs
+    #[clap(long = "fstab-labels")]
+    pub fstab_labels: bool,
+
+    /// Let the wheel group run COMMAND (with its full path, and optionally arguments) without a
+    /// password. Can be given multiple times
+    #[clap(long = "sudo-nopasswd", value_name = "COMMAND")]
+    pub sudo_nopasswd: Vec<String>,
+
+    /// Minutes before sudo asks for the password again (0 always asks)
+    #[clap(long = "sudo-timeout", value_name = "MINUTES")]
+    pub sudo_timeout: Option<u32>,
+
+    /// When sudo shows its lecture about using root privileges responsibly
+    #[clap(long = "sudo-lecture", value_enum)]
+    pub sudo_lecture: Option<SudoLecture>,
+
+    /// Install sudo-rs, a memory safe implementation of sudo, and make it the default sudo
+    #[clap(long = "sudo-rs")]
+    pub sudo_rs: bool,
+
+    /// Message of the day shown after logging in (/etc/motd): a file, or the template text
+    /// itself. {{image_name}}, {{build_date}}, {{manifest_hash}}, {{manifest_hash_short}} and
+    /// {{alma_version}} are replaced with the build metadata
+    #[clap(long = "motd", value_name = "FILE_OR_TEMPLATE")]
+    pub motd: Option<String>,
+
+    /// Message shown above the login prompt (/etc/issue), templated like --motd
+    #[clap(long = "issue", value_name = "FILE_OR_TEMPLATE")]
+    pub issue: Option<String>,
+
+    /// Name identifying the image in --motd and --issue [default: the file name of the target]
+    #[clap(long = "image-name", value_name = "NAME")]
+    pub image_name: Option<String>,
+
+    /// Comma separated mount options for the root filesystem (and its btrfs subvolumes), e.g.
+    /// "noatime,discard=async,commit=120". Replaces the defaults ("noatime", plus
+    /// "compress=zstd:3" on btrfs) and ends up in fstab. Overrides the mount_options of presets
+    #[clap(long = "mount-options", value_name = "OPTIONS")]
+    pub mount_options: Option<String>,
+
+    /// Create a swap file of the given size. On btrfs it is placed in a dedicated @swap
+    /// subvolume. Raw numbers are treated as MiB. [default with --hibernate: 4GiB]
+    #[clap(
+        long = "swapfile",
+        alias = "swap-size",
+        value_name = "SIZE_WITH_UNIT",
+        value_parser = parse_bytes
+    )]
+    pub swapfile: Option<Byte>,
+
+    /// Mount /tmp as a tmpfs of at most the given size, to spare flash media from temporary
+    /// files. Raw numbers are treated as MiB
+    #[clap(long = "tmpfs", value_name = "SIZE_WITH_UNIT", value_parser = parse_bytes)]
+    pub tmpfs: Option<Byte>,
+
+    /// Also mount /var/tmp as a tmpfs of the --tmpfs size. Its files then no longer survive a
+    /// reboot
+    #[clap(long = "tmpfs-var-tmp", requires = "tmpfs")]
+    pub tmpfs_var_tmp: bool,
+
+    /// Extra kernel module to add to MODULES in mkinitcpio.conf. Can be given multiple times
+    #[clap(long = "initramfs-module", value_name = "MODULE")]
+    pub initramfs_modules: Vec<String>,
+
+    /// Extra binary to add to BINARIES in mkinitcpio.conf. Can be given multiple times
+    #[clap(long = "initramfs-binary", value_name = "BINARY")]
+    pub initramfs_binaries: Vec<String>,
+
+    /// Extra file to add to FILES in mkinitcpio.conf. Can be given multiple times
+    #[clap(long = "initramfs-file", value_name = "FILE")]
+    pub initramfs_files: Vec<String>,
+
+    /// Extra mkinitcpio hook, inserted before the filesystems hook. Can be given multiple times
+    #[clap(long = "initramfs-hook", value_name = "HOOK")]
+    pub initramfs_hooks: Vec<String>,
+
+    /// mkinitcpio compression, optionally with a level, e.g. "lz4" or "zstd:19"
+    #[clap(long = "initramfs-compression", value_name = "ALGORITHM[:LEVEL]")]
+    pub initramfs_compression: Option<Compression>,
+
+    /// Install pacman hooks that keep the initramfs and removable GRUB/shim installation
+    /// hardware-independent across system upgrades
+    #[clap(long = "pacman-hooks")]
+    pub pacman_hooks: bool,
+
+    /// Only warn instead of failing when host tools are older than the minimum supported versions
+    #[clap(long = "ignore-tool-versions")]
+    pub ignore_tool_versions: bool,
+
+    /// Skip the given phases, e.g. --skip aur,presets
+    #[clap(
+        long = "skip",
+        value_enum,
+        value_delimiter = ',',
+        conflicts_with = "only"
+    )]
+    pub skip: Vec<Phase>,
+
+    /// Only run the given phases, e.g. --only bootloader. Unless the partition phase is included,
+    /// the existing partitions of a previous run on the same target are reused
+    #[clap(long = "only", value_enum, value_delimiter = ',')]
+    pub only: Vec<Phase>,
+
+    /// Continue a failed build on the same target after the last phase it completed, as recorded
+    /// on the target
+    #[clap(
+        long = "resume",
+        requires = "path",
+        conflicts_with_all = ["skip", "only", "iso", "from_image", "root_partition"]
+    )]
+    pub resume: bool,
+
+    /// Before wiping the device (and the --raid1 mirror), save its lsblk listing and a backup of
+    /// its partition table, to restore with sgdisk --load-backup, in DIR
+    #[clap(long = "backup-table", value_name = "DIR")]
+    pub backup_table: Option<PathBuf>,
+
+    /// Leave the target mounted if the installation fails, and print the commands to clean it up,
+    /// so the partially built system can be inspected in place
+    #[clap(long = "keep-mounts-on-error")]
+    pub keep_mounts_on_error: bool,
+
+    /// Shell command run when a phase starts and when the installation succeeds or fails, with
+    /// $ALMA_EVENT (phase, success or failure), $ALMA_PHASE, $ALMA_MESSAGE and $ALMA_TARGET set
+    #[clap(long = "notify-cmd", value_name = "COMMAND")]
+    pub notify_cmd: Option<String>,
+
+    /// Show a desktop notification (with notify-send) when the installation succeeds or fails
+    #[clap(long = "notify-desktop")]
+    pub notify_desktop: bool,
+
+    /// Derive the disk, partition and filesystem UUIDs from SEED, clear /etc/machine-id and set
+    /// every file timestamp to $SOURCE_DATE_EPOCH, so builds from the same inputs can be compared
+    #[clap(long = "reproducible", value_name = "SEED")]
+    pub reproducible: Option<String>,
+
+    /// Fail at the given point (post-partition, mid-pacstrap or preset-N), to test the cleanup
+    /// and recovery paths
+    #[clap(long = "fail-at", value_name = "POINT", hide = true)]
+    pub fail_at: Option<FailPoint>,
+
+    /// Do not ask for confirmation (not supported for encryption, or for Omarchy without
+    /// --answers)
     #[clap(long = "noconfirm")]
     pub noconfirm: bool,
 
+    /// Read the user, hostname, password hash, timezone, locales and package selections from
+    /// this TOML file instead of the interactive setup, for unattended builds
+    #[clap(long = "answers", value_name = "ANSWERS_FILE")]
+    pub answers: Option<PathBuf>,
+
+    /// Create this user instead of asking for one in the interactive setup
+    #[clap(long = "username", value_name = "USERNAME")]
+    pub username: Option<String>,
+
+    /// Hostname of the system, with --username or --answers [default: alma-linux]
+    #[clap(long = "hostname", value_name = "HOSTNAME")]
+    pub hostname: Option<String>,
+
+    /// Timezone of the system, e.g. Europe/London, with --username or --answers [default: UTC]
+    #[clap(long = "timezone", value_name = "TIMEZONE")]
+    pub timezone: Option<String>,
+
+    /// Read the user's password from the first line of this file, e.g. /dev/stdin or /dev/fd/3,
+    /// with --username or --answers
+    #[clap(long = "password-file", value_name = "FILE")]
+    pub password_file: Option<PathBuf>,
+
     /// Print commands instead of executing them
     #[clap(long = "dryrun")]
     pub dryrun: bool,
+
+    /// Like --dryrun, and also write the commands and file changes to SCRIPT as a shell script,
+    /// to audit or run by hand
+    #[clap(
+        long = "dryrun-script",
+        value_name = "SCRIPT",
+        conflicts_with = "from_image"
+    )]
+    pub dryrun_script: Option<PathBuf>,
+
+    /// The format of the dry run plan: shell commands, or one JSON object per step
+    #[clap(
+        long = "output",
+        value_name = "FORMAT",
+        value_enum,
+        default_value_t = PlanFormat::Shell,
+        requires = "dryrun_mode"
+    )]
+    pub output: PlanFormat,
+
+    /// Settings used instead of prompting for them, set by `alma install` from the manifest
+    #[clap(skip)]
+    pub user_settings: Option<UserSettings>,
+
+    /// The PBKDF2 hash of the --grub-password, set once the password has been read
+    #[clap(skip)]
+    pub grub_password_hash: Option<String>,
+}
+
+impl CreateCommand {
+    /// Whether the given phase should run, according to --skip and --only
+    pub fn runs(&self, phase: Phase) -> bool {
+        !self.skip.contains(&phase) && (self.only.is_empty() || self.only.contains(&phase))
+    }
+
+    /// Whether shim is installed in front of the GRUB EFI loader
+    pub fn shim(&self) -> bool {
+        !self.no_shim && self.grub_targets.efi() && Arch::target().has_shim()
+    }
+
+    /// Size of the swap file in MiB, if one should be created
+    pub fn swap_size_mb(&self) -> Option<u64> {
+        self.swapfile
+            .map(|size| size.as_u64() / (1024 * 1024))
+            .or(self.hibernate.then_some(DEFAULT_SWAP_MB))
+    }
+
+    /// Unallocated space to leave at the end of the disk in MiB
+    pub fn leave_free_mb(&self) -> u64 {
+        self.leave_free
+            .map_or(0, |size| size.as_u64() / (1024 * 1024))
+    }
+
+    /// Size of the exFAT data partition in MiB, if one should be created
+    pub fn data_partition_mb(&self) -> Option<u64> {
+        self.data_partition
+            .map(|size| size.as_u64() / (1024 * 1024))
+    }
+
+    /// Size limit of the /tmp tmpfs in MiB, if one should be mounted
+    pub fn tmpfs_size_mb(&self) -> Option<u64> {
+        self.tmpfs.map(|size| size.as_u64() / (1024 * 1024))
+    }
+
+    /// The sudo settings given on the command line
+    pub fn sudo_options(&self) -> SudoOptions {
+        SudoOptions {
+            nopasswd_commands: self.sudo_nopasswd.clone(),
+            timeout: self.sudo_timeout,
+            lecture: self.sudo_lecture,
+            sudo_rs: self.sudo_rs,
+        }
+    }
+
+    /// The mkinitcpio.conf entries given on the command line
+    pub fn initcpio_options(&self) -> InitcpioOptions {
+        InitcpioOptions {
+            modules: self.initramfs_modules.clone(),
+            binaries: self.initramfs_binaries.clone(),
+            files: self.initramfs_files.clone(),
+            hooks: self.initramfs_hooks.clone(),
+            compression: self.initramfs_compression.clone(),
+        }
+    }
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -186,6 +1099,16 @@ pub struct InstallCommand {
     )]
     pub boot_partition: Option<PathBuf>,
 
+    /// Install to a raw image file of this size at TARGET_BLOCK_DEVICE instead, e.g. to archive
+    /// the current machine
+    #[clap(
+        long = "image",
+        value_name = "SIZE_WITH_UNIT",
+        requires = "target_device",
+        value_parser = parse_bytes
+    )]
+    pub image: Option<Byte>,
+
     /// Allow installation on non-removable devices. Use with extreme caution!
     #[clap(long = "allow-non-removable")]
     pub allow_non_removable: bool,
@@ -202,6 +1125,15 @@ pub struct ChrootCommand {
     pub block_device: PathBuf,
     #[clap(long = "allow-non-removable")]
     pub allow_non_removable: bool,
+    /// Open the LUKS container and mount the filesystems read-only, without replaying their
+    /// journals, so the device is inspected without being modified
+    #[clap(long = "read-only")]
+    pub read_only: bool,
+    /// Comma separated mount options for the root filesystem, replacing ALMA's defaults, e.g.
+    /// "subvol=@,compress=zstd:6". On btrfs, a subvol or subvolid option mounts that subvolume
+    /// alone instead of ALMA's @, @home, @log and @pkg layout
+    #[clap(long = "mount-options", alias = "mount-opts", value_name = "OPTIONS")]
+    pub mount_options: Option<String>,
     #[clap()]
     pub command: Vec<String>,
 }
@@ -211,11 +1143,425 @@ pub struct QemuCommand {
     /// Path to the ALMA system's block device or image file
     #[clap()]
     pub block_device: PathBuf,
+    /// Boot the kernel and initramfs of the default GRUB entry directly, bypassing the firmware
+    /// and bootloader for a faster boot
+    #[clap(long = "direct-kernel")]
+    pub direct_kernel: bool,
+    /// Comma separated mount options for the boot partition read by --direct-kernel, e.g.
+    /// "codepage=437,iocharset=ascii". It is always mounted read-only
+    #[clap(
+        long = "mount-options",
+        alias = "mount-opts",
+        value_name = "OPTIONS",
+        requires = "direct_kernel"
+    )]
+    pub mount_options: Option<String>,
+    /// Attach a further disk image or block device, e.g. a data drive. qcow2, vmdk and vdi
+    /// images are recognised by their extension, anything else is attached as raw. Repeatable
+    #[clap(long = "attach-disk", value_name = "PATH")]
+    pub attach_disks: Vec<PathBuf>,
+    /// Pass a USB device of the host through to the VM, identified by the vendor and product ID
+    /// shown by lsusb, e.g. 0781:5581. Repeatable
+    #[clap(long = "usb-passthrough", value_name = "VENDOR:PRODUCT")]
+    pub usb_passthrough: Vec<UsbId>,
     /// Arguments to pass to qemu
     #[clap()]
     pub args: Vec<String>,
 }
 
+#[derive(Parser, Debug, Clone)]
+pub struct ResizeCommand {
+    /// Path to the ALMA image file
+    #[clap(value_name = "IMAGE")]
+    pub image: PathBuf,
+    /// The new size of the image, which must be larger than the current one. Raw numbers are
+    /// treated as MiB
+    #[clap(value_name = "SIZE_WITH_UNIT", value_parser = parse_bytes)]
+    pub size: Byte,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct FixGptCommand {
+    /// The block device (or image file) an ALMA image was written to
+    #[clap(value_name = "DEVICE")]
+    pub device: PathBuf,
+    /// Also grow the root partition, the LUKS container (if any) and the filesystem to fill the
+    /// device
+    #[clap(long = "expand")]
+    pub expand: bool,
+    /// Allow repairing non-removable devices. Use with extreme caution!
+    #[clap(long = "allow-non-removable")]
+    pub allow_non_removable: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct RestoreGptCommand {
+    /// The block device whose partition table is restored
+    #[clap(value_name = "DEVICE")]
+    pub device: PathBuf,
+    /// The `.sgdisk` file saved by `alma create --backup-table`, or any `sgdisk --backup`
+    #[clap(value_name = "BACKUP")]
+    pub backup: PathBuf,
+    /// Allow restoring non-removable devices. Use with extreme caution!
+    #[clap(long = "allow-non-removable")]
+    pub allow_non_removable: bool,
+    /// Do not ask for confirmation before replacing the partition table
+    #[clap(long = "noconfirm")]
+    pub noconfirm: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct SeedCommand {
+    /// The image file or block device of the master system, which must have a btrfs root
+    /// filesystem. It can only be mounted read-only afterwards
+    #[clap(value_name = "MASTER")]
+    pub master: PathBuf,
+    /// Allow marking non-removable devices. Use with extreme caution!
+    #[clap(long = "allow-non-removable")]
+    pub allow_non_removable: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct SproutCommand {
+    /// The image file or block device marked with `alma seed`
+    #[clap(value_name = "SEED")]
+    pub seed: PathBuf,
+    /// The block device to create the copy on, at least as large as the seed
+    #[clap(value_name = "DEVICE")]
+    pub device: PathBuf,
+    /// Allow writing to non-removable devices. Use with extreme caution!
+    #[clap(long = "allow-non-removable")]
+    pub allow_non_removable: bool,
+    /// Do not ask for confirmation before wiping the device
+    #[clap(long = "noconfirm")]
+    pub noconfirm: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct CloneCommand {
+    /// The ALMA system's block device or image file
+    #[clap(value_name = "SOURCE")]
+    pub source: PathBuf,
+    /// The block device to copy it to, at least as large as the source
+    #[clap(value_name = "DEVICE")]
+    pub device: PathBuf,
+    /// Grow the root partition and filesystem of the copy to fill the device
+    #[clap(long = "expand")]
+    pub expand: bool,
+    /// Allow reading from and writing to non-removable devices. Use with extreme caution!
+    #[clap(long = "allow-non-removable")]
+    pub allow_non_removable: bool,
+    /// Do not ask for confirmation before wiping the device
+    #[clap(long = "noconfirm")]
+    pub noconfirm: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct UpdateCommand {
+    /// Path to the ALMA system's block device or image file
+    #[clap(value_name = "DEVICE")]
+    pub block_device: PathBuf,
+    /// Only upgrade the repository packages, not the AUR ones
+    #[clap(long = "no-aur")]
+    pub no_aur: bool,
+    #[clap(long = "allow-non-removable")]
+    pub allow_non_removable: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct InfoCommand {
+    /// Path to the ALMA system's block device or image file
+    #[clap(value_name = "DEVICE")]
+    pub block_device: PathBuf,
+    /// Print the summary as JSON
+    #[clap(long = "json")]
+    pub json: bool,
+    #[clap(long = "allow-non-removable")]
+    pub allow_non_removable: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct BackupCommand {
+    /// The block device of the ALMA system
+    #[clap(value_name = "DEVICE")]
+    pub device: PathBuf,
+    /// The image file to write. A .zst or .xz extension compresses it with zstd or xz
+    #[clap(value_name = "IMAGE")]
+    pub image: PathBuf,
+    /// Shrink the ext4 root filesystem and its partition to the space in use, so the image is
+    /// only as large as the data. Not available with a data partition
+    #[clap(long = "shrink")]
+    pub shrink: bool,
+    /// Allow reading from non-removable devices
+    #[clap(long = "allow-non-removable")]
+    pub allow_non_removable: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct FlashCommand {
+    /// The image file to write, e.g. one built with `alma create --image`
+    #[clap(value_name = "IMAGE")]
+    pub image: PathBuf,
+    /// The block device to write to, at least as large as the image
+    #[clap(value_name = "DEVICE")]
+    pub device: PathBuf,
+    /// Do not read the device back to compare its checksum with the image's
+    #[clap(long = "no-verify")]
+    pub no_verify: bool,
+    /// Allow writing to non-removable devices. Use with extreme caution!
+    #[clap(long = "allow-non-removable")]
+    pub allow_non_removable: bool,
+    /// Do not ask for confirmation before wiping the device
+    #[clap(long = "noconfirm")]
+    pub noconfirm: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct ScheduleCommand {
+    /// Name of the schedule, used for the systemd units (alma-NAME.timer) and the image files
+    #[clap(value_name = "NAME")]
+    pub name: String,
+    /// Configuration file the images are built from, see `alma create --config`
+    #[clap(long = "config", value_name = "CONFIG_FILE")]
+    pub config: PathBuf,
+    /// Size of the images
+    #[clap(long = "image", value_name = "SIZE_WITH_UNIT", value_parser = parse_bytes)]
+    pub image: Byte,
+    /// Directory the images are written to, as NAME-YYYYMMDD-HHMM.img
+    #[clap(long = "output-dir", value_name = "DIR")]
+    pub output_dir: PathBuf,
+    /// Number of images to keep, older ones are removed after each build
+    #[clap(long = "keep", value_name = "COUNT", default_value_t = 4)]
+    pub keep: usize,
+    /// When to rebuild, as a systemd calendar event
+    #[clap(
+        long = "on-calendar",
+        value_name = "CALENDAR",
+        default_value = "weekly"
+    )]
+    pub on_calendar: String,
+    /// Build an image and rotate the old ones now, instead of installing the timer. This is what
+    /// the timer runs
+    #[clap(long = "run")]
+    pub run: bool,
+    /// Print the units and commands without installing anything
+    #[clap(long = "dryrun")]
+    pub dryrun: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct ExportCommand {
+    #[clap(subcommand)]
+    pub format: ExportFormat,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub enum ExportFormat {
+    #[clap(name = "vagrant", about = "Package an image file as a Vagrant box")]
+    Vagrant(VagrantExportCommand),
+}
+
+/// The Vagrant providers a box can be made for
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VagrantProvider {
+    /// vagrant-libvirt, with a qcow2 disk
+    #[default]
+    Libvirt,
+    /// VirtualBox, with an OVF machine and a VMDK disk
+    Virtualbox,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct VagrantExportCommand {
+    /// The image file built with `alma create --image`. Vagrant logs in as the "vagrant" user,
+    /// which presets/vagrant.toml sets up
+    #[clap(value_name = "IMAGE")]
+    pub image: PathBuf,
+    /// The provider the box is made for
+    #[clap(long = "provider", value_enum, default_value_t = VagrantProvider::Libvirt)]
+    pub provider: VagrantProvider,
+    /// The box file to write [default: IMAGE-PROVIDER.box, next to the image]
+    #[clap(long = "output", value_name = "BOX_FILE")]
+    pub output: Option<PathBuf>,
+    /// Memory of the virtual machine, in MiB
+    #[clap(long = "memory", value_name = "MIB", default_value_t = 2048)]
+    pub memory: u32,
+    /// Number of virtual CPUs
+    #[clap(long = "cpus", value_name = "COUNT", default_value_t = 2)]
+    pub cpus: u32,
+    /// Print the commands without running them
+    #[clap(long = "dryrun")]
+    pub dryrun: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct CleanupCommand {
+    /// Print the commands without running them
+    #[clap(long = "dryrun")]
+    pub dryrun: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct ListDevicesCommand {
+    /// Also list the non-removable disks
+    #[clap(long = "all")]
+    pub all: bool,
+    /// Print the devices as JSON
+    #[clap(long = "json")]
+    pub json: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct GcCommand {
+    /// Only remove the git history of the sources, which keeps them usable by alma install
+    #[clap(long = "history-only")]
+    pub history_only: bool,
+    /// Do not ask for confirmation
+    #[clap(long = "noconfirm")]
+    pub noconfirm: bool,
+    /// Print the commands without running them
+    #[clap(long = "dryrun")]
+    pub dryrun: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct DoctorCommand {
+    /// Skip the network reachability checks
+    #[clap(long = "no-network")]
+    pub no_network: bool,
+    /// Install the missing and outdated packages which alma create requires with pacman
+    #[clap(long = "install")]
+    pub install: bool,
+}
+
+/// Returns the ALMA configuration directory, i.e. `$XDG_CONFIG_HOME/alma` or `~/.config/alma`.
+pub fn config_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|dir| dir.join("alma"))
+}
+
+/// Returns the ALMA cache directory, i.e. `$XDG_CACHE_HOME/alma` or `~/.cache/alma`.
+pub fn cache_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .map(|dir| dir.join("alma"))
+}
+
+/// The system-wide configuration file, under the user's
+pub const SYSTEM_CONFIG_PATH: &str = "/etc/alma/config.toml";
+
+/// Defaults for frequently used flags, read from `config.toml` in the config directory and
+/// [`SYSTEM_CONFIG_PATH`]. Values given on the command line always take precedence.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub allow_non_removable: Option<bool>,
+    pub aur_helper: Option<String>,
+    pub extra_packages: Option<Vec<String>>,
+    pub presets: Option<Vec<String>>,
+    pub pacman_conf: Option<PathBuf>,
+    pub boot_size: Option<String>,
+}
+
+impl Config {
+    /// Loads the given configuration file, or else the user configuration file over the system
+    /// one, returning an empty configuration if neither exists.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        if let Some(path) = path {
+            return Self::from_file(path);
+        }
+        let mut config = Self::default();
+        let user_path = config_dir().map(|dir| dir.join("config.toml"));
+        for path in user_path
+            .iter()
+            .map(PathBuf::as_path)
+            .chain([Path::new(SYSTEM_CONFIG_PATH)])
+        {
+            if path.exists() {
+                config = config.or(Self::from_file(path)?);
+            }
+        }
+        Ok(config)
+    }
+
+    /// Fills in the values missing from this configuration from `base`
+    fn or(self, base: Self) -> Self {
+        Self {
+            allow_non_removable: self.allow_non_removable.or(base.allow_non_removable),
+            aur_helper: self.aur_helper.or(base.aur_helper),
+            extra_packages: self.extra_packages.or(base.extra_packages),
+            presets: self.presets.or(base.presets),
+            pacman_conf: self.pacman_conf.or(base.pacman_conf),
+            boot_size: self.boot_size.or(base.boot_size),
+        }
+    }
+
+    fn from_file(path: &Path) -> anyhow::Result<Self> {
+        debug!("Loading configuration from {}", path.display());
+        let data = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        toml::from_str(&data)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))
+    }
+
+    /// Merges the configuration under the values explicitly given on the command line.
+    fn apply_to_create(
+        self,
+        command: &mut CreateCommand,
+        matches: &ArgMatches,
+    ) -> anyhow::Result<()> {
+        let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+        if let Some(allow_non_removable) = self.allow_non_removable
+            && !from_cli("allow_non_removable")
+        {
+            command.allow_non_removable = allow_non_removable;
+        }
+
+        if let Some(aur_helper) = self.aur_helper
+            && !from_cli("aur_helper")
+        {
+            command.aur_helper = aur_helper.to_lowercase().parse()?;
+        }
+
+        if let Some(extra_packages) = self.extra_packages
+            && !from_cli("extra_packages")
+        {
+            command.extra_packages = extra_packages;
+        }
+
+        if let Some(presets) = self.presets
+            && !from_cli("presets")
+        {
+            command.presets = presets
+                .iter()
+                .map(|p| parse_presets_path(p))
+                .collect::<anyhow::Result<Vec<PresetsPath>>>()
+                .context("Invalid presets path in config file")?;
+        }
+
+        if let Some(pacman_conf) = self.pacman_conf
+            && !from_cli("pacman_conf")
+        {
+            command.pacman_conf = Some(pacman_conf);
+        }
+
+        if let Some(boot_size) = self.boot_size
+            && !from_cli("boot_size")
+        {
+            command.boot_size =
+                Some(parse_bytes(&boot_size).context("Invalid boot_size in config file")?);
+        }
+
+        Ok(())
+    }
+}
+
 // Structs for the manifest file
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Manifest {
@@ -223,16 +1569,113 @@ pub struct Manifest {
     pub system_variant: SystemVariant,
     pub filesystem: RootFilesystemType,
     pub encrypted_root: bool,
+    #[serde(default = "default_kernels")]
+    pub kernels: Vec<Kernel>,
     pub aur_helper: String,
+    #[serde(default)]
+    pub no_shim: bool,
+    #[serde(default)]
+    pub pacman_hooks: bool,
+    #[serde(default)]
+    pub grub_timeout: Option<u32>,
+    #[serde(default)]
+    pub grub_default: Option<String>,
+    #[serde(default)]
+    pub grub_password: bool,
+    #[serde(default)]
+    pub grub_targets: GrubTargets,
+    #[serde(default)]
+    pub kernel_cmdline: Option<String>,
+    #[serde(default)]
+    pub board: Option<BoardProfile>,
+    #[serde(default)]
+    pub initramfs_autodetect: bool,
+    #[serde(default)]
+    pub initramfs: InitramfsGenerator,
+    #[serde(default)]
+    pub initcpio: InitcpioOptions,
+    #[serde(default)]
+    pub systemd_initramfs: bool,
+    #[serde(default)]
+    pub hibernate: bool,
+    #[serde(default)]
+    pub swap_size_mb: Option<u64>,
+    #[serde(default)]
+    pub nodatacow: Vec<PathBuf>,
+    #[serde(default)]
+    pub leave_free_mb: u64,
+    #[serde(default)]
+    pub refresh: RefreshPolicy,
+    #[serde(default)]
+    pub data_partition_mb: Option<u64>,
+    #[serde(default)]
+    pub raid1: bool,
+    #[serde(default)]
+    pub partitioner: Partitioner,
+    #[serde(default)]
+    pub volatile_root: bool,
+    #[serde(default)]
+    pub iso: bool,
+    #[serde(default)]
+    pub cloud_init: bool,
+    #[serde(default)]
+    pub keymap: Option<String>,
+    #[serde(default)]
+    pub locales: Vec<String>,
+    /// The /dev/disk/by-id link of the disk the system was installed to, none for images
+    #[serde(default)]
+    pub device_id: Option<PathBuf>,
+    /// GPT type GUIDs of the partitions ALMA created, by partition number
+    #[serde(default)]
+    pub partition_types: BTreeMap<u8, String>,
+    #[serde(default)]
+    pub tmpfs_size_mb: Option<u64>,
+    #[serde(default)]
+    pub tmpfs_var_tmp: bool,
+    #[serde(default)]
+    pub mount_options: Option<String>,
+    #[serde(default = "default_root_label")]
+    pub root_label: String,
+    #[serde(default = "default_boot_label")]
+    pub boot_label: String,
+    #[serde(default)]
+    pub fstab_labels: bool,
+    #[serde(default)]
+    pub bake_shallow: bool,
+    #[serde(default)]
+    pub sudo: SudoOptions,
+    /// The --motd and --issue templates
+    #[serde(default)]
+    pub motd: Option<String>,
+    #[serde(default)]
+    pub issue: Option<String>,
+    #[serde(default)]
+    pub image_name: Option<String>,
+    #[serde(default)]
+    pub aur_failures: Vec<AurFailure>,
+    /// The interactive settings the system was created with, without the password
+    #[serde(default)]
+    pub user_settings: Option<UserSettings>,
     pub original_command: String,
     pub sources: Vec<Source>,
 }
 
+/// A batch of AUR packages which failed to install with --continue-on-aur-failure
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AurFailure {
+    pub batch: String,
+    pub packages: Vec<String>,
+    pub error: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Source {
     pub r#type: String,      // "preset" or "system"
     pub origin: String,      // URL or original local path
     pub baked_path: PathBuf, // Path inside the image
+    /// The commit baked, for sources cloned from git
+    #[serde(default)]
+    pub commit: Option<String>,
 }
 
 #[cfg(test)]
@@ -308,4 +1751,197 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_output_requires_dryrun() {
+        assert!(App::try_parse_from(["alma", "create", "--output", "json", "/path/test"]).is_err());
+        assert!(
+            App::try_parse_from([
+                "alma",
+                "create",
+                "--dryrun",
+                "--output",
+                "json",
+                "/path/test"
+            ])
+            .is_ok()
+        );
+        assert!(
+            App::try_parse_from([
+                "alma",
+                "create",
+                "--dryrun-script",
+                "plan.sh",
+                "--output",
+                "json",
+                "/path/test"
+            ])
+            .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_config_merged_under_cli() {
+        let config: Config = toml::from_str(
+            r#"
+            allow_non_removable = true
+            aur_helper = "yay"
+            extra_packages = ["vim", "htop"]
+            boot_size = "512"
+            "#,
+        )
+        .unwrap();
+        let system: Config = toml::from_str(
+            r#"
+            aur_helper = "pikaur"
+            pacman_conf = "/etc/alma/pacman.conf"
+            "#,
+        )
+        .unwrap();
+        let config = config.or(system);
+
+        let matches = App::command()
+            .try_get_matches_from(["alma", "create", "--aur-helper", "paru", "/path/test"])
+            .unwrap();
+        let mut app = App::from_arg_matches(&matches).unwrap();
+        let (_, sub_matches) = matches.subcommand().unwrap();
+        if let Command::Create(cmd) = &mut app.cmd {
+            config.apply_to_create(cmd, sub_matches).unwrap();
+            assert!(cmd.allow_non_removable);
+            assert!(matches!(cmd.aur_helper, AurHelper::Paru));
+            assert_eq!(cmd.extra_packages, vec!["vim", "htop"]);
+            assert!(cmd.presets.is_empty());
+            assert_eq!(
+                cmd.pacman_conf,
+                Some(PathBuf::from("/etc/alma/pacman.conf"))
+            );
+            assert_eq!(cmd.boot_size, Some(Byte::from_u64(512 * 1024 * 1024)));
+        } else {
+            panic!("was not Create command")
+        }
+    }
+
+    #[test]
+    fn test_package_lists() {
+        let dir = tempfile::tempdir().unwrap();
+        let list = dir.path().join("packages.txt");
+        fs::write(
+            &list,
+            "# Editors\nvim\nneovim  # for the plugins\n\n  htop tmux\n#git\n",
+        )
+        .unwrap();
+        let packages = [
+            String::from("firefox"),
+            format!("@{}", list.display()),
+            String::from("mpv"),
+        ];
+        assert_eq!(
+            expand_package_lists(&packages).unwrap(),
+            ["firefox", "vim", "neovim", "htop", "tmux", "mpv"]
+        );
+        assert!(expand_package_lists(&[String::from("@/nonexistent/packages.txt")]).is_err());
+    }
+
+    #[test]
+    fn test_phase_selection() {
+        let parse = |args: &[&str]| match App::try_parse_from(args).unwrap().cmd {
+            Command::Create(command) => command,
+            _ => panic!("Expected the create command"),
+        };
+
+        let command = parse(&["alma", "create", "--skip", "aur,presets", "/dev/sdz"]);
+        assert!(command.runs(Phase::Bootstrap));
+        assert!(!command.runs(Phase::Aur));
+        assert!(!command.runs(Phase::Presets));
+
+        let command = parse(&["alma", "create", "--only", "bootloader", "/dev/sdz"]);
+        assert!(command.runs(Phase::Bootloader));
+        assert!(!command.runs(Phase::Partition));
+
+        assert!(
+            App::try_parse_from(["alma", "create", "--skip", "aur", "--only", "bake"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_swap_size() {
+        let parse = |args: &[&str]| match App::try_parse_from(args).unwrap().cmd {
+            Command::Create(command) => command,
+            _ => panic!("Expected the create command"),
+        };
+
+        assert_eq!(parse(&["alma", "create", "/dev/sdz"]).swap_size_mb(), None);
+        assert_eq!(
+            parse(&["alma", "create", "--hibernate", "/dev/sdz"]).swap_size_mb(),
+            Some(DEFAULT_SWAP_MB)
+        );
+        assert_eq!(
+            parse(&["alma", "create", "--swapfile", "2GiB", "/dev/sdz"]).swap_size_mb(),
+            Some(2048)
+        );
+    }
+
+    #[test]
+    fn test_refresh_policy() {
+        let parse = |args: &[&str]| match App::try_parse_from(args).unwrap().cmd {
+            Command::Create(command) => command.refresh,
+            _ => panic!("Expected the create command"),
+        };
+
+        assert_eq!(parse(&["alma", "create", "/dev/sdz"]), RefreshPolicy::Auto);
+        assert_eq!(
+            parse(&["alma", "create", "--refresh", "never", "/dev/sdz"]),
+            RefreshPolicy::Never
+        );
+        assert!(
+            App::try_parse_from(["alma", "create", "--refresh", "always", "/dev/sdz"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_preallocate_requires_image() {
+        assert!(
+            App::try_parse_from(["alma", "create", "--preallocate", "full", "/dev/sdz"]).is_err()
+        );
+        let app = App::try_parse_from([
+            "alma",
+            "create",
+            "--image",
+            "1GiB",
+            "--preallocate",
+            "falloc",
+            "test.img",
+        ])
+        .unwrap();
+        match app.cmd {
+            Command::Create(command) => assert_eq!(command.preallocate, Preallocation::Falloc),
+            _ => panic!("Expected the create command"),
+        }
+    }
+
+    #[test]
+    fn test_kernel_parsing() {
+        let app = App::try_parse_from(["alma", "create", "/path/test"]).unwrap();
+        if let Command::Create(cmd) = app.cmd {
+            assert_eq!(cmd.kernels, vec![Arch::target().default_kernel()]);
+        } else {
+            panic!("was not Create command")
+        }
+
+        let app = App::try_parse_from([
+            "alma",
+            "create",
+            "--kernel",
+            "linux-lts",
+            "--kernel",
+            "linux-zen",
+            "/path/test",
+        ])
+        .unwrap();
+        if let Command::Create(cmd) = app.cmd {
+            assert_eq!(cmd.kernels, vec![Kernel::LinuxLts, Kernel::LinuxZen]);
+        } else {
+            panic!("was not Create command")
+        }
+    }
 }
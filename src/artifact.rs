@@ -0,0 +1,304 @@
+//! Files written next to image files: metadata, so CI and artifact registries can index an
+//! image without mounting it, and virtual machine disks and compressed copies made from the
+//! image.
+
+use crate::args::{DiskFormat, ImageCompression};
+use crate::constants::MIN_TOOL_VERSIONS;
+use crate::effects;
+use crate::plan::{self, Action};
+use crate::process::CommandExt;
+use crate::tool::Tool;
+use anyhow::Context;
+use byte_unit::{Byte, UnitType};
+use clap::ValueEnum;
+use log::{debug, info};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[derive(Debug, Serialize)]
+pub struct ArtifactMetadata {
+    pub image: String,
+    /// Apparent size of the image in bytes
+    pub size: u64,
+    /// Bytes actually allocated on disk, smaller than the size for sparse images
+    pub allocated: u64,
+    pub checksums: BTreeMap<&'static str, String>,
+    pub build_duration_secs: u64,
+    pub alma_version: &'static str,
+    /// Versions of the host tools which shaped the image
+    pub tool_versions: BTreeMap<&'static str, String>,
+    /// The manifest stored in the image, unless the manifest phase was skipped
+    pub manifest: Option<serde_json::Value>,
+}
+
+/// `<image>.json`, next to the image
+pub fn metadata_path(image: &Path) -> PathBuf {
+    with_suffix(image, ".json")
+}
+
+/// `<image>.zst` or `<image>.xz`, next to the image
+pub fn compressed_path(image: &Path, compression: ImageCompression) -> PathBuf {
+    with_suffix(image, &format!(".{}", compression.extension()))
+}
+
+/// The compression `compressed_path` gives `path`, judging by its extension
+pub fn compression_of(path: &Path) -> Option<ImageCompression> {
+    let extension = path.extension()?;
+    ImageCompression::value_variants()
+        .iter()
+        .copied()
+        .find(|compression| extension == compression.extension())
+}
+
+/// `<file>.sha256`, which `sha256sum -c` checks
+pub fn checksum_path(file: &Path) -> PathBuf {
+    with_suffix(file, ".sha256")
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path
+        .file_name()
+        .map_or_else(OsString::new, |n| n.to_os_string());
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Writes the metadata of a finished image, returning the path of the metadata file
+pub fn write_metadata(
+    image: &Path,
+    manifest_json: Option<&str>,
+    build_duration: Duration,
+) -> anyhow::Result<PathBuf> {
+    info!("Computing the checksum of {}", image.display());
+    let file_metadata =
+        fs::metadata(image).with_context(|| format!("Cannot access {}", image.display()))?;
+    let mut checksums = BTreeMap::new();
+    checksums.insert("sha256", sha256_file(image)?);
+
+    let metadata = ArtifactMetadata {
+        image: image
+            .file_name()
+            .map_or_else(String::new, |n| n.to_string_lossy().into_owned()),
+        size: file_metadata.len(),
+        allocated: file_metadata.blocks() * 512,
+        checksums,
+        build_duration_secs: build_duration.as_secs(),
+        alma_version: env!("CARGO_PKG_VERSION"),
+        tool_versions: tool_versions(),
+        manifest: manifest_json
+            .map(serde_json::from_str)
+            .transpose()
+            .context("Invalid manifest")?,
+    };
+
+    let path = metadata_path(image);
+    fs::write(&path, serde_json::to_string_pretty(&metadata)?)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(path)
+}
+
+/// A summary of the apparent size and the allocated space of an image, with a warning about
+/// copying it when it is sparse
+pub fn size_report(image: &Path) -> anyhow::Result<String> {
+    let metadata =
+        fs::metadata(image).with_context(|| format!("Cannot access {}", image.display()))?;
+    Ok(describe_size(
+        image,
+        metadata.len(),
+        metadata.blocks() * 512,
+    ))
+}
+
+fn describe_size(image: &Path, size: u64, allocated: u64) -> String {
+    let unit = |bytes: u64| {
+        format!(
+            "{:.2}",
+            Byte::from_u64(bytes).get_appropriate_unit(UnitType::Binary)
+        )
+    };
+    if allocated >= size {
+        return format!("{} is {}, fully allocated", image.display(), unit(size));
+    }
+    format!(
+        "{} is {} but only {} are allocated. It is sparse, so copies made without \
+         `cp --sparse=always`, `rsync --sparse` or `alma flash` take up the full size",
+        image.display(),
+        unit(size),
+        unit(allocated)
+    )
+}
+
+/// The image with the extension of the disk format, like alma.img to alma.qcow2
+pub fn converted_path(image: &Path, format: DiskFormat) -> PathBuf {
+    image.with_extension(format.name())
+}
+
+/// Converts a finished raw image to a virtual machine disk, returning the path of the disk
+pub fn convert(
+    qemu_img: &Tool,
+    image: &Path,
+    format: DiskFormat,
+    dryrun: bool,
+) -> anyhow::Result<PathBuf> {
+    let disk = converted_path(image, format);
+    info!("Converting {} to {}", image.display(), disk.display());
+    qemu_img
+        .execute()
+        .args(["convert", "-f", "raw", "-O", format.name()])
+        .arg(image)
+        .arg(&disk)
+        .run(dryrun)
+        .with_context(|| format!("Failed to convert the image to {}", format.name()))?;
+    Ok(disk)
+}
+
+/// Compresses a finished image next to it and writes the checksum of the compressed file,
+/// returning its path. The compressor streams the image, so the only extra space needed is that
+/// of the compressed file.
+pub fn compress(
+    compressor: &Tool,
+    image: &Path,
+    compression: ImageCompression,
+    dryrun: bool,
+) -> anyhow::Result<PathBuf> {
+    let compressed = compressed_path(image, compression);
+    info!(
+        "Compressing {} to {}",
+        image.display(),
+        compressed.display()
+    );
+    // Both write <image>.<extension>, keeping the image
+    compressor
+        .execute()
+        .args(["-T0", "--keep", "--force", "--quiet"])
+        .arg(image)
+        .run(dryrun)
+        .with_context(|| format!("Failed to compress the image with {}", compression.name()))?;
+
+    let checksum_file = checksum_path(&compressed);
+    if dryrun {
+        plan::print(Action::Command {
+            argv: vec![
+                String::from("sha256sum"),
+                compressed.to_string_lossy().into_owned(),
+            ],
+            env: Default::default(),
+            stdout: Some(checksum_file.clone()),
+        });
+    } else {
+        let name = compressed
+            .file_name()
+            .map_or_else(String::new, |n| n.to_string_lossy().into_owned());
+        effects::write(
+            &checksum_file,
+            checksum_line(&sha256_file(&compressed)?, &name),
+            false,
+        )?;
+    }
+    Ok(compressed)
+}
+
+/// A line in the format of sha256sum, with the file name relative to the checksum file
+fn checksum_line(checksum: &str, name: &str) -> String {
+    format!("{checksum}  {name}\n")
+}
+
+fn sha256_file(path: &Path) -> anyhow::Result<String> {
+    let mut file =
+        fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Versions of the installed tools which have a minimum supported version
+fn tool_versions() -> BTreeMap<&'static str, String> {
+    MIN_TOOL_VERSIONS
+        .iter()
+        .filter_map(|(name, _, _)| {
+            let version = Tool::find(name, false).and_then(|tool| tool.version());
+            match version {
+                Ok(version) => Some((*name, version.to_string())),
+                Err(e) => {
+                    debug!("Not recording the version of {name}: {e}");
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("alma.img");
+        fs::write(&image, "abc").unwrap();
+        assert_eq!(metadata_path(&image), dir.path().join("alma.img.json"));
+
+        let path = write_metadata(
+            &image,
+            Some(r#"{"alma_version": "0.11.0"}"#),
+            Duration::from_secs(90),
+        )
+        .unwrap();
+        let metadata: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+        assert_eq!(metadata["size"], 3);
+        assert_eq!(
+            metadata["checksums"]["sha256"],
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(metadata["build_duration_secs"], 90);
+        assert_eq!(metadata["manifest"]["alma_version"], "0.11.0");
+    }
+
+    #[test]
+    fn test_compressed_path() {
+        let compressed = compressed_path(Path::new("/srv/alma.img"), ImageCompression::Zstd);
+        assert_eq!(compressed, PathBuf::from("/srv/alma.img.zst"));
+        assert_eq!(compression_of(&compressed), Some(ImageCompression::Zstd));
+        assert_eq!(compression_of(Path::new("/srv/alma.img")), None);
+        assert_eq!(
+            checksum_path(&compressed),
+            PathBuf::from("/srv/alma.img.zst.sha256")
+        );
+        assert_eq!(checksum_line("abc", "alma.img.xz"), "abc  alma.img.xz\n");
+    }
+
+    #[test]
+    fn test_describe_size() {
+        let image = Path::new("alma.img");
+        assert_eq!(
+            describe_size(image, 8 << 30, 8 << 30),
+            "alma.img is 8.00 GiB, fully allocated"
+        );
+        assert!(
+            describe_size(image, 8 << 30, 3 << 29)
+                .starts_with("alma.img is 8.00 GiB but only 1.50 GiB are allocated. It is sparse")
+        );
+    }
+
+    #[test]
+    fn test_converted_path() {
+        assert_eq!(
+            converted_path(Path::new("/srv/alma.img"), DiskFormat::Qcow2),
+            PathBuf::from("/srv/alma.qcow2")
+        );
+        assert_eq!(
+            converted_path(Path::new("alma"), DiskFormat::Vdi),
+            PathBuf::from("alma.vdi")
+        );
+    }
+}
@@ -1,9 +1,131 @@
-use anyhow::anyhow;
+use crate::error::{ErrorKind, ErrorKindExt};
+use anyhow::{Context, anyhow};
 use clap::ValueEnum;
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
 use std::str::FromStr;
 use strum::EnumIter;
 use strum::IntoEnumIterator;
 
+/// AUR packages installed by a single run of the AUR helper, so a broken package only fails its
+/// own batch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AurBatch {
+    /// Where the packages come from: a preset, --aur-packages...
+    pub name: String,
+    pub packages: Vec<String>,
+}
+
+impl AurBatch {
+    pub fn new(name: impl Into<String>, packages: Vec<String>) -> Self {
+        Self {
+            name: name.into(),
+            packages,
+        }
+    }
+}
+
+/// Drops packages which an earlier batch already installs, and batches left empty
+pub fn dedup_batches(batches: Vec<AurBatch>) -> Vec<AurBatch> {
+    let mut seen = HashSet::new();
+    batches
+        .into_iter()
+        .filter_map(|mut batch| {
+            batch.packages.retain(|p| seen.insert(p.clone()));
+            (!batch.packages.is_empty()).then_some(batch)
+        })
+        .collect()
+}
+
+/// An upstream release archive of an AUR helper, with its expected SHA-256 checksum
+#[derive(Debug, PartialEq, Eq)]
+pub struct PrebuiltRelease {
+    pub url: String,
+    pub sha256: String,
+}
+
+impl PrebuiltRelease {
+    pub fn verify(&self, data: &[u8]) -> anyhow::Result<()> {
+        let actual = format!("{:x}", Sha256::digest(data));
+        if !actual.eq_ignore_ascii_case(&self.sha256) {
+            return Err(anyhow!(
+                "Checksum mismatch for {}: expected {}, got {actual}",
+                self.url,
+                self.sha256
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Reads the first source for `arch` and its checksum from a PKGBUILD. Only plain assignments
+/// are understood, which is all -bin packages use.
+fn parse_pkgbuild(pkgbuild: &str, arch: &str) -> anyhow::Result<PrebuiltRelease> {
+    let variable = Regex::new(r"\$\{(\w+)\}|\$(\w+)").expect("valid regex");
+    let mut values: HashMap<String, Vec<String>> = HashMap::new();
+
+    let mut lines = pkgbuild.lines();
+    while let Some(line) = lines.next() {
+        let Some((name, value)) = line.split_once('=') else {
+            continue;
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            continue;
+        }
+
+        let mut value = value.trim().to_owned();
+        if value.starts_with('(') {
+            while !value.contains(')') {
+                let Some(next) = lines.next() else { break };
+                value.push(' ');
+                value.push_str(next.trim());
+            }
+            value = value.trim_start_matches('(').to_owned();
+            value.truncate(value.find(')').unwrap_or(value.len()));
+        }
+
+        let items = value
+            .split_whitespace()
+            .map(|item| {
+                let item = item.trim_matches(|c| c == '\'' || c == '"');
+                variable
+                    .replace_all(item, |caps: &regex::Captures| {
+                        let name = caps.get(1).or(caps.get(2)).unwrap().as_str();
+                        values
+                            .get(name)
+                            .and_then(|v| v.first())
+                            .cloned()
+                            .unwrap_or_default()
+                    })
+                    .into_owned()
+            })
+            .collect();
+        values.insert(name.to_owned(), items);
+    }
+
+    let first = |name: &str| {
+        values
+            .get(&format!("{name}_{arch}"))
+            .or_else(|| values.get(name))
+            .and_then(|v| v.first())
+            .cloned()
+            .ok_or_else(|| anyhow!("No {name} for {arch} in the PKGBUILD"))
+    };
+    let source = first("source")?;
+    // A source may be renamed with "name::url"
+    let url = source
+        .split_once("::")
+        .map_or(source.as_str(), |(_, url)| url)
+        .to_owned();
+    let sha256 = first("sha256sums")?;
+    if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow!("No usable SHA-256 checksum for {url}: {sha256}"));
+    }
+
+    Ok(PrebuiltRelease { url, sha256 })
+}
+
 #[derive(EnumIter, Clone, Debug)]
 pub enum AurHelper {
     Paru,
@@ -18,6 +140,29 @@ impl AurHelper {
         }
     }
 
+    pub fn get_binary_name(&self) -> &'static str {
+        match self {
+            Self::Paru => "paru",
+            Self::Yay => "yay",
+        }
+    }
+
+    /// Looks up the upstream release archive for this machine in the PKGBUILD of the -bin AUR
+    /// package, which is kept up to date with the releases and their checksums
+    pub fn fetch_prebuilt_release(&self) -> anyhow::Result<PrebuiltRelease> {
+        let url = format!(
+            "https://aur.archlinux.org/cgit/aur.git/plain/PKGBUILD?h={}",
+            self.get_package_name()
+        );
+        let pkgbuild = reqwest::blocking::get(&url)
+            .and_then(|r| r.error_for_status())
+            .and_then(|r| r.text())
+            .with_context(|| format!("Failed to download {url}"))
+            .kind(ErrorKind::Network)?;
+        parse_pkgbuild(&pkgbuild, std::env::consts::ARCH)
+            .with_context(|| format!("Failed to read the release of {}", self.get_package_name()))
+    }
+
     pub fn get_install_command(&self) -> Vec<String> {
         match self {
             Self::Paru => vec![
@@ -54,6 +199,38 @@ impl AurHelper {
             ],
         }
     }
+
+    /// Upgrades the installed AUR packages, without touching the repository packages
+    pub fn get_upgrade_command(&self) -> Vec<String> {
+        match self {
+            Self::Paru => vec![
+                String::from("paru"),
+                String::from("-Sua"),
+                String::from("--skipreview"),
+                String::from("--noupgrademenu"),
+                String::from("--useask"),
+                String::from("--removemake"),
+                String::from("--mflags"),
+                String::from(""),
+                String::from("--noconfirm"),
+            ],
+            Self::Yay => vec![
+                String::from("yay"),
+                String::from("-Sua"),
+                String::from("--noconfirm"),
+                String::from("--useask"),
+                String::from("--removemake"),
+                String::from("--answeredit"),
+                String::from("None"),
+                String::from("--answerclean"),
+                String::from("None"),
+                String::from("--answerdiff"),
+                String::from("None"),
+                String::from("--mflags"),
+                String::from("--noconfirm"),
+            ],
+        }
+    }
 }
 
 impl FromStr for AurHelper {
@@ -91,3 +268,58 @@ impl ValueEnum for AurHelper {
         Some(clap::builder::PossibleValue::new(name))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_pkgbuild() {
+        let pkgbuild = r#"
+pkgname=paru-bin
+_pkgname=paru
+pkgver=2.0.4
+arch=('x86_64' 'aarch64')
+source_x86_64=("${_pkgname}-$pkgver.tar.zst::https://github.com/Morganamilo/paru/releases/download/v$pkgver/paru-v${pkgver}-x86_64.tar.zst")
+source_aarch64=("https://github.com/Morganamilo/paru/releases/download/v$pkgver/paru-v${pkgver}-aarch64.tar.zst")
+sha256sums_x86_64=('0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef')
+sha256sums_aarch64=('SKIP')
+
+package() {
+  install -Dm755 paru "$pkgdir/usr/bin/paru"
+}
+"#;
+        assert_eq!(
+            parse_pkgbuild(pkgbuild, "x86_64").unwrap(),
+            PrebuiltRelease {
+                url: String::from(
+                    "https://github.com/Morganamilo/paru/releases/download/v2.0.4/paru-v2.0.4-x86_64.tar.zst"
+                ),
+                sha256: String::from(
+                    "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef"
+                ),
+            }
+        );
+        assert!(parse_pkgbuild(pkgbuild, "aarch64").is_err());
+        assert!(parse_pkgbuild(pkgbuild, "riscv64").is_err());
+    }
+
+    #[test]
+    fn test_dedup_batches() {
+        let batches = dedup_batches(vec![
+            AurBatch::new("shim", vec![String::from("shim-signed")]),
+            AurBatch::new(
+                "desktop.toml",
+                vec![String::from("shim-signed"), String::from("slack-desktop")],
+            ),
+            AurBatch::new("dev.toml", vec![String::from("slack-desktop")]),
+        ]);
+        assert_eq!(
+            batches,
+            [
+                AurBatch::new("shim", vec![String::from("shim-signed")]),
+                AurBatch::new("desktop.toml", vec![String::from("slack-desktop")]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,231 @@
+//! `alma backup`: captures an ALMA device into an image file, to checkpoint a portable system
+//! before risky changes. `--shrink` shrinks the root filesystem and partition of the copy to the
+//! space in use, and a .zst or .xz image is compressed. `alma flash` writes it back.
+
+use crate::args::BackupCommand;
+use crate::artifact;
+use crate::error::{ErrorKind, ErrorKindExt};
+use crate::process::CommandExt;
+use crate::resize;
+use crate::state;
+use crate::storage::filesystem::FilesystemType;
+use crate::storage::{
+    BlockDevice, EncryptedDevice, LoopDevice, StorageDevice, is_encrypted_device,
+};
+use crate::tool::Tool;
+use anyhow::{Context, anyhow};
+use log::info;
+use std::fs;
+use std::path::Path;
+
+/// Space left after the last partition for the backup GPT, which needs 33 sectors, rounded up
+/// to keep the image a whole number of MiB
+const GPT_BACKUP_SPACE: u64 = 1024 * 1024;
+
+pub fn backup(command: BackupCommand) -> anyhow::Result<()> {
+    let compression = artifact::compression_of(&command.image);
+    // The compressor writes <raw image>.<extension>, which is the requested image
+    let raw_image = match compression {
+        Some(_) => command.image.with_extension(""),
+        None => command.image.clone(),
+    };
+    for path in [&command.image, &raw_image] {
+        if path.exists() {
+            return Err(anyhow!("{} already exists", path.display())).kind(ErrorKind::Usage);
+        }
+    }
+    let compressor = compression
+        .map(|compression| Tool::find(compression.name(), false))
+        .transpose()?;
+    let dd = Tool::find("dd", false)?;
+
+    let mut device =
+        StorageDevice::from_path_read_only(&command.device, command.allow_non_removable)?;
+    if command.shrink {
+        check_shrinkable(&device)?;
+    }
+    device.umount_if_needed();
+
+    info!("Copying {device} to {}", raw_image.display());
+    dd.execute()
+        .arg(format!("if={}", device.path().display()))
+        .arg(format!("of={}", raw_image.display()))
+        .args(["bs=4M", "conv=sparse", "status=progress"])
+        .run(false)
+        .with_context(|| format!("Failed to copy {device}"))?;
+
+    if command.shrink {
+        shrink_image(&raw_image)?;
+    }
+    if let Some((compression, compressor)) = compression.zip(compressor) {
+        artifact::compress(&compressor, &raw_image, compression, false)?;
+        fs::remove_file(&raw_image)
+            .with_context(|| format!("Failed to remove {}", raw_image.display()))?;
+    }
+
+    info!("{}", artifact::size_report(&command.image)?);
+    Ok(())
+}
+
+/// Refuses --shrink before copying when it is known to fail. The filesystem in a LUKS container
+/// is only checked once the container is opened.
+fn check_shrinkable(device: &StorageDevice) -> anyhow::Result<()> {
+    if !resize::root_is_last_partition(device.path())? {
+        return Err(anyhow!(
+            "--shrink can only shrink the root partition when it is the last one, but {device} has a data partition after it"
+        ))
+        .kind(ErrorKind::Usage);
+    }
+    let root_partition = device.get_partition(resize::find_root_partition(device.path())?)?;
+    if is_encrypted_device(&root_partition)? {
+        return Ok(());
+    }
+    let fs_type = filesystem_type(&root_partition)?;
+    FilesystemType::from_mount_type(&fs_type)
+        .map(|fs_type| fs_type.ops().shrink(root_partition.path()))
+        .unwrap_or_else(|| Err(anyhow!("{fs_type} filesystems cannot be shrunk")))
+        .kind(ErrorKind::Usage)?;
+    Ok(())
+}
+
+/// Shrinks the root filesystem and partition of the image, then truncates the image after the
+/// partition and moves the backup GPT header to its new end
+fn shrink_image(image: &Path) -> anyhow::Result<()> {
+    let sfdisk = Tool::find("sfdisk", false)?;
+    let sgdisk = Tool::find("sgdisk", false)?;
+
+    let end = {
+        let loop_device = LoopDevice::create(image, false)?;
+        let storage_device = StorageDevice::from_path(loop_device.path(), true, false)?;
+        let root_partition =
+            storage_device.get_partition(resize::find_root_partition(loop_device.path())?)?;
+        let root_size = shrink_root_filesystem(&root_partition)?;
+        resize::shrink_root_partition(&sfdisk, loop_device.path(), root_size)?;
+        let table = sfdisk
+            .execute()
+            .arg("--json")
+            .arg(loop_device.path())
+            .run_text_output(false)
+            .context("Failed to read the partition table")?;
+        partitions_end(&table)?
+    };
+
+    let size = end + GPT_BACKUP_SPACE;
+    info!("Truncating {} to {size} bytes", image.display());
+    fs::OpenOptions::new()
+        .write(true)
+        .open(image)
+        .and_then(|file| file.set_len(size))
+        .context("Error truncating the image file")?;
+    resize::relocate_backup_header(&sgdisk, image)
+}
+
+/// Shrinks the filesystem of the root partition, in its LUKS container if any, to its minimum
+/// size, returning the size the partition needs to hold them
+fn shrink_root_filesystem(root_partition: &dyn BlockDevice) -> anyhow::Result<u64> {
+    let cryptsetup;
+    let (encrypted_root, header_size) = if is_encrypted_device(root_partition)? {
+        cryptsetup = Tool::find("cryptsetup", false)?;
+        let encrypted_root =
+            EncryptedDevice::open(&cryptsetup, root_partition, state::root_mapper_name())?;
+        let status = cryptsetup
+            .execute()
+            .arg("status")
+            .arg(encrypted_root.name())
+            .run_text_output(false)
+            .context("Failed to read the LUKS header size")?;
+        let header_size = luks_offset(&status)
+            .ok_or_else(|| anyhow!("cryptsetup status printed no data offset"))?;
+        (Some(encrypted_root), header_size)
+    } else {
+        (None, 0)
+    };
+    let root_device: &dyn BlockDevice = encrypted_root
+        .as_ref()
+        .map_or(root_partition, |e| e as &dyn BlockDevice);
+
+    let fs_type = filesystem_type(root_device)?;
+    let ops = FilesystemType::from_mount_type(&fs_type)
+        .ok_or_else(|| anyhow!("Unsupported filesystem type '{fs_type}' on the root partition"))?
+        .ops();
+    info!("Shrinking the {} filesystem", ops.mount_type());
+    for step in ops.shrink(root_device.path()).kind(ErrorKind::Usage)? {
+        step.run(&Tool::find(step.tool, false)?)
+            .context("Failed to shrink the root filesystem")?;
+    }
+
+    // Only ext4 can be shrunk
+    let superblock = Tool::find("dumpe2fs", false)?
+        .execute()
+        .arg("-h")
+        .arg(root_device.path())
+        .run_text_output(false)
+        .context("Failed to read the size of the root filesystem")?;
+    let filesystem_size = ext4_size(&superblock)
+        .ok_or_else(|| anyhow!("dumpe2fs printed no block count and block size"))?;
+    Ok(header_size + filesystem_size)
+}
+
+fn filesystem_type(device: &dyn BlockDevice) -> anyhow::Result<String> {
+    let output = Tool::find("blkid", false)?
+        .execute()
+        .args(["-s", "TYPE", "-o", "value"])
+        .arg(device.path())
+        .run_text_output(false)
+        .with_context(|| format!("Failed to probe {}", device.path().display()))?;
+    Ok(output.trim().to_owned())
+}
+
+/// The value of a `Key: value` line of dumpe2fs or cryptsetup output
+fn field<'a>(output: &'a str, key: &str) -> Option<&'a str> {
+    output.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        (name.trim() == key).then(|| value.trim())
+    })
+}
+
+/// The size in bytes of an ext4 filesystem, from the superblock printed by `dumpe2fs -h`
+fn ext4_size(superblock: &str) -> Option<u64> {
+    let blocks: u64 = field(superblock, "Block count")?.parse().ok()?;
+    let block_size: u64 = field(superblock, "Block size")?.parse().ok()?;
+    Some(blocks * block_size)
+}
+
+/// The size in bytes of the LUKS header in front of the data, from `cryptsetup status`
+fn luks_offset(status: &str) -> Option<u64> {
+    let sectors = field(status, "offset")?.strip_suffix("sectors")?.trim();
+    Some(sectors.parse::<u64>().ok()? * 512)
+}
+
+/// The end in bytes of the last partition, from `sfdisk --json`
+fn partitions_end(table: &str) -> anyhow::Result<u64> {
+    let table: serde_json::Value = serde_json::from_str(table).context("Invalid sfdisk output")?;
+    let table = &table["partitiontable"];
+    let sector_size = table["sectorsize"].as_u64().unwrap_or(512);
+    table["partitions"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|partition| Some(partition["start"].as_u64()? + partition["size"].as_u64()?))
+        .max()
+        .map(|end| end * sector_size)
+        .ok_or_else(|| anyhow!("The image has no partitions"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sizes() {
+        let superblock = "Filesystem volume name:   alma-root\nBlock count:              262144\nBlock size:               4096\n";
+        assert_eq!(ext4_size(superblock), Some(1024 * 1024 * 1024));
+        let status = "/dev/mapper/alma_root is active.\n  type:    LUKS2\n  offset:  32768 sectors\n  size:    2064384 sectors\n";
+        assert_eq!(luks_offset(status), Some(16 * 1024 * 1024));
+        let table = r#"{"partitiontable": {"label": "gpt", "sectorsize": 512, "partitions": [
+            {"node": "/dev/loop0p1", "start": 2048, "size": 614400},
+            {"node": "/dev/loop0p3", "start": 618496, "size": 2097152},
+            {"node": "/dev/loop0p2", "start": 616448, "size": 2048}]}}"#;
+        assert_eq!(partitions_end(table).unwrap(), 2715648 * 512);
+    }
+}
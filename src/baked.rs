@@ -0,0 +1,186 @@
+//! Sources baked into a system so `alma install` can reinstall it offline: the presets under
+//! /usr/share/alma/baked_sources and the Omarchy clone. With their git history they can take
+//! gigabytes, so `--bake-shallow` leaves the history out, and `alma gc` removes them from an
+//! installed system once they are no longer needed.
+
+use crate::args::{GcCommand, Source};
+use crate::error::{ErrorKind, ErrorKindExt};
+use crate::install::MANIFEST_PATH;
+use crate::plan::{self, Action};
+use anyhow::{Context, anyhow};
+use byte_unit::Byte;
+use dialoguer::{Confirm, theme::ColorfulTheme};
+use log::info;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const BAKED_SOURCES_DIR: &str = "usr/share/alma/baked_sources";
+pub const OMARCHY_DIR: &str = "usr/share/omarchy";
+
+/// The size of the files under `path`, without following symlinks
+pub fn disk_usage(path: &Path) -> u64 {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    fs::read_dir(path)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| disk_usage(&entry.path()))
+        .sum()
+}
+
+pub fn human_size(bytes: u64) -> String {
+    format!(
+        "{:.1}",
+        Byte::from_u64(bytes).get_appropriate_unit(byte_unit::UnitType::Binary)
+    )
+}
+
+/// The .git directories under `dir`, not looking into them
+fn git_dirs(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_dir()))
+        .flat_map(|entry| {
+            if entry.file_name() == ".git" {
+                vec![entry.path()]
+            } else {
+                git_dirs(&entry.path())
+            }
+        })
+        .collect()
+}
+
+/// Removes the git history under `dir`, returning the space freed
+pub fn remove_git_history(dir: &Path, dryrun: bool) -> anyhow::Result<u64> {
+    let mut freed = 0;
+    for git_dir in git_dirs(dir) {
+        freed += disk_usage(&git_dir);
+        remove_dir(&git_dir, dryrun)?;
+    }
+    Ok(freed)
+}
+
+fn remove_dir(path: &Path, dryrun: bool) -> anyhow::Result<()> {
+    if dryrun {
+        plan::print(Action::Remove {
+            path: path.to_path_buf(),
+            recursive: true,
+        });
+        return Ok(());
+    }
+    fs::remove_dir_all(path).with_context(|| format!("Failed to remove {}", path.display()))
+}
+
+/// The baked sources of the system at `root`, as absolute paths in that system
+pub fn baked_paths(root: &Path) -> Vec<PathBuf> {
+    let baked_sources_dir = root.join(BAKED_SOURCES_DIR);
+    let mut paths: Vec<PathBuf> = fs::read_dir(&baked_sources_dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| {
+            Path::new("/")
+                .join(BAKED_SOURCES_DIR)
+                .join(entry.file_name())
+        })
+        .collect();
+    paths.sort();
+    if root.join(OMARCHY_DIR).is_dir() {
+        paths.push(Path::new("/").join(OMARCHY_DIR));
+    }
+    paths
+}
+
+/// The size of each source recorded in the manifest of the system at `root`, none for the ones
+/// which were removed
+pub fn source_sizes(root: &Path, sources: &[Source]) -> Vec<(PathBuf, Option<u64>)> {
+    sources
+        .iter()
+        .map(|source| {
+            let path = root.join(
+                source
+                    .baked_path
+                    .strip_prefix("/")
+                    .unwrap_or(&source.baked_path),
+            );
+            let size = path.exists().then(|| disk_usage(&path));
+            (source.baked_path.clone(), size)
+        })
+        .collect()
+}
+
+/// `alma gc`: frees the space of the baked sources of the running system
+pub fn gc(command: GcCommand) -> anyhow::Result<()> {
+    if !Path::new(MANIFEST_PATH).exists() {
+        return Err(anyhow!(
+            "Manifest file not found at {MANIFEST_PATH}. This command can only be run from a system created by 'alma create'."
+        ))
+        .kind(ErrorKind::Usage);
+    }
+    let paths = baked_paths(Path::new("/"));
+    if paths.is_empty() {
+        info!("There are no baked sources to remove");
+        return Ok(());
+    }
+
+    if !command.history_only && !command.noconfirm {
+        let confirmed = Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt(
+                "Without its baked presets, this system can no longer be reinstalled with 'alma install'. Continue?",
+            )
+            .default(false)
+            .interact()?;
+        if !confirmed {
+            return Err(anyhow!("User aborted operation.")).kind(ErrorKind::Aborted);
+        }
+    }
+
+    let mut freed = 0;
+    for path in &paths {
+        if command.history_only {
+            freed += remove_git_history(path, command.dryrun)?;
+        } else {
+            let size = disk_usage(path);
+            info!("Removing {} ({})", path.display(), human_size(size));
+            remove_dir(path, command.dryrun)?;
+            freed += size;
+        }
+    }
+    info!("Freed {}", human_size(freed));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remove_git_history() {
+        let root = tempfile::tempdir().unwrap();
+        let preset = root.path().join(BAKED_SOURCES_DIR).join("preset_0");
+        fs::create_dir_all(preset.join("repo/.git/objects")).unwrap();
+        fs::write(preset.join("repo/.git/objects/pack"), [0; 1000]).unwrap();
+        fs::write(preset.join("repo/preset.toml"), [0; 10]).unwrap();
+        fs::create_dir_all(root.path().join(OMARCHY_DIR)).unwrap();
+
+        assert_eq!(
+            baked_paths(root.path()),
+            [
+                Path::new("/usr/share/alma/baked_sources/preset_0"),
+                Path::new("/usr/share/omarchy")
+            ]
+        );
+        assert_eq!(disk_usage(&preset), 1010);
+        assert_eq!(remove_git_history(&preset, false).unwrap(), 1000);
+        assert!(!preset.join("repo/.git").exists());
+        assert_eq!(disk_usage(&preset), 10);
+    }
+}
@@ -0,0 +1,366 @@
+//! `--board`: profiles of single board computers, whose boot ROM loads U-Boot (or its SPL) from
+//! raw offsets of the disk or from the boot areas of an eMMC rather than from a partition. U-Boot
+//! then boots GRUB's arm64 EFI loader from the ESP like UEFI firmware would, and GRUB passes the
+//! kernel the device tree of the board.
+
+use crate::arch::Arch;
+use crate::effects;
+use crate::process::CommandExt;
+use crate::tool::Tool;
+use anyhow::{Context, anyhow};
+use byte_unit::Byte;
+use log::info;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const MIB: u64 = 1024 * 1024;
+
+/// The protective MBR, GPT header and the 128 partition entries, which firmware must not overwrite
+const GPT_END: u64 = 34 * 512;
+
+/// GRUB's 10_linux passes /boot/dtb to every kernel it finds in /boot
+pub const DTB_PATH: &str = "boot/dtb";
+/// Where Arch Linux ARM kernels install their device trees
+const DTBS_DIR: &str = "boot/dtbs";
+
+pub const DTB_HOOK: &str = "80-alma-dtb.hook";
+pub const DTB_SCRIPT: &str = "dtb.sh";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BoardProfile {
+    pub name: String,
+    #[serde(default = "default_arch")]
+    pub arch: Arch,
+    /// Packages providing the firmware and whatever else the board needs, e.g. uboot-rock64
+    #[serde(default)]
+    pub packages: Vec<String>,
+    /// Kernel parameters the board needs, e.g. its serial console
+    #[serde(default)]
+    pub kernel_cmdline: Option<String>,
+    /// The device tree of the board, relative to /boot/dtbs, e.g. rockchip/rk3328-rock64.dtb
+    #[serde(default)]
+    pub dtb: Option<String>,
+    /// Where the first partition starts, the space before it is left to the firmware
+    #[serde(
+        default = "default_partitions_start",
+        deserialize_with = "deserialize_size"
+    )]
+    pub partitions_start: u64,
+    #[serde(default)]
+    pub firmware: Vec<Firmware>,
+}
+
+/// An image written outside of the partitions
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Firmware {
+    /// The image in the installed system, e.g. /boot/idbloader.img of uboot-rock64
+    pub file: PathBuf,
+    /// In bytes, or with a unit like "32KiB"
+    #[serde(deserialize_with = "deserialize_size")]
+    pub offset: u64,
+    #[serde(default)]
+    pub target: FirmwareTarget,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FirmwareTarget {
+    /// The disk ALMA installs to, before the first partition
+    #[default]
+    Disk,
+    /// The first boot area of an eMMC
+    Boot0,
+    /// The second boot area of an eMMC
+    Boot1,
+}
+
+fn default_arch() -> Arch {
+    Arch::Aarch64
+}
+
+fn default_partitions_start() -> u64 {
+    MIB
+}
+
+fn deserialize_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+    match Size::deserialize(deserializer)? {
+        Size::Bytes(bytes) => Ok(bytes),
+        Size::Text(text) => Byte::parse_str(&text, true)
+            .map(|b| b.as_u64())
+            .map_err(|e| serde::de::Error::custom(format!("invalid size '{text}': {e:?}"))),
+    }
+}
+
+/// Reads the profile of `--board`
+pub fn load(path: &str) -> anyhow::Result<BoardProfile> {
+    let data = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read the board profile {path}"))?;
+    let profile: BoardProfile = toml::from_str(&data)
+        .with_context(|| format!("Failed to parse the board profile {path}"))?;
+    profile.validate()?;
+    Ok(profile)
+}
+
+impl BoardProfile {
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.partitions_start < MIB || !self.partitions_start.is_multiple_of(MIB) {
+            return Err(anyhow!(
+                "partitions_start of the board {} must be a whole number of MiB",
+                self.name
+            ));
+        }
+        for firmware in &self.firmware {
+            if !firmware.file.is_absolute() {
+                return Err(anyhow!(
+                    "The firmware {} of the board {} must be an absolute path in the installed system",
+                    firmware.file.display(),
+                    self.name
+                ));
+            }
+            if firmware.target == FirmwareTarget::Disk
+                && !(GPT_END..self.partitions_start).contains(&firmware.offset)
+            {
+                return Err(anyhow!(
+                    "The firmware {} of the board {} must be written between the partition table, which ends at {GPT_END} bytes, and partitions_start",
+                    firmware.file.display(),
+                    self.name
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The space before the first partition in MiB, unless it is the default
+    pub fn partitions_start_mb(&self) -> Option<u64> {
+        (self.partitions_start != default_partitions_start()).then_some(self.partitions_start / MIB)
+    }
+
+    pub fn uses_boot_areas(&self) -> bool {
+        self.firmware
+            .iter()
+            .any(|f| f.target != FirmwareTarget::Disk)
+    }
+
+    /// Writes the firmware, which the packages of the system at `mount_path` installed, to
+    /// `device` and its eMMC boot areas. With `mirror`, only the firmware of the disk is written,
+    /// the boot areas belong to the eMMC of the main device.
+    pub fn write_firmware(
+        &self,
+        device: &Path,
+        mount_path: &Path,
+        mirror: bool,
+        dryrun: bool,
+    ) -> anyhow::Result<()> {
+        let dd = Tool::find("dd", dryrun)?;
+        let mut boot_area = None;
+        for firmware in &self.firmware {
+            if mirror && firmware.target != FirmwareTarget::Disk {
+                continue;
+            }
+            let source = mount_path.join(firmware.file.strip_prefix("/")?);
+            if !dryrun {
+                let size = fs::metadata(&source)
+                    .with_context(|| {
+                        format!(
+                            "The firmware {} of the board {} is not installed, check the packages of its profile",
+                            firmware.file.display(),
+                            self.name
+                        )
+                    })?
+                    .len();
+                if firmware.target == FirmwareTarget::Disk
+                    && firmware.offset + size > self.partitions_start
+                {
+                    return Err(anyhow!(
+                        "The firmware {} would overwrite the first partition, move partitions_start of the board {} past {} bytes",
+                        firmware.file.display(),
+                        self.name,
+                        firmware.offset + size
+                    ));
+                }
+            }
+
+            let (target, force_ro) = match firmware.target {
+                FirmwareTarget::Disk => (device.to_path_buf(), None),
+                FirmwareTarget::Boot0 | FirmwareTarget::Boot1 => {
+                    let area = boot_area_name(device, firmware.target)?;
+                    boot_area.get_or_insert(firmware.target);
+                    (
+                        Path::new("/dev").join(&area),
+                        Some(Path::new("/sys/block").join(&area).join("force_ro")),
+                    )
+                }
+            };
+            info!(
+                "Writing {} to {} at {} bytes",
+                firmware.file.display(),
+                target.display(),
+                firmware.offset
+            );
+            // The kernel keeps the boot areas read-only until asked otherwise
+            if let Some(force_ro) = &force_ro {
+                effects::write(force_ro, "0", dryrun)?;
+            }
+            dd.execute()
+                .arg(format!("if={}", source.display()))
+                .arg(format!("of={}", target.display()))
+                .args(["bs=4096", "oflag=seek_bytes", "conv=notrunc,fsync"])
+                .arg(format!("seek={}", firmware.offset))
+                .run(dryrun)
+                .with_context(|| format!("Failed to write {}", firmware.file.display()))?;
+            if let Some(force_ro) = &force_ro {
+                effects::write(force_ro, "1", dryrun)?;
+            }
+        }
+
+        if let Some(area) = boot_area {
+            info!("Booting {} from its {area:?} boot area", device.display());
+            Tool::find("mmc", dryrun)
+                .context("Writing to eMMC boot areas needs the mmc tool of mmc-utils")?
+                .execute()
+                .args(["bootpart", "enable"])
+                .arg(if area == FirmwareTarget::Boot0 {
+                    "1"
+                } else {
+                    "2"
+                })
+                .arg("1")
+                .arg(device)
+                .run(dryrun)
+                .context("Failed to enable the eMMC boot area")?;
+        }
+        Ok(())
+    }
+
+    /// Copies the device tree to where GRUB loads it from, and installs a pacman hook keeping
+    /// the copy up to date with the kernel
+    pub fn install_dtb(
+        &self,
+        mount_path: &Path,
+        hooks_dir: &str,
+        scripts_dir: &str,
+        dryrun: bool,
+    ) -> anyhow::Result<()> {
+        let Some(dtb) = &self.dtb else {
+            return Ok(());
+        };
+        let source = mount_path.join(DTBS_DIR).join(dtb);
+        if !dryrun && !source.exists() {
+            return Err(anyhow!(
+                "The kernel has no device tree {dtb} for the board {}, /{DTBS_DIR} lists the ones it has",
+                self.name
+            ));
+        }
+        info!("Loading the device tree {dtb}");
+        effects::copy(&source, &mount_path.join(DTB_PATH), dryrun)?;
+
+        effects::create_dir_all(&mount_path.join(hooks_dir), dryrun)?;
+        effects::create_dir_all(&mount_path.join(scripts_dir), dryrun)?;
+        effects::write(
+            &mount_path.join(hooks_dir).join(DTB_HOOK),
+            dtb_hook(dtb, scripts_dir),
+            dryrun,
+        )?;
+        let script = mount_path.join(scripts_dir).join(DTB_SCRIPT);
+        effects::write(&script, dtb_script(dtb), dryrun)?;
+        effects::set_mode(&script, 0o755, dryrun)
+    }
+}
+
+/// The boot area of an eMMC, e.g. mmcblk0boot0 of /dev/mmcblk0
+fn boot_area_name(device: &Path, target: FirmwareTarget) -> anyhow::Result<String> {
+    let name = device
+        .file_name()
+        .and_then(|n| n.to_str())
+        .filter(|n| n.starts_with("mmcblk"))
+        .ok_or_else(|| anyhow!("{} is not an eMMC, it has no boot areas", device.display()))?;
+    Ok(match target {
+        FirmwareTarget::Boot1 => format!("{name}boot1"),
+        _ => format!("{name}boot0"),
+    })
+}
+
+fn dtb_hook(dtb: &str, scripts_dir: &str) -> String {
+    format!(
+        "[Trigger]
+Type = Path
+Operation = Install
+Operation = Upgrade
+Target = {DTBS_DIR}/{dtb}
+
+[Action]
+Description = Copying the device tree of the board (ALMA)...
+When = PostTransaction
+Exec = /{scripts_dir}/{DTB_SCRIPT}
+"
+    )
+}
+
+fn dtb_script(dtb: &str) -> String {
+    format!("#!/bin/sh\nset -e\ncp /{DTBS_DIR}/{dtb} /{DTB_PATH}\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_board_profile() {
+        let profile: BoardProfile = toml::from_str(
+            r#"
+name = "Pine64 ROCK64"
+packages = ["uboot-rock64"]
+dtb = "rockchip/rk3328-rock64.dtb"
+kernel_cmdline = "console=ttyS2,1500000"
+partitions_start = "16MiB"
+
+[[firmware]]
+file = "/boot/idbloader.img"
+offset = "32KiB"
+
+[[firmware]]
+file = "/boot/u-boot.itb"
+offset = 8388608
+"#,
+        )
+        .unwrap();
+        assert!(profile.validate().is_ok());
+        assert_eq!(profile.arch, Arch::Aarch64);
+        assert_eq!(profile.partitions_start_mb(), Some(16));
+        assert_eq!(profile.firmware[0].offset, 32768);
+        assert_eq!(profile.firmware[1].target, FirmwareTarget::Disk);
+        assert!(!profile.uses_boot_areas());
+        // The manifest keeps the profile
+        let json = serde_json::to_string(&profile).unwrap();
+        assert_eq!(
+            serde_json::from_str::<BoardProfile>(&json).unwrap(),
+            profile
+        );
+
+        let mut overlapping = profile.clone();
+        overlapping.firmware[0].offset = 8192;
+        assert!(overlapping.validate().is_err());
+        overlapping.firmware[0].target = FirmwareTarget::Boot0;
+        assert!(overlapping.validate().is_ok());
+        assert!(overlapping.uses_boot_areas());
+
+        assert_eq!(
+            boot_area_name(Path::new("/dev/mmcblk1"), FirmwareTarget::Boot1).unwrap(),
+            "mmcblk1boot1"
+        );
+        assert!(boot_area_name(Path::new("/dev/sda"), FirmwareTarget::Boot0).is_err());
+        assert_eq!(
+            dtb_script("rockchip/rk3328-rock64.dtb"),
+            "#!/bin/sh\nset -e\ncp /boot/dtbs/rockchip/rk3328-rock64.dtb /boot/dtb\n"
+        );
+    }
+}
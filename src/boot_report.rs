@@ -0,0 +1,211 @@
+//! The boot report: what a system boots with, gathered once `alma create` (and `alma install`,
+//! which runs it) is done. These are the facts to check when a stick does not boot, so the report
+//! is printed at the end and stored next to the manifest.
+
+use crate::args::InitramfsGenerator;
+use crate::storage::{BlockDevice, ids};
+use crate::tool::Tool;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+pub const REPORT_PATH: &str = "usr/share/alma/boot-report.txt";
+
+/// The name the initramfs opens the LUKS container as, under /dev/mapper
+pub fn mapping_name(generator: InitramfsGenerator, luks_uuid: &str) -> String {
+    match generator {
+        // Both the encrypt and the sd-encrypt hooks, see initcpio::crypttab
+        InitramfsGenerator::Mkinitcpio => String::from("luks_root"),
+        InitramfsGenerator::Dracut => format!("luks-{luks_uuid}"),
+    }
+}
+
+#[derive(Debug)]
+pub struct BootReport {
+    root_uuid: String,
+    boot_uuid: Option<String>,
+    /// The LUKS UUID and the mapping name
+    luks: Option<(String, String)>,
+    /// The package and version of each kernel with modules in the system
+    kernels: Vec<String>,
+    initramfs: Vec<String>,
+    grub_entries: Vec<String>,
+    efi_loaders: Vec<String>,
+}
+
+impl BootReport {
+    /// Gathers the report of the system mounted at `mount_path`. `luks_container` is the
+    /// partition holding the root filesystem when it is encrypted.
+    pub fn collect(
+        blkid: &Tool,
+        mount_path: &Path,
+        root_device: &dyn BlockDevice,
+        boot_partition: Option<&dyn BlockDevice>,
+        luks_container: Option<&dyn BlockDevice>,
+        generator: InitramfsGenerator,
+    ) -> anyhow::Result<Self> {
+        let luks = luks_container
+            .map(|container| -> anyhow::Result<(String, String)> {
+                let uuid = ids::uuid(blkid, container)?;
+                let mapping = mapping_name(generator, &uuid);
+                Ok((uuid, mapping))
+            })
+            .transpose()?;
+        let boot = mount_path.join("boot");
+        Ok(Self {
+            root_uuid: ids::uuid(blkid, root_device)?,
+            boot_uuid: boot_partition
+                .map(|partition| ids::uuid(blkid, partition))
+                .transpose()?,
+            luks,
+            kernels: installed_kernels(mount_path),
+            initramfs: list_files(&boot, &|name| {
+                name.starts_with("initramfs-") && name.ends_with(".img")
+            })
+            .into_iter()
+            .map(|name| format!("/boot/{name}"))
+            .collect(),
+            grub_entries: fs::read_to_string(boot.join("grub/grub.cfg"))
+                .map(|grub_cfg| menu_entries(&grub_cfg))
+                .unwrap_or_default(),
+            efi_loaders: efi_loaders(&boot.join("EFI"), "/boot/EFI"),
+        })
+    }
+}
+
+impl fmt::Display for BootReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Root filesystem UUID:  {}", self.root_uuid)?;
+        writeln!(
+            f,
+            "Boot partition UUID:   {}",
+            self.boot_uuid.as_deref().unwrap_or("none")
+        )?;
+        match &self.luks {
+            Some((uuid, mapping)) => {
+                writeln!(f, "LUKS UUID:             {uuid}")?;
+                writeln!(f, "LUKS mapping:          /dev/mapper/{mapping}")?;
+            }
+            None => writeln!(f, "Encryption:            none")?,
+        }
+        write_list(f, "Kernels:", &self.kernels)?;
+        write_list(f, "Initramfs:", &self.initramfs)?;
+        write_list(f, "GRUB entries:", &self.grub_entries)?;
+        write_list(f, "EFI loaders:", &self.efi_loaders)
+    }
+}
+
+/// Writes a labelled list, one item per line
+fn write_list(f: &mut fmt::Formatter<'_>, label: &str, items: &[String]) -> fmt::Result {
+    if items.is_empty() {
+        return writeln!(f, "{label:<23}none");
+    }
+    for (i, item) in items.iter().enumerate() {
+        writeln!(f, "{:<23}{item}", if i == 0 { label } else { "" })?;
+    }
+    Ok(())
+}
+
+/// The kernels with modules in /usr/lib/modules, whose pkgbase file names their package
+fn installed_kernels(mount_path: &Path) -> Vec<String> {
+    let modules = mount_path.join("usr/lib/modules");
+    list_files(&modules, &|_| true)
+        .into_iter()
+        .filter_map(|version| {
+            let package = fs::read_to_string(modules.join(&version).join("pkgbase")).ok()?;
+            Some(format!("{} {version}", package.trim()))
+        })
+        .collect()
+}
+
+/// The sorted names of the entries of `dir` accepted by `filter`
+fn list_files(dir: &Path, filter: &dyn Fn(&str) -> bool) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| filter(name))
+        .collect();
+    names.sort();
+    names
+}
+
+/// The .efi files under `dir` (the EFI directory of the boot partition), as paths under `shown`
+fn efi_loaders(dir: &Path, shown: &str) -> Vec<String> {
+    list_files(dir, &|_| true)
+        .into_iter()
+        .flat_map(|name| {
+            let path = dir.join(&name);
+            let shown = format!("{shown}/{name}");
+            if path.is_dir() {
+                efi_loaders(&path, &shown)
+            } else if name.to_lowercase().ends_with(".efi") {
+                vec![shown]
+            } else {
+                Vec::new()
+            }
+        })
+        .collect()
+}
+
+/// The titles of the menu entries of a grub.cfg, including the ones in submenus
+fn menu_entries(grub_cfg: &str) -> Vec<String> {
+    grub_cfg
+        .lines()
+        .filter_map(|line| {
+            let rest = line.trim_start().strip_prefix("menuentry ")?.trim_start();
+            let quote = rest.chars().next().filter(|c| *c == '\'' || *c == '"')?;
+            let title = &rest[1..];
+            Some(title[..title.find(quote)?].to_owned())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boot_files() {
+        let grub_cfg = "### BEGIN /etc/grub.d/10_linux ###
+menuentry 'Arch Linux' --class arch --class gnu-linux $menuentry_id_option 'gnulinux-simple-1234' {
+\tlinux\t/vmlinuz-linux root=UUID=1234 rw
+}
+submenu 'Advanced options for Arch Linux' $menuentry_id_option 'gnulinux-advanced-1234' {
+\tmenuentry \"Arch Linux, with Linux linux (fallback initramfs)\" --class arch {
+\t}
+}
+";
+        assert_eq!(
+            menu_entries(grub_cfg),
+            [
+                "Arch Linux",
+                "Arch Linux, with Linux linux (fallback initramfs)"
+            ]
+        );
+
+        let root = tempfile::tempdir().unwrap();
+        let efi = root.path().join("boot/EFI");
+        fs::create_dir_all(efi.join("BOOT")).unwrap();
+        fs::create_dir_all(efi.join("GRUB")).unwrap();
+        fs::write(efi.join("BOOT/BOOTX64.EFI"), "").unwrap();
+        fs::write(efi.join("GRUB/grubx64.efi"), "").unwrap();
+        fs::write(efi.join("GRUB/grub.cfg"), "").unwrap();
+        assert_eq!(
+            efi_loaders(&efi, "/boot/EFI"),
+            ["/boot/EFI/BOOT/BOOTX64.EFI", "/boot/EFI/GRUB/grubx64.efi"]
+        );
+
+        let modules = root.path().join("usr/lib/modules/6.10.1-arch1-1");
+        fs::create_dir_all(&modules).unwrap();
+        fs::write(modules.join("pkgbase"), "linux\n").unwrap();
+        assert_eq!(installed_kernels(root.path()), ["linux 6.10.1-arch1-1"]);
+
+        assert_eq!(
+            mapping_name(InitramfsGenerator::Dracut, "abcd"),
+            "luks-abcd"
+        );
+    }
+}
@@ -0,0 +1,168 @@
+//! The phases `alma create` completed on a target, recorded on the target itself as they finish,
+//! so `alma create --resume` can continue a failed build after the last of them instead of
+//! starting again from pacstrap. Removed once the manifest is written.
+//!
+//! The settings of the interactive setup are recorded along with the setup phase, so the phases
+//! after it use the same user without asking again.
+
+use crate::args::{CreateCommand, Phase};
+use crate::error::{ErrorKind, ErrorKindExt};
+use crate::interactive::UserSettings;
+use crate::tool::{MountOptions, with_mounted_system};
+use anyhow::{Context, anyhow};
+use clap::ValueEnum;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+pub const CHECKPOINT_PATH: &str = "usr/share/alma/checkpoint.json";
+
+#[derive(Default, Serialize, Deserialize)]
+struct Checkpoint {
+    completed: Vec<Phase>,
+    #[serde(default)]
+    user_settings: Option<UserSettings>,
+    /// Not serialized with the rest of `user_settings`, which the manifest records too
+    #[serde(default)]
+    git_identity: Option<(String, String)>,
+}
+
+/// The checkpoint of the system mounted at `mount_path`, if a build was interrupted there
+fn read(mount_path: &Path) -> Option<Checkpoint> {
+    let json = fs::read_to_string(mount_path.join(CHECKPOINT_PATH)).ok()?;
+    serde_json::from_str(&json).ok()
+}
+
+fn write(mount_path: &Path, checkpoint: &Checkpoint) -> anyhow::Result<()> {
+    let path = mount_path.join(CHECKPOINT_PATH);
+    fs::create_dir_all(path.parent().expect("The checkpoint is in a directory"))
+        .with_context(|| format!("Failed to create the directory of {}", path.display()))?;
+    let json =
+        serde_json::to_string_pretty(checkpoint).context("Failed to serialize the checkpoint")?;
+    fs::write(&path, json).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Records that `phase` completed on the system mounted at `mount_path`
+pub fn record(mount_path: &Path, phase: Phase, dryrun: bool) -> anyhow::Result<()> {
+    if dryrun {
+        return Ok(());
+    }
+    let mut checkpoint = read(mount_path).unwrap_or_default();
+    if checkpoint.completed.contains(&phase) {
+        return Ok(());
+    }
+    checkpoint.completed.push(phase);
+    write(mount_path, &checkpoint)
+}
+
+/// Records the settings the system was set up with, which `resume` restores
+pub fn record_user_settings(
+    mount_path: &Path,
+    settings: &UserSettings,
+    dryrun: bool,
+) -> anyhow::Result<()> {
+    if dryrun {
+        return Ok(());
+    }
+    let mut checkpoint = read(mount_path).unwrap_or_default();
+    checkpoint.user_settings = Some(settings.clone());
+    checkpoint.git_identity = settings.git_identity.clone();
+    write(mount_path, &checkpoint)
+}
+
+/// Removes the checkpoint once the build is complete, so it does not end up in the system
+pub fn remove(mount_path: &Path, dryrun: bool) -> anyhow::Result<()> {
+    let path = mount_path.join(CHECKPOINT_PATH);
+    if dryrun || !path.exists() {
+        return Ok(());
+    }
+    fs::remove_file(&path).with_context(|| format!("Failed to remove {}", path.display()))
+}
+
+/// Reads the checkpoint of the target of `--resume`, skips the phases it records and restores the
+/// settings of the interactive setup. The target is mounted read-only for this, so an encrypted
+/// root asks for its passphrase twice.
+pub fn resume(command: &mut CreateCommand) -> anyhow::Result<()> {
+    let path = command
+        .path
+        .clone()
+        .ok_or_else(|| anyhow!("--resume needs the device or image of the failed build"))
+        .kind(ErrorKind::Usage)?;
+    let options = MountOptions {
+        allow_non_removable: command.allow_non_removable,
+        read_only: true,
+        ..MountOptions::default()
+    };
+
+    let mut checkpoint = None;
+    with_mounted_system(&path, &options, |system| {
+        checkpoint = read(system.mount_point.path());
+        Ok(())
+    })?;
+    let checkpoint = checkpoint
+        .ok_or_else(|| {
+            anyhow!(
+                "{} has no checkpoint at /{CHECKPOINT_PATH}: its build either completed or did not get past partitioning",
+                path.display()
+            )
+        })
+        .kind(ErrorKind::Usage)?;
+
+    match checkpoint
+        .completed
+        .last()
+        .and_then(|last| last.to_possible_value())
+    {
+        Some(last) => info!("Resuming after the {} phase", last.get_name()),
+        None => info!("Resuming after partitioning"),
+    }
+    if let Some(mut settings) = checkpoint.user_settings {
+        info!("Resuming with the settings of user {}", settings.username);
+        settings.git_identity = checkpoint.git_identity;
+        command.user_settings = Some(settings);
+    }
+    command.skip = checkpoint.completed;
+    // The partitions and filesystems with the checkpoint on them are reused
+    if !command.skip.contains(&Phase::Partition) {
+        command.skip.push(Phase::Partition);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checkpoint() {
+        let root = tempfile::tempdir().unwrap();
+        assert!(read(root.path()).is_none());
+
+        record(root.path(), Phase::Partition, false).unwrap();
+        record(root.path(), Phase::Bootstrap, false).unwrap();
+        record(root.path(), Phase::Bootstrap, false).unwrap();
+        record(root.path(), Phase::Aur, true).unwrap();
+        assert_eq!(
+            read(root.path()).unwrap().completed,
+            [Phase::Partition, Phase::Bootstrap]
+        );
+
+        let mut settings = UserSettings::for_user("archie".to_string()).unwrap();
+        settings.git_identity = Some(("Archie".to_string(), "archie@example.com".to_string()));
+        record_user_settings(root.path(), &settings, false).unwrap();
+        record(root.path(), Phase::Setup, false).unwrap();
+        let checkpoint = read(root.path()).unwrap();
+        assert_eq!(checkpoint.user_settings.unwrap().username, "archie");
+        assert_eq!(checkpoint.git_identity, settings.git_identity);
+        assert_eq!(checkpoint.completed.last(), Some(&Phase::Setup));
+        assert!(
+            fs::read_to_string(root.path().join(CHECKPOINT_PATH))
+                .unwrap()
+                .contains("\"bootstrap\"")
+        );
+
+        remove(root.path(), false).unwrap();
+        assert!(read(root.path()).is_none());
+    }
+}
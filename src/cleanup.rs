@@ -0,0 +1,334 @@
+//! `alma cleanup`: tears down the mounts, LVM volume groups, LUKS mappers, RAID arrays and loop
+//! devices of ALMA runs which crashed or were killed, as recorded in the state registry.
+
+use crate::args::CleanupCommand;
+use crate::plan::{self, Action};
+use crate::process::CommandExt;
+use crate::state::{self, LoopEntry, RunState};
+use crate::tool::Tool;
+use anyhow::{Context, anyhow};
+use log::{info, warn};
+use nix::mount::{MntFlags, umount, umount2};
+use nix::sys::signal::{Signal, kill};
+use nix::unistd::Pid;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+pub fn cleanup(command: CleanupCommand) -> anyhow::Result<()> {
+    let runs = state::load_runs()?;
+    let mut failed = false;
+    let mut cleaned = 0;
+
+    for (path, run) in runs {
+        if run.is_alive() {
+            info!(
+                "Skipping `{}` (PID {}), which is still running",
+                run.command, run.pid
+            );
+            continue;
+        }
+
+        info!("Cleaning up after `{}` (PID {})", run.command, run.pid);
+        match cleanup_run(&run, command.dryrun) {
+            Ok(()) => {
+                cleaned += 1;
+                if !command.dryrun {
+                    fs::remove_file(&path)
+                        .with_context(|| format!("Failed to remove {}", path.display()))?;
+                }
+            }
+            Err(e) => {
+                warn!("Cleanup of PID {} is incomplete: {e:#}", run.pid);
+                failed = true;
+            }
+        }
+    }
+
+    if failed {
+        return Err(anyhow!(
+            "Some leftovers could not be removed, run `alma cleanup` again once they are no longer in use"
+        ));
+    }
+    if cleaned == 0 {
+        info!("Nothing to clean up");
+    }
+    Ok(())
+}
+
+/// Tears down what an interrupted run still holds once its MountStack, EncryptedDevice and
+/// LoopDevice were dropped, which fail to release what is busy
+pub fn release_current_run() {
+    let Some(run) = state::current_run() else {
+        return;
+    };
+    info!("Cleaning up after the interrupted run");
+    stop_chroot_processes();
+    match cleanup_run(&run, false) {
+        Ok(()) => state::forget_current_run(),
+        Err(e) => warn!("Cleanup is incomplete, run `alma cleanup` to finish it: {e:#}"),
+    }
+}
+
+/// Kills the processes running in a chroot of this run, like a gpg-agent started by pacman-key,
+/// which would keep its filesystems busy
+pub fn stop_chroot_processes() {
+    let Some(run) = state::current_run() else {
+        return;
+    };
+    let pids = chroot_processes(Path::new("/proc"), &run.mounts);
+    for pid in &pids {
+        warn!("Killing process {pid}, which is still running in the target");
+        let _ = kill(Pid::from_raw(*pid), Signal::SIGKILL);
+    }
+    if !pids.is_empty() {
+        // Their files are only closed once they have exited
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+/// The processes under `proc_dir` whose root directory is one of the mount points, or below one
+fn chroot_processes(proc_dir: &Path, mounts: &[PathBuf]) -> Vec<i32> {
+    let Ok(entries) = fs::read_dir(proc_dir) else {
+        return Vec::new();
+    };
+    let own_pid = process::id() as i32;
+    let mut pids: Vec<i32> = entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<i32>().ok())
+        .filter(|pid| *pid != own_pid)
+        .filter(|pid| {
+            fs::read_link(proc_dir.join(pid.to_string()).join("root"))
+                .is_ok_and(|root| mounts.iter().any(|mount| root.starts_with(mount)))
+        })
+        .collect();
+    pids.sort();
+    pids
+}
+
+/// Undoes the resources of a run in the opposite order they are set up in: filesystems are
+/// unmounted before the volume groups are deactivated, those before the mappers are closed, those
+/// before the arrays are stopped, and those before their loop devices are detached. A volume
+/// group inside a LUKS container is deactivated in the same run, one holding a LUKS container
+/// only once the container is closed, by running `alma cleanup` again.
+fn cleanup_run(run: &RunState, dryrun: bool) -> anyhow::Result<()> {
+    let mut result = Ok(());
+
+    let mounted = mounted_targets()?;
+    for target in run.mounts.iter().rev() {
+        if mounted.contains(target)
+            && let Err(e) = unmount(target, dryrun)
+        {
+            result = Err(e);
+            continue;
+        }
+        if state::is_temp_mount_point(target) && target.exists() {
+            info!("Removing {}", target.display());
+            if dryrun {
+                plan::print(Action::Rmdir {
+                    path: target.clone(),
+                });
+            } else if let Err(e) = fs::remove_dir(target) {
+                warn!("Unable to remove {}: {e}", target.display());
+            }
+        }
+    }
+
+    if !run.volume_groups.is_empty() {
+        let vgchange = Tool::find("vgchange", dryrun)?;
+        for volume_group in &run.volume_groups {
+            // /dev/<volume group> only exists while some of its logical volumes are active
+            if !Path::new("/dev").join(volume_group).exists() {
+                continue;
+            }
+            info!("Deactivating the LVM volume group {volume_group}");
+            if let Err(e) = vgchange
+                .execute()
+                .arg("-an")
+                .arg(volume_group)
+                .run(dryrun)
+                .with_context(|| format!("Failed to deactivate {volume_group}"))
+            {
+                result = Err(e);
+            }
+        }
+    }
+
+    if !run.mappers.is_empty() {
+        let cryptsetup = Tool::find("cryptsetup", dryrun)?;
+        for mapper in &run.mappers {
+            if !Path::new("/dev/mapper").join(mapper).exists() {
+                continue;
+            }
+            info!("Closing the encrypted device {mapper}");
+            if let Err(e) = cryptsetup
+                .execute()
+                .arg("close")
+                .arg(mapper)
+                .run(dryrun)
+                .with_context(|| format!("Failed to close {mapper}"))
+            {
+                result = Err(e);
+            }
+        }
+    }
+
+    if !run.arrays.is_empty() {
+        let mdadm = Tool::find("mdadm", dryrun)?;
+        for array in &run.arrays {
+            if !array.exists() {
+                continue;
+            }
+            info!("Stopping the RAID array {}", array.display());
+            if let Err(e) = mdadm
+                .execute()
+                .arg("--stop")
+                .arg(array)
+                .run(dryrun)
+                .with_context(|| format!("Failed to stop {}", array.display()))
+            {
+                result = Err(e);
+            }
+        }
+    }
+
+    if !run.loop_devices.is_empty() {
+        let losetup = Tool::find("losetup", dryrun)?;
+        for entry in &run.loop_devices {
+            // The loop device may have been reused for another file since
+            if !is_attached(entry) {
+                continue;
+            }
+            info!(
+                "Detaching loop device {} of {}",
+                entry.device.display(),
+                entry.file.display()
+            );
+            if let Err(e) = losetup
+                .execute()
+                .arg("-d")
+                .arg(&entry.device)
+                .run(dryrun)
+                .with_context(|| format!("Failed to detach {}", entry.device.display()))
+            {
+                result = Err(e);
+            }
+        }
+    }
+
+    result
+}
+
+fn unmount(target: &Path, dryrun: bool) -> anyhow::Result<()> {
+    info!("Unmounting {}", target.display());
+    if dryrun {
+        plan::print(Action::Umount {
+            target: target.to_path_buf(),
+        });
+        return Ok(());
+    }
+    if let Err(e) = umount(target) {
+        // Whatever keeps it busy most likely died with the run, so a lazy unmount is safe
+        warn!(
+            "Unable to unmount {} ({e}), detaching it instead",
+            target.display()
+        );
+        umount2(target, MntFlags::MNT_DETACH)
+            .with_context(|| format!("Failed to unmount {}", target.display()))?;
+    }
+    Ok(())
+}
+
+fn mounted_targets() -> anyhow::Result<Vec<PathBuf>> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")
+        .context("Failed to read the list of mounted filesystems")?;
+    Ok(parse_mount_points(&mountinfo))
+}
+
+/// The mount points of /proc/self/mountinfo, in its fifth field with spaces and the like escaped
+/// as octal
+fn parse_mount_points(mountinfo: &str) -> Vec<PathBuf> {
+    mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .map(|field| PathBuf::from(unescape_octal(field)))
+        .collect()
+}
+
+fn unescape_octal(field: &str) -> String {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut rest = field;
+    while let Some(index) = rest.find('\\') {
+        unescaped.push_str(&rest[..index]);
+        let code = rest
+            .get(index + 1..index + 4)
+            .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+        match code {
+            Some(code) => {
+                unescaped.push(char::from(code));
+                rest = &rest[index + 4..];
+            }
+            None => {
+                unescaped.push('\\');
+                rest = &rest[index + 1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+/// Whether the loop device is still attached to the image file it was created for
+fn is_attached(entry: &LoopEntry) -> bool {
+    let Some(name) = entry.device.file_name() else {
+        return false;
+    };
+    let backing_file = Path::new("/sys/block").join(name).join("loop/backing_file");
+    fs::read_to_string(backing_file).is_ok_and(|file| Path::new(file.trim_end()) == entry.file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mount_points() {
+        let mountinfo = "\
+22 1 259:2 / / rw,relatime shared:1 - ext4 /dev/nvme0n1p2 rw
+310 22 7:0 / /tmp/alma-x1Yz rw,noatime shared:150 - ext4 /dev/loop0p3 rw
+311 310 7:0 / /tmp/alma-x1Yz/boot\\040files rw shared:151 - vfat /dev/loop0p1 rw
+";
+        assert_eq!(
+            parse_mount_points(mountinfo),
+            vec![
+                PathBuf::from("/"),
+                PathBuf::from("/tmp/alma-x1Yz"),
+                PathBuf::from("/tmp/alma-x1Yz/boot files"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_chroot_processes() {
+        let proc_dir = tempfile::tempdir().unwrap();
+        for (pid, root) in [
+            ("1", "/"),
+            ("812", "/tmp/alma-x1Yz"),
+            ("815", "/tmp/alma-x1Yz/var/lib/pacman"),
+            ("907", "/tmp/alma-other"),
+        ] {
+            let dir = proc_dir.path().join(pid);
+            fs::create_dir(&dir).unwrap();
+            std::os::unix::fs::symlink(root, dir.join("root")).unwrap();
+        }
+        fs::create_dir(proc_dir.path().join("self")).unwrap();
+
+        assert_eq!(
+            chroot_processes(proc_dir.path(), &[PathBuf::from("/tmp/alma-x1Yz")]),
+            [812, 815]
+        );
+        assert!(chroot_processes(proc_dir.path(), &[]).is_empty());
+    }
+}
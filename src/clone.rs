@@ -0,0 +1,265 @@
+//! `alma clone`: copies an ALMA system to another drive partition by partition, then gives the
+//! copy new partition GUIDs, filesystem UUIDs and LUKS UUID and regenerates its initramfs and
+//! bootloader against them, so both drives boot independently, even when plugged in together.
+
+use crate::args::{CloneCommand, GrubTargets, InitramfsGenerator};
+use crate::constants::{
+    BIOS_BOOT_PARTITION_INDEX, BOOT_PARTITION_INDEX, DATA_PARTITION_INDEX, ROOT_PARTITION_INDEX,
+};
+use crate::create;
+use crate::error::{ErrorKind, ErrorKindExt};
+use crate::grub;
+use crate::install;
+use crate::process::CommandExt;
+use crate::reproducible::Reproducible;
+use crate::resize;
+use crate::seed;
+use crate::state;
+use crate::storage::filesystem::FilesystemType;
+use crate::storage::{
+    BlockDevice, EncryptedDevice, Filesystem, StorageDevice, ids, is_encrypted_device,
+};
+use crate::tool::{Tool, mount};
+use anyhow::{Context, anyhow};
+use dialoguer::{Confirm, theme::ColorfulTheme};
+use log::{info, warn};
+
+/// Files of the installed system which refer to the filesystems and the LUKS container by UUID
+const UUID_FILES: [&str; 4] = [
+    "etc/fstab",
+    "etc/default/grub",
+    "etc/crypttab",
+    "etc/crypttab.initramfs",
+];
+
+pub fn clone(command: CloneCommand) -> anyhow::Result<()> {
+    let sgdisk = Tool::find("sgdisk", false)?;
+    let blkid = Tool::find("blkid", false)?;
+    let arch_chroot = Tool::find("arch-chroot", false)?;
+
+    let loop_device = seed::attach_if_file(&command.source)?;
+    let source_path = loop_device
+        .as_ref()
+        .map_or(command.source.as_path(), |l| l.path());
+    let mut source = StorageDevice::from_path_read_only(
+        source_path,
+        command.allow_non_removable || loop_device.is_some(),
+    )?;
+    source.umount_if_needed();
+    let source_root = source.get_partition(ROOT_PARTITION_INDEX)?;
+    if filesystem_type(&blkid, &source_root)? == "linux_raid_member" {
+        return Err(anyhow!(
+            "{} is one disk of a --raid1 system, which cannot be cloned",
+            command.source.display()
+        ))
+        .kind(ErrorKind::Usage);
+    }
+    // The exFAT data partition is copied with its serial number, fstab mounts it by label
+    let has_data_partition = source.get_partition(DATA_PARTITION_INDEX).is_ok();
+    if command.expand && has_data_partition {
+        return Err(anyhow!(
+            "--expand can only grow the root partition when it is the last one, but {} has a data partition after it",
+            command.source.display()
+        ))
+        .kind(ErrorKind::Usage);
+    }
+
+    let mut target = StorageDevice::from_path(&command.device, command.allow_non_removable, false)?;
+    if target.path() == source.path() {
+        return Err(anyhow!("{target} cannot be cloned onto itself")).kind(ErrorKind::Usage);
+    }
+    if target.size() < source.size() {
+        return Err(anyhow!(
+            "{target} ({}) is smaller than {source} ({})",
+            target.size(),
+            source.size()
+        ))
+        .kind(ErrorKind::DeviceSafety);
+    }
+    if !command.noconfirm {
+        let confirmed = Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt(format!("All data on {target} will be erased. Continue?"))
+            .default(false)
+            .interact()?;
+        if !confirmed {
+            return Err(anyhow!("User aborted operation.")).kind(ErrorKind::Aborted);
+        }
+    }
+    target.umount_if_needed();
+
+    seed::copy_partition_table(&sgdisk, source.path(), target.path(), command.expand)?;
+    let mut indices = vec![
+        BOOT_PARTITION_INDEX,
+        BIOS_BOOT_PARTITION_INDEX,
+        ROOT_PARTITION_INDEX,
+    ];
+    if has_data_partition {
+        indices.push(DATA_PARTITION_INDEX);
+    }
+    for index in indices {
+        seed::copy_partition(&source.get_partition(index)?, &target.get_partition(index)?)?;
+    }
+
+    let identifiers = new_identifiers()?;
+    // (old, new) pairs of the UUIDs to replace in the configuration of the copy
+    let mut replacements = Vec::new();
+
+    let target_boot = target.get_partition(BOOT_PARTITION_INDEX)?;
+    replacements.push(set_uuid(
+        &blkid,
+        &target_boot,
+        FilesystemType::Vfat,
+        &identifiers,
+    )?);
+
+    let target_root = target.get_partition(ROOT_PARTITION_INDEX)?;
+    let cryptsetup;
+    let encrypted_root = if is_encrypted_device(&target_root)? {
+        cryptsetup = Tool::find("cryptsetup", false)?;
+        let old_uuid = ids::uuid(&blkid, &target_root)?;
+        let new_uuid = identifiers.uuid("luks");
+        info!("Changing the LUKS UUID to {new_uuid}");
+        cryptsetup
+            .execute()
+            .args(["luksUUID", "-q", "--uuid", &new_uuid])
+            .arg(target_root.path())
+            .run(false)
+            .context("Failed to change the LUKS UUID")?;
+        replacements.push((old_uuid, new_uuid));
+        let encrypted_root =
+            EncryptedDevice::open(&cryptsetup, &target_root, state::root_mapper_name())?;
+        if command.expand {
+            resize::grow_luks(&cryptsetup, &encrypted_root)?;
+        }
+        Some(encrypted_root)
+    } else {
+        None
+    };
+    let root_device: &dyn BlockDevice = encrypted_root
+        .as_ref()
+        .map_or(&target_root, |e| e as &dyn BlockDevice);
+    let root_fs_type = match FilesystemType::from_mount_type(&filesystem_type(&blkid, root_device)?)
+    {
+        Some(fs_type @ (FilesystemType::Ext4 | FilesystemType::Btrfs)) => fs_type,
+        _ => {
+            return Err(anyhow!(
+                "Could not find an ext4 or btrfs root filesystem on {target}"
+            ));
+        }
+    };
+    replacements.push(set_uuid(&blkid, root_device, root_fs_type, &identifiers)?);
+
+    if command.expand {
+        resize::grow_filesystem(root_device)?;
+    }
+
+    let mount_point = state::temp_mount_point()?;
+    let boot_filesystem = Some(Filesystem::from_partition(
+        &target_boot,
+        FilesystemType::Vfat,
+    ));
+    let root_filesystem = Filesystem::from_partition(root_device, root_fs_type);
+    let mount_stack = mount(
+        mount_point.path(),
+        &boot_filesystem,
+        &root_filesystem,
+        None,
+        false,
+        false,
+    )?;
+
+    for file in UUID_FILES {
+        for (old_uuid, new_uuid) in &replacements {
+            seed::replace_in_file(&mount_point.path().join(file), old_uuid, new_uuid)?;
+        }
+    }
+
+    let manifest = install::read_manifest(mount_point.path());
+    if manifest.is_none() {
+        warn!(
+            "{} has no ALMA manifest, assuming a mkinitcpio initramfs and GRUB for BIOS and UEFI",
+            command.source.display()
+        );
+    }
+    let initramfs = manifest
+        .as_ref()
+        .map_or(InitramfsGenerator::Mkinitcpio, |m| m.initramfs);
+    let grub_targets = manifest
+        .as_ref()
+        .map_or(GrubTargets::Both, |m| m.grub_targets);
+
+    // sd-encrypt and hostonly dracut images embed the UUIDs
+    create::regenerate_initramfs(&arch_chroot, mount_point.path(), initramfs)?;
+
+    // grub-install embeds the volume ID of the boot partition in the loaders
+    create::reinstall_bootloader(
+        &target,
+        &mount_point,
+        &arch_chroot,
+        &blkid,
+        root_device,
+        grub_targets,
+    )?;
+
+    info!("Unmounting filesystems");
+    mount_stack.umount()?;
+
+    info!(
+        "Cloned {} to {}",
+        command.source.display(),
+        command.device.display()
+    );
+    Ok(())
+}
+
+/// The identifiers of the copy, derived from a random seed
+fn new_identifiers() -> anyhow::Result<Reproducible> {
+    let seed: String = grub::random_salt()?
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    Reproducible::new(&seed)
+}
+
+fn filesystem_type(blkid: &Tool, device: &dyn BlockDevice) -> anyhow::Result<String> {
+    let output = blkid
+        .execute()
+        .args(["-s", "TYPE", "-o", "value"])
+        .arg(device.path())
+        .run_text_output(false)
+        .with_context(|| format!("Failed to probe {}", device.path().display()))?;
+    Ok(output.trim().to_owned())
+}
+
+/// Gives the unmounted filesystem a new UUID, returning the old and the new one
+fn set_uuid(
+    blkid: &Tool,
+    device: &dyn BlockDevice,
+    fs_type: FilesystemType,
+    identifiers: &Reproducible,
+) -> anyhow::Result<(String, String)> {
+    let old_uuid = ids::uuid(blkid, device)?;
+    for step in fs_type.ops().set_uuid(device.path(), identifiers)? {
+        step.run(&Tool::find(step.tool, false)?)
+            .with_context(|| format!("Failed to change the UUID of {}", device.path().display()))?;
+    }
+    let new_uuid = ids::uuid(blkid, device)?;
+    info!(
+        "Changed the UUID of {} from {old_uuid} to {new_uuid}",
+        device.path().display()
+    );
+    Ok((old_uuid, new_uuid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_identifiers() {
+        let first = new_identifiers().unwrap();
+        let second = new_identifiers().unwrap();
+        assert_ne!(first.uuid("ext4"), second.uuid("ext4"));
+        assert_ne!(first.volume_id("vfat"), second.volume_id("vfat"));
+    }
+}
@@ -0,0 +1,94 @@
+//! `--cloud-init`: installs cloud-init, so the image configures itself from the metadata of the
+//! cloud it is booted on, or from a NoCloud seed embedded with `--cloud-init-seed`.
+
+use crate::effects;
+use crate::process::CommandExt;
+use crate::tool::Tool;
+use anyhow::{Context, anyhow};
+use std::path::Path;
+
+/// cloud-init sets up the ssh keys of the default user, so the server is installed with it
+pub const PACKAGES: [&str; 2] = ["cloud-init", "openssh"];
+
+/// The stages of cloud-init, in boot order. Newer releases split cloud-init.service into
+/// cloud-init-main.service and cloud-init-network.service, so only those which exist are enabled.
+const SERVICES: [&str; 6] = [
+    "cloud-init-main.service",
+    "cloud-init-local.service",
+    "cloud-init-network.service",
+    "cloud-init.service",
+    "cloud-config.service",
+    "cloud-final.service",
+];
+
+/// Where the NoCloud datasource looks for a seed, relative to the root of the installation
+const SEED_DIR: &str = "var/lib/cloud/seed/nocloud";
+
+/// Files of a NoCloud seed, copied when present in the seed directory
+const REQUIRED_SEED_FILES: [&str; 2] = ["user-data", "meta-data"];
+const OPTIONAL_SEED_FILES: [&str; 2] = ["network-config", "vendor-data"];
+
+/// Checks that `seed` is a directory with user-data and meta-data
+pub fn validate_seed(seed: &Path) -> anyhow::Result<()> {
+    for file in REQUIRED_SEED_FILES {
+        if !seed.join(file).is_file() {
+            return Err(anyhow!(
+                "The cloud-init seed {} has no {file} file, a NoCloud seed needs user-data and meta-data (which may be empty)",
+                seed.display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Enables the cloud-init services and sshd, and copies the seed into the installation
+pub fn configure(
+    arch_chroot: &Tool,
+    mount_path: &Path,
+    seed: Option<&Path>,
+    dryrun: bool,
+) -> anyhow::Result<()> {
+    let unit_dir = mount_path.join("usr/lib/systemd/system");
+    let services: Vec<&str> = SERVICES
+        .into_iter()
+        .filter(|service| dryrun || unit_dir.join(service).exists())
+        .collect();
+    arch_chroot
+        .execute()
+        .arg(mount_path)
+        .args(["systemctl", "enable", "sshd.service"])
+        .args(services)
+        .run(dryrun)
+        .context("Failed to enable the cloud-init services")?;
+
+    if let Some(seed) = seed {
+        let seed_dir = mount_path.join(SEED_DIR);
+        effects::create_dir_all(&seed_dir, dryrun)?;
+        for file in REQUIRED_SEED_FILES.into_iter().chain(OPTIONAL_SEED_FILES) {
+            let source = seed.join(file);
+            if !source.is_file() {
+                continue;
+            }
+            let target = seed_dir.join(file);
+            effects::copy(&source, &target, dryrun)?;
+            // user-data may contain passwords
+            effects::set_mode(&target, 0o600, dryrun)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_validate_seed() {
+        let seed = tempfile::tempdir().unwrap();
+        fs::write(seed.path().join("user-data"), "#cloud-config\n").unwrap();
+        assert!(validate_seed(seed.path()).is_err());
+        fs::write(seed.path().join("meta-data"), "").unwrap();
+        validate_seed(seed.path()).unwrap();
+    }
+}
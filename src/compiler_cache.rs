@@ -0,0 +1,168 @@
+//! `--compiler-cache`: builds the AUR packages with ccache or sccache, whose cache is kept on the
+//! host in the ALMA cache directory and bind mounted into the installation for the builds, so
+//! rebuilding an image does not compile large packages like nvidia-dkms from scratch again.
+
+use crate::args::{self, CompilerCache};
+use crate::effects;
+use crate::process::CommandExt;
+use crate::storage::MountStack;
+use crate::tool::Tool;
+use anyhow::Context;
+use log::{info, warn};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::process::Stdio;
+
+/// Where the cache is mounted in the installation
+const CACHE_MOUNT: &str = "var/cache/alma-compiler-cache";
+
+/// The makepkg drop-in turning the cache on, removed with the cache
+const MAKEPKG_CONF: &str = "etc/makepkg.conf.d/alma-compiler-cache.conf";
+
+/// The makepkg configuration using the cache in `dir`
+fn makepkg_conf(cache: CompilerCache, dir: &str) -> String {
+    match cache {
+        CompilerCache::Ccache => {
+            format!("BUILDENV=(!distcc color ccache check !sign)\nexport CCACHE_DIR={dir}\n")
+        }
+        CompilerCache::Sccache => {
+            format!("export RUSTC_WRAPPER=/usr/bin/sccache\nexport SCCACHE_DIR={dir}\n")
+        }
+    }
+}
+
+/// The idmapping showing the files of the cache, owned by whoever created it on the host, as
+/// the AUR user's in the installation. Files the builds add are owned by the host's owner too.
+fn aur_idmap(
+    host_dir: &Path,
+    arch_chroot: &Tool,
+    mount_path: &Path,
+    dryrun: bool,
+) -> anyhow::Result<String> {
+    // Neither exists in dry runs
+    let (host_uid, host_gid) = fs::metadata(host_dir).map_or((0, 0), |m| (m.uid(), m.gid()));
+    let (uid, gid) = if dryrun {
+        (String::from("AUR_UID"), String::from("AUR_GID"))
+    } else {
+        let aur_id = |flag: &str| {
+            arch_chroot
+                .execute()
+                .arg(mount_path)
+                .args(["id", flag, "aur"])
+                .run_text_output(false)
+                .map(|id| id.trim().to_owned())
+                .context("Failed to look up the AUR user")
+        };
+        (aur_id("-u")?, aur_id("-g")?)
+    };
+    Ok(format!("u:{host_uid}:{uid}:1 g:{host_gid}:{gid}:1"))
+}
+
+/// The cache set up in the installation, until `remove` takes it out again
+pub struct Provisioned<'a> {
+    cache: CompilerCache,
+    mount_stack: MountStack<'a>,
+    /// Whether the package was installed for the builds only
+    installed: bool,
+}
+
+/// Installs the cache in the installation at `mount_path` and points makepkg at it. The AUR
+/// user must exist, since the cache is mapped to it.
+pub fn provision<'a>(
+    cache: CompilerCache,
+    arch_chroot: &Tool,
+    mount_path: &Path,
+    dryrun: bool,
+) -> anyhow::Result<Option<Provisioned<'a>>> {
+    let Some(host_dir) = args::cache_dir().map(|dir| dir.join(cache.name())) else {
+        warn!(
+            "Neither $XDG_CACHE_HOME nor $HOME is set, building the AUR packages without a compiler cache"
+        );
+        return Ok(None);
+    };
+    info!(
+        "Building the AUR packages with {}, cached in {}",
+        cache.name(),
+        host_dir.display()
+    );
+
+    // Only removed afterwards if the installation did not have it already
+    let installed = dryrun
+        || arch_chroot
+            .execute()
+            .arg(mount_path)
+            .args(["pacman", "-Q", cache.name()])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .run(dryrun)
+            .is_err();
+    arch_chroot
+        .execute()
+        .arg(mount_path)
+        .args(["pacman", "-S", "--needed", "--noconfirm", cache.name()])
+        .run(dryrun)
+        .with_context(|| format!("Failed to install {}", cache.name()))?;
+
+    let target = mount_path.join(CACHE_MOUNT);
+    effects::create_dir_all(&host_dir, dryrun)?;
+    effects::create_dir_all(&target, dryrun)?;
+    // The AUR user has a new UID in every build, which only the mount maps the cache to, so
+    // the ownership on the host stays as it is
+    let idmap = aur_idmap(&host_dir, arch_chroot, mount_path, dryrun)?;
+    let mut mount_stack = MountStack::new(dryrun);
+    mount_stack
+        .bind_mount_idmapped(host_dir, target, &idmap)
+        .context("Failed to mount the compiler cache")?;
+
+    let conf = mount_path.join(MAKEPKG_CONF);
+    effects::create_dir_all(
+        conf.parent().expect("The drop-in is in a directory"),
+        dryrun,
+    )?;
+    effects::write(
+        &conf,
+        makepkg_conf(cache, &format!("/{CACHE_MOUNT}")),
+        dryrun,
+    )?;
+
+    Ok(Some(Provisioned {
+        cache,
+        mount_stack,
+        installed,
+    }))
+}
+
+impl Provisioned<'_> {
+    /// Takes the cache out of the installation again, leaving its contents on the host
+    pub fn remove(self, arch_chroot: &Tool, mount_path: &Path, dryrun: bool) -> anyhow::Result<()> {
+        effects::remove_file(&mount_path.join(MAKEPKG_CONF), dryrun)?;
+        self.mount_stack.umount()?;
+        effects::remove_dir(&mount_path.join(CACHE_MOUNT), dryrun)?;
+        if self.installed {
+            arch_chroot
+                .execute()
+                .arg(mount_path)
+                .args(["pacman", "-Rns", "--noconfirm", self.cache.name()])
+                .run(dryrun)
+                .with_context(|| format!("Failed to remove {}", self.cache.name()))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_makepkg_conf() {
+        let conf = makepkg_conf(CompilerCache::Ccache, "/var/cache/alma-compiler-cache");
+        assert!(conf.starts_with("BUILDENV=(!distcc color ccache check !sign)\n"));
+        assert!(conf.contains("export CCACHE_DIR=/var/cache/alma-compiler-cache\n"));
+        assert!(
+            makepkg_conf(CompilerCache::Sccache, "/cache")
+                .contains("RUSTC_WRAPPER=/usr/bin/sccache")
+        );
+    }
+}
@@ -0,0 +1,269 @@
+//! Packages which cannot be installed together, found before pacstrap from the conflicts and
+//! provides in the host's sync databases. pacstrap would only stop at them halfway through the
+//! build, so the user chooses which one to leave out up front.
+
+use crate::args::CreateCommand;
+use crate::create;
+use crate::error::{ErrorKind, ErrorKindExt};
+use crate::interactive::UserSettings;
+use crate::presets::PresetsCollection;
+use anyhow::anyhow;
+use dialoguer::{Select, theme::ColorfulTheme};
+use log::{debug, info, warn};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use which::which;
+
+#[derive(Debug, Default)]
+struct PackageInfo {
+    name: String,
+    provides: Vec<String>,
+    conflicts: Vec<String>,
+}
+
+/// Strips the version constraint of a relation like `jack>=1.9`. Conflicts are taken as
+/// unconditional, which is what they are for the current versions in practice.
+fn relation_name(relation: &str) -> &str {
+    relation.split(['<', '>', '=']).next().unwrap_or(relation)
+}
+
+/// Parses the output of `pacman -Si`
+fn parse_package_info(output: &str) -> Vec<PackageInfo> {
+    output
+        .split("\n\n")
+        .filter_map(|block| {
+            let mut info = PackageInfo::default();
+            for line in block.lines() {
+                let Some((key, value)) = line.split_once(" : ") else {
+                    continue;
+                };
+                let relations = || {
+                    value
+                        .split_whitespace()
+                        .filter(|v| *v != "None")
+                        .map(|v| relation_name(v).to_owned())
+                        .collect()
+                };
+                match key.trim() {
+                    "Name" => info.name = value.trim().to_owned(),
+                    "Provides" => info.provides = relations(),
+                    "Conflicts With" => info.conflicts = relations(),
+                    _ => (),
+                }
+            }
+            (!info.name.is_empty()).then_some(info)
+        })
+        .collect()
+}
+
+/// The pairs of packages where one conflicts with the other, or with something it provides
+fn find_conflicts(packages: &[PackageInfo]) -> Vec<(String, String)> {
+    let mut pairs = Vec::new();
+    for a in packages {
+        for b in packages.iter().filter(|b| b.name != a.name) {
+            if a.conflicts
+                .iter()
+                .any(|c| *c == b.name || b.provides.contains(c))
+            {
+                let pair = if a.name < b.name {
+                    (a.name.clone(), b.name.clone())
+                } else {
+                    (b.name.clone(), a.name.clone())
+                };
+                if !pairs.contains(&pair) {
+                    pairs.push(pair);
+                }
+            }
+        }
+    }
+    pairs.sort();
+    pairs
+}
+
+/// Looks the packages up in the sync databases of `pacman_conf`. pacman fails when some are not
+/// found, such as groups, but still describes the others.
+fn query(pacman_conf: &Path, packages: &[String]) -> Option<Vec<PackageInfo>> {
+    debug!("Looking up {} packages for conflicts", packages.len());
+    let output = Command::new("pacman")
+        .arg("--config")
+        .arg(pacman_conf)
+        .arg("-Si")
+        .args(packages)
+        .env("LC_ALL", "C")
+        .output()
+        .ok()?;
+    Some(parse_package_info(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Where a package was asked for, if it is not one ALMA needs itself
+fn origin(
+    package: &str,
+    command: &CreateCommand,
+    presets: &PresetsCollection,
+    user_settings: Option<&UserSettings>,
+) -> Option<&'static str> {
+    if command.extra_packages.iter().any(|p| p == package) {
+        Some("--extra-packages")
+    } else if presets.packages.contains(package) {
+        Some("a preset")
+    } else if user_settings.is_some_and(|s| {
+        [
+            &s.graphics_packages,
+            &s.font_packages,
+            &s.application_packages,
+        ]
+        .iter()
+        .any(|list| list.iter().any(|p| p == package))
+    }) {
+        Some("the interactive setup")
+    } else {
+        None
+    }
+}
+
+fn leave_out(
+    package: &str,
+    command: &mut CreateCommand,
+    presets: &mut PresetsCollection,
+    user_settings: Option<&mut UserSettings>,
+) {
+    command.extra_packages.retain(|p| p != package);
+    presets.packages.remove(package);
+    if let Some(settings) = user_settings {
+        for list in [
+            &mut settings.graphics_packages,
+            &mut settings.font_packages,
+            &mut settings.application_packages,
+        ] {
+            list.retain(|p| p != package);
+        }
+    }
+}
+
+/// Warns about the packages asked for twice, and has the user leave out one package of each
+/// conflicting pair. With --noconfirm, conflicts are an error instead.
+pub fn resolve(
+    command: &mut CreateCommand,
+    presets: &mut PresetsCollection,
+    user_settings: &mut Option<UserSettings>,
+) -> anyhow::Result<()> {
+    for package in &command.extra_packages {
+        if presets.packages.contains(package) {
+            warn!("{package} is given with --extra-packages and in a preset");
+        }
+    }
+    if which("pacman").is_err() {
+        return Ok(());
+    }
+
+    let mut packages: Vec<String> =
+        create::bootstrap_packages(command, presets, user_settings.as_ref())
+            .into_iter()
+            .chain(command.extra_packages.iter().cloned())
+            .collect();
+    packages.sort();
+    packages.dedup();
+    let pacman_conf = command
+        .pacman_conf
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("/etc/pacman.conf"));
+    let Some(infos) = query(&pacman_conf, &packages) else {
+        warn!("Could not look up the packages to install, they were not checked for conflicts");
+        return Ok(());
+    };
+
+    let mut left_out: Vec<String> = Vec::new();
+    for (a, b) in find_conflicts(&infos) {
+        if left_out.contains(&a) || left_out.contains(&b) {
+            continue;
+        }
+        let describe = |package: &str| {
+            let origin = origin(package, command, presets, user_settings.as_ref());
+            format!("{package} (from {})", origin.unwrap_or("ALMA"))
+        };
+        let message = format!("{} conflicts with {}", describe(&a), describe(&b));
+        let removable: Vec<&String> = [&a, &b]
+            .into_iter()
+            .filter(|p| origin(p, command, presets, user_settings.as_ref()).is_some())
+            .collect();
+        if removable.is_empty() {
+            warn!("{message}");
+            continue;
+        }
+        if command.noconfirm {
+            return Err(anyhow!("{message}, remove one of them")).kind(ErrorKind::Usage);
+        }
+
+        let items: Vec<String> = removable
+            .iter()
+            .map(|p| format!("Leave out {p}"))
+            .chain([String::from("Abort")])
+            .collect();
+        let choice = Select::with_theme(&ColorfulTheme::default())
+            .with_prompt(&message)
+            .items(&items)
+            .default(0)
+            .interact()?;
+        let Some(package) = removable.get(choice) else {
+            return Err(anyhow!("User aborted operation.")).kind(ErrorKind::Aborted);
+        };
+        info!("Leaving out {package}");
+        leave_out(package, command, presets, user_settings.as_mut());
+        left_out.push(package.to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_conflicts() {
+        let output = "Repository      : extra
+Name            : pipewire-jack
+Version         : 1:1.2.7-1
+Provides        : jack  libjack.so=0-64  libjackserver.so=0-64
+Optional Deps   : None
+Conflicts With  : jack  jack2
+Replaces        : None
+
+Repository      : extra
+Name            : jack2
+Version         : 1.9.22-1
+Provides        : jack  libjack.so=0-64
+Conflicts With  : jack
+Replaces        : None
+
+Repository      : extra
+Name            : nvidia-open-dkms
+Provides        : NVIDIA-MODULE
+Conflicts With  : NVIDIA-MODULE
+
+Repository      : extra
+Name            : nvidia-dkms
+Provides        : NVIDIA-MODULE
+Conflicts With  : NVIDIA-MODULE
+
+Repository      : core
+Name            : vim
+Provides        : xxd
+Conflicts With  : gvim  vim-minimal<9.1
+";
+        let infos = parse_package_info(output);
+        assert_eq!(infos.len(), 5);
+        assert_eq!(infos[4].conflicts, ["gvim", "vim-minimal"]);
+        assert!(infos[0].provides.contains(&String::from("libjack.so")));
+
+        assert_eq!(
+            find_conflicts(&infos),
+            [
+                (String::from("jack2"), String::from("pipewire-jack")),
+                (
+                    String::from("nvidia-dkms"),
+                    String::from("nvidia-open-dkms")
+                ),
+            ]
+        );
+    }
+}
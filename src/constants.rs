@@ -1,11 +1,39 @@
 pub const BOOT_PARTITION_INDEX: u8 = 1;
+pub const BIOS_BOOT_PARTITION_INDEX: u8 = 2;
 pub const ROOT_PARTITION_INDEX: u8 = 3;
+pub const DATA_PARTITION_INDEX: u8 = 4;
+
+// GPT partition type GUIDs. The root types are the ones of the Discoverable Partitions
+// Specification, which lets systemd-gpt-auto-generator find the root partition without root=.
+// ALMA creates no home or swap partitions, /home is a subvolume or directory of the root
+// filesystem and swap is a file.
+pub const ESP_PARTITION_TYPE: &str = "c12a7328-f81f-11d2-ba4b-00a0c93ec93b";
+pub const BIOS_BOOT_PARTITION_TYPE: &str = "21686148-6453-6f6e-744e-656564454649";
+pub const ROOT_X86_64_PARTITION_TYPE: &str = "4f68bce3-e8cd-4db1-96e7-fbcaf984b709";
+pub const ROOT_AARCH64_PARTITION_TYPE: &str = "b921b045-1df0-41c3-af44-4c6f280d3fae";
+/// Linux RAID, for the members of the --raid1 array, which cannot be discovered as root
+pub const RAID_PARTITION_TYPE: &str = "a19d880f-05fc-4d3b-a006-743f0f84911e";
+/// Microsoft basic data, so Windows and macOS offer to open the data partition
+pub const DATA_PARTITION_TYPE: &str = "ebd0a0a2-b9e5-4433-87c0-68b6b72699c7";
 
 pub const MIN_BOOT_MB: u32 = 200;
 pub const DEFAULT_BOOT_MB: u32 = 300;
 pub const MAX_BOOT_MB: u32 = 2048; // 2GiB
 
+pub const DEFAULT_SWAP_MB: u64 = 4096;
+
+/// With --refresh auto, host package databases older than this trigger a warning
+pub const STALE_DATABASE_DAYS: u64 = 7;
+
+pub const DEFAULT_ROOT_LABEL: &str = "alma-root";
+pub const DEFAULT_BOOT_LABEL: &str = "ALMA-BOOT";
+/// Label of the --data-partition, which fstab mounts it by
+pub const DATA_LABEL: &str = "ALMA-DATA";
+
 pub const OMARCHY_DEFAULT_BOOT_MB: u32 = 512;
+/// Arch Linux ARM kernels install their device trees and a second copy of the kernel image to
+/// the boot partition
+pub const AARCH64_DEFAULT_BOOT_MB: u32 = 512;
 pub const OMARCHY_MIN_BOOT_MB: u32 = 512;
 pub const OMARCHY_MIN_TOTAL_GIB: u64 = 15;
 
@@ -15,23 +43,79 @@ Storage=volatile
 SystemMaxUse=16M
 ";
 
-// Base packages for all installations
-pub const BASE_PACKAGES: [&str; 13] = [
+// Base packages for all installations, the kernel packages are added from --kernel and the
+// microcode and drivers only built for x86_64 from the architecture
+pub const BASE_PACKAGES: [&str; 9] = [
     "base",
-    "linux",
     "linux-firmware",
     "grub",
     "efibootmgr",
-    "intel-ucode",
-    "amd-ucode",
     "networkmanager",
-    "broadcom-wl",
     "rsync",
     "os-prober",
     "git",
     "base-devel",
 ];
 
+/// External tools used by ALMA: (binary, host package, only needed for)
+pub const HOST_TOOLS: &[(&str, &str, Option<&str>)] = &[
+    ("pacstrap", "arch-install-scripts", None),
+    ("arch-chroot", "arch-install-scripts", None),
+    ("genfstab", "arch-install-scripts", None),
+    ("sgdisk", "gptfdisk", None),
+    ("mkfs.fat", "dosfstools", None),
+    ("mkfs.ext4", "e2fsprogs", Some("ext4 root filesystems")),
+    ("mkfs.btrfs", "btrfs-progs", Some("btrfs root filesystems")),
+    ("btrfs", "btrfs-progs", Some("btrfs root filesystems")),
+    ("btrfstune", "btrfs-progs", Some("alma seed and alma clone")),
+    ("tune2fs", "e2fsprogs", Some("alma clone")),
+    ("fatlabel", "dosfstools", Some("alma clone")),
+    ("losetup", "util-linux", None),
+    ("blkid", "util-linux", None),
+    ("sfdisk", "util-linux", None),
+    ("lsblk", "util-linux", None),
+    ("git", "git", None),
+    ("cryptsetup", "cryptsetup", Some("--encrypted-root")),
+    ("mkfs.exfat", "exfatprogs", Some("--data-partition")),
+    ("mdadm", "mdadm", Some("--raid1")),
+    ("systemd-repart", "systemd", Some("--partitioner repart")),
+    ("mksquashfs", "squashfs-tools", Some("--iso")),
+    ("grub-mkrescue", "grub", Some("--iso")),
+    ("xorriso", "libisoburn", Some("--iso")),
+    ("mformat", "mtools", Some("--iso")),
+    ("filefrag", "e2fsprogs", Some("--hibernate on ext4")),
+    ("dumpe2fs", "e2fsprogs", Some("alma backup --shrink")),
+    ("qemu-system-x86_64", "qemu-system-x86", Some("alma qemu")),
+    ("qemu-img", "qemu-img", Some("--convert and alma export")),
+    ("zstd", "zstd", Some("--compress zstd")),
+    ("xz", "xz", Some("--compress xz")),
+    (
+        "mmc",
+        "mmc-utils",
+        Some("--board profiles writing to eMMC boot areas"),
+    ),
+];
+
+/// Minimum supported tool versions: (binary, version argument, minimum version)
+pub const MIN_TOOL_VERSIONS: &[(&str, &str, &str)] = &[
+    ("sgdisk", "--version", "1.0.5"),
+    ("cryptsetup", "--version", "2.1.0"),
+    ("mkfs.btrfs", "--version", "5.10"),
+    ("mkfs.ext4", "-V", "1.45"),
+];
+
+/// Minimum supported package versions, for tools without a version argument: (package, minimum
+/// version, what needs it)
+pub const MIN_PACKAGE_VERSIONS: &[(&str, &str, &str)] =
+    &[("arch-install-scripts", "28", "pacstrap -K")];
+
+/// Locations of the OVMF UEFI firmware used when booting USB devices in QEMU
+pub const OVMF_PATHS: [&str; 3] = [
+    "/usr/share/edk2/x64/OVMF_CODE.4m.fd",
+    "/usr/share/edk2-ovmf/x64/OVMF_CODE.fd",
+    "/usr/share/ovmf/x64/OVMF.fd",
+];
+
 // AUR dependencies for installing AUR helper
 pub const AUR_DEPENDENCIES: [&str; 1] = ["sudo"];
 
@@ -46,7 +130,10 @@ pub fn omarchy_branch() -> String {
     std::env::var("OMARCHY_REF").unwrap_or_else(|_| OMARCHY_DEFAULT_BRANCH.to_string())
 }
 
-pub const FONT_PACKAGES: &[(&str, &[&str])] = &[
+/// A choice of the interactive setup and the packages it installs
+pub type PackageChoice = (&'static str, &'static [&'static str]);
+
+pub const FONT_PACKAGES: &[PackageChoice] = &[
     (
         "Noto Fonts (Recommended)",
         &[
@@ -62,7 +149,47 @@ pub const FONT_PACKAGES: &[(&str, &[&str])] = &[
     ("IBM Plex Fonts", &["ttf-ibm-plex"]),
 ];
 
-pub const VIDEO_PACKAGES: &[(&str, &[&str])] = &[
+/// Curated applications offered by the interactive setup, by category
+pub const APPLICATION_CATEGORIES: &[(&str, &[PackageChoice])] = &[
+    (
+        "Browsers",
+        &[
+            ("Firefox", &["firefox"]),
+            ("Chromium", &["chromium"]),
+            ("Tor Browser", &["torbrowser-launcher"]),
+        ],
+    ),
+    (
+        "Office",
+        &[
+            ("LibreOffice", &["libreoffice-fresh"]),
+            ("Thunderbird", &["thunderbird"]),
+            ("Evince (PDF viewer)", &["evince"]),
+        ],
+    ),
+    (
+        "Development",
+        &[
+            ("Build tools and git", &["base-devel", "git"]),
+            ("Neovim", &["neovim"]),
+            ("Code - OSS", &["code"]),
+            ("Docker", &["docker", "docker-compose"]),
+            ("Python", &["python", "python-pip"]),
+        ],
+    ),
+    (
+        "Multimedia",
+        &[
+            ("VLC", &["vlc"]),
+            ("mpv", &["mpv"]),
+            ("GIMP", &["gimp"]),
+            ("Audacity", &["audacity"]),
+            ("OBS Studio", &["obs-studio"]),
+        ],
+    ),
+];
+
+pub const VIDEO_PACKAGES: &[PackageChoice] = &[
     (
         "AMD/Intel (Mesa)",
         &[
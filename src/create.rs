@@ -1,31 +1,78 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::env;
 use std::fs;
-use std::io::Write;
+use std::io::{self, Write};
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::{Context, anyhow};
 use byte_unit::Byte;
 use console::style;
 use dialoguer::Input;
-use dialoguer::{Confirm, Select, theme::ColorfulTheme};
-use log::{debug, info, warn};
+use dialoguer::{Confirm, Password, Select, theme::ColorfulTheme};
+use log::{debug, error, info, warn};
+use nix::fcntl::{FallocateFlags, fallocate};
 use nix::mount::MsFlags;
-
-use crate::args::{CreateCommand, Manifest, RootFilesystemType, Source, SystemVariant};
-use crate::aur::AurHelper;
+use nix::sys::statfs::{BTRFS_SUPER_MAGIC, NFS_SUPER_MAGIC, statfs};
+use sha2::{Digest, Sha256};
+
+use crate::arch::{self, Arch};
+use crate::args::{
+    AurFailure, CreateCommand, FixGptCommand, FlashCommand, GrubTargets, InitramfsGenerator,
+    Manifest, Partitioner, Phase, PlanFormat, Preallocation, RefreshPolicy, RootFilesystemType,
+    Source, SystemVariant,
+};
+use crate::artifact;
+use crate::aur::{self, AurBatch};
+use crate::baked;
+use crate::board::BoardProfile;
+use crate::boot_report::{self, BootReport};
+use crate::checkpoint;
+use crate::cloud_init;
+use crate::compiler_cache;
+use crate::conflicts;
 use crate::constants::{self, omarchy_branch, omarchy_repo_url};
 use crate::constants::{DEFAULT_BOOT_MB, MAX_BOOT_MB, MIN_BOOT_MB, OMARCHY_MIN_TOTAL_GIB};
-use crate::initcpio;
+use crate::dkms;
+use crate::dracut;
+use crate::effects;
+use crate::error::{ErrorKind, ErrorKindExt};
+use crate::fault::{self, FailPoint};
+use crate::flash;
+use crate::grub;
+use crate::initcpio::{self, InitcpioOptions};
 use crate::interactive::UserSettings;
-use crate::presets::{PathWrapper, PresetsCollection, Script};
+use crate::intercept::{InterceptStack, Interception};
+use crate::interrupt;
+use crate::iso;
+use crate::keymap;
+use crate::locale;
+use crate::mirrors;
+use crate::motd;
+use crate::notify::Notifier;
+use crate::omarchy_patch;
+use crate::pacman_hooks::{self, PacmanHooks};
+use crate::plan::{self, Action};
+use crate::presets::{self, PathWrapper, PresetsCollection, Script};
 use crate::process::CommandExt;
+use crate::repart::{self, Layout};
+use crate::reproducible::Reproducible;
+use crate::resize;
+use crate::state;
 use crate::storage::filesystem::FilesystemType;
 use crate::storage::{
-    self, BlockDevice, EncryptedDevice, Filesystem, LoopDevice, MountStack, StorageDevice,
+    self, BlockDevice, DeviceWatch, EncryptedDevice, Filesystem, LoopDevice, MountStack, RaidArray,
+    StorageDevice,
+    ids::{self, Identifier},
     partition::Partition,
+    raid,
 };
+use crate::sudo::{self, SudoOptions};
+use crate::swap;
+use crate::table_backup;
+use crate::timezone;
 use crate::tool::mount;
 use crate::tool::{Tool, Tools};
 use tempfile::TempDir;
@@ -33,24 +80,193 @@ use tempfile::TempDir;
 fn fix_fstab(fstab: &str) -> String {
     fstab
         .lines()
-        .filter(|line| !line.contains("swap") && !line.starts_with('#'))
+        .filter(|line| !swap::is_swap_entry(line) && !line.starts_with('#'))
         .collect::<Vec<&str>>()
         .join("\n")
 }
 
+/// fstab entries mounting /tmp (and /var/tmp) as size limited tmpfs
+fn tmpfs_entries(size_mb: u64, var_tmp: bool) -> String {
+    let mut mount_points = vec!["/tmp"];
+    if var_tmp {
+        mount_points.push("/var/tmp");
+    }
+    mount_points
+        .into_iter()
+        .map(|mount_point| {
+            format!(
+                "tmpfs {mount_point} tmpfs rw,nosuid,nodev,noatime,size={size_mb}M,mode=1777 0 0\n"
+            )
+        })
+        .collect()
+}
+
+/// fstab entry mounting the --data-partition at /data. exFAT has no ownership, so the files
+/// belong to the first user when one is created and are writable by everyone otherwise. nofail
+/// keeps the system booting if the partition was reformatted by another machine.
+fn data_partition_entry(has_user: bool) -> String {
+    let ownership = if has_user {
+        "uid=1000,gid=1000,dmask=022,fmask=133"
+    } else {
+        "umask=000"
+    };
+    FilesystemType::Exfat.ops().fstab_entry(
+        &Identifier::Label.spec(constants::DATA_LABEL),
+        "/data",
+        &format!("rw,nofail,noatime,{ownership}"),
+    )
+}
+
 pub fn create(mut command: CreateCommand) -> anyhow::Result<()> {
+    if let Some(script) = &command.dryrun_script {
+        command.dryrun = true;
+        plan::start_script(script)?;
+    }
+    plan::print_json(command.output == PlanFormat::Json);
+    if let Some(image) = command.from_image.clone() {
+        return create_from_image(&command, &image);
+    }
+    if command.resume {
+        checkpoint::resume(&mut command)?;
+    }
+    let iso = command.iso.clone();
+    if let Some(iso) = &iso {
+        iso::prepare_command(&mut command, iso);
+    }
+    let target = command
+        .path
+        .as_ref()
+        .map_or_else(|| String::from("ALMA"), |p| p.display().to_string());
+    let notifier = Notifier::new(
+        command.notify_cmd.clone(),
+        command.notify_desktop,
+        target,
+        command.dryrun,
+    );
+
+    // Checked up front rather than after a long build
+    let qemu_img = command
+        .convert
+        .map(|_| Tool::find("qemu-img", command.dryrun))
+        .transpose()
+        .map_err(|_| {
+            anyhow!("qemu-img is required for --convert. Please install the 'qemu-img' package.")
+        })
+        .kind(ErrorKind::Tooling)?;
+    let convert = command.convert.zip(qemu_img);
+    let remove_raw = command.remove_raw;
+    let compressor = command
+        .compress
+        .map(|compression| {
+            Tool::find(compression.name(), command.dryrun).map_err(|_| {
+                anyhow!(
+                    "{0} is required for --compress {0}. Please install the '{0}' package.",
+                    compression.name()
+                )
+            })
+        })
+        .transpose()
+        .kind(ErrorKind::Tooling)?;
+    let compress = command.compress.zip(compressor);
+
+    let started = Instant::now();
+    let build_image = command.image.and(command.path.clone());
+    // The metadata describes the ISO rather than the image it was built in
+    let mut image_path = iso.clone().or(build_image.clone());
+    let dryrun = command.dryrun;
+
+    let result = create_system(command, &notifier).and_then(|manifest_json| {
+        if let (Some(_), Some(build_image)) = (&iso, &build_image) {
+            effects::remove_file(build_image, dryrun)?;
+        }
+        // The loop device of the image was detached when the system was created
+        if let (Some((format, qemu_img)), Some(raw)) = (&convert, &build_image) {
+            let disk = artifact::convert(qemu_img, raw, *format, dryrun)?;
+            if remove_raw {
+                effects::remove_file(raw, dryrun)?;
+            }
+            // The metadata describes the disk, which is what gets shipped
+            image_path = Some(disk);
+        }
+        // Written once the image is fully released, so its checksum is final
+        if let Some(image) = &image_path {
+            if dryrun {
+                plan::print(Action::Note {
+                    text: format!(
+                        "Write the image metadata to {}",
+                        artifact::metadata_path(image).display()
+                    ),
+                });
+            } else {
+                let path =
+                    artifact::write_metadata(image, manifest_json.as_deref(), started.elapsed())?;
+                info!("Wrote the image metadata to {}", path.display());
+                info!("{}", artifact::size_report(image)?);
+            }
+            if let Some((compression, compressor)) = &compress {
+                let compressed = artifact::compress(compressor, image, *compression, dryrun)?;
+                info!("Wrote {}", compressed.display());
+            }
+        }
+        Ok(())
+    });
+    match &result {
+        Ok(()) => notifier.success(),
+        Err(err) => notifier.failure(err),
+    }
+    // Written even when the dry run failed, as far as it got
+    if let Some(script) = plan::finish_script()? {
+        info!("Wrote the dry run to {}", script.display());
+    }
+    result
+}
+
+/// Creates the system, returning the manifest (as JSON) if it was generated
+fn create_system(
+    mut command: CreateCommand,
+    notifier: &Notifier,
+) -> anyhow::Result<Option<String>> {
     // --- Initial Command Validation & Adjustments ---
-    validate_command(&command)?;
+    validate_command(&command).kind(ErrorKind::Usage)?;
+    let reproducible = command
+        .reproducible
+        .as_deref()
+        .map(Reproducible::new)
+        .transpose()
+        .kind(ErrorKind::Usage)?;
     adjust_command_for_system(&mut command)?;
     // We only prompt for user settings if we are NOT in non-interactive mode.
-    let user_settings: Option<UserSettings> = if !command.noconfirm {
+    let mut user_settings: Option<UserSettings> = if command.user_settings.is_some() {
+        command.user_settings.take()
+    } else if let Some(answers) = &command.answers {
+        Some(user_settings_from_flags(
+            &command,
+            UserSettings::from_answers(answers)?,
+        )?)
+    } else if let Some(username) = &command.username {
+        Some(user_settings_from_flags(
+            &command,
+            UserSettings::for_user(username.clone())?,
+        )?)
+    } else if !command.noconfirm && (command.runs(Phase::Bootstrap) || command.runs(Phase::Setup)) {
         Some(UserSettings::prompt()?)
+    } else if !command.noconfirm {
+        None
     } else {
         info!(
             "--noconfirm specified, skipping interactive setup. System will be configured by presets."
         );
         None
     };
+    // --keymap takes precedence over the keymap of the interactive setup or answers file
+    if command.keymap.is_none() {
+        command.keymap = user_settings.as_ref().and_then(|s| s.keymap.clone());
+    }
+
+    // Asked for up front rather than after a long build
+    if command.grub_password && command.runs(Phase::Bootloader) {
+        command.grub_password_hash = Some(grub_password_hash(&command, reproducible.as_ref())?);
+    }
 
     let original_command_string = env::args().collect::<Vec<String>>().join(" ");
     let mut manifest_sources: Vec<Source> = Vec::new();
@@ -63,33 +279,79 @@ pub fn create(mut command: CreateCommand) -> anyhow::Result<()> {
         .map(|p| p.into_path_wrapper(command.noconfirm))
         .collect::<anyhow::Result<Vec<PathWrapper>>>()?;
 
-    for (i, _p_path) in presets_paths.iter().enumerate() {
+    for (i, p_path) in presets_paths.iter().enumerate() {
         let origin_path = command.presets[i].to_string();
-        let baked_path = PathBuf::from("/usr/share/alma/baked_sources").join(format!("preset_{i}"));
+        let baked_path = Path::new("/")
+            .join(baked::BAKED_SOURCES_DIR)
+            .join(format!("preset_{i}"));
         manifest_sources.push(Source {
             r#type: "preset".to_string(),
             origin: origin_path,
             baked_path,
+            commit: presets::git_commit(p_path.to_path()),
         });
     }
 
-    let presets = PresetsCollection::load(
+    let mut presets = PresetsCollection::load(
         &presets_paths
             .iter()
             .map(|x| x.to_path())
             .collect::<Vec<&Path>>(),
     )?;
 
+    if command.mount_options.is_none() {
+        command.mount_options = presets.mount_options.clone();
+    }
+
+    let mut initcpio_options = presets.initcpio.clone();
+    initcpio_options.merge(&command.initcpio_options());
+    if command.initramfs == InitramfsGenerator::Dracut && !presets.initcpio.is_empty() {
+        warn!("Ignoring the mkinitcpio settings of the presets, since dracut is used");
+    }
+
+    // Read early, so an unreadable template fails before the build rather than at its very end
+    let login_templates = (
+        command
+            .motd
+            .as_deref()
+            .map(motd::load_template)
+            .transpose()?,
+        command
+            .issue
+            .as_deref()
+            .map(motd::load_template)
+            .transpose()?,
+    );
+
     // 2. Prepare tools
-    let tools = Tools::new(&command)?;
+    let tools = Tools::new(&command).kind(ErrorKind::Tooling)?;
+
+    // Before partitioning, so a failed refresh or a package conflict leaves the device untouched
+    if command.runs(Phase::Bootstrap) {
+        refresh_host(&command)?;
+        conflicts::resolve(&mut command, &mut presets, &mut user_settings)?;
+    }
 
     // 3. Resolve device path and create image file if needed
-    let (storage_device_path, _image_loop) = resolve_device_path_and_image(&command)?;
+    let (storage_device_path, image_loop) = resolve_device_path_and_image(&command)?;
     let mut storage_device = StorageDevice::from_path(
         &storage_device_path,
         command.allow_non_removable,
         command.dryrun,
     )?;
+    let mut mirror_device = command
+        .raid1
+        .as_deref()
+        .map(|path| StorageDevice::from_path(path, command.allow_non_removable, command.dryrun))
+        .transpose()?;
+    if let Some(mirror_device) = &mirror_device
+        && mirror_device.path() == storage_device.path()
+    {
+        return Err(anyhow!(
+            "The --raid1 mirror must be another device than {storage_device}"
+        ))
+        .kind(ErrorKind::Usage);
+    }
 
     // Check total device/image size for Omarchy
     if command.system == SystemVariant::Omarchy {
@@ -103,6 +365,12 @@ pub fn create(mut command: CreateCommand) -> anyhow::Result<()> {
         } else {
             storage_device.size()
         };
+        let total_size = byte_unit::Byte::from_u128(total_size.as_u128().saturating_sub(
+            u128::from(command.leave_free_mb() + command.data_partition_mb().unwrap_or(0))
+                * 1024
+                * 1024,
+        ))
+        .expect("Size fits in a Byte");
 
         if total_size.as_u128() < min_total_bytes {
             warn!(
@@ -120,23 +388,76 @@ pub fn create(mut command: CreateCommand) -> anyhow::Result<()> {
                 if !confirmed {
                     return Err(anyhow!(
                         "User aborted operation due to insufficient device size for Omarchy."
-                    ));
+                    ))
+                    .kind(ErrorKind::Aborted);
                 }
             }
         }
     }
 
     // 4. Safety checks and partitioning
-    confirm_and_wipe_device(&mut storage_device, &command)?;
-    let (boot_partition, root_partition_base) =
-        partition_and_format(&command, &tools, &storage_device)?;
+    let (boot_partition, root_partition_base) = if command.runs(Phase::Partition) {
+        notifier.phase(Phase::Partition);
+        // A new image file has nothing to save
+        if let Some(dir) = &command.backup_table
+            && command.image.is_none()
+        {
+            table_backup::save(&tools.sgdisk, &storage_device, dir, command.dryrun)?;
+            if let Some(mirror_device) = &mirror_device {
+                table_backup::save(&tools.sgdisk, mirror_device, dir, command.dryrun)?;
+            }
+        }
+        confirm_and_wipe_device(&mut storage_device, &command)?;
+        if let Some(mirror_device) = &mut mirror_device {
+            confirm_and_wipe_device(mirror_device, &command)?;
+        }
+        partition_and_format(
+            &command,
+            &tools,
+            &storage_device,
+            mirror_device.as_ref(),
+            reproducible.as_ref(),
+        )?
+    } else {
+        info!("Skipping partitioning, reusing the existing partitions and filesystems");
+        storage_device.umount_if_needed();
+        if let Some(mirror_device) = &mut mirror_device {
+            mirror_device.umount_if_needed();
+        }
+        existing_partitions(&command, &storage_device)?
+    };
+
+    // The root filesystem (or its LUKS container) goes on the array instead of the partition
+    let raid_array = match &mirror_device {
+        Some(mirror_device) => Some(open_raid_array(
+            &command,
+            &tools,
+            &root_partition_base,
+            mirror_device,
+            reproducible.as_ref(),
+        )?),
+        None => None,
+    };
+    let root_partition_base = match &raid_array {
+        Some(array) => Partition::new::<StorageDevice>(array.path().to_path_buf()),
+        None => root_partition_base,
+    };
+
+    if command.encrypted_root && command.runs(Phase::Partition) {
+        keymap::warn_on_passphrase_mismatch(command.keymap.as_deref());
+        EncryptedDevice::prepare(
+            tools.cryptsetup.as_ref().unwrap(),
+            &root_partition_base,
+            reproducible.as_ref().map(|r| r.uuid("luks")).as_deref(),
+        )?;
+    }
 
     // 5. Open encrypted container if requested
     let encrypted_root = if command.encrypted_root {
         Some(EncryptedDevice::open(
             tools.cryptsetup.as_ref().unwrap(),
             &root_partition_base,
-            "alma_root".into(),
+            state::root_mapper_name(),
         )?)
     } else {
         None
@@ -146,86 +467,316 @@ pub fn create(mut command: CreateCommand) -> anyhow::Result<()> {
         .map_or(&root_partition_base, |e| e as &dyn BlockDevice);
     let root_fs_type: FilesystemType = command.filesystem.into();
 
-    if root_fs_type == FilesystemType::Btrfs {
-        setup_btrfs_subvolumes(
-            root_block_device,
-            tools.mkbtrfs.as_ref().ok_or_else(|| {
-                anyhow!("Please install the btrfs-progs package to create btrfs filesystems")
-            })?,
-            tools.btrfs.as_ref().ok_or_else(|| {
-                anyhow!("Please install the btrfs-progs package to create btrfs filesystems")
-            })?,
-            command.dryrun,
-        )?;
-    } else {
-        Filesystem::format(
-            root_block_device,
-            root_fs_type,
-            tools.mkext4.as_ref().context("mkfs.ext4 tool missing")?,
-        )?;
+    // When the partition phase is skipped, the filesystems were created by an earlier run
+    if command.runs(Phase::Partition) {
+        if root_fs_type == FilesystemType::Btrfs {
+            setup_btrfs_subvolumes(
+                root_block_device,
+                tools.mkbtrfs.as_ref().ok_or_else(|| {
+                    anyhow!("Please install the btrfs-progs package to create btrfs filesystems")
+                })?,
+                tools.btrfs.as_ref().ok_or_else(|| {
+                    anyhow!("Please install the btrfs-progs package to create btrfs filesystems")
+                })?,
+                &command.root_label,
+                command.swap_size_mb().is_some(),
+                reproducible.as_ref(),
+                command.dryrun,
+            )?;
+        } else {
+            Filesystem::format(
+                root_block_device,
+                root_fs_type,
+                tools.mkext4.as_ref().context("mkfs.ext4 tool missing")?,
+                Some(&command.root_label),
+                reproducible.as_ref(),
+            )?;
+        }
     }
 
+    fault::inject(command.fail_at, FailPoint::PostPartition)?;
+
     let boot_filesystem = boot_partition
         .as_ref()
         .map(|p| Filesystem::from_partition(p, FilesystemType::Vfat));
     let root_filesystem = Filesystem::from_partition(root_block_device, root_fs_type);
 
-    // 6. Bootstrap system
-    // The `bootstrap_system` function now implicitly uses the new smart `mount` tool
-    let (mount_point, mount_stack) = bootstrap_system(
-        &command,
-        &tools,
+    // 6. Mount and bootstrap system
+    let mount_point = state::temp_mount_point()?;
+    // Only created for real, as the dry run does not mount anything there
+    if command.dryrun {
+        effects::create_dir_all(mount_point.path(), true)?;
+    }
+    let mut mount_stack = mount(
+        mount_point.path(),
         &boot_filesystem,
         &root_filesystem,
-        &presets,
-        user_settings.as_ref(),
+        command.mount_options.as_deref(),
+        command.swap_size_mb().is_some(),
+        command.dryrun,
     )?;
+    let mut device_watch = DeviceWatch::new(
+        &storage_device,
+        encrypted_root.is_some() || raid_array.is_some(),
+    );
+
+    // Everything from here on runs against the mounted target, which --keep-mounts-on-error
+    // leaves in place if it fails
+    let result = (|| -> anyhow::Result<(Option<String>, Option<BootReport>)> {
+        let mut manifest_json = None;
+        let completed = |phase| checkpoint::record(mount_point.path(), phase, command.dryrun);
+        if command.runs(Phase::Partition) {
+            completed(Phase::Partition)?;
+        }
+        if command.runs(Phase::Bootstrap) {
+            notifier.phase(Phase::Bootstrap);
+            bootstrap_system(
+                &command,
+                &tools,
+                mount_point.path(),
+                &presets,
+                user_settings.as_ref(),
+            )?;
+            disable_copy_on_write(&command, &tools.arch_chroot, mount_point.path(), &presets)?;
+            completed(Phase::Bootstrap)?;
+        }
+
+        // 7. Copy baked sources into the image
+        device_watch.check(&mut mount_stack)?;
+        if command.runs(Phase::Bake) {
+            notifier.phase(Phase::Bake);
+            bake_sources_into_image(&tools, mount_point.path(), &presets_paths, &command)?;
+            completed(Phase::Bake)?;
+        }
 
-    // 7. Copy baked sources into the image
-    bake_sources_into_image(&tools, mount_point.path(), &presets_paths, &command)?;
+        device_watch.check(&mut mount_stack)?;
+        if let Some(settings) = &user_settings
+            && command.runs(Phase::Setup)
+        {
+            notifier.phase(Phase::Setup);
+            info!("Applying settings from interactive setup...");
+            let setup_script = settings.generate_setup_script()?;
+            run_recoverable_step(
+                &command,
+                &tools.arch_chroot,
+                mount_point.path(),
+                "Applying the interactive setup",
+                || {
+                    run_script_in_chroot(
+                        &setup_script,
+                        &tools.arch_chroot,
+                        mount_point.path(),
+                        command.dryrun,
+                    )?;
+                    set_user_password(
+                        settings,
+                        &tools.arch_chroot,
+                        mount_point.path(),
+                        command.dryrun,
+                    )
+                },
+            )
+            .kind(ErrorKind::Chroot)?;
+        }
+
+        let sudo_options = command.sudo_options();
+        if command.runs(Phase::Setup) && (user_settings.is_some() || !sudo_options.is_empty()) {
+            configure_sudo(
+                &sudo_options,
+                user_settings.as_ref().is_some_and(|s| s.passwordless_sudo),
+                &tools.arch_chroot,
+                mount_point.path(),
+                command.dryrun,
+            )
+            .kind(ErrorKind::Chroot)?;
+        }
+        if command.runs(Phase::Setup) {
+            if let Some(settings) = &user_settings {
+                checkpoint::record_user_settings(mount_point.path(), settings, command.dryrun)?;
+            }
+            completed(Phase::Setup)?;
+        }
 
-    if let Some(settings) = &user_settings {
-        info!("Applying settings from interactive setup...");
-        let setup_script = settings.generate_setup_script()?;
-        run_script_in_chroot(
-            &setup_script,
+        // 8. Apply customizations (AUR, presets)
+        device_watch.check(&mut mount_stack)?;
+        let aur_failures = apply_customizations(
+            &command,
             &tools.arch_chroot,
+            &presets,
             mount_point.path(),
-            command.dryrun,
-        )?;
-    }
+            notifier,
+        )
+        .kind(ErrorKind::Chroot)?;
+        // Preset scripts may have changed the sudoers files
+        if command.runs(Phase::Presets) && !presets.scripts.is_empty() {
+            check_sudoers(&tools.arch_chroot, mount_point.path(), command.dryrun)
+                .kind(ErrorKind::Chroot)?;
+        }
+        if command.runs(Phase::Presets) {
+            completed(Phase::Presets)?;
+        }
 
-    // 8. Apply customizations (AUR, presets)
-    apply_customizations(&command, &tools.arch_chroot, &presets, mount_point.path())?;
+        // 9. Finalize installation (bootloader, services)
+        device_watch.check(&mut mount_stack)?;
+        if command.runs(Phase::Bootloader) {
+            notifier.phase(Phase::Bootloader);
+            // Read by the initramfs, which the bootloader phase generates
+            if let Some(array) = &raid_array {
+                configure_mdadm(array, mount_point.path(), command.dryrun)?;
+            }
+            finalize_installation(
+                &command,
+                &tools,
+                &storage_device,
+                &mount_point,
+                encrypted_root.as_ref(),
+                &root_partition_base,
+                &initcpio_options,
+            )?;
+            // With a mirror, the phase completes once the boot partition is mirrored below
+            if mirror_device.is_none() {
+                completed(Phase::Bootloader)?;
+            }
+        }
 
-    // 9. Finalize installation (bootloader, services)
-    finalize_installation(
-        &command,
-        &tools,
-        &storage_device,
-        &mount_point,
-        encrypted_root.as_ref(),
-        &root_partition_base,
-    )?;
+        // 10. Install Omarchy if requested
+        device_watch.check(&mut mount_stack)?;
+        if command.system == SystemVariant::Omarchy && command.runs(Phase::Omarchy) {
+            // We need the username. In interactive mode, we have it, and a resumed build restores
+            // it from the checkpoint.
+            // In non-interactive, presets are expected to have created the user.
+            // We will default to a common name if not in interactive mode, but this path is less robust.
+            if user_settings.is_none() && command.resume {
+                return Err(anyhow!(
+                    "The checkpoint has no user to install Omarchy for, pass the user of the failed build with --username"
+                ))
+                .kind(ErrorKind::Usage);
+            }
+            let username = user_settings.as_ref().map_or("user", |s| &s.username);
+            let git_identity = user_settings.as_ref().and_then(|s| s.git_identity.as_ref());
+            notifier.phase(Phase::Omarchy);
+            run_recoverable_step(
+                &command,
+                &tools.arch_chroot,
+                mount_point.path(),
+                "Installing Omarchy",
+                || {
+                    install_omarchy(
+                        &tools,
+                        mount_point.path(),
+                        &command,
+                        &presets,
+                        username,
+                        git_identity,
+                    )
+                },
+            )
+            .kind(ErrorKind::Chroot)?;
+            completed(Phase::Omarchy)?;
+        }
 
-    // 10. Install Omarchy if requested
-    if command.system == SystemVariant::Omarchy {
-        // We need the username. In interactive mode, we have it.
-        // In non-interactive, presets are expected to have created the user.
-        // We will default to a common name if not in interactive mode, but this path is less robust.
-        let username = user_settings.as_ref().map_or("user", |s| &s.username);
-        install_omarchy(&tools, mount_point.path(), &command, username)?;
-    }
+        // Once nothing else writes to the boot partition
+        if let Some(mirror_device) = &mirror_device
+            && command.runs(Phase::Bootloader)
+        {
+            mirror_boot_partition(&command, &tools, mirror_device, mount_point.path())
+                .kind(ErrorKind::Bootloader)?;
+            completed(Phase::Bootloader)?;
+        }
 
-    // 11. Generate manifest
-    generate_manifest(
-        &command,
-        &mount_point,
-        &original_command_string,
-        &mut manifest_sources,
-    )?;
+        // 11. Generate manifest
+        device_watch.check(&mut mount_stack)?;
+        if command.runs(Phase::Manifest) {
+            // Every package is in by now, e.g. the nvidia driver Omarchy picks
+            dkms::check_modules(&tools.arch_chroot, mount_point.path(), command.dryrun)?;
+            notifier.phase(Phase::Manifest);
+            let json = generate_manifest(
+                &command,
+                &mount_point,
+                &original_command_string,
+                &mut manifest_sources,
+                &aur_failures,
+                user_settings.as_ref(),
+                &initcpio_options,
+                &login_templates,
+                storage_device.id_link(),
+            )?;
+            write_login_messages(
+                &command,
+                &login_templates,
+                format!("{:x}", Sha256::digest(json.as_bytes())),
+                reproducible.as_ref(),
+                mount_point.path(),
+            )?;
+            manifest_json = Some(json);
+            // The build is complete, there is nothing left to resume
+            checkpoint::remove(mount_point.path(), command.dryrun)?;
+        }
+
+        // Gathered once the initramfs and bootloader are final. An ISO boots in its own way.
+        let boot_report = if command.dryrun || command.iso.is_some() {
+            None
+        } else {
+            match BootReport::collect(
+                &tools.blkid,
+                mount_point.path(),
+                root_block_device,
+                boot_partition.as_ref().map(|p| p as &dyn BlockDevice),
+                encrypted_root
+                    .as_ref()
+                    .map(|_| &root_partition_base as &dyn BlockDevice),
+                command.initramfs,
+            ) {
+                Ok(report) => {
+                    let report_path = mount_point.path().join(boot_report::REPORT_PATH);
+                    // The manifest phase creates the directory, unless it was skipped
+                    if let Some(dir) = report_path.parent() {
+                        effects::create_dir_all(dir, false)?;
+                    }
+                    effects::write(&report_path, report.to_string(), false)?;
+                    Some(report)
+                }
+                Err(err) => {
+                    warn!("Could not gather the boot report: {err:#}");
+                    None
+                }
+            }
+        };
+
+        // 12. Normalize the installation, this must come after everything writing to it
+        if let Some(reproducible) = &reproducible {
+            normalize_for_reproducibility(reproducible, mount_point.path(), command.dryrun)?;
+        }
+
+        // 13. Pack the installation into a live ISO
+        if let Some(iso) = &command.iso {
+            iso::build(
+                &command,
+                &tools,
+                mount_point.path(),
+                iso,
+                reproducible.as_ref(),
+            )?;
+        }
+
+        Ok((manifest_json, boot_report))
+    })();
+
+    let (manifest_json, boot_report) = match result {
+        Ok(result) => result,
+        Err(err) => {
+            device_watch.warn_if_disconnected();
+            // Ctrl+C asks to stop, not to inspect the target
+            if command.keep_mounts_on_error && !command.dryrun && !interrupt::interrupted() {
+                // Forgetting the stack skips the unmounting done when it is dropped
+                std::mem::forget(mount_stack);
+                keep_mounts(mount_point, encrypted_root, raid_array, image_loop);
+            }
+            return Err(err);
+        }
+    };
 
-    // 12. Interactive chroot and cleanup
+    // 13. Interactive chroot and cleanup
     interactive_chroot_and_cleanup(
         &command,
         &tools.arch_chroot,
@@ -234,28 +785,38 @@ pub fn create(mut command: CreateCommand) -> anyhow::Result<()> {
     )?;
 
     info!("Installation complete!");
-    Ok(())
+    if let Some(report) = boot_report {
+        info!(
+            "Boot report, also stored at /{}:\n{report}",
+            boot_report::REPORT_PATH
+        );
+    }
+    Ok(manifest_json)
 }
 
-/// Creates a btrfs filesystem and the standard subvolume layout.
+/// Creates a btrfs filesystem and the standard subvolume layout, plus the @swap subvolume if a
+/// swap file will be created.
 fn setup_btrfs_subvolumes(
     device: &dyn BlockDevice,
     mkbtrfs: &Tool,
     btrfs: &Tool,
+    label: &str,
+    swap: bool,
+    reproducible: Option<&Reproducible>,
     dryrun: bool,
 ) -> anyhow::Result<()> {
     info!("Creating Btrfs filesystem with subvolumes...");
     // 1. Format the partition
-    mkbtrfs
-        .execute()
-        .arg("-f")
-        .arg("-L")
-        .arg("alma-root")
-        .arg(device.path())
-        .run(dryrun)?;
+    Filesystem::format(
+        device,
+        FilesystemType::Btrfs,
+        mkbtrfs,
+        Some(label),
+        reproducible,
+    )?;
 
     // 2. Mount top-level to create subvolumes
-    let temp_mount = tempfile::tempdir().context("Failed to create temp dir for btrfs setup")?;
+    let temp_mount = state::temp_mount_point()?;
     let mut temp_mount_stack = MountStack::new(dryrun);
 
     // We pass `noatime` as a flag and the `data` (options string) as None.
@@ -268,7 +829,10 @@ fn setup_btrfs_subvolumes(
     )?;
 
     // 3. Create subvolumes
-    let subvolumes = ["@", "@home", "@log", "@pkg"];
+    let mut subvolumes = vec!["@", "@home", "@log", "@pkg"];
+    if swap {
+        subvolumes.push(swap::SWAP_SUBVOLUME);
+    }
     for vol in &subvolumes {
         let vol_path = temp_mount.path().join(vol);
         info!("Creating subvolume: {}", vol_path.display());
@@ -285,11 +849,149 @@ fn setup_btrfs_subvolumes(
 }
 
 fn validate_command(command: &CreateCommand) -> anyhow::Result<()> {
-    if matches!(command.system, SystemVariant::Omarchy) && command.noconfirm {
+    let Some(arch) = Arch::host() else {
+        return Err(anyhow!(
+            "ALMA only builds {} systems, {} hosts are not supported",
+            Arch::ALL.map(Arch::name).join(" and "),
+            env::consts::ARCH
+        ));
+    };
+    if let Some(kernel) = command.kernels.iter().find(|k| k.arch() != arch) {
+        return Err(anyhow!(
+            "The {kernel} kernel is not built for {arch}, use --kernel {}",
+            arch.default_kernel()
+        ));
+    }
+    if arch == Arch::Aarch64 && command.grub_targets.bios() {
+        return Err(anyhow!(
+            "aarch64 machines have no BIOS, use --grub-targets efi"
+        ));
+    }
+    if arch == Arch::Aarch64 && command.initramfs == InitramfsGenerator::Dracut {
+        return Err(anyhow!(
+            "--initramfs dracut is not supported on aarch64, Arch Linux ARM kernels install no vmlinuz for its hook to copy"
+        ));
+    }
+    if let Some(board) = &command.board {
+        if board.arch != arch {
+            return Err(anyhow!(
+                "The board {} is {1}, its systems can only be built on {1} hosts",
+                board.name,
+                board.arch
+            ));
+        }
+        if command.root_partition.is_some() {
+            return Err(anyhow!(
+                "--board writes the firmware before the first partition, which needs the whole disk rather than --root-partition"
+            ));
+        }
+        if command.partitioner == Partitioner::Repart && board.partitions_start_mb().is_some() {
+            return Err(anyhow!(
+                "systemd-repart cannot leave space for the firmware of the board {} before the first partition, use --partitioner sgdisk",
+                board.name
+            ));
+        }
+        if command.image.is_some() && board.uses_boot_areas() {
+            return Err(anyhow!(
+                "An --image has no eMMC boot areas for the firmware of the board {}, install to the eMMC directly",
+                board.name
+            ));
+        }
+    }
+    if command.initramfs == InitramfsGenerator::Dracut && command.systemd_initramfs {
+        return Err(anyhow!(
+            "--systemd-initramfs only applies to mkinitcpio, dracut always builds a systemd based initramfs"
+        ));
+    }
+    if command.initramfs == InitramfsGenerator::Dracut && !command.initcpio_options().is_empty() {
+        return Err(anyhow!(
+            "The --initramfs-module, --initramfs-binary, --initramfs-file, --initramfs-hook and --initramfs-compression options only apply to mkinitcpio"
+        ));
+    }
+    if let (Some(format), Some(path)) = (command.convert, &command.path)
+        && artifact::converted_path(path, format) == *path
+    {
+        return Err(anyhow!(
+            "The image {} would be overwritten by its conversion to {}, use another extension",
+            path.display(),
+            format.name()
+        ));
+    }
+    FilesystemType::from(command.filesystem)
+        .validate_label(&command.root_label)
+        .context("Invalid --root-label")?;
+    FilesystemType::Vfat
+        .validate_label(&command.boot_label)
+        .context("Invalid --boot-label")?;
+    command.sudo_options().validate()?;
+    if let Some(keymap) = &command.keymap {
+        keymap::validate(keymap)?;
+    }
+    for locale in &command.locales {
+        locale::validate(locale)?;
+    }
+    if let Some(timezone) = &command.timezone {
+        timezone::validate(timezone)?;
+    }
+    if let Some(seed) = &command.cloud_init_seed {
+        cloud_init::validate_seed(seed)?;
+    }
+    if command.volatile_root
+        && command.initramfs == InitramfsGenerator::Mkinitcpio
+        && !command.systemd_initramfs
+    {
+        return Err(anyhow!(
+            "--volatile-root needs a systemd based initramfs, pass --systemd-initramfs or --initramfs dracut"
+        ));
+    }
+    if command.tmpfs_size_mb() == Some(0) {
+        return Err(anyhow!("--tmpfs must be at least 1MiB"));
+    }
+    if command.iso.is_some() && command.initramfs == InitramfsGenerator::Dracut {
+        return Err(anyhow!(
+            "--iso builds its live initramfs with the archiso hooks of mkinitcpio, which cannot be combined with --initramfs dracut"
+        ));
+    }
+    if command.iso.is_some() && command.filesystem == RootFilesystemType::Btrfs {
+        return Err(anyhow!(
+            "--iso needs an ext4 root filesystem, the btrfs subvolumes would be left out of the squashfs"
+        ));
+    }
+    if command.data_partition_mb() == Some(0) {
+        return Err(anyhow!("--data-partition must be at least 1MiB"));
+    }
+    if let Some(dir) = command.nodatacow.iter().find(|d| !d.is_absolute()) {
+        return Err(anyhow!(
+            "--nodatacow takes absolute paths inside the installation, got {}",
+            dir.display()
+        ));
+    }
+    if command.initramfs_autodetect {
+        warn!(
+            "--initramfs-autodetect only includes kernel modules for the hardware ALMA is running on. The resulting system may not boot on other machines."
+        );
+    }
+    if matches!(command.system, SystemVariant::Omarchy)
+        && command.noconfirm
+        && command.answers.is_none()
+        && command.username.is_none()
+    {
         return Err(anyhow!(
-            "Non-interactive installation (--noconfirm) is not supported for Omarchy."
+            "Non-interactive installation (--noconfirm) is only supported for Omarchy with --answers or --username."
         ));
     }
+    if command.answers.is_none() && command.username.is_none() {
+        let flag = [
+            ("--hostname", command.hostname.is_some()),
+            ("--timezone", command.timezone.is_some()),
+            ("--password-file", command.password_file.is_some()),
+        ]
+        .into_iter()
+        .find_map(|(flag, given)| given.then_some(flag));
+        if let Some(flag) = flag {
+            return Err(anyhow!("{flag} needs --username or --answers"));
+        }
+    }
     if command.encrypted_root && command.noconfirm {
         return Err(anyhow!(
             "Non-interactive encrypted root setup is not supported. The passphrase must be entered manually."
@@ -298,6 +1000,28 @@ fn validate_command(command: &CreateCommand) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Applies --hostname, --timezone and --password-file over the user settings of --username or
+/// --answers
+fn user_settings_from_flags(
+    command: &CreateCommand,
+    mut settings: UserSettings,
+) -> anyhow::Result<UserSettings> {
+    if let Some(username) = &command.username {
+        settings.username = UserSettings::for_user(username.clone())?.username;
+    }
+    if let Some(hostname) = &command.hostname {
+        settings.hostname = hostname.clone();
+    }
+    if let Some(timezone) = &command.timezone {
+        settings.timezone = timezone.clone();
+    }
+    if let Some(path) = &command.password_file {
+        settings.user_password = Some(UserSettings::read_password_file(path)?);
+        settings.password_is_hash = false;
+    }
+    Ok(settings)
+}
+
 fn adjust_command_for_system(command: &mut CreateCommand) -> anyhow::Result<()> {
     if command.system == SystemVariant::Omarchy {
         let user_set_fs = env::args().any(|arg| arg.starts_with("--filesystem"));
@@ -314,7 +1038,8 @@ fn adjust_command_for_system(command: &mut CreateCommand) -> anyhow::Result<()>
                 if !confirmed {
                     return Err(anyhow!(
                         "User aborted due to filesystem mismatch for Omarchy."
-                    ));
+                    ))
+                    .kind(ErrorKind::Aborted);
                 }
             }
         // User confirmed, so we leave it as ext4.
@@ -343,10 +1068,14 @@ fn resolve_device_path_and_image(
         select_block_device(command.allow_non_removable, command.noconfirm)?
     };
 
-    let image_loop = if let Some(size) = command.image {
+    let image_loop = if !command.runs(Phase::Partition) && command.image.is_some() {
+        // Reuse the image built by an earlier run as is
+        Some(LoopDevice::create(&storage_device_path, command.dryrun)?)
+    } else if let Some(size) = command.image {
         Some(create_image(
             &storage_device_path,
             size,
+            command.preallocate,
             command.overwrite,
             command.dryrun,
         )?)
@@ -386,6 +1115,7 @@ fn select_block_device(allow_non_removable: bool, noconfirm: bool) -> anyhow::Re
 fn create_image(
     path: &Path,
     size: Byte,
+    preallocate: Preallocation,
     overwrite: bool,
     dryrun: bool,
 ) -> anyhow::Result<LoopDevice> {
@@ -393,19 +1123,97 @@ fn create_image(
         let mut options = fs::OpenOptions::new();
         options.write(true);
         if overwrite {
-            options.create(true);
+            options.create(true).truncate(true);
         } else {
             options.create_new(true);
         }
-        let file = options
+        let mut file = options
             .open(path)
             .context("Error creating the image file")?;
-        file.set_len(size.as_u64())
+        prepare_image_backing(path);
+        allocate_image(&mut file, size.as_u64(), preallocate)
             .context("Error setting image file size")?;
+    } else {
+        let path = path.to_string_lossy();
+        let argv = match preallocate {
+            Preallocation::None => vec![
+                String::from("truncate"),
+                String::from("-s"),
+                size.as_u64().to_string(),
+                path.into_owned(),
+            ],
+            Preallocation::Falloc => vec![
+                String::from("fallocate"),
+                String::from("-l"),
+                size.as_u64().to_string(),
+                path.into_owned(),
+            ],
+            Preallocation::Full => vec![
+                String::from("dd"),
+                String::from("if=/dev/zero"),
+                format!("of={path}"),
+                String::from("bs=1M"),
+                format!("count={}", size.as_u64().div_ceil(1024 * 1024)),
+            ],
+        };
+        plan::print(Action::Command {
+            argv,
+            env: Default::default(),
+            stdout: None,
+        });
     }
     LoopDevice::create(path, dryrun)
 }
 
+/// Adapts a new, still empty image file to the filesystem it is stored on
+fn prepare_image_backing(path: &Path) {
+    let fs_type = match statfs(path) {
+        Ok(stat) => stat.filesystem_type(),
+        Err(e) => {
+            debug!(
+                "Unable to determine the filesystem of {}: {e}",
+                path.display()
+            );
+            return;
+        }
+    };
+
+    if fs_type == BTRFS_SUPER_MAGIC {
+        // Copy-on-write fragments the image as it is written through the loop device, and can
+        // only be disabled while the file is empty
+        info!("The image is on btrfs, disabling copy-on-write for it");
+        let chattr = Tool::find("chattr", false)
+            .and_then(|chattr| chattr.execute().arg("+C").arg(path).run_text_output(false));
+        if let Err(e) = chattr {
+            warn!("Unable to disable copy-on-write for the image: {e}");
+        }
+    } else if fs_type == NFS_SUPER_MAGIC {
+        warn!(
+            "The image is on NFS. Building it through a loop device is slow, and the image may be corrupted if the connection drops. Consider building it on a local filesystem and copying it afterwards."
+        );
+    }
+}
+
+fn allocate_image(file: &mut fs::File, size: u64, preallocate: Preallocation) -> io::Result<()> {
+    match preallocate {
+        Preallocation::None => file.set_len(size),
+        Preallocation::Falloc => {
+            fallocate(&*file, FallocateFlags::empty(), 0, size as i64).map_err(io::Error::from)
+        }
+        Preallocation::Full => {
+            info!("Writing zeroes to the image file, this may take a while");
+            let zeroes = vec![0; 1024 * 1024];
+            let mut remaining = size;
+            while remaining > 0 {
+                let chunk = remaining.min(zeroes.len() as u64) as usize;
+                file.write_all(&zeroes[..chunk])?;
+                remaining -= chunk as u64;
+            }
+            file.sync_all()
+        }
+    }
+}
+
 fn confirm_and_wipe_device(
     storage_device: &mut StorageDevice,
     command: &CreateCommand,
@@ -414,10 +1222,10 @@ fn confirm_and_wipe_device(
         if !command.noconfirm {
             let confirmed = Confirm::with_theme(&ColorfulTheme::default())
                 .with_prompt(format!("{} Device {} has mounted partitions. This will unmount them and WIPE ALL DATA. Continue?",
-                    style("WARNING:").red().bold(), storage_device.path().display()))
+                    style("WARNING:").red().bold(), storage_device))
                 .default(false).interact()?;
             if !confirmed {
-                return Err(anyhow!("User aborted operation."));
+                return Err(anyhow!("User aborted operation.")).kind(ErrorKind::Aborted);
             }
         }
         storage_device.umount_if_needed();
@@ -425,20 +1233,44 @@ fn confirm_and_wipe_device(
     Ok(())
 }
 
-fn partition_and_format<'a>(
-    command: &CreateCommand,
-    tools: &Tools,
-    storage_device: &'a StorageDevice,
-) -> anyhow::Result<(Option<Partition<'a>>, Partition<'a>)> {
+/// Size of the boot partition in MiB, as given or the default of the system variant
+fn boot_size_mb(command: &CreateCommand) -> u32 {
     let default_boot_mb = if command.system == SystemVariant::Omarchy {
         constants::OMARCHY_DEFAULT_BOOT_MB
+    } else if Arch::target() == Arch::Aarch64 {
+        constants::AARCH64_DEFAULT_BOOT_MB
     } else {
         DEFAULT_BOOT_MB
     };
 
-    let boot_size_mb = command
+    command
         .boot_size
-        .map_or(default_boot_mb, |b| (b.as_u128() / 1_048_576) as u32);
+        .map_or(default_boot_mb, |b| (b.as_u128() / 1_048_576) as u32)
+}
+
+/// The partition layout of the disk (and the --raid1 mirror) given on the command line
+fn layout(command: &CreateCommand) -> Layout {
+    Layout {
+        boot_size_mb: boot_size_mb(command),
+        leave_free_mb: command.leave_free_mb(),
+        data_partition_mb: command.data_partition_mb(),
+        raid: command.raid1.is_some(),
+        start_mb: command
+            .board
+            .as_ref()
+            .and_then(BoardProfile::partitions_start_mb),
+        arch: Arch::target(),
+    }
+}
+
+fn partition_and_format<'a>(
+    command: &CreateCommand,
+    tools: &Tools,
+    storage_device: &'a StorageDevice,
+    mirror_device: Option<&StorageDevice>,
+    reproducible: Option<&Reproducible>,
+) -> anyhow::Result<(Option<Partition<'a>>, Partition<'a>)> {
+    let boot_size_mb = boot_size_mb(command);
 
     if command.system == SystemVariant::Omarchy {
         if boot_size_mb < constants::OMARCHY_MIN_BOOT_MB {
@@ -455,7 +1287,8 @@ fn partition_and_format<'a>(
                 if !confirmed {
                     return Err(anyhow!(
                         "User aborted operation due to small boot partition size for Omarchy."
-                    ));
+                    ))
+                    .kind(ErrorKind::Aborted);
                 }
             }
         }
@@ -475,100 +1308,403 @@ fn partition_and_format<'a>(
             if !confirmed {
                 return Err(anyhow!(
                     "User aborted operation due to boot partition size warning."
-                ));
+                ))
+                .kind(ErrorKind::Aborted);
             }
         }
     }
 
-    let (boot_partition, root_partition_base) = if let Some(root_partition_path) =
-        &command.root_partition
-    {
-        (
-            command
-                .boot_partition
-                .clone()
-                .map(Partition::new::<StorageDevice>),
-            Partition::new::<StorageDevice>(root_partition_path.clone()),
-        )
-    } else {
-        let parts = repartition_disk(storage_device, boot_size_mb, &tools.sgdisk, command.dryrun)?;
-        (Some(parts.boot_partition), parts.root_partition_base)
-    };
+    let (boot_partition, root_partition_base) =
+        if let Some(root_partition_path) = &command.root_partition {
+            (
+                command
+                    .boot_partition
+                    .clone()
+                    .map(Partition::new::<StorageDevice>),
+                Partition::new::<StorageDevice>(root_partition_path.clone()),
+            )
+        } else {
+            let layout = layout(command);
+            let parts = match command.partitioner {
+                Partitioner::Sgdisk => repartition_disk(
+                    storage_device,
+                    &layout,
+                    &tools.sgdisk,
+                    reproducible,
+                    command.dryrun,
+                )?,
+                Partitioner::Repart => repart_disk(
+                    storage_device,
+                    &layout,
+                    tools.repart.as_ref().expect("No tool for systemd-repart"),
+                    reproducible,
+                    command.dryrun,
+                )?,
+            };
+            if let Some(data_partition) = &parts.data_partition {
+                Filesystem::format(
+                    data_partition,
+                    FilesystemType::Exfat,
+                    tools.mkexfat.as_ref().context("mkfs.exfat tool missing")?,
+                    Some(constants::DATA_LABEL),
+                    reproducible,
+                )?;
+            }
+            (Some(parts.boot_partition), parts.root_partition_base)
+        };
 
     if let Some(bp) = &boot_partition {
-        Filesystem::format(bp, FilesystemType::Vfat, &tools.mkfat)?;
+        Filesystem::format(
+            bp,
+            FilesystemType::Vfat,
+            &tools.mkfat,
+            Some(&command.boot_label),
+            reproducible,
+        )?;
     }
 
-    if command.encrypted_root {
-        EncryptedDevice::prepare(tools.cryptsetup.as_ref().unwrap(), &root_partition_base)?;
+    if let Some(mirror_device) = mirror_device {
+        let mirror_reproducible = reproducible.map(|r| r.derive("mirror"));
+        let layout = Layout {
+            data_partition_mb: None,
+            ..layout(command)
+        };
+        let mirror = repartition_disk(
+            mirror_device,
+            &layout,
+            &tools.sgdisk,
+            mirror_reproducible.as_ref(),
+            command.dryrun,
+        )?;
+        format_mirror_boot(
+            tools,
+            boot_partition
+                .as_ref()
+                .expect("--raid1 conflicts with --root-partition"),
+            &mirror.boot_partition,
+            &command.boot_label,
+            command.dryrun,
+        )?;
     }
 
     Ok((boot_partition, root_partition_base))
 }
 
+/// Formats the boot partition of the --raid1 mirror with the volume ID of the first one, so
+/// fstab and the bootloader find whichever of the two is present
+fn format_mirror_boot(
+    tools: &Tools,
+    boot_partition: &Partition,
+    mirror_boot_partition: &Partition,
+    label: &str,
+    dryrun: bool,
+) -> anyhow::Result<()> {
+    // blkid shows FAT volume IDs as XXXX-XXXX
+    let volume_id = ids::uuid(&tools.blkid, boot_partition)?.replace('-', "");
+    let volume_id = if dryrun {
+        String::from("00000000")
+    } else {
+        volume_id
+    };
+    tools
+        .mkfat
+        .execute()
+        .args(["-F32", "-n", label, "-i", &volume_id])
+        .arg(mirror_boot_partition.path())
+        .run(dryrun)
+        .context("Error formatting the boot partition of the mirror")
+}
+
+/// Creates the RAID-1 array of the root partitions of both devices, or assembles the one of an
+/// earlier run when the partition phase is skipped
+fn open_raid_array<'t>(
+    command: &CreateCommand,
+    tools: &'t Tools,
+    root_partition: &Partition,
+    mirror_device: &StorageDevice,
+    reproducible: Option<&Reproducible>,
+) -> anyhow::Result<RaidArray<'t>> {
+    let mdadm = tools.mdadm.as_ref().expect("No tool for mdadm");
+    let mirror_root = mirror_device.get_partition(constants::ROOT_PARTITION_INDEX)?;
+    let members: [&dyn BlockDevice; 2] = [root_partition, &mirror_root];
+    if command.runs(Phase::Partition) {
+        info!("Creating the RAID-1 array of the root partitions");
+        RaidArray::create(
+            mdadm,
+            members,
+            &state::raid_node_name(),
+            reproducible.map(|r| r.uuid("raid")).as_deref(),
+        )
+    } else {
+        RaidArray::assemble(mdadm, members, &state::raid_node_name())
+    }
+}
+
+/// Returns the partitions created by an earlier run, for when the partition phase is skipped
+fn existing_partitions<'a>(
+    command: &CreateCommand,
+    storage_device: &'a StorageDevice,
+) -> anyhow::Result<(Option<Partition<'a>>, Partition<'a>)> {
+    if let Some(root_partition_path) = &command.root_partition {
+        return Ok((
+            command
+                .boot_partition
+                .clone()
+                .map(Partition::new::<StorageDevice>),
+            Partition::new::<StorageDevice>(root_partition_path.clone()),
+        ));
+    }
+
+    Ok((
+        Some(storage_device.get_partition(constants::BOOT_PARTITION_INDEX)?),
+        storage_device.get_partition(constants::ROOT_PARTITION_INDEX)?,
+    ))
+}
+
 struct DiskPartitions<'a> {
     boot_partition: Partition<'a>,
     root_partition_base: Partition<'a>,
+    data_partition: Option<Partition<'a>>,
 }
 
 fn repartition_disk<'a>(
     storage_device: &'a StorageDevice,
-    boot_size_mb: u32,
+    layout: &Layout,
     sgdisk: &Tool,
+    reproducible: Option<&Reproducible>,
     dryrun: bool,
 ) -> anyhow::Result<DiskPartitions<'a>> {
-    info!("Wiping and partitioning the block device");
-    sgdisk
-        .execute()
-        .args([
-            "-Z",
-            "-o",
-            &format!("--new=1::+{boot_size_mb}M"),
-            "--new=2::+1M",
-            "--largest-new=3",
-            "--typecode=1:EF00",
-            "--typecode=2:EF02",
-        ])
+    info!("Wiping and partitioning {storage_device}");
+    let Layout {
+        boot_size_mb,
+        leave_free_mb,
+        data_partition_mb,
+        start_mb,
+        ..
+    } = *layout;
+    // A negative end is relative to the end of the disk
+    let root_end_mb = leave_free_mb + data_partition_mb.unwrap_or(0);
+    let root_partition = if root_end_mb > 0 {
+        format!("--new=3:0:-{root_end_mb}M")
+    } else {
+        String::from("--largest-new=3")
+    };
+    let mut command = sgdisk.execute();
+    command.args([
+        "-Z",
+        "-o",
+        &format!(
+            "--new=1:{}:+{boot_size_mb}M",
+            start_mb.map_or(String::new(), |mb| format!("{mb}M"))
+        ),
+        "--new=2::+1M",
+        &root_partition,
+    ]);
+    if data_partition_mb.is_some() {
+        command.arg(if leave_free_mb > 0 {
+            format!("--new=4:0:-{leave_free_mb}M")
+        } else {
+            String::from("--largest-new=4")
+        });
+    }
+    for (partition, partition_type) in layout.partition_types() {
+        command.arg(format!("--typecode={partition}:{partition_type}"));
+    }
+    let partition_count = if data_partition_mb.is_some() { 4 } else { 3 };
+    if let Some(reproducible) = reproducible {
+        command.arg(format!("--disk-guid={}", reproducible.uuid("disk")));
+        for partition in 1..=partition_count {
+            command.arg(format!(
+                "--partition-guid={partition}:{}",
+                reproducible.uuid(&format!("partition-{partition}"))
+            ));
+        }
+    }
+    command
+        .arg(storage_device.path())
+        .run(dryrun)
+        .context("Partitioning error")?;
+    std::thread::sleep(std::time::Duration::from_millis(1000));
+    disk_partitions(storage_device, layout)
+}
+
+/// Partitions the disk with systemd-repart, into the same layout as `repartition_disk`
+fn repart_disk<'a>(
+    storage_device: &'a StorageDevice,
+    layout: &Layout,
+    repart: &Tool,
+    reproducible: Option<&Reproducible>,
+    dryrun: bool,
+) -> anyhow::Result<DiskPartitions<'a>> {
+    info!("Wiping and partitioning {storage_device} with systemd-repart");
+    let definitions_dir = tempfile::tempdir().context("Error creating a temporary directory")?;
+    for (name, contents) in layout.definitions() {
+        effects::write(&definitions_dir.path().join(name), contents, dryrun)?;
+    }
+
+    let mut command = repart.execute();
+    command
+        .args(["--dry-run=no", "--empty=force", "--no-pager"])
+        .arg(format!(
+            "--definitions={}",
+            definitions_dir.path().display()
+        ));
+    if let Some(reproducible) = reproducible {
+        // Every partition UUID and the disk GUID are derived from the seed
+        command.arg(format!("--seed={}", reproducible.uuid("disk")));
+    }
+    command
         .arg(storage_device.path())
         .run(dryrun)
         .context("Partitioning error")?;
     std::thread::sleep(std::time::Duration::from_millis(1000));
+    disk_partitions(storage_device, layout)
+}
+
+fn disk_partitions<'a>(
+    storage_device: &'a StorageDevice,
+    layout: &Layout,
+) -> anyhow::Result<DiskPartitions<'a>> {
     Ok(DiskPartitions {
         boot_partition: storage_device.get_partition(constants::BOOT_PARTITION_INDEX)?,
         root_partition_base: storage_device.get_partition(constants::ROOT_PARTITION_INDEX)?,
+        data_partition: layout
+            .data_partition_mb
+            .map(|_| storage_device.get_partition(constants::DATA_PARTITION_INDEX))
+            .transpose()?,
     })
 }
 
-fn bootstrap_system<'a>(
+/// Installs the partition layout to /etc/repart.d, so systemd-repart grows the root partition
+/// when the system boots from a larger disk
+fn install_repart_definitions(command: &CreateCommand, mount_path: &Path) -> anyhow::Result<()> {
+    let layout = layout(command);
+    let dir = mount_path.join(repart::DEFINITIONS_DIR);
+    effects::create_dir_all(&dir, command.dryrun)?;
+    for (name, contents) in layout.definitions() {
+        effects::write(&dir.join(name), contents, command.dryrun)?;
+    }
+    Ok(())
+}
+
+/// Applies --refresh on the host, whose keyring pacstrap copies into the target
+fn refresh_host(command: &CreateCommand) -> anyhow::Result<()> {
+    match command.refresh {
+        RefreshPolicy::Never => Ok(()),
+        RefreshPolicy::Auto => {
+            let stale = Duration::from_secs(constants::STALE_DATABASE_DAYS * 24 * 60 * 60);
+            if let Some(age) = host_database_age()
+                && age > stale
+            {
+                warn!(
+                    "The host's package databases were last synced {} days ago, its keyring may be too old to verify current packages. Pass --refresh force to update them first.",
+                    age.as_secs() / (24 * 60 * 60)
+                );
+            }
+            Ok(())
+        }
+        RefreshPolicy::Force => {
+            info!("Refreshing the host's package databases and keyring");
+            Tool::find("pacman", command.dryrun)?
+                .execute()
+                .args(["-Syy", "--needed", "--noconfirm", "archlinux-keyring"])
+                .run(command.dryrun)
+                .context("Failed to refresh the host's package databases")
+        }
+    }
+}
+
+/// Time since the host's package databases were last synced, going by the newest of them
+fn host_database_age() -> Option<Duration> {
+    fs::read_dir("/var/lib/pacman/sync")
+        .ok()?
+        .filter_map(|entry| entry.ok()?.metadata().ok()?.modified().ok())
+        .max()?
+        .elapsed()
+        .ok()
+}
+
+/// Marks the --nodatacow directories and those of the presets with chattr +C, so files created in
+/// them later are not copy-on-write
+fn disable_copy_on_write(
     command: &CreateCommand,
-    tools: &Tools,
-    boot_filesystem: &'a Option<Filesystem>,
-    root_filesystem: &'a Filesystem,
+    arch_chroot: &Tool,
+    mount_path: &Path,
     presets: &PresetsCollection,
-    user_settings: Option<&UserSettings>,
-) -> anyhow::Result<(tempfile::TempDir, MountStack<'a>)> {
-    let mount_point = tempfile::tempdir().context("Error creating a temporary directory")?;
-    let mount_stack = mount(
-        mount_point.path(),
-        boot_filesystem,
-        root_filesystem,
-        command.dryrun,
-    )?;
+) -> anyhow::Result<()> {
+    let mut dirs = command.nodatacow.clone();
+    for dir in &presets.nodatacow {
+        if !dirs.contains(dir) {
+            dirs.push(dir.clone());
+        }
+    }
+    if dirs.is_empty() {
+        return Ok(());
+    }
+    if command.filesystem != RootFilesystemType::Btrfs {
+        warn!("Ignoring the nodatacow directories, copy-on-write only exists on btrfs");
+        return Ok(());
+    }
+
+    for dir in dirs {
+        info!("Disabling copy-on-write for {}", dir.display());
+        let host_dir = mount_path.join(dir.strip_prefix("/").unwrap_or(&dir));
+        if !command.dryrun
+            && fs::read_dir(&host_dir).is_ok_and(|mut entries| entries.next().is_some())
+        {
+            warn!(
+                "{} is not empty, its existing files remain copy-on-write",
+                dir.display()
+            );
+        }
+        arch_chroot
+            .execute()
+            .arg(mount_path)
+            .args(["mkdir", "-p"])
+            .arg(&dir)
+            .run(command.dryrun)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+        arch_chroot
+            .execute()
+            .arg(mount_path)
+            .args(["chattr", "+C"])
+            .arg(&dir)
+            .run(command.dryrun)
+            .with_context(|| format!("Failed to disable copy-on-write for {}", dir.display()))?;
+    }
+
+    Ok(())
+}
 
+/// The packages pacstrap installs, apart from --extra-packages
+pub fn bootstrap_packages(
+    command: &CreateCommand,
+    presets: &PresetsCollection,
+    user_settings: Option<&UserSettings>,
+) -> HashSet<String> {
     let mut packages: HashSet<String> = constants::BASE_PACKAGES
         .iter()
+        .chain(Arch::target().packages())
         .map(|s| String::from(*s))
         .collect();
 
+    packages.extend(command.kernels.iter().map(|k| k.package_name().to_string()));
+    if command.initramfs == InitramfsGenerator::Dracut {
+        // Installing dracut in the same transaction satisfies the kernels' initramfs dependency,
+        // so mkinitcpio is not pulled in
+        packages.insert(String::from("dracut"));
+    }
+
     // Add interactive packages if applicable
     if let Some(settings) = user_settings {
-        info!("Adding packages selected during interactive setup...");
+        debug!("Adding packages selected during interactive setup...");
         packages.extend(settings.graphics_packages.iter().cloned());
         packages.extend(settings.font_packages.iter().cloned());
+        packages.extend(settings.application_packages.iter().cloned());
     }
 
     if command.system == SystemVariant::Omarchy {
-        info!("Adding Omarchy specific packages (PipeWire, Bluetooth)...");
+        debug!("Adding Omarchy specific packages (PipeWire, Bluetooth)...");
         packages.extend(
             [
                 "wget",
@@ -591,83 +1727,265 @@ fn bootstrap_system<'a>(
         );
     }
 
+    if command.sudo_rs {
+        packages.insert(String::from("sudo-rs"));
+    }
+
     if command.filesystem == RootFilesystemType::Btrfs {
-        info!("Adding btrfs-progs for Btrfs filesystem...");
+        debug!("Adding btrfs-progs for Btrfs filesystem...");
         packages.insert("btrfs-progs".to_string());
     }
 
+    if command.data_partition.is_some() {
+        packages.insert(String::from("exfatprogs"));
+    }
+
+    if command.raid1.is_some() {
+        packages.insert(String::from("mdadm"));
+    }
+
+    if command.iso.is_some() {
+        packages.insert(String::from(iso::HOOKS_PACKAGE));
+    }
+
+    if command.cloud_init {
+        packages.extend(cloud_init::PACKAGES.iter().map(|s| String::from(*s)));
+    }
+
+    if let Some(board) = &command.board {
+        packages.extend(board.packages.iter().cloned());
+    }
+
     // Add packages from presets and AUR dependencies
     packages.extend(presets.packages.clone());
     packages.extend(constants::AUR_DEPENDENCIES.iter().map(|s| String::from(*s)));
 
+    // DKMS modules build against the headers of each kernel, in the pacman hook which installs
+    // them, so the headers go in first
+    let aur_packages = presets
+        .aur_packages
+        .iter()
+        .flat_map(|batch| &batch.packages)
+        .chain(&command.aur_packages);
+    if packages
+        .iter()
+        .chain(aur_packages)
+        .any(|package| dkms::is_dkms_package(package))
+    {
+        let headers = dkms::header_packages(&command.kernels);
+        info!("Adding {} for the DKMS modules", headers.join(", "));
+        packages.extend(headers);
+    }
+    packages
+}
+
+/// Copies the /boot/Image of the Arch Linux ARM kernel to where GRUB and the mkinitcpio preset
+/// look for it, now and whenever the kernel is upgraded
+fn install_kernel_image_hook(
+    arch_chroot: &Tool,
+    mount_path: &Path,
+    dryrun: bool,
+) -> anyhow::Result<()> {
+    let hooks_dir = mount_path.join(pacman_hooks::HOOKS_DIR);
+    let scripts_dir = mount_path.join(pacman_hooks::SCRIPTS_DIR);
+    effects::create_dir_all(&hooks_dir, dryrun)?;
+    effects::create_dir_all(&scripts_dir, dryrun)?;
+    effects::write(
+        &hooks_dir.join(arch::KERNEL_IMAGE_HOOK),
+        arch::kernel_image_hook(pacman_hooks::SCRIPTS_DIR),
+        dryrun,
+    )?;
+    let script = scripts_dir.join(arch::KERNEL_IMAGE_SCRIPT);
+    effects::write(&script, arch::kernel_image_script(), dryrun)?;
+    effects::set_mode(&script, 0o755, dryrun)?;
+    arch_chroot
+        .execute()
+        .arg(mount_path)
+        .arg(
+            Path::new("/")
+                .join(pacman_hooks::SCRIPTS_DIR)
+                .join(arch::KERNEL_IMAGE_SCRIPT),
+        )
+        .run(dryrun)
+        .context("Failed to copy the kernel image")
+}
+
+fn bootstrap_system(
+    command: &CreateCommand,
+    tools: &Tools,
+    mount_path: &Path,
+    presets: &PresetsCollection,
+    user_settings: Option<&UserSettings>,
+) -> anyhow::Result<()> {
+    info!(
+        "Installing kernel(s): {}",
+        command
+            .kernels
+            .iter()
+            .map(|k| k.package_name())
+            .collect::<Vec<&str>>()
+            .join(", ")
+    );
+    let packages = bootstrap_packages(command, presets, user_settings);
+
     let pacman_conf_path = command
         .pacman_conf
         .clone()
         .unwrap_or_else(|| "/etc/pacman.conf".into());
 
+    let mirrorlist = if command.mirrors_country.is_empty() {
+        None
+    } else {
+        Some(mirrors::Mirrorlist::generate(
+            &command.mirrors_country,
+            &pacman_conf_path,
+            command.dryrun,
+        )?)
+    };
+
     info!("Bootstrapping system");
-    tools
-        .pacstrap
-        .execute()
-        .arg("-C")
-        .arg(&pacman_conf_path)
-        .arg("-c")
-        .arg(mount_point.path())
+    // pacstrap copies the host's mirrorlist and keyring into the target unless told otherwise,
+    // which saves populating a new keyring from scratch
+    let mut pacstrap = tools.pacstrap.execute();
+    pacstrap.arg("-C");
+    match &mirrorlist {
+        Some(mirrorlist) => pacstrap.arg(mirrorlist.pacman_conf()),
+        None => pacstrap.arg(&pacman_conf_path),
+    };
+    pacstrap.arg("-c");
+    if command.no_host_mirrorlist || mirrorlist.is_some() {
+        pacstrap.arg("-M");
+    }
+    if command.no_host_keyring {
+        pacstrap.arg("-K");
+    }
+    // Sorted, so the same inputs always lead to the same pacstrap command line
+    let mut packages: Vec<String> = packages.into_iter().collect();
+    packages.sort();
+    pacstrap.arg(mount_path);
+    if command.refresh == RefreshPolicy::Force {
+        // pacstrap always runs pacman -Sy, a second -y downloads the databases even if up to date
+        pacstrap.arg("--refresh");
+    }
+    pacstrap
         .args(packages) // The `packages` set now contains all conditional packages
         .args(&command.extra_packages)
         .run(command.dryrun)
         .context("Pacstrap error")?;
+    fault::inject(command.fail_at, FailPoint::MidPacstrap)?;
 
-    if !command.dryrun {
-        fs::copy(pacman_conf_path, mount_point.path().join("etc/pacman.conf"))
-            .context("Failed copying pacman.conf")?;
+    if Arch::target() == Arch::Aarch64 {
+        install_kernel_image_hook(&tools.arch_chroot, mount_path, command.dryrun)?;
     }
 
-    let fstab = fix_fstab(
+    if command.fresh_keyring {
+        info!("Replacing the host's pacman keyring with a freshly generated one");
+        tools
+            .arch_chroot
+            .execute()
+            .arg(mount_path)
+            .args([
+                "bash",
+                "-c",
+                "rm -rf /etc/pacman.d/gnupg && pacman-key --init && pacman-key --populate",
+            ])
+            .run(command.dryrun)
+            .context("Failed to regenerate the pacman keyring")?;
+    }
+
+    effects::copy(
+        &pacman_conf_path,
+        &mount_path.join("etc/pacman.conf"),
+        command.dryrun,
+    )?;
+    if let Some(mirrorlist) = mirrorlist {
+        effects::write(
+            &mount_path.join("etc/pacman.d/mirrorlist"),
+            mirrorlist.contents,
+            command.dryrun,
+        )?;
+    }
+
+    let mut fstab = fix_fstab(
         &tools
             .genfstab
             .execute()
-            .arg("-U")
-            .arg(mount_point.path())
+            .arg(if command.fstab_labels { "-L" } else { "-U" })
+            .arg(mount_path)
             .run_text_output(command.dryrun)
             .context("fstab error")?,
     );
 
-    if !command.dryrun {
-        debug!("fstab:\n{fstab}");
-        fs::write(mount_point.path().join("etc/fstab"), fstab).context("fstab error")?;
-    };
+    if command.partitioner == Partitioner::Repart {
+        install_repart_definitions(command, mount_path)?;
+        fstab = repart::grow_root_filesystem(&fstab);
+    }
+
+    if let Some(size_mb) = command.tmpfs_size_mb() {
+        fstab.push_str("\n\n");
+        fstab.push_str(&tmpfs_entries(size_mb, command.tmpfs_var_tmp));
+    }
+
+    if command.data_partition.is_some() {
+        fstab.push_str("\n\n");
+        fstab.push_str(&data_partition_entry(user_settings.is_some()));
+        effects::create_dir_all(&mount_path.join("data"), command.dryrun)?;
+    }
+
+    debug!("fstab:\n{fstab}");
+    effects::write(&mount_path.join("etc/fstab"), fstab, command.dryrun)?;
 
     tools
         .arch_chroot
         .execute()
-        .arg(mount_point.path())
+        .arg(mount_path)
         .args(["passwd", "-d", "root"])
         .run(command.dryrun)
         .context("Failed to delete the root password")?;
 
-    info!("Setting locale");
-    if !command.dryrun {
-        fs::OpenOptions::new()
-            .append(true)
-            .open(mount_point.path().join("etc/locale.gen"))
-            .and_then(|mut locale_gen| locale_gen.write_all(b"en_US.UTF-8 UTF-8\n"))
-            .context("Failed to create locale.gen")?;
-        fs::write(
-            mount_point.path().join("etc/locale.conf"),
-            "LANG=en_US.UTF-8",
-        )
-        .context("Failed to write to locale.conf")?;
-    }
+    let locales = system_locales(command, user_settings);
+    info!(
+        "Setting locale {}",
+        locales
+            .first()
+            .map_or(locale::DEFAULT_LOCALE, String::as_str)
+    );
+    // Checked against the glibc just installed, which may know other locales than the host's.
+    // Nothing was installed in dry runs, so the host's stands in for it.
+    let supported = locale::supported(if command.dryrun {
+        Path::new("/")
+    } else {
+        mount_path
+    });
+    let locale_gen = locale::locale_gen(supported.as_deref(), locales).kind(ErrorKind::Usage)?;
+    effects::append(
+        &mount_path.join("etc/locale.gen"),
+        locale_gen,
+        command.dryrun,
+    )?;
+    effects::write(
+        &mount_path.join("etc/locale.conf"),
+        locale::locale_conf(locales),
+        command.dryrun,
+    )?;
     tools
         .arch_chroot
         .execute()
-        .arg(mount_point.path())
+        .arg(mount_path)
         .arg("locale-gen")
         .run(command.dryrun)
         .context("locale-gen failed")?;
 
-    Ok((mount_point, mount_stack))
+    if let Some(keymap) = &command.keymap {
+        info!("Setting the console keymap to {keymap}");
+        effects::write(
+            &mount_path.join(keymap::VCONSOLE_CONF),
+            keymap::vconsole_conf(keymap),
+            command.dryrun,
+        )?;
+    }
+
+    Ok(())
 }
 
 fn bake_sources_into_image(
@@ -677,10 +1995,8 @@ fn bake_sources_into_image(
     command: &CreateCommand,
 ) -> anyhow::Result<()> {
     info!("Baking sources into image for offline installation...");
-    let baked_sources_dir = mount_path.join("usr/share/alma/baked_sources");
-    if !command.dryrun {
-        fs::create_dir_all(&baked_sources_dir)?;
-    }
+    let baked_sources_dir = mount_path.join(baked::BAKED_SOURCES_DIR);
+    effects::create_dir_all(&baked_sources_dir, command.dryrun)?;
     // Copy presets
     for (i, preset_wrapper) in presets_paths.iter().enumerate() {
         let dest = baked_sources_dir.join(format!("preset_{i}"));
@@ -689,27 +2005,33 @@ fn bake_sources_into_image(
             command.presets[i],
             dest.display()
         );
-        if !command.dryrun {
-            fs_extra::dir::copy(
-                preset_wrapper.to_path(),
-                &dest,
-                &fs_extra::dir::CopyOptions::new(),
-            )?;
+        effects::copy_dir(preset_wrapper.to_path(), &dest, false, command.dryrun)?;
+        if command.bake_shallow {
+            baked::remove_git_history(&dest, command.dryrun)?;
         }
     }
     // Bake Omarchy if needed
     if command.system == SystemVariant::Omarchy {
-        let omarchy_baked_path = mount_path.join("usr/share/omarchy");
+        let omarchy_baked_path = mount_path.join(baked::OMARCHY_DIR);
         info!("Cloning Omarchy repo to bake into image...");
+        // A blob filter would save nothing more, the checkout needs every blob of the commit
         tools
             .git
             .execute()
-            .arg("clone")
+            .args(["clone", "--depth", "1", "--single-branch"])
             .arg("-b")
             .arg(omarchy_branch())
             .arg(omarchy_repo_url())
             .arg(&omarchy_baked_path)
-            .run(command.dryrun)?;
+            .run(command.dryrun)
+            .kind(ErrorKind::Network)?;
+    }
+
+    if !command.dryrun {
+        for path in baked::baked_paths(mount_path) {
+            let size = baked::disk_usage(&mount_path.join(path.strip_prefix("/")?));
+            info!("Baked {} ({})", path.display(), baked::human_size(size));
+        }
     }
     Ok(())
 }
@@ -718,7 +2040,9 @@ fn install_omarchy(
     tools: &Tools,
     mount_path: &Path,
     command: &CreateCommand,
+    presets: &PresetsCollection,
     username: &str,
+    git_identity: Option<&(String, String)>,
 ) -> anyhow::Result<()> {
     info!("Installing Omarchy as user '{username}'...");
 
@@ -729,99 +2053,64 @@ fn install_omarchy(
     let install_script_path_chroot = user_home_dir_chroot.join(".local/share/omarchy/install.sh");
     let baked_omarchy_dir = mount_path.join("usr/share/omarchy");
 
-    if !command.dryrun {
-        // Ensure the user's home directory exists and copy files.
-        // This is a safeguard; `useradd -m` should have created the home dir.
-        fs::create_dir_all(&user_home_dir_host)?;
-        fs::create_dir_all(&target_omarchy_base_dir_host)?;
-
-        let mut copy_options = fs_extra::dir::CopyOptions::new();
-        copy_options.overwrite = true;
-        fs_extra::dir::copy(
-            &baked_omarchy_dir,
-            &target_omarchy_base_dir_host,
-            &copy_options,
-        )?;
-
-        // Copy firewall.sh to user home dir
-        let firewall_src_path = target_omarchy_base_dir_host
-            .join("omarchy")
-            .join("install")
-            .join("first-run")
-            .join("firewall.sh");
-        let firewall_dest_path = user_home_dir_host.join("firewall.sh");
-        info!("Copying firewall.sh to user's home directory.");
-        fs::copy(&firewall_src_path, &firewall_dest_path)?;
+    // Ensure the user's home directory exists and copy files.
+    // This is a safeguard; `useradd -m` should have created the home dir.
+    effects::create_dir_all(&user_home_dir_host, command.dryrun)?;
+    effects::create_dir_all(&target_omarchy_base_dir_host, command.dryrun)?;
+    effects::copy_dir(
+        &baked_omarchy_dir,
+        &target_omarchy_base_dir_host,
+        true,
+        command.dryrun,
+    )?;
 
-        info!("Setting ownership for user '{username}'");
+    // Copy firewall.sh to user home dir
+    let firewall_src_path = target_omarchy_base_dir_host
+        .join("omarchy")
+        .join("install")
+        .join("first-run")
+        .join("firewall.sh");
+    let firewall_dest_path = user_home_dir_host.join("firewall.sh");
+    info!("Copying firewall.sh to user's home directory.");
+    effects::copy(&firewall_src_path, &firewall_dest_path, command.dryrun)?;
+
+    info!("Setting ownership for user '{username}'");
+    tools
+        .arch_chroot
+        .execute()
+        .arg(mount_path)
+        .args([
+            "chown",
+            "-R",
+            &format!("{username}:{username}"),
+            user_home_dir_chroot.to_str().unwrap(),
+        ])
+        .run(command.dryrun)?;
+
+    let (git_name, git_email) = match git_identity {
+        Some(identity) => identity.clone(),
+        // Nobody is there to answer the prompts
+        None if command.noconfirm => (username.to_string(), String::new()),
+        None => (
+            Input::with_theme(&ColorfulTheme::default())
+                .with_prompt("Enter your full name (for git config)".to_string())
+                .default(username.to_string())
+                .interact_text()?,
+            Input::with_theme(&ColorfulTheme::default())
+                .with_prompt("Enter your email address (for git config)".to_string())
+                .default(String::new())
+                .interact_text()?,
+        ),
+    };
 
-        tools
-            .arch_chroot
-            .execute()
-            .arg(mount_path)
-            .args([
-                "chown",
-                "-R",
-                &format!("{username}:{username}"),
-                user_home_dir_chroot.to_str().unwrap(),
-            ])
-            .run(command.dryrun)?;
+    if command.omarchy_no_patch {
+        warn!("Not patching the Omarchy install scripts (--omarchy-no-patch)");
     } else {
-        println!(
-            "cp -r {} {}",
-            baked_omarchy_dir.display(),
-            target_omarchy_base_dir_host.display()
-        );
-        let firewall_src_path = target_omarchy_base_dir_host
-            .join("omarchy")
-            .join("install")
-            .join("first-run")
-            .join("firewall.sh");
-        let firewall_dest_path = user_home_dir_host.join("firewall.sh");
-        println!(
-            "cp {} {}",
-            firewall_src_path.display(),
-            firewall_dest_path.display()
-        );
-    }
-
-    let git_name = Input::with_theme(&ColorfulTheme::default())
-        .with_prompt("Enter your full name (for git config)".to_string())
-        .default(username.to_string())
-        .interact_text()?;
-
-    let git_email = Input::with_theme(&ColorfulTheme::default())
-        .with_prompt("Enter your email address (for git config)".to_string())
-        .default(String::new())
-        .interact_text()?;
-
-    info!("Patching Omarchy scripts to remove systemctl '--now' flag...");
-    let patch_command = format!(
-        "find /home/{username}/.local/share/omarchy -type f -name '*.sh' -print0 | xargs -0 sed -i \
-            -e 's/enable --now/enable/g' \
-            -e 's/sudo ufw enable/sudo systemctl enable ufw.service/g' \
-            -e 's/^reboot/# reboot (disabled in chroot)/g' \
-            -e 's/sudo ufw reload/# sudo ufw reload (disabled in chroot)/g'",
-    );
-
-    // If we already installed yay-bin, then make sure Omarchy does not install yay (source package)
-    if matches!(command.aur_helper, AurHelper::Yay) {
-        // Remove yay from install/packages.sh
-        let remove_yay_command = format!(
-            "sed -i '/^\\s*yay\\s*\\\\/d' /home/{username}/.local/share/omarchy/install/packages.sh"
-        );
-        tools
-            .arch_chroot
-            .execute()
-            .arg(mount_path)
-            .args(["bash", "-c", &remove_yay_command])
-            .run(command.dryrun)?;
+        patch_omarchy(&target_omarchy_base_dir_host.join("omarchy"), command)?;
     }
 
-    let ufw_path = mount_path.join("usr/bin/ufw");
-    let ufw_real_path = mount_path.join("usr/bin/ufw.real");
-
-    let wrapper_script = r#"#!/bin/bash
+    // ufw cannot be configured in a chroot, only its service enabled
+    let ufw_wrapper = r#"#!/bin/bash
 echo "[alma-nv wrapper] Intercepted ufw command: ufw $@" >&2
 if [[ "$1" == "enable" ]]; then
   echo "[alma-nv wrapper] Executing 'systemctl enable ufw.service' instead." >&2
@@ -831,35 +2120,12 @@ else
 fi
 exit 0
 "#;
-
-    // 1. Rename the real ufw and create the wrapper
-    info!("Wrapping ufw command to make it chroot-safe...");
-    if !command.dryrun {
-        if ufw_path.exists() {
-            fs::rename(&ufw_path, &ufw_real_path).context("Failed to move real ufw binary")?;
-            fs::write(&ufw_path, wrapper_script).context("Failed to write ufw wrapper script")?;
-            fs::set_permissions(
-                &ufw_path,
-                std::os::unix::fs::PermissionsExt::from_mode(0o755),
-            )?;
-        }
-    } else if command.dryrun {
-        println!("mv {} {}", ufw_path.display(), ufw_real_path.display());
-        println!(
-            "echo '...' > {} && chmod 755 {}",
-            ufw_path.display(),
-            ufw_path.display()
-        );
+    let mut intercepts = InterceptStack::new(mount_path, command.dryrun);
+    intercepts.wrap(&Interception::new("ufw", ufw_wrapper))?;
+    for interception in &presets.intercepts {
+        intercepts.wrap(interception)?;
     }
 
-    tools
-        .arch_chroot
-        .execute()
-        .arg(mount_path)
-        .args(["bash", "-c", &patch_command])
-        .run(command.dryrun)
-        .context("Failed to patch Omarchy install scripts.")?;
-
     info!("Running patched Omarchy install script as user '{username}'. This will be interactive.");
 
     let repo_url = omarchy_repo_url();
@@ -893,28 +2159,110 @@ exit 0
         .run(command.dryrun)
         .context("Omarchy installation script failed.")?;
 
-    info!("Restoring original ufw command...");
-    if !command.dryrun && ufw_real_path.exists() {
-        fs::rename(&ufw_real_path, &ufw_path).context("Failed to restore real ufw binary")?;
-    } else if command.dryrun {
-        println!("mv {} {}", ufw_real_path.display(), ufw_path.display());
+    intercepts.restore()
+}
+
+/// Applies the declarative patch rules making the Omarchy install scripts chroot-safe
+fn patch_omarchy(omarchy_dir: &Path, command: &CreateCommand) -> anyhow::Result<()> {
+    let rules = omarchy_patch::load_rules(command.omarchy_patches.as_deref())?;
+    let aur_helper = command.aur_helper.to_string();
+
+    if command.dryrun {
+        for rule in &rules {
+            plan::print(Action::Note {
+                text: format!(
+                    "Omarchy patch rule '{}': {}",
+                    rule.name,
+                    rule.description.as_deref().unwrap_or("")
+                ),
+            });
+        }
+        return Ok(());
+    }
+
+    let version = omarchy_patch::omarchy_version(omarchy_dir);
+    match &version {
+        Some(version) => info!("Patching Omarchy {version} install scripts..."),
+        None => info!("Omarchy version unknown, applying every patch rule..."),
+    }
+
+    let reports = omarchy_patch::apply(&rules, omarchy_dir, version.as_ref(), &aur_helper)
+        .context("Failed to patch Omarchy install scripts.")?;
+    for report in reports {
+        if report.lines == 0 {
+            warn!(
+                "Omarchy patch rule '{}' did not match anything, the install scripts may have changed",
+                report.rule
+            );
+        } else {
+            debug!(
+                "Omarchy patch rule '{}' changed {} lines in {} files",
+                report.rule, report.lines, report.files
+            );
+        }
     }
 
     Ok(())
 }
 
+/// Clears the files which identify a particular build and sets every timestamp to the epoch
+fn normalize_for_reproducibility(
+    reproducible: &Reproducible,
+    mount_path: &Path,
+    dryrun: bool,
+) -> anyhow::Result<()> {
+    info!("Normalizing the installation for a reproducible build");
+
+    // An empty machine-id is generated on first boot, see machine-id(5)
+    let machine_id = mount_path.join("etc/machine-id");
+    let random_seed = mount_path.join("var/lib/systemd/random-seed");
+    effects::write(&machine_id, "", dryrun)?;
+    if dryrun || random_seed.exists() {
+        effects::remove_file(&random_seed, dryrun)?;
+    }
+
+    Tool::find("find", dryrun)?
+        .execute()
+        .arg(mount_path)
+        .args(["-exec", "touch", "--no-dereference"])
+        .arg(format!("--date=@{}", reproducible.epoch()))
+        .args(["{}", "+"])
+        .run(dryrun)
+        .context("Failed to normalize the file timestamps")
+}
+
+/// The locales the system is set up with. --locale takes precedence over the locales of the
+/// interactive setup or answers file.
+fn system_locales<'a>(
+    command: &'a CreateCommand,
+    user_settings: Option<&'a UserSettings>,
+) -> &'a [String] {
+    match (&command.locales, user_settings) {
+        (locales, _) if !locales.is_empty() => locales.as_slice(),
+        (_, Some(settings)) => settings.locales.as_slice(),
+        (_, None) => &[],
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn generate_manifest(
     command: &CreateCommand,
     mount_point: &tempfile::TempDir,
     original_command: &str,
     sources: &mut Vec<Source>,
-) -> anyhow::Result<()> {
+    aur_failures: &[AurFailure],
+    user_settings: Option<&UserSettings>,
+    initcpio_options: &InitcpioOptions,
+    login_templates: &(Option<String>, Option<String>),
+    device_id: Option<&Path>,
+) -> anyhow::Result<String> {
     info!("Generating installation manifest...");
     if command.system == SystemVariant::Omarchy {
         sources.push(Source {
             r#type: "system".to_string(),
             origin: omarchy_repo_url(),
             baked_path: PathBuf::from("/usr/share/omarchy"),
+            commit: presets::git_commit(&mount_point.path().join(baked::OMARCHY_DIR)),
         });
     }
 
@@ -923,83 +2271,207 @@ fn generate_manifest(
         system_variant: command.system,
         filesystem: command.filesystem,
         encrypted_root: command.encrypted_root,
+        kernels: command.kernels.clone(),
         aur_helper: command.aur_helper.to_string(),
+        no_shim: command.no_shim,
+        pacman_hooks: command.pacman_hooks,
+        grub_timeout: command.grub_timeout,
+        grub_default: command.grub_default.clone(),
+        grub_password: command.grub_password,
+        grub_targets: command.grub_targets,
+        kernel_cmdline: command.kernel_cmdline.clone(),
+        board: command.board.clone(),
+        initramfs_autodetect: command.initramfs_autodetect,
+        initramfs: command.initramfs,
+        // As applied, with the options of the presets merged in
+        initcpio: initcpio_options.clone(),
+        systemd_initramfs: command.systemd_initramfs,
+        hibernate: command.hibernate,
+        swap_size_mb: command.swap_size_mb(),
+        nodatacow: command.nodatacow.clone(),
+        leave_free_mb: command.leave_free_mb(),
+        raid1: command.raid1.is_some(),
+        partitioner: command.partitioner,
+        volatile_root: command.volatile_root,
+        iso: command.iso.is_some(),
+        cloud_init: command.cloud_init,
+        keymap: command.keymap.clone(),
+        locales: system_locales(command, user_settings).to_vec(),
+        device_id: device_id.map(Path::to_path_buf),
+        // Only ALMA's own layout, not partitions given with --root-partition
+        partition_types: if command.root_partition.is_none() {
+            layout(command)
+                .partition_types()
+                .into_iter()
+                .map(|(partition, partition_type)| (partition, partition_type.to_owned()))
+                .collect()
+        } else {
+            BTreeMap::new()
+        },
+        refresh: command.refresh,
+        data_partition_mb: command.data_partition_mb(),
+        tmpfs_size_mb: command.tmpfs_size_mb(),
+        tmpfs_var_tmp: command.tmpfs_var_tmp,
+        mount_options: command.mount_options.clone(),
+        root_label: command.root_label.clone(),
+        boot_label: command.boot_label.clone(),
+        fstab_labels: command.fstab_labels,
+        bake_shallow: command.bake_shallow,
+        sudo: command.sudo_options(),
+        motd: login_templates.0.clone(),
+        issue: login_templates.1.clone(),
+        image_name: command.image_name.clone(),
+        aur_failures: aur_failures.to_vec(),
+        user_settings: user_settings.cloned(),
         original_command: original_command.to_string(),
         sources: std::mem::take(sources),
     };
 
     let manifest_path = mount_point.path().join("usr/share/alma/manifest.json");
-    if !command.dryrun {
-        let json = serde_json::to_string_pretty(&manifest)?;
-        fs::write(manifest_path, json)?;
+    let json = serde_json::to_string_pretty(&manifest)?;
+    effects::write(&manifest_path, &json, command.dryrun)?;
+    Ok(json)
+}
+
+/// Writes the --motd and --issue templates, filled in with the build metadata
+fn write_login_messages(
+    command: &CreateCommand,
+    templates: &(Option<String>, Option<String>),
+    manifest_hash: String,
+    reproducible: Option<&Reproducible>,
+    mount_path: &Path,
+) -> anyhow::Result<()> {
+    let build_time = match reproducible {
+        Some(reproducible) => reproducible.epoch(),
+        None => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+    };
+    let image_name = command.image_name.clone().unwrap_or_else(|| {
+        command
+            .path
+            .as_ref()
+            .and_then(|p| p.file_name())
+            .map_or_else(
+                || String::from("ALMA"),
+                |n| n.to_string_lossy().into_owned(),
+            )
+    });
+    let info = motd::BuildInfo {
+        image_name,
+        build_time,
+        manifest_hash,
+    };
+
+    for (template, path) in [(&templates.0, "etc/motd"), (&templates.1, "etc/issue")] {
+        let Some(template) = template else {
+            continue;
+        };
+        let path = mount_path.join(path);
+        effects::write(&path, motd::render(template, &info), command.dryrun)?;
     }
     Ok(())
 }
 
 pub fn setup_bootloader(
+    command: &CreateCommand,
+    tools: &Tools,
     storage_device: &StorageDevice,
     mount_point: &TempDir,
-    arch_chroot: &Tool,
     encrypted_root: Option<&EncryptedDevice>,
     root_partition_base: &Partition,
-    blkid: Option<&Tool>,
-    dryrun: bool,
+    initcpio_options: &InitcpioOptions,
 ) -> anyhow::Result<()> {
+    let arch_chroot = &tools.arch_chroot;
+    let dryrun = command.dryrun;
     info!("Starting bootloader initialisation tasks");
     // If boot partition was generated or given, then it is already mounted at /boot in the MountStack by this stage
 
-    info!("Generating initramfs");
+    // The UUID of the LUKS container, needed by both the initramfs and the kernel command line
+    let luks_uuid = match encrypted_root {
+        Some(_) => {
+            let uuid = ids::uuid(&tools.blkid, root_partition_base)?;
+            debug!("Root partition UUID: {uuid}");
+            Some(uuid)
+        }
+        None => None,
+    };
+
+    info!("Generating initramfs with {}", command.initramfs);
     let plymouth_exists = Path::new(&mount_point.path().join("usr/bin/plymouth")).exists();
+    match command.initramfs {
+        InitramfsGenerator::Mkinitcpio => generate_initramfs_mkinitcpio(
+            command,
+            arch_chroot,
+            mount_point.path(),
+            luks_uuid.as_deref(),
+            plymouth_exists,
+            initcpio_options,
+        )?,
+        InitramfsGenerator::Dracut => generate_initramfs_dracut(
+            command,
+            arch_chroot,
+            mount_point.path(),
+            encrypted_root.is_some(),
+            plymouth_exists,
+        )?,
+    }
+
     if !dryrun {
-        fs::write(
-            mount_point.path().join("etc/mkinitcpio.conf"),
-            initcpio::Initcpio::new(encrypted_root.is_some(), plymouth_exists).to_config()?,
-        )
-        .context("Failed to write to mkinitcpio.conf")?;
+        for kernel in &command.kernels {
+            for image in [
+                format!("boot/vmlinuz-{kernel}"),
+                format!("boot/initramfs-{kernel}.img"),
+                format!("boot/initramfs-{kernel}-fallback.img"),
+            ] {
+                if !mount_point.path().join(&image).exists() {
+                    return Err(anyhow!(
+                        "/{image} is missing for the {kernel} kernel, {} did not generate it",
+                        command.initramfs
+                    ));
+                }
+            }
+        }
     }
-    arch_chroot
-        .execute()
-        .arg(mount_point.path())
-        .args(["mkinitcpio", "-P"])
-        .run(dryrun)
-        .context("Failed to run mkinitcpio - do you have the base and linux packages installed?")?;
 
-    if encrypted_root.is_some() {
+    // TODO: Handle multiple encrypted partitions with osprober?
+    let root_device: &dyn BlockDevice = match encrypted_root {
+        Some(encrypted_root) => encrypted_root,
+        None => root_partition_base,
+    };
+    let cryptdevice_cmdline = luks_uuid.as_deref().and_then(|uuid| {
         debug!("Setting up GRUB for an encrypted root partition");
-
-        let uuid = blkid
-            .expect("No tool for blkid")
-            .execute()
-            .arg(root_partition_base.path())
-            .args(["-o", "value", "-s", "UUID"])
-            .run_text_output(dryrun)
-            .context("Failed to run blkid")?;
-        let trimmed = uuid.trim();
-        debug!("Root partition UUID: {trimmed}");
-
-        if !dryrun {
-            let mut grub_file = fs::OpenOptions::new()
-                .append(true)
-                .open(mount_point.path().join("etc/default/grub"))
-                .context("Failed to create /etc/default/grub")?;
-
-            // TODO: Handle multiple encrypted partitions with osprober?
-            write!(
-                &mut grub_file,
-                "GRUB_CMDLINE_LINUX=\"cryptdevice=UUID={trimmed}:luks_root\""
-            )
-            .context("Failed to write to /etc/default/grub")?;
+        match command.initramfs {
+            // sd-encrypt reads /etc/crypttab.initramfs instead
+            InitramfsGenerator::Mkinitcpio if command.systemd_initramfs => None,
+            InitramfsGenerator::Mkinitcpio => Some(format!("cryptdevice=UUID={uuid}:luks_root")),
+            InitramfsGenerator::Dracut => Some(format!("rd.luks.uuid={uuid}")),
         }
-    }
+    });
+
+    let resume_cmdline = if command.hibernate {
+        Some(resume_cmdline(
+            command,
+            tools,
+            root_device,
+            mount_point.path(),
+        )?)
+    } else {
+        None
+    };
 
     // TODO: add grub os-prober?
     // TODO: Allow choice of bootloader - systemd-boot + refind?
-    // TODO: Add systemd volatile root option
 
     info!("Enabling os-prober for multi-boot detection");
-    if !dryrun {
+    {
         let grub_conf_path = mount_point.path().join("etc/default/grub");
-        let mut grub_conf = fs::read_to_string(&grub_conf_path)?;
+        // Nothing was installed in dry runs, the plan only shows the settings ALMA adds
+        let mut grub_conf = if dryrun {
+            String::new()
+        } else {
+            fs::read_to_string(&grub_conf_path)?
+        };
 
         // Ensure GRUB_DISABLE_OS_PROBER is false and add required options for os-prober
         grub_conf = grub_conf.replace(
@@ -1007,44 +2479,359 @@ pub fn setup_bootloader(
             "GRUB_DISABLE_OS_PROBER=false",
         );
 
-        // Add or ensure that os-prober is enabled in the grub configuration
-        // We're just adding a standard configuration line.
-        if !grub_conf.contains("GRUB_CMDLINE_LINUX") {
+        if let Some(params) = &resume_cmdline {
+            grub::append_cmdline(&mut grub_conf, "GRUB_CMDLINE_LINUX", params);
+        }
+
+        if let Some(params) = command
+            .board
+            .as_ref()
+            .and_then(|b| b.kernel_cmdline.as_ref())
+        {
+            grub::append_cmdline(&mut grub_conf, "GRUB_CMDLINE_LINUX", params);
+        }
+
+        if command.volatile_root {
+            info!("Mounting the root filesystem as the read-only layer of an overlay");
+            grub::append_cmdline(
+                &mut grub_conf,
+                "GRUB_CMDLINE_LINUX",
+                "systemd.volatile=overlay",
+            );
+        }
+
+        if let Some(params) = &cryptdevice_cmdline {
+            grub::append_cmdline(&mut grub_conf, "GRUB_CMDLINE_LINUX", params);
+        } else if !grub_conf.contains("GRUB_CMDLINE_LINUX") {
+            // Add or ensure that os-prober is enabled in the grub configuration
+            // We're just adding a standard configuration line.
             grub_conf.push_str("\nGRUB_CMDLINE_LINUX=\"\"\n");
         }
 
-        fs::write(grub_conf_path, grub_conf)?;
+        apply_grub_options(command, &mut grub_conf);
+
+        effects::write(&grub_conf_path, grub_conf, dryrun)?;
+    }
+
+    if let Some(hash) = &command.grub_password_hash {
+        protect_grub(hash, mount_point.path(), dryrun)?;
+    }
+
+    // Before grub-mkconfig, which only adds the device tree to the menu entries if it exists
+    if let Some(board) = &command.board {
+        board.install_dtb(
+            mount_point.path(),
+            pacman_hooks::HOOKS_DIR,
+            pacman_hooks::SCRIPTS_DIR,
+            dryrun,
+        )?;
     }
 
     info!("Installing the Bootloader");
-    run_grub_mkconfig_scoped(storage_device, mount_point, arch_chroot, dryrun)?;
+    run_grub_mkconfig_scoped(
+        storage_device,
+        mount_point,
+        arch_chroot,
+        command.grub_targets,
+        dryrun,
+    )?;
+    pin_root_device(&tools.blkid, root_device, mount_point.path())?;
 
-    let bootloader = mount_point.path().join("boot/EFI/BOOT/BOOTX64.efi");
+    if !command.shim() {
+        info!("Skipping shim installation, GRUB will be booted directly");
+        return Ok(());
+    }
+    install_shim(mount_point.path(), dryrun)?;
 
     if !dryrun {
-        fs::rename(
-            &bootloader,
-            mount_point.path().join("boot/EFI/BOOT/grubx64.efi"),
-        )
-        .context("Cannot move out grub")?;
-        fs::copy(
-            mount_point.path().join("usr/share/shim-signed/mmx64.efi"),
-            mount_point.path().join("boot/EFI/BOOT/mmx64.efi"),
-        )
-        .context("Failed copying mmx64")?;
-        fs::copy(
-            mount_point.path().join("usr/share/shim-signed/shimx64.efi"),
-            bootloader,
-        )
-        .context("Failed copying shim")?;
+        debug!(
+            "GRUB configuration: {}",
+            fs::read_to_string(mount_point.path().join("boot/grub/grub.cfg"))
+                .unwrap_or_else(|e| e.to_string())
+        );
+    }
+    Ok(())
+}
+
+fn generate_initramfs_mkinitcpio(
+    command: &CreateCommand,
+    arch_chroot: &Tool,
+    mount_path: &Path,
+    luks_uuid: Option<&str>,
+    plymouth: bool,
+    options: &InitcpioOptions,
+) -> anyhow::Result<()> {
+    effects::write(
+        &mount_path.join("etc/mkinitcpio.conf"),
+        initcpio::Initcpio::new(luks_uuid.is_some(), plymouth, command.initramfs_autodetect)
+            .with_systemd(command.systemd_initramfs)
+            .with_resume(command.hibernate)
+            .with_raid(command.raid1.is_some())
+            .with_volatile_root(command.volatile_root)
+            .with_options(options.clone())
+            .to_config()?,
+        command.dryrun,
+    )?;
+
+    if command.systemd_initramfs
+        && let Some(uuid) = luks_uuid
+    {
+        effects::write(
+            &mount_path.join("etc/crypttab.initramfs"),
+            initcpio::crypttab(uuid),
+            command.dryrun,
+        )?;
+    }
+
+    for kernel in &command.kernels {
+        effects::write(
+            &mount_path.join(format!("etc/mkinitcpio.d/{kernel}.preset")),
+            initcpio::preset(kernel.package_name()),
+            command.dryrun,
+        )?;
+    }
+    arch_chroot
+        .execute()
+        .arg(mount_path)
+        .args(["mkinitcpio", "-P"])
+        .run(command.dryrun)
+        .context("Failed to run mkinitcpio - do you have the base and linux packages installed?")?;
+
+    Ok(())
+}
+
+/// Configures dracut and installs a pacman hook which copies the kernels to /boot and
+/// generates their images, then runs that hook's script once for the freshly installed kernels
+fn generate_initramfs_dracut(
+    command: &CreateCommand,
+    arch_chroot: &Tool,
+    mount_path: &Path,
+    encrypted: bool,
+    plymouth: bool,
+) -> anyhow::Result<()> {
+    let conf_path = mount_path.join(dracut::CONF_PATH);
+    let hook_path = mount_path
+        .join(pacman_hooks::HOOKS_DIR)
+        .join(dracut::HOOK_NAME);
+    let script_path = mount_path
+        .join(pacman_hooks::SCRIPTS_DIR)
+        .join(dracut::SCRIPT_NAME);
+
+    let config = dracut::Dracut::new(
+        encrypted,
+        command.filesystem == RootFilesystemType::Btrfs,
+        plymouth,
+        command.initramfs_autodetect,
+    )
+    .with_resume(command.hibernate)
+    .with_raid(command.raid1.is_some())
+    .with_volatile_root(command.volatile_root)
+    .to_config()?;
+    for path in [&conf_path, &hook_path, &script_path] {
+        if let Some(parent) = path.parent() {
+            effects::create_dir_all(parent, command.dryrun)?;
+        }
+    }
+    effects::write(&conf_path, config, command.dryrun)?;
+    effects::write(
+        &hook_path,
+        dracut::pacman_hook(pacman_hooks::SCRIPTS_DIR),
+        command.dryrun,
+    )?;
+    effects::write(&script_path, dracut::install_script(), command.dryrun)?;
+    effects::set_mode(&script_path, 0o755, command.dryrun)?;
+
+    arch_chroot
+        .execute()
+        .arg(mount_path)
+        .arg(format!(
+            "/{}/{}",
+            pacman_hooks::SCRIPTS_DIR,
+            dracut::SCRIPT_NAME
+        ))
+        .run(command.dryrun)
+        .context("Failed to run dracut")?;
+
+    Ok(())
+}
+
+/// Creates the swap file and adds it to fstab
+fn create_swap_file(
+    command: &CreateCommand,
+    arch_chroot: &Tool,
+    mount_path: &Path,
+    size_mb: u64,
+) -> anyhow::Result<()> {
+    info!("Creating a {size_mb} MiB swap file");
+    let swap_file = format!("/{}", swap::swap_file(command.filesystem));
+    match command.filesystem {
+        RootFilesystemType::Btrfs => {
+            // The @swap subvolume must not be copy-on-write, which mkswapfile also ensures
+            // for the file itself
+            arch_chroot
+                .execute()
+                .arg(mount_path)
+                .args(["chattr", "+C"])
+                .arg(format!("/{}", swap::SWAP_DIR))
+                .run(command.dryrun)
+                .context("Failed to disable copy-on-write for the swap subvolume")?;
+            arch_chroot
+                .execute()
+                .arg(mount_path)
+                .args(["btrfs", "filesystem", "mkswapfile", "--size"])
+                .arg(format!("{size_mb}m"))
+                .arg(&swap_file)
+                .run(command.dryrun)
+                .context("Failed to create the swap file")?;
+        }
+        RootFilesystemType::Ext4 => {
+            arch_chroot
+                .execute()
+                .arg(mount_path)
+                .args(["mkswap", "--file", "--size"])
+                .arg(format!("{size_mb}MiB"))
+                .arg(&swap_file)
+                .run(command.dryrun)
+                .context("Failed to create the swap file")?;
+        }
+    }
+
+    let fstab_path = mount_path.join("etc/fstab");
+    let fstab_entry = swap::fstab_entry(command.filesystem);
+    effects::append(&fstab_path, fstab_entry, command.dryrun)
+        .context("Failed to add the swap file to fstab")?;
+
+    Ok(())
+}
+
+/// Returns the resume= and resume_offset= kernel parameters pointing at the swap file
+fn resume_cmdline(
+    command: &CreateCommand,
+    tools: &Tools,
+    root_device: &dyn BlockDevice,
+    mount_path: &Path,
+) -> anyhow::Result<String> {
+    let dryrun = command.dryrun;
+    let uuid = ids::uuid(&tools.blkid, root_device)?;
+
+    let swap_file = mount_path.join(swap::swap_file(command.filesystem));
+    let offset = match command.filesystem {
+        RootFilesystemType::Btrfs => tools
+            .btrfs
+            .as_ref()
+            .context("btrfs tool missing")?
+            .execute()
+            .args(["inspect-internal", "map-swapfile", "-r"])
+            .arg(&swap_file)
+            .run_text_output(dryrun)
+            .context("Failed to find the swap file offset")?
+            .trim()
+            .parse::<u64>()
+            .ok(),
+        RootFilesystemType::Ext4 => swap::parse_filefrag_offset(
+            &Tool::find("filefrag", dryrun)?
+                .execute()
+                .arg("-v")
+                .arg(&swap_file)
+                .run_text_output(dryrun)
+                .context("Failed to find the swap file offset")?,
+        ),
+    };
+    let offset = match offset {
+        Some(offset) => offset,
+        None if dryrun => 0,
+        None => return Err(anyhow!("Unable to determine the offset of the swap file")),
+    };
+
+    Ok(format!(
+        "resume={} resume_offset={offset}",
+        Identifier::Uuid.spec(&uuid)
+    ))
+}
+
+/// Reads the --grub-password from $ALMA_GRUB_PASSWORD or prompts for it, and hashes it
+fn grub_password_hash(
+    command: &CreateCommand,
+    reproducible: Option<&Reproducible>,
+) -> anyhow::Result<String> {
+    let password = match env::var(grub::PASSWORD_ENV) {
+        Ok(password) => password,
+        Err(_) if command.noconfirm => {
+            return Err(anyhow!(
+                "--grub-password with --noconfirm reads the password from ${}, which is not set",
+                grub::PASSWORD_ENV
+            ))
+            .kind(ErrorKind::Usage);
+        }
+        Err(_) => Password::with_theme(&ColorfulTheme::default())
+            .with_prompt("Enter the GRUB password")
+            .with_confirmation("Confirm the GRUB password", "Passwords do not match.")
+            .interact()?,
+    };
+    if password.is_empty() {
+        return Err(anyhow!("The GRUB password must not be empty")).kind(ErrorKind::Usage);
+    }
+    // Derived from the seed in reproducible builds, so grub.cfg only depends on the inputs
+    let salt = match reproducible {
+        Some(reproducible) => reproducible.bytes("grub-password").to_vec(),
+        None => grub::random_salt()?.to_vec(),
+    };
+    Ok(grub::hash_password(&password, &salt))
+}
 
-        debug!(
-            "GRUB configuration: {}",
-            fs::read_to_string(mount_point.path().join("boot/grub/grub.cfg"))
-                .unwrap_or_else(|e| e.to_string())
+/// Makes "root" the GRUB superuser, so editing the menu entries or opening the GRUB shell asks
+/// for the password while the entries still boot without it
+fn protect_grub(hash: &str, mount_path: &Path, dryrun: bool) -> anyhow::Result<()> {
+    info!("Protecting the GRUB menu with a password");
+    let password_script = mount_path.join(grub::PASSWORD_SCRIPT);
+    effects::write(&password_script, grub::password_script(hash), dryrun)?;
+    effects::set_mode(&password_script, 0o755, dryrun)?;
+
+    let linux_script = mount_path.join(grub::LINUX_SCRIPT);
+    let contents = if dryrun {
+        String::new()
+    } else {
+        fs::read_to_string(&linux_script)
+            .with_context(|| format!("Failed to read {}", linux_script.display()))?
+    };
+    effects::write(&linux_script, grub::unrestrict_entries(&contents), dryrun)?;
+
+    let hooks_dir = mount_path.join(pacman_hooks::HOOKS_DIR);
+    effects::create_dir_all(&hooks_dir, dryrun)?;
+    effects::write(
+        &hooks_dir.join(grub::UNRESTRICT_HOOK),
+        grub::unrestrict_hook(),
+        dryrun,
+    )
+}
+
+/// Merges the GRUB options given on the command line into /etc/default/grub
+fn apply_grub_options(command: &CreateCommand, grub_conf: &mut String) {
+    // Show the normal and fallback entries of every kernel in the main menu rather than
+    // hiding them in the "Advanced options" submenu, with the first selected kernel on top
+    grub::set_option(grub_conf, "GRUB_DISABLE_SUBMENU", "y");
+    if let Some(kernel) = command.kernels.first() {
+        grub::set_option(
+            grub_conf,
+            "GRUB_TOP_LEVEL",
+            &format!("/boot/vmlinuz-{kernel}"),
         );
     }
-    Ok(())
+    if let Some(timeout) = command.grub_timeout {
+        info!("Setting GRUB timeout to {timeout} seconds");
+        grub::set_option(grub_conf, "GRUB_TIMEOUT", &timeout.to_string());
+    }
+    if let Some(default) = &command.grub_default {
+        info!("Setting GRUB default entry to {default}");
+        grub::set_option(grub_conf, "GRUB_DEFAULT", default);
+        if default == "saved" {
+            grub::set_option(grub_conf, "GRUB_SAVEDEFAULT", "true");
+        }
+    }
+    if let Some(cmdline) = &command.kernel_cmdline {
+        info!("Adding kernel parameters: {cmdline}");
+        grub::append_cmdline(grub_conf, "GRUB_CMDLINE_LINUX_DEFAULT", cmdline);
+    }
 }
 
 fn apply_customizations(
@@ -1052,30 +2839,93 @@ fn apply_customizations(
     arch_chroot: &Tool,
     presets: &PresetsCollection,
     mount_path: &Path,
-) -> anyhow::Result<()> {
-    // Install AUR helper and packages
-    info!("Installing AUR packages");
-    let aur_packages = {
-        let mut p = vec![String::from("shim-signed")];
-        p.extend(presets.aur_packages.clone());
-        p.extend(command.aur_packages.clone());
-        p
+    notifier: &Notifier,
+) -> anyhow::Result<Vec<AurFailure>> {
+    // Install AUR helper and packages, one batch per preset
+    let aur_batches = {
+        let mut b = Vec::new();
+        if command.shim() {
+            b.push(AurBatch::new("shim", vec![String::from("shim-signed")]));
+        }
+        b.extend(presets.aur_packages.iter().cloned());
+        b.push(AurBatch::new(
+            "--aur-packages",
+            command.aur_packages.clone(),
+        ));
+        aur::dedup_batches(b)
     };
+    let mut aur_failures = Vec::new();
+
+    if !aur_batches.is_empty() && command.runs(Phase::Aur) {
+        notifier.phase(Phase::Aur);
+        info!("Installing AUR packages");
+        aur_failures = with_aur_user(arch_chroot, mount_path, command.dryrun, || {
+            // Set up before the AUR helper, which is built with it too
+            let compiler_cache = match command.compiler_cache {
+                Some(cache) => {
+                    compiler_cache::provision(cache, arch_chroot, mount_path, command.dryrun)?
+                }
+                None => None,
+            };
+
+            let installed = install_aur_packages(command, arch_chroot, mount_path, &aur_batches);
+            // Also on failures, since the drop-in points at the cache, which is gone with the mount
+            let removed = compiler_cache.map_or(Ok(()), |compiler_cache| {
+                compiler_cache.remove(arch_chroot, mount_path, command.dryrun)
+            });
+            let failures = installed?;
+            removed?;
+            Ok(failures)
+        })?;
+    }
+    if command.runs(Phase::Aur) {
+        checkpoint::record(mount_path, Phase::Aur, command.dryrun)?;
+    }
 
-    if !aur_packages.is_empty() {
-        arch_chroot
-            .execute()
-            .arg(mount_path)
-            .args(["useradd", "-m", "aur"])
-            .run(command.dryrun)
-            .context("Failed to create temporary user to install AUR packages")?;
+    // Run preset scripts
+    if !command.runs(Phase::Presets) {
+        return Ok(aur_failures);
+    }
 
-        let aur_sudoers = mount_path.join("etc/sudoers.d/aur");
-        if !command.dryrun {
-            fs::write(&aur_sudoers, "aur ALL=(ALL) NOPASSWD: ALL")
-                .context("Failed to modify sudoers file for AUR packages")?;
-        }
+    if !presets.scripts.is_empty() {
+        notifier.phase(Phase::Presets);
+        info!("Running custom scripts");
+    }
 
+    let mut intercepts = InterceptStack::new(mount_path, command.dryrun);
+    for interception in &presets.intercepts {
+        intercepts.wrap(interception)?;
+    }
+
+    for (i, script) in presets.scripts.iter().enumerate() {
+        run_recoverable_step(
+            command,
+            arch_chroot,
+            mount_path,
+            &format!("Preset script {}/{}", i + 1, presets.scripts.len()),
+            || {
+                fault::inject(command.fail_at, FailPoint::Preset(i + 1))?;
+                run_preset_script(command, arch_chroot, script, mount_path)
+            },
+        )?;
+    }
+
+    intercepts.restore()?;
+    Ok(aur_failures)
+}
+
+/// Installs the AUR helper, then the AUR packages batch by batch, returning the batches which
+/// failed with --continue-on-aur-failure
+fn install_aur_packages(
+    command: &CreateCommand,
+    arch_chroot: &Tool,
+    mount_path: &Path,
+    aur_batches: &[AurBatch],
+) -> anyhow::Result<Vec<AurFailure>> {
+    let mut aur_failures = Vec::new();
+    if command.aur_helper_prebuilt {
+        install_prebuilt_aur_helper(command, arch_chroot, mount_path)?;
+    } else {
         arch_chroot
             .execute()
             .arg(mount_path)
@@ -1103,40 +2953,205 @@ fn apply_customizations(
             ])
             .run(command.dryrun)
             .context("Failed to build AUR helper")?;
+    }
 
-        arch_chroot
+    for batch in aur_batches {
+        let result = run_recoverable_step(
+            command,
+            arch_chroot,
+            mount_path,
+            &format!("Installing the AUR packages of {}", batch.name),
+            || {
+                arch_chroot
+                    .execute()
+                    .arg(mount_path)
+                    .args(["sudo", "-u", "aur"])
+                    .args(command.aur_helper.get_install_command())
+                    .args(&batch.packages)
+                    .run(command.dryrun)
+                    .with_context(|| {
+                        format!("Failed to install the AUR packages of {}", batch.name)
+                    })
+            },
+        );
+        match result {
+            Ok(()) => (),
+            Err(err) if command.continue_on_aur_failure => {
+                warn!("{err:#}, continuing without them");
+                aur_failures.push(AurFailure {
+                    batch: batch.name.clone(),
+                    packages: batch.packages.clone(),
+                    error: format!("{err:#}"),
+                });
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(aur_failures)
+}
+
+const AUR_SUDOERS: &str = "etc/sudoers.d/aur";
+
+/// Runs `f` with the temporary AUR user added, and removes it again whether `f` fails or not, so
+/// it is not left in the image
+pub fn with_aur_user<T>(
+    arch_chroot: &Tool,
+    mount_path: &Path,
+    dryrun: bool,
+    f: impl FnOnce() -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    add_aur_user(arch_chroot, mount_path, dryrun)?;
+    let result = f();
+    let removed = remove_aur_user(arch_chroot, mount_path, dryrun);
+    let result = result?;
+    removed?;
+    Ok(result)
+}
+
+/// Adds the temporary user which AUR helpers run as, since makepkg refuses to run as root, with
+/// passwordless sudo to install what it builds. An existing user, left over by an interrupted
+/// run which is being resumed, is reused.
+fn add_aur_user(arch_chroot: &Tool, mount_path: &Path, dryrun: bool) -> anyhow::Result<()> {
+    let exists = !dryrun
+        && arch_chroot
             .execute()
             .arg(mount_path)
-            .args(["sudo", "-u", "aur"])
-            .args(command.aur_helper.get_install_command())
-            .args(aur_packages)
-            .run(command.dryrun)
-            .context("Failed to install AUR packages")?;
+            .args(["id", "aur"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .run(false)
+            .is_ok();
 
-        // Clean up aur user:
+    if exists {
+        debug!("Reusing the existing aur user");
+    } else {
         arch_chroot
             .execute()
             .arg(mount_path)
-            .args(["userdel", "-r", "aur"])
-            .run(command.dryrun)
-            .context("Failed to delete temporary aur user")?;
-
-        if !command.dryrun {
-            fs::remove_file(&aur_sudoers)
-                .context("Cannot delete the AUR sudoers temporary file")?;
-        }
+            .args(["useradd", "-m", "aur"])
+            .run(dryrun)
+            .context("Failed to create temporary user to install AUR packages")?;
     }
 
-    // Run preset scripts
-    if !presets.scripts.is_empty() {
-        info!("Running custom scripts");
-    }
+    effects::write(
+        &mount_path.join(AUR_SUDOERS),
+        "aur ALL=(ALL) NOPASSWD: ALL",
+        dryrun,
+    )
+    .context("Failed to modify sudoers file for AUR packages")
+}
+
+/// Removes the user added by `add_aur_user`, with its home directory and sudoers file
+fn remove_aur_user(arch_chroot: &Tool, mount_path: &Path, dryrun: bool) -> anyhow::Result<()> {
+    arch_chroot
+        .execute()
+        .arg(mount_path)
+        .args(["userdel", "-r", "aur"])
+        .run(dryrun)
+        .context("Failed to delete temporary aur user")?;
 
-    for script in &presets.scripts {
-        run_preset_script(command, arch_chroot, script, mount_path)?;
+    effects::remove_file(&mount_path.join(AUR_SUDOERS), dryrun)
+        .context("Cannot delete the AUR sudoers temporary file")
+}
+
+/// Installs the upstream release binary of the AUR helper, which avoids building it with makepkg
+fn install_prebuilt_aur_helper(
+    command: &CreateCommand,
+    arch_chroot: &Tool,
+    mount_path: &Path,
+) -> anyhow::Result<()> {
+    let binary = command.aur_helper.get_binary_name();
+    // arch-chroot mounts a tmpfs over /tmp, so the archive goes to /var/tmp
+    let archive = format!("/var/tmp/{binary}-release");
+    info!("Installing the prebuilt {binary} release");
+
+    if command.dryrun {
+        plan::print(Action::Note {
+            text: format!(
+                "Download the release of {} for {} to {}{archive}",
+                command.aur_helper.get_package_name(),
+                env::consts::ARCH,
+                mount_path.display()
+            ),
+        });
+    } else {
+        let release = command.aur_helper.fetch_prebuilt_release()?;
+        info!("Downloading {}", release.url);
+        let data = reqwest::blocking::get(&release.url)
+            .and_then(|r| r.error_for_status())
+            .and_then(|r| r.bytes())
+            .with_context(|| format!("Failed to download {}", release.url))
+            .kind(ErrorKind::Network)?;
+        release.verify(&data)?;
+        fs::write(mount_path.join(archive.trim_start_matches('/')), &data)
+            .with_context(|| format!("Failed to write {archive}"))?;
     }
 
-    Ok(())
+    arch_chroot
+        .execute()
+        .arg(mount_path)
+        .args([
+            "bash",
+            "-c",
+            &format!(
+                "mkdir -p {archive}.d && tar -xf {archive} -C {archive}.d && \\
+                 install -Dm755 \"$(find {archive}.d -type f -name {binary} | head -n 1)\" /usr/bin/{binary} && \\
+                 rm -rf {archive} {archive}.d"
+            ),
+        ])
+        .run(command.dryrun)
+        .with_context(|| format!("Failed to install the prebuilt {binary}"))
+}
+
+/// Runs a step of the installation into the mounted system. When running interactively, a
+/// failure offers to retry or skip the step, or to fix things up from a chroot shell, instead
+/// of tearing down the whole build.
+fn run_recoverable_step(
+    command: &CreateCommand,
+    arch_chroot: &Tool,
+    mount_path: &Path,
+    description: &str,
+    mut step: impl FnMut() -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    loop {
+        let err = match step() {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        // Ctrl+C aborts the build rather than offering to retry the step it interrupted
+        if command.noconfirm || command.dryrun || interrupt::interrupted() {
+            return Err(err);
+        }
+
+        error!("{description} failed: {err:?}");
+        loop {
+            let choice = Select::with_theme(&ColorfulTheme::default())
+                .with_prompt(format!("{description} failed. What would you like to do?"))
+                .items([
+                    "Retry",
+                    "Skip this step",
+                    "Open a shell in the chroot",
+                    "Abort and clean up",
+                ])
+                .default(0)
+                .interact()?;
+
+            match choice {
+                0 => break,
+                1 => {
+                    warn!("Skipping: {description}");
+                    return Ok(());
+                }
+                2 => {
+                    info!("Opening a shell in the chroot. Type 'exit' to return to this menu");
+                    if let Err(e) = arch_chroot.execute().arg(mount_path).run(false) {
+                        warn!("The chroot shell exited with an error: {e}");
+                    }
+                }
+                _ => return Err(err),
+            }
+        }
+    }
 }
 
 fn run_preset_script(
@@ -1152,12 +3167,8 @@ fn run_preset_script(
                 .join(PathBuf::from("shared_dirs/"))
                 .join(dir.file_name().expect("Dir had no filename"));
 
-            if !command.dryrun {
-                std::fs::create_dir_all(&shared_dirs_path)
-                    .context("Failed mounting shared directories in preset")?;
-            } else {
-                println!("mkdir -p {}", shared_dirs_path.display());
-            }
+            effects::create_dir_all(&shared_dirs_path, command.dryrun)
+                .context("Failed mounting shared directories in preset")?;
 
             bind_mount_stack
                 .bind_mount(dir.clone(), shared_dirs_path, None)
@@ -1201,6 +3212,7 @@ fn finalize_installation(
     mount_point: &TempDir,
     encrypted_root: Option<&EncryptedDevice>,
     root_partition_base: &Partition,
+    initcpio_options: &InitcpioOptions,
 ) -> anyhow::Result<()> {
     info!("Performing post installation tasks");
 
@@ -1212,31 +3224,196 @@ fn finalize_installation(
         .run(command.dryrun)
         .context("Failed to enable NetworkManager")?;
 
+    if command.cloud_init {
+        info!("Configuring cloud-init");
+        cloud_init::configure(
+            &tools.arch_chroot,
+            mount_point.path(),
+            command.cloud_init_seed.as_deref(),
+            command.dryrun,
+        )?;
+    }
+
     info!("Configuring journald");
-    if !command.dryrun {
-        fs::write(
-            mount_point.path().join("etc/systemd/journald.conf"),
-            constants::JOURNALD_CONF,
-        )
-        .context("Failed to write to journald.conf")?;
+    effects::write(
+        &mount_point.path().join("etc/systemd/journald.conf"),
+        constants::JOURNALD_CONF,
+        command.dryrun,
+    )?;
+
+    if let Some(size_mb) = command.swap_size_mb() {
+        create_swap_file(command, &tools.arch_chroot, mount_point.path(), size_mb)?;
     }
 
     // Only set up bootloader if boot partition is mounted
     if command.root_partition.is_none() || command.boot_partition.is_some() {
-        setup_bootloader(
-            storage_device,
-            mount_point,
+        run_recoverable_step(
+            command,
             &tools.arch_chroot,
-            encrypted_root,
-            root_partition_base,
-            tools.blkid.as_ref(),
+            mount_point.path(),
+            "Setting up the bootloader",
+            || {
+                setup_bootloader(
+                    command,
+                    tools,
+                    storage_device,
+                    mount_point,
+                    encrypted_root,
+                    root_partition_base,
+                    initcpio_options,
+                )
+            },
+        )
+        .kind(ErrorKind::Bootloader)?;
+
+        if let Some(board) = &command.board {
+            board
+                .write_firmware(
+                    storage_device.path(),
+                    mount_point.path(),
+                    false,
+                    command.dryrun,
+                )
+                .kind(ErrorKind::Bootloader)?;
+        }
+
+        if command.pacman_hooks {
+            install_pacman_hooks(command, mount_point.path())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Adds the root array to /etc/mdadm.conf, so the initramfs assembles it under a stable name
+fn configure_mdadm(array: &RaidArray, mount_path: &Path, dryrun: bool) -> anyhow::Result<()> {
+    info!("Adding the RAID array to mdadm.conf");
+    let entry = raid::mdadm_conf_entry(&array.uuid()?);
+    let path = mount_path.join("etc/mdadm.conf");
+    let conf = fs::read_to_string(&path).unwrap_or_default();
+    let separator = if conf.is_empty() || conf.ends_with('\n') {
+        ""
+    } else {
+        "\n"
+    };
+    effects::append(&path, format!("{separator}{entry}"), dryrun)
+}
+
+/// Installs GRUB on the --raid1 mirror and copies the boot partition to its own, so the system
+/// boots from either device alone. Later kernel updates only reach the mounted boot partition.
+fn mirror_boot_partition(
+    command: &CreateCommand,
+    tools: &Tools,
+    mirror_device: &StorageDevice,
+    mount_path: &Path,
+) -> anyhow::Result<()> {
+    info!("Copying the boot partition to the mirror {mirror_device}");
+    // The EFI loader is in the copied files, only the BIOS one lives outside the boot partition
+    if command.grub_targets.bios() {
+        tools
+            .arch_chroot
+            .execute()
+            .arg(mount_path)
+            .args([
+                "grub-install",
+                "--target=i386-pc",
+                "--boot-directory",
+                "/boot",
+            ])
+            .arg(mirror_device.path())
+            .run(command.dryrun)
+            .context("Failed to install GRUB on the mirror")?;
+    }
+
+    let mirror_boot = mirror_device.get_partition(constants::BOOT_PARTITION_INDEX)?;
+    let mirror_mount = state::temp_mount_point()?;
+    let mut mount_stack = MountStack::new(command.dryrun);
+    mount_stack
+        .mount_single(
+            mirror_boot.path(),
+            mirror_mount.path(),
+            Some("vfat"),
+            MsFlags::MS_NOATIME,
+            None,
+        )
+        .context("Failed to mount the boot partition of the mirror")?;
+    Tool::find("cp", command.dryrun)?
+        .execute()
+        .arg("-rT")
+        .arg(mount_path.join("boot"))
+        .arg(mirror_mount.path())
+        .run(command.dryrun)
+        .context("Failed to copy the boot partition to the mirror")?;
+    mount_stack.umount()?;
+
+    if let Some(board) = &command.board {
+        board.write_firmware(mirror_device.path(), mount_path, true, command.dryrun)?;
+    }
+    Ok(())
+}
+
+fn install_pacman_hooks(command: &CreateCommand, mount_path: &Path) -> anyhow::Result<()> {
+    info!("Installing ALMA pacman hooks");
+    let mkinitcpio = command.initramfs == InitramfsGenerator::Mkinitcpio;
+    let hooks = PacmanHooks::new(command.grub_targets, command.shim(), mkinitcpio);
+    let hooks_dir = mount_path.join(pacman_hooks::HOOKS_DIR);
+    let scripts_dir = mount_path.join(pacman_hooks::SCRIPTS_DIR);
+
+    effects::create_dir_all(&hooks_dir, command.dryrun)?;
+    effects::create_dir_all(&scripts_dir, command.dryrun)?;
+    for (name, contents) in hooks.hooks() {
+        effects::write(&hooks_dir.join(name), contents, command.dryrun)?;
+    }
+    for (name, contents) in hooks.scripts() {
+        let path = scripts_dir.join(name);
+        effects::write(&path, contents, command.dryrun)?;
+        effects::set_mode(&path, 0o755, command.dryrun)?;
+    }
+
+    if mkinitcpio {
+        effects::copy(
+            &mount_path.join("etc/mkinitcpio.conf"),
+            &mount_path.join(pacman_hooks::MKINITCPIO_CONF_COPY),
             command.dryrun,
-        )?;
+        )
+        .context("Failed to save a reference copy of mkinitcpio.conf")?;
     }
 
     Ok(())
 }
 
+/// Leaves the target mounted, with its encrypted root and image loop device still open, so a
+/// failed build can be inspected in place. Prints the commands to tear it down afterwards.
+fn keep_mounts(
+    mount_point: TempDir,
+    encrypted_root: Option<EncryptedDevice>,
+    raid_array: Option<RaidArray>,
+    image_loop: Option<LoopDevice>,
+) {
+    let mount_path = mount_point.keep();
+
+    warn!(
+        "Keeping the partially built system mounted at {}",
+        mount_path.display()
+    );
+    println!("To clean up once you are done inspecting it, run:");
+    println!("  sudo umount -R {}", mount_path.display());
+    if let Some(encrypted_root) = encrypted_root {
+        println!("  sudo cryptsetup close {}", encrypted_root.name());
+        std::mem::forget(encrypted_root);
+    }
+    if let Some(raid_array) = raid_array {
+        println!("  sudo mdadm --stop {}", raid_array.path().display());
+        std::mem::forget(raid_array);
+    }
+    if let Some(image_loop) = image_loop {
+        println!("  sudo losetup -d {}", image_loop.path().display());
+        std::mem::forget(image_loop);
+    }
+    println!("  sudo rmdir {}", mount_path.display());
+    println!("or run `sudo alma cleanup`.");
+}
+
 fn interactive_chroot_and_cleanup(
     command: &CreateCommand,
     arch_chroot: &Tool,
@@ -1260,6 +3437,92 @@ fn interactive_chroot_and_cleanup(
     Ok(())
 }
 
+/// Writes the sudo policy of the wheel group and checks the sudoers files with visudo
+fn configure_sudo(
+    options: &SudoOptions,
+    passwordless: bool,
+    arch_chroot: &Tool,
+    mount_path: &Path,
+    dryrun: bool,
+) -> anyhow::Result<()> {
+    info!("Configuring sudo");
+    let sudoers_path = mount_path.join(sudo::SUDOERS_PATH);
+    let sudoers = options.sudoers(passwordless);
+    effects::write(&sudoers_path, sudoers, dryrun)?;
+    effects::set_mode(&sudoers_path, 0o440, dryrun)?;
+
+    if options.sudo_rs {
+        // /usr/local/bin comes first in PATH, the original sudo stays available in /usr/bin
+        arch_chroot
+            .execute()
+            .arg(mount_path)
+            .args(["ln", "-sf", "/usr/bin/sudo-rs", "/usr/local/bin/sudo"])
+            .run(dryrun)
+            .context("Failed to make sudo-rs the default sudo")?;
+    }
+
+    check_sudoers(arch_chroot, mount_path, dryrun)
+}
+
+/// A broken sudoers file locks everybody out of sudo, so the build fails instead
+fn check_sudoers(arch_chroot: &Tool, mount_path: &Path, dryrun: bool) -> anyhow::Result<()> {
+    arch_chroot
+        .execute()
+        .arg(mount_path)
+        .args(["/usr/bin/visudo", "-c"])
+        .run(dryrun)
+        .context("The sudoers configuration is invalid")
+}
+
+/// Sets the password of the interactive user through the stdin of chpasswd, so it never ends up
+/// in a file, a command line or the logs
+fn set_user_password(
+    settings: &UserSettings,
+    arch_chroot: &Tool,
+    mount_path: &Path,
+    dryrun: bool,
+) -> anyhow::Result<()> {
+    let Some(input) = settings.chpasswd_input() else {
+        return Ok(());
+    };
+    if dryrun {
+        // The password stays out of the plan, passwd asks for it when the script is run
+        plan::print(Action::Command {
+            argv: vec![
+                arch_chroot.exec.to_string_lossy().into_owned(),
+                mount_path.to_string_lossy().into_owned(),
+                String::from("passwd"),
+                settings.username.clone(),
+            ],
+            env: Default::default(),
+            stdout: None,
+        });
+        return Ok(());
+    }
+
+    let mut chpasswd = arch_chroot
+        .execute()
+        .arg(mount_path)
+        .arg("chpasswd")
+        .args(settings.password_is_hash.then_some("-e"))
+        .stdin(Stdio::piped())
+        .spawn()
+        .context("Failed to run chpasswd")?;
+    chpasswd
+        .stdin
+        .take()
+        .expect("chpasswd stdin is piped")
+        .write_all(input.as_bytes())
+        .context("Failed to pass the password to chpasswd")?;
+    if !chpasswd.wait()?.success() {
+        return Err(anyhow!(
+            "Failed to set the password of user '{}'",
+            settings.username
+        ));
+    }
+    Ok(())
+}
+
 fn run_script_in_chroot(
     script_text: &str,
     arch_chroot: &Tool,
@@ -1280,7 +3543,7 @@ fn run_script_in_chroot(
 
     // 3. Set permissions on the now-closed file.
     let mut perms = fs::metadata(&temp_path)?.permissions();
-    perms.set_mode(0o755); // This now works because PermissionsExt is in scope
+    perms.set_mode(0o700); // Only root runs it, and nobody else should read it
     fs::set_permissions(&temp_path, perms)?; // This now works because `perms` is the right type
 
     let script_path_in_chroot =
@@ -1301,18 +3564,137 @@ fn run_script_in_chroot(
     result.with_context(|| format!("Failed running setup script:\n{script_text}"))
 }
 
+/// `--from-image`: writes a finished image to the device rather than building a system on it,
+/// then grows the root partition into the rest of the device, unless a data partition follows it
+fn create_from_image(command: &CreateCommand, image: &Path) -> anyhow::Result<()> {
+    let device = command
+        .path
+        .clone()
+        .expect("--from-image requires a block device");
+    let expand = resize::root_is_last_partition(image)?;
+    flash::flash(FlashCommand {
+        image: image.to_path_buf(),
+        device: device.clone(),
+        no_verify: false,
+        allow_non_removable: command.allow_non_removable,
+        noconfirm: command.noconfirm,
+    })?;
+    if !expand {
+        info!("Not growing the root partition, the image has a data partition after it");
+    }
+    resize::fix_gpt(FixGptCommand {
+        device,
+        expand,
+        allow_non_removable: command.allow_non_removable,
+    })
+}
+
+/// Puts shim in front of the removable GRUB EFI loader which grub-install just wrote, which shim
+/// loads as grubx64.efi
+pub fn install_shim(mount_path: &Path, dryrun: bool) -> anyhow::Result<()> {
+    let bootloader = mount_path.join("boot/EFI/BOOT/BOOTX64.efi");
+    let grub = mount_path.join("boot/EFI/BOOT/grubx64.efi");
+    let shim = mount_path.join("usr/share/shim-signed/shimx64.efi");
+
+    // A retried bootloader step finds GRUB moved out already, and possibly shim in its place
+    let grub_moved = !dryrun
+        && grub.exists()
+        && match (fs::read(&bootloader), fs::read(&shim)) {
+            (Err(_), _) => true,
+            (Ok(bootloader), Ok(shim)) => bootloader == shim,
+            (Ok(_), Err(_)) => false,
+        };
+    if grub_moved {
+        debug!("GRUB was already moved to {}", grub.display());
+    } else {
+        effects::rename(&bootloader, &grub, dryrun).context("Cannot move out grub")?;
+    }
+    effects::copy(
+        &mount_path.join("usr/share/shim-signed/mmx64.efi"),
+        &mount_path.join("boot/EFI/BOOT/mmx64.efi"),
+        dryrun,
+    )?;
+    effects::copy(&shim, &bootloader, dryrun)
+}
+
+/// Makes every menu entry of grub.cfg find the root filesystem by UUID, rather than by the path
+/// it had on the build host
+pub fn pin_root_device(
+    blkid: &Tool,
+    root_device: &dyn BlockDevice,
+    mount_path: &Path,
+) -> anyhow::Result<()> {
+    let root_spec = Identifier::Uuid.spec(&ids::uuid(blkid, root_device)?);
+    if blkid.dryrun {
+        return Ok(());
+    }
+    let grub_cfg_path = mount_path.join("boot/grub/grub.cfg");
+    let grub_cfg = fs::read_to_string(&grub_cfg_path).context("Failed to read grub.cfg")?;
+    let pinned = grub::pin_root_device(&grub_cfg, &root_spec);
+    if pinned != grub_cfg {
+        debug!("Replacing the root device path in grub.cfg with {root_spec}");
+        fs::write(&grub_cfg_path, pinned).context("Failed to write grub.cfg")?;
+    }
+    Ok(())
+}
+
+/// Reinstalls GRUB on an installed system and regenerates grub.cfg, putting shim back in front
+/// of GRUB if the system booted through it
+pub fn reinstall_bootloader(
+    storage_device: &StorageDevice,
+    mount_point: &tempfile::TempDir,
+    arch_chroot: &Tool,
+    blkid: &Tool,
+    root_device: &dyn BlockDevice,
+    targets: GrubTargets,
+) -> anyhow::Result<()> {
+    let shim = mount_point
+        .path()
+        .join("boot/EFI/BOOT/grubx64.efi")
+        .exists();
+    run_grub_mkconfig_scoped(storage_device, mount_point, arch_chroot, targets, false)?;
+    pin_root_device(blkid, root_device, mount_point.path())?;
+    if shim {
+        install_shim(mount_point.path(), false)?;
+    }
+    Ok(())
+}
+
+/// Regenerates the initramfs images of an installed system with its generator, like the pacman
+/// hooks do after a kernel upgrade
+pub fn regenerate_initramfs(
+    arch_chroot: &Tool,
+    mount_path: &Path,
+    generator: InitramfsGenerator,
+) -> anyhow::Result<()> {
+    info!("Regenerating the initramfs with {generator}");
+    let mut command = arch_chroot.execute();
+    command.arg(mount_path);
+    match generator {
+        InitramfsGenerator::Mkinitcpio => command.args(["mkinitcpio", "-P"]),
+        InitramfsGenerator::Dracut => command.arg(format!(
+            "/{}/{}",
+            pacman_hooks::SCRIPTS_DIR,
+            dracut::SCRIPT_NAME
+        )),
+    };
+    command
+        .run(false)
+        .context("Failed to regenerate the initramfs")
+}
+
 /// Runs grub-mkconfig with os-prober temporarily wrapped to only scan the target device.
-fn run_grub_mkconfig_scoped(
+pub fn run_grub_mkconfig_scoped(
     storage_device: &StorageDevice,
     mount_point: &tempfile::TempDir,
     arch_chroot: &Tool,
+    targets: GrubTargets,
     dryrun: bool,
 ) -> anyhow::Result<()> {
     info!("Installing GRUB and running scoped os-prober...");
 
-    let disk_path = storage_device.path();
-    let os_prober_path = mount_point.path().join("usr/bin/os-prober");
-    let os_prober_real_path = mount_point.path().join("usr/bin/os-prober.real");
+    // Not the path it had when the build started, should it have been disconnected since
+    let disk_path = storage_device.current_path();
 
     // The wrapper script that limits os-prober's scope
     let wrapper_script = format!(
@@ -1320,59 +3702,84 @@ fn run_grub_mkconfig_scoped(
         disk_path.display()
     );
 
-    // 1. Rename the real os-prober
     info!(
         "Wrapping os-prober to limit scan to {}",
         disk_path.display()
     );
-    if !dryrun && os_prober_path.exists() {
-        fs::rename(&os_prober_path, &os_prober_real_path)
-            .context("Failed to move real os-prober")?;
-    } else if dryrun {
-        println!(
-            "mv {} {}",
-            os_prober_path.display(),
-            os_prober_real_path.display()
-        );
-    }
+    let mut intercepts = InterceptStack::new(mount_point.path(), dryrun);
+    intercepts.wrap(&Interception::new("os-prober", wrapper_script))?;
 
-    // 2. Write and chmod the wrapper script
-    if !dryrun && os_prober_real_path.exists() {
-        fs::write(&os_prober_path, &wrapper_script)
-            .context("Failed to write os-prober wrapper script")?;
-        fs::set_permissions(
-            &os_prober_path,
-            std::os::unix::fs::PermissionsExt::from_mode(0o755),
-        )?;
-    } else if dryrun {
-        println!("echo '{}' > {}", wrapper_script, os_prober_path.display());
-        println!("chmod 755 {}", os_prober_path.display());
-    }
-
-    // 3. Run grub-install and grub-mkconfig
-    let result = arch_chroot.execute()
+    // Run grub-install and grub-mkconfig
+    let result = arch_chroot
+        .execute()
         .arg(mount_point.path())
         .args(["bash", "-c"])
-        .arg(format!(
-            "grub-install --target=i386-pc --boot-directory /boot {0} && \
-             grub-install --target=x86_64-efi --efi-directory /boot --boot-directory /boot --removable {0} && \
-             grub-mkconfig -o /boot/grub/grub.cfg",
-            disk_path.display()
-        ))
+        .arg(grub::install_script(targets, &disk_path))
         .run(dryrun);
 
-    // 4. Clean up: restore the real os-prober, regardless of the result
+    // Restore the real os-prober, regardless of the result
     info!("Unwrapping os-prober...");
-    if !dryrun && os_prober_real_path.exists() {
-        fs::rename(&os_prober_real_path, &os_prober_path)
-            .context("Failed to restore real os-prober")?;
-    } else if dryrun {
-        println!(
-            "mv {} {}",
-            os_prober_real_path.display(),
-            os_prober_path.display()
-        );
-    }
+    intercepts.restore()?;
 
     result.context("Failed to install grub or run grub-mkconfig")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fakes arch-chroot with a script keeping the aur user as a marker file, and logging the
+    /// commands it is given
+    fn fake_arch_chroot(dir: &Path) -> Tool {
+        let exec = dir.join("arch-chroot");
+        fs::write(
+            &exec,
+            format!(
+                r#"#!/bin/sh
+shift
+echo "$1" >> {dir}/log
+case "$1" in
+    id) test -e {dir}/user ;;
+    useradd) ! test -e {dir}/user && touch {dir}/user ;;
+    userdel) rm {dir}/user ;;
+esac
+"#,
+                dir = dir.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&exec, fs::Permissions::from_mode(0o755)).unwrap();
+        Tool {
+            exec,
+            dryrun: false,
+        }
+    }
+
+    #[test]
+    fn test_with_aur_user() {
+        let dir = tempfile::tempdir().unwrap();
+        let arch_chroot = fake_arch_chroot(dir.path());
+        let mount_path = dir.path().join("root");
+        fs::create_dir_all(mount_path.join("etc/sudoers.d")).unwrap();
+
+        // A failed AUR step still removes the user, so resuming can add it again
+        let result: anyhow::Result<()> = with_aur_user(&arch_chroot, &mount_path, false, || {
+            assert!(mount_path.join(AUR_SUDOERS).exists());
+            Err(anyhow!("AUR helper failed"))
+        });
+        assert!(result.is_err());
+        assert!(!dir.path().join("user").exists());
+        assert!(!mount_path.join(AUR_SUDOERS).exists());
+
+        // A user left over by an interrupted run is reused on resume
+        fs::write(dir.path().join("user"), "").unwrap();
+        fs::write(dir.path().join("log"), "").unwrap();
+        with_aur_user(&arch_chroot, &mount_path, false, || Ok(())).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.path().join("log")).unwrap(),
+            "id\nuserdel\n"
+        );
+        assert!(!dir.path().join("user").exists());
+        assert!(!mount_path.join(AUR_SUDOERS).exists());
+    }
+}
@@ -0,0 +1,187 @@
+//! Out-of-tree kernel modules built with DKMS, like nvidia-dkms. They only build against the
+//! headers of each kernel, and a module missing for the kernel that boots leaves e.g. a black
+//! screen instead of a desktop, so the headers are installed with them and the build fails when a
+//! module did not build for every installed kernel.
+
+use crate::args::Kernel;
+use crate::error::{ErrorKind, ErrorKindExt};
+use crate::process::CommandExt;
+use crate::tool::Tool;
+use anyhow::{Context, anyhow};
+use log::{debug, info};
+use std::fs;
+use std::path::Path;
+
+pub fn is_dkms_package(package: &str) -> bool {
+    package.ends_with("-dkms")
+}
+
+/// The headers the DKMS modules are built against, one package per kernel
+pub fn header_packages(kernels: &[Kernel]) -> Vec<String> {
+    kernels
+        .iter()
+        .map(|kernel| format!("{}-headers", kernel.package_name()))
+        .collect()
+}
+
+/// A line of `dkms status`
+#[derive(Debug, PartialEq, Eq)]
+struct ModuleStatus {
+    /// `name/version`
+    module: String,
+    /// The kernel release it was built for, unless it was only added
+    kernel: Option<String>,
+    status: String,
+}
+
+/// Parses `dkms status`, in the `name/version, kernel, arch: status` format of DKMS 3 and the
+/// `name, version, kernel, arch: status` one of older releases
+fn parse_status(output: &str) -> Vec<ModuleStatus> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (fields, status) = line.rsplit_once(": ")?;
+            let mut fields = fields.split(", ");
+            let mut module = fields.next()?.to_owned();
+            if !module.contains('/') {
+                module = format!("{module}/{}", fields.next()?);
+            }
+            // Warnings like "(WARNING! Diff between built and installed module!)" follow
+            let status = status.split_whitespace().next()?.trim_end_matches(',');
+            Some(ModuleStatus {
+                module,
+                kernel: fields.next().map(String::from),
+                status: status.to_owned(),
+            })
+        })
+        .collect()
+}
+
+/// The installed kernels of the system at `mount_path`, as package names and module directories
+fn installed_kernels(mount_path: &Path) -> Vec<(String, String)> {
+    let Ok(entries) = fs::read_dir(mount_path.join("usr/lib/modules")) else {
+        return Vec::new();
+    };
+    let mut kernels: Vec<(String, String)> = entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let pkgbase = fs::read_to_string(entry.path().join("pkgbase")).ok()?;
+            Some((
+                pkgbase.trim().to_owned(),
+                entry.file_name().to_string_lossy().into_owned(),
+            ))
+        })
+        .collect();
+    kernels.sort();
+    kernels
+}
+
+/// The modules which are not installed for some kernel, with that kernel's package
+fn missing_builds(
+    statuses: &[ModuleStatus],
+    kernels: &[(String, String)],
+) -> Vec<(String, String)> {
+    let mut modules: Vec<&str> = statuses.iter().map(|s| s.module.as_str()).collect();
+    modules.sort();
+    modules.dedup();
+    let mut missing = Vec::new();
+    for module in modules {
+        for (package, release) in kernels {
+            let built = statuses.iter().any(|s| {
+                s.module == module
+                    && s.kernel.as_deref() == Some(release.as_str())
+                    && s.status == "installed"
+            });
+            if !built {
+                missing.push((module.to_owned(), package.clone()));
+            }
+        }
+    }
+    missing
+}
+
+/// Fails if a DKMS module of the system at `mount_path` is not built for every installed kernel
+pub fn check_modules(arch_chroot: &Tool, mount_path: &Path, dryrun: bool) -> anyhow::Result<()> {
+    if dryrun || !mount_path.join("usr/bin/dkms").exists() {
+        return Ok(());
+    }
+    let output = arch_chroot
+        .execute()
+        .arg(mount_path)
+        .args(["dkms", "status"])
+        .run_text_output(false)
+        .context("Failed to list the DKMS modules")?;
+    let statuses = parse_status(&output);
+    if statuses.is_empty() {
+        return Ok(());
+    }
+    debug!("DKMS modules: {statuses:?}");
+
+    let missing = missing_builds(&statuses, &installed_kernels(mount_path));
+    if let Some((module, kernel)) = missing.first() {
+        return Err(anyhow!(
+            "The DKMS module {module} is not built for {kernel}, which would boot without it (e.g. to a black screen for a graphics driver). Check that {kernel}-headers is installed and that the module supports this kernel, then run `dkms autoinstall` in `alma chroot`{}",
+            match missing.len() {
+                1 => String::new(),
+                n => format!(". {} more modules are missing", n - 1),
+            }
+        ))
+        .kind(ErrorKind::Chroot);
+    }
+    info!(
+        "The DKMS modules are built for every kernel: {}",
+        statuses
+            .iter()
+            .filter(|s| s.kernel.is_some())
+            .map(|s| s.module.as_str())
+            .collect::<std::collections::BTreeSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_builds() {
+        let statuses = parse_status(
+            "nvidia/550.78, 6.9.3-arch1-1, x86_64: installed
+nvidia/550.78, 6.6.32-1-lts, x86_64: built
+v4l2loopback, 0.13.2, 6.9.3-arch1-1, x86_64: installed (WARNING! Diff between built and installed module!)
+v4l2loopback, 0.13.2, 6.6.32-1-lts, x86_64: installed
+broken/1.0: added
+",
+        );
+        assert_eq!(
+            statuses[2],
+            ModuleStatus {
+                module: String::from("v4l2loopback/0.13.2"),
+                kernel: Some(String::from("6.9.3-arch1-1")),
+                status: String::from("installed"),
+            }
+        );
+        assert_eq!(statuses[4].kernel, None);
+
+        let kernels = [
+            (String::from("linux"), String::from("6.9.3-arch1-1")),
+            (String::from("linux-lts"), String::from("6.6.32-1-lts")),
+        ];
+        assert_eq!(
+            missing_builds(&statuses, &kernels),
+            [
+                (String::from("broken/1.0"), String::from("linux")),
+                (String::from("broken/1.0"), String::from("linux-lts")),
+                (String::from("nvidia/550.78"), String::from("linux-lts")),
+            ]
+        );
+        assert!(is_dkms_package("nvidia-open-dkms"));
+        assert_eq!(
+            header_packages(&[Kernel::Linux, Kernel::LinuxLts]),
+            ["linux-headers", "linux-lts-headers"]
+        );
+    }
+}
@@ -0,0 +1,385 @@
+use crate::arch::Arch;
+use crate::args::DoctorCommand;
+use crate::constants::{HOST_TOOLS, MIN_PACKAGE_VERSIONS, OVMF_PATHS};
+use crate::process::CommandExt;
+use crate::tool::{Tool, Version};
+use anyhow::{Context, anyhow};
+use byte_unit::Byte;
+use console::style;
+use log::info;
+use nix::sys::statvfs::statvfs;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+use which::which;
+
+const MIN_FREE_SPACE_GIB: u64 = 10;
+const REACHABILITY_URLS: [&str; 2] = ["https://archlinux.org", "https://aur.archlinux.org"];
+
+#[derive(Debug, PartialEq, Eq)]
+enum Status {
+    Ok,
+    Warning,
+    Failed,
+}
+
+#[derive(Debug)]
+struct Check {
+    name: String,
+    status: Status,
+    detail: String,
+    /// The host package which fixes the check
+    package: Option<String>,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, status: Status, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            status,
+            detail: detail.into(),
+            package: None,
+        }
+    }
+
+    fn fixed_by(mut self, package: &str) -> Self {
+        self.package = Some(package.to_owned());
+        self
+    }
+}
+
+/// Checks the host for everything ALMA needs and prints a readiness report
+pub fn doctor(command: DoctorCommand) -> anyhow::Result<()> {
+    info!("Checking host requirements...");
+
+    let mut checks = vec![check_root(), check_architecture()];
+    checks.extend(check_tools());
+    checks.extend(check_package_versions());
+    checks.push(check_ovmf());
+    checks.push(check_kernel_module("loop", true));
+    checks.push(check_kernel_module("dm_crypt", false));
+    checks.extend(check_free_space());
+    if !command.no_network {
+        checks.extend(check_network());
+    }
+
+    print_report(&checks);
+
+    let required = packages_to_install(&checks, Status::Failed);
+    let mut optional = packages_to_install(&checks, Status::Warning);
+    optional.retain(|package| !required.contains(package));
+    if !required.is_empty() {
+        println!(
+            "\nInstall the required packages with:\n  sudo pacman -S --needed {}",
+            required.join(" ")
+        );
+    }
+    if !optional.is_empty() {
+        println!(
+            "\nInstall the packages for the optional features with:\n  sudo pacman -S --needed {}",
+            optional.join(" ")
+        );
+    }
+    if command.install && !required.is_empty() {
+        install_packages(&required)?;
+        info!("Installed the required packages, run 'alma doctor' again to check the host");
+        return Ok(());
+    }
+
+    let failed = checks.iter().filter(|c| c.status == Status::Failed).count();
+    if failed > 0 {
+        return Err(anyhow!(
+            "{failed} required check(s) failed. See the report above for how to fix them."
+        ));
+    }
+
+    info!("The host is ready to run ALMA.");
+    Ok(())
+}
+
+fn print_report(checks: &[Check]) {
+    for check in checks {
+        let mark = match check.status {
+            Status::Ok => style("✓").green(),
+            Status::Warning => style("!").yellow(),
+            Status::Failed => style("✗").red(),
+        };
+        println!("{} {:<28} {}", mark.bold(), check.name, check.detail);
+    }
+}
+
+fn check_root() -> Check {
+    if nix::unistd::geteuid().is_root() {
+        Check::new("root privileges", Status::Ok, "running as root")
+    } else {
+        Check::new(
+            "root privileges",
+            Status::Warning,
+            "not running as root, 'alma create' must be run with sudo",
+        )
+    }
+}
+
+fn check_architecture() -> Check {
+    let arch = std::env::consts::ARCH;
+    match Arch::host() {
+        Some(_) => Check::new("architecture", Status::Ok, arch),
+        None => Check::new(
+            "architecture",
+            Status::Failed,
+            format!(
+                "{arch} is not supported, ALMA only builds {} systems",
+                Arch::ALL.map(Arch::name).join(" and ")
+            ),
+        ),
+    }
+}
+
+fn check_tools() -> Vec<Check> {
+    HOST_TOOLS
+        .iter()
+        .map(|(name, package, needed_for)| match which(name) {
+            Ok(path) => check_tool_version(name, package, path),
+            Err(_) => match needed_for {
+                None => Check::new(
+                    *name,
+                    Status::Failed,
+                    format!("missing, install the '{package}' package"),
+                )
+                .fixed_by(package),
+                Some(feature) => Check::new(
+                    *name,
+                    Status::Warning,
+                    format!("missing, install the '{package}' package to use {feature}"),
+                )
+                .fixed_by(package),
+            },
+        })
+        .collect()
+}
+
+fn check_tool_version(name: &str, package: &str, path: PathBuf) -> Check {
+    let tool = Tool {
+        exec: path,
+        dryrun: false,
+    };
+    let Some(min) = tool.min_version() else {
+        return Check::new(name, Status::Ok, tool.exec.display().to_string());
+    };
+
+    match tool.version() {
+        Ok(version) if version >= min => Check::new(
+            name,
+            Status::Ok,
+            format!("{} (version {version})", tool.exec.display()),
+        ),
+        Ok(version) => Check::new(
+            name,
+            Status::Failed,
+            format!("version {version} is older than {min}, upgrade the '{package}' package"),
+        )
+        .fixed_by(package),
+        Err(e) => Check::new(name, Status::Warning, format!("{e}")),
+    }
+}
+
+/// Checks the packages whose tools have no version argument with pacman. Skipped on hosts
+/// without pacman, such as the Docker image's host.
+fn check_package_versions() -> Vec<Check> {
+    if which("pacman").is_err() {
+        return Vec::new();
+    }
+    MIN_PACKAGE_VERSIONS
+        .iter()
+        .map(|(package, min, needed_for)| {
+            let output = Command::new("pacman").args(["-Q", package]).output();
+            let installed = output
+                .ok()
+                .filter(|o| o.status.success())
+                .and_then(|o| package_version(&String::from_utf8_lossy(&o.stdout)));
+            let min: Version = min.parse().expect("Valid minimum version");
+            match installed {
+                Some(version) if version >= min => {
+                    Check::new(*package, Status::Ok, format!("version {version}"))
+                }
+                Some(version) => Check::new(
+                    *package,
+                    Status::Failed,
+                    format!(
+                        "version {version} is older than {min}, which {needed_for} needs, upgrade it"
+                    ),
+                )
+                .fixed_by(package),
+                None => Check::new(
+                    *package,
+                    Status::Failed,
+                    format!("not installed, install it for {needed_for}"),
+                )
+                .fixed_by(package),
+            }
+        })
+        .collect()
+}
+
+/// The upstream version of a package from `pacman -Q`, e.g. 29 from "arch-install-scripts 29-1"
+fn package_version(query: &str) -> Option<Version> {
+    let version = query.split_whitespace().nth(1)?;
+    // Drop the epoch and the package release
+    let version = version.rsplit_once(':').map_or(version, |(_, v)| v);
+    version.split('-').next()?.parse().ok()
+}
+
+/// The packages fixing the checks with the given status, each once
+fn packages_to_install(checks: &[Check], status: Status) -> Vec<String> {
+    checks
+        .iter()
+        .filter(|check| check.status == status)
+        .filter_map(|check| check.package.clone())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+fn install_packages(packages: &[String]) -> anyhow::Result<()> {
+    info!("Installing {}", packages.join(" "));
+    Tool::find("pacman", false)?
+        .execute()
+        .args(["-S", "--needed"])
+        .args(packages)
+        .run(false)
+        .context("Failed to install the packages, run 'alma doctor --install' with sudo")
+}
+
+fn check_ovmf() -> Check {
+    match OVMF_PATHS.iter().find(|p| Path::new(p).exists()) {
+        Some(path) => Check::new("OVMF firmware", Status::Ok, *path),
+        None => Check::new(
+            "OVMF firmware",
+            Status::Warning,
+            "missing, install the 'edk2-ovmf' package to boot USB devices with 'alma qemu'",
+        )
+        .fixed_by("edk2-ovmf"),
+    }
+}
+
+/// Checks whether a kernel module is loaded or can be loaded on demand
+fn check_kernel_module(module: &str, required: bool) -> Check {
+    let name = format!("kernel module {module}");
+    if Path::new("/sys/module").join(module).exists() {
+        return Check::new(name, Status::Ok, "loaded");
+    }
+
+    let available = Command::new("modinfo")
+        .arg("-n")
+        .arg(module)
+        .output()
+        .map(|o| o.status.success())
+        .unwrap_or(false);
+    if available {
+        return Check::new(name, Status::Ok, "available, loaded on demand");
+    }
+
+    let status = if required {
+        Status::Failed
+    } else {
+        Status::Warning
+    };
+    Check::new(
+        name,
+        status,
+        "not available, reboot if you have installed a kernel update since your last reboot",
+    )
+}
+
+fn check_free_space() -> Vec<Check> {
+    let mut dirs = vec![std::env::temp_dir()];
+    if let Ok(cwd) = std::env::current_dir() {
+        dirs.push(cwd);
+    }
+    dirs.dedup();
+
+    dirs.iter().map(check_free_space_in).collect()
+}
+
+fn check_free_space_in(dir: &PathBuf) -> Check {
+    let name = format!("free space in {}", dir.display());
+    let stat = match statvfs(dir) {
+        Ok(stat) => stat,
+        Err(e) => return Check::new(name, Status::Warning, format!("unable to query: {e}")),
+    };
+
+    let free = stat.blocks_available() as u64 * stat.fragment_size() as u64;
+    let free_display = Byte::from_u64(free).get_appropriate_unit(byte_unit::UnitType::Binary);
+    let min_free = MIN_FREE_SPACE_GIB * 1024 * 1024 * 1024;
+    if free < min_free {
+        Check::new(
+            name,
+            Status::Warning,
+            format!(
+                "{free_display:.2} free, at least {MIN_FREE_SPACE_GIB} GiB is recommended for --image builds"
+            ),
+        )
+    } else {
+        Check::new(name, Status::Ok, format!("{free_display:.2} free"))
+    }
+}
+
+fn check_network() -> Vec<Check> {
+    let client = match reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+    {
+        Ok(client) => client,
+        Err(e) => {
+            return vec![Check::new(
+                "network",
+                Status::Warning,
+                format!("unable to create HTTP client: {e}"),
+            )];
+        }
+    };
+
+    REACHABILITY_URLS
+        .iter()
+        .map(|url| match client.head(*url).send() {
+            Ok(_) => Check::new(*url, Status::Ok, "reachable"),
+            Err(e) => Check::new(
+                *url,
+                Status::Failed,
+                format!("unreachable ({e}), check your network connection and proxy settings"),
+            ),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packages_to_install() {
+        assert_eq!(
+            package_version("arch-install-scripts 29-1\n"),
+            Some("29".parse().unwrap())
+        );
+        assert_eq!(
+            package_version("gptfdisk 1:1.0.10-1"),
+            Some("1.0.10".parse().unwrap())
+        );
+        assert_eq!(package_version(""), None);
+
+        let checks = [
+            Check::new("sgdisk", Status::Failed, "missing").fixed_by("gptfdisk"),
+            Check::new("sfdisk", Status::Failed, "missing").fixed_by("util-linux"),
+            Check::new("losetup", Status::Failed, "missing").fixed_by("util-linux"),
+            Check::new("zstd", Status::Warning, "missing").fixed_by("zstd"),
+            Check::new("architecture", Status::Failed, "aarch64 is not supported"),
+        ];
+        assert_eq!(
+            packages_to_install(&checks, Status::Failed),
+            ["gptfdisk", "util-linux"]
+        );
+        assert_eq!(packages_to_install(&checks, Status::Warning), ["zstd"]);
+    }
+}
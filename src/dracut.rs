@@ -0,0 +1,159 @@
+use std::fmt::Write;
+
+/// Drop-in configuration read by dracut in addition to /etc/dracut.conf
+pub const CONF_PATH: &str = "etc/dracut.conf.d/alma.conf";
+pub const HOOK_NAME: &str = "90-alma-dracut-install.hook";
+pub const SCRIPT_NAME: &str = "dracut-install.sh";
+
+/// The dracut counterpart of `Initcpio`
+pub struct Dracut {
+    encrypted: bool,
+    btrfs: bool,
+    plymouth: bool,
+    hostonly: bool,
+    resume: bool,
+    raid: bool,
+    volatile_root: bool,
+}
+
+impl Dracut {
+    pub fn new(encrypted: bool, btrfs: bool, plymouth: bool, hostonly: bool) -> Self {
+        Self {
+            encrypted,
+            btrfs,
+            plymouth,
+            hostonly,
+            resume: false,
+            raid: false,
+            volatile_root: false,
+        }
+    }
+
+    /// Includes the resume module, which hostonly images only pick up if the host uses swap
+    pub fn with_resume(mut self, resume: bool) -> Self {
+        self.resume = resume;
+        self
+    }
+
+    /// Includes the mdraid module and /etc/mdadm.conf, to assemble the root array
+    pub fn with_raid(mut self, raid: bool) -> Self {
+        self.raid = raid;
+        self
+    }
+
+    /// Includes the overlay module and systemd-volatile-root, which mounts the root with it for
+    /// systemd.volatile=overlay
+    pub fn with_volatile_root(mut self, volatile_root: bool) -> Self {
+        self.volatile_root = volatile_root;
+        self
+    }
+
+    pub fn to_config(&self) -> anyhow::Result<String> {
+        let mut output = String::from("# Generated by ALMA\n");
+
+        // Like autodetect for mkinitcpio, hostonly only includes the drivers of the host ALMA runs on
+        writeln!(
+            output,
+            "hostonly=\"{}\"",
+            if self.hostonly { "yes" } else { "no" }
+        )?;
+
+        if self.encrypted {
+            // i18n loads the keymap of vconsole.conf, which the passphrase is typed with
+            output.write_str("add_dracutmodules+=\" crypt i18n \"\n")?;
+        }
+
+        if self.btrfs {
+            output.write_str("add_dracutmodules+=\" btrfs \"\n")?;
+        }
+
+        if self.plymouth {
+            output.write_str("add_dracutmodules+=\" plymouth \"\n")?;
+        }
+
+        if self.resume {
+            output.write_str("add_dracutmodules+=\" resume \"\n")?;
+        }
+
+        if self.raid {
+            output.write_str("add_dracutmodules+=\" mdraid \"\nmdadmconf=\"yes\"\n")?;
+        }
+
+        if self.volatile_root {
+            output.write_str("add_drivers+=\" overlay \"\n")?;
+            output.write_str(
+                "install_items+=\" /usr/lib/systemd/systemd-volatile-root /usr/lib/systemd/system/systemd-volatile-root.service /usr/lib/systemd/system/initrd-root-fs.target.wants/systemd-volatile-root.service \"\n",
+            )?;
+        }
+
+        Ok(output)
+    }
+}
+
+/// Pacman hook regenerating the initramfs images whenever a kernel is installed or upgraded,
+/// since Arch Linux only ships such a hook for mkinitcpio.
+pub fn pacman_hook(scripts_dir: &str) -> String {
+    format!(
+        "[Trigger]
+Type = Path
+Operation = Install
+Operation = Upgrade
+Target = usr/lib/modules/*/vmlinuz
+
+[Trigger]
+Type = Package
+Operation = Install
+Operation = Upgrade
+Target = dracut
+
+[Action]
+Description = Generating dracut initramfs images (ALMA)...
+When = PostTransaction
+Exec = /{scripts_dir}/{SCRIPT_NAME}
+"
+    )
+}
+
+/// Script copying every installed kernel to /boot and generating its default and fallback
+/// images, using the same file names as mkinitcpio so the GRUB configuration is unchanged.
+pub fn install_script() -> &'static str {
+    r#"#!/bin/sh
+set -e
+for dir in /usr/lib/modules/*/; do
+  [ -f "$dir/pkgbase" ] || continue
+  kver="$(basename "$dir")"
+  pkgbase="$(cat "$dir/pkgbase")"
+  install -Dm644 "$dir/vmlinuz" "/boot/vmlinuz-$pkgbase"
+  dracut --force --kver "$kver" "/boot/initramfs-$pkgbase.img"
+  dracut --force --no-hostonly --kver "$kver" "/boot/initramfs-$pkgbase-fallback.img"
+done
+"#
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_not_hostonly_by_default() {
+        let config = Dracut::new(false, false, false, false).to_config().unwrap();
+        assert!(config.contains("hostonly=\"no\"\n"));
+        assert!(!config.contains("add_dracutmodules"));
+    }
+
+    #[test]
+    fn test_encrypted_btrfs_modules() {
+        let config = Dracut::new(true, true, false, true).to_config().unwrap();
+        assert!(config.contains("hostonly=\"yes\"\n"));
+        assert!(config.contains("add_dracutmodules+=\" crypt i18n \"\n"));
+        assert!(config.contains("add_dracutmodules+=\" btrfs \"\n"));
+        assert!(!config.contains("overlay"));
+
+        let config = Dracut::new(false, false, false, false)
+            .with_volatile_root(true)
+            .to_config()
+            .unwrap();
+        assert!(config.contains("add_drivers+=\" overlay \"\n"));
+        assert!(config.contains("/usr/lib/systemd/system/systemd-volatile-root.service"));
+    }
+}
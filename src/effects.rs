@@ -0,0 +1,122 @@
+//! Changes to files of the host and of the new system. In dry runs, each change is printed as a
+//! step of the plan instead, so together with the commands ALMA runs, the output is the complete
+//! plan of the build, in order.
+
+use crate::plan::{self, Action};
+use anyhow::Context;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+pub fn write(path: &Path, contents: impl AsRef<[u8]>, dryrun: bool) -> anyhow::Result<()> {
+    let contents = contents.as_ref();
+    if dryrun {
+        plan::print(Action::write(path, contents, false));
+        return Ok(());
+    }
+    fs::write(path, contents).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Appends to the file, creating it if needed
+pub fn append(path: &Path, contents: impl AsRef<[u8]>, dryrun: bool) -> anyhow::Result<()> {
+    let contents = contents.as_ref();
+    if dryrun {
+        plan::print(Action::write(path, contents, true));
+        return Ok(());
+    }
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(contents))
+        .with_context(|| format!("Failed to append to {}", path.display()))
+}
+
+pub fn copy(from: &Path, to: &Path, dryrun: bool) -> anyhow::Result<()> {
+    if dryrun {
+        plan::print(Action::Copy {
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+            recursive: false,
+        });
+        return Ok(());
+    }
+    fs::copy(from, to)
+        .map(|_| ())
+        .with_context(|| format!("Failed to copy {} to {}", from.display(), to.display()))
+}
+
+/// Copies the directory `from` into the directory `into`, replacing files which already exist
+/// there with `overwrite`
+pub fn copy_dir(from: &Path, into: &Path, overwrite: bool, dryrun: bool) -> anyhow::Result<()> {
+    if dryrun {
+        plan::print(Action::Copy {
+            from: from.to_path_buf(),
+            to: into.to_path_buf(),
+            recursive: true,
+        });
+        return Ok(());
+    }
+    let mut options = fs_extra::dir::CopyOptions::new();
+    options.overwrite = overwrite;
+    fs_extra::dir::copy(from, into, &options)
+        .map(|_| ())
+        .with_context(|| format!("Failed to copy {} to {}", from.display(), into.display()))
+}
+
+pub fn create_dir_all(path: &Path, dryrun: bool) -> anyhow::Result<()> {
+    if dryrun {
+        plan::print(Action::Mkdir {
+            path: path.to_path_buf(),
+        });
+        return Ok(());
+    }
+    fs::create_dir_all(path).with_context(|| format!("Failed to create {}", path.display()))
+}
+
+pub fn remove_file(path: &Path, dryrun: bool) -> anyhow::Result<()> {
+    if dryrun {
+        plan::print(Action::Remove {
+            path: path.to_path_buf(),
+            recursive: false,
+        });
+        return Ok(());
+    }
+    fs::remove_file(path).with_context(|| format!("Failed to remove {}", path.display()))
+}
+
+/// Removes an empty directory
+pub fn remove_dir(path: &Path, dryrun: bool) -> anyhow::Result<()> {
+    if dryrun {
+        plan::print(Action::Rmdir {
+            path: path.to_path_buf(),
+        });
+        return Ok(());
+    }
+    fs::remove_dir(path).with_context(|| format!("Failed to remove {}", path.display()))
+}
+
+pub fn rename(from: &Path, to: &Path, dryrun: bool) -> anyhow::Result<()> {
+    if dryrun {
+        plan::print(Action::Rename {
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+        });
+        return Ok(());
+    }
+    fs::rename(from, to)
+        .with_context(|| format!("Failed to move {} to {}", from.display(), to.display()))
+}
+
+pub fn set_mode(path: &Path, mode: u32, dryrun: bool) -> anyhow::Result<()> {
+    if dryrun {
+        plan::print(Action::Chmod {
+            path: path.to_path_buf(),
+            mode: format!("{mode:o}"),
+        });
+        return Ok(());
+    }
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+        .with_context(|| format!("Failed to set the permissions of {}", path.display()))
+}
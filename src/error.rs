@@ -0,0 +1,129 @@
+use std::error::Error as StdError;
+use std::fmt;
+
+/// Categories of failures, each with its own process exit code so scripts can branch on them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Invalid arguments or configuration
+    Usage,
+    /// The target device failed a safety check (non-removable, mounted, too small, ...)
+    DeviceSafety,
+    /// A required host tool is missing or too old
+    Tooling,
+    /// Downloading presets, cloning repositories or reaching mirrors failed
+    Network,
+    /// A command run inside the target chroot failed
+    Chroot,
+    /// Installing or configuring the bootloader failed
+    Bootloader,
+    /// The user declined a confirmation prompt
+    Aborted,
+}
+
+impl ErrorKind {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ErrorKind::Usage => 2,
+            ErrorKind::DeviceSafety => 10,
+            ErrorKind::Tooling => 11,
+            ErrorKind::Network => 12,
+            ErrorKind::Chroot => 13,
+            ErrorKind::Bootloader => 14,
+            ErrorKind::Aborted => 15,
+        }
+    }
+
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            ErrorKind::Usage => Some("Run 'alma help <command>' to see the available options."),
+            ErrorKind::DeviceSafety => Some(
+                "Double-check the target device path. Only use --allow-non-removable if you are sure it is the right disk.",
+            ),
+            ErrorKind::Tooling => {
+                Some("Run 'alma doctor' to see which host packages are missing or outdated.")
+            }
+            ErrorKind::Network => Some(
+                "Check your network connection and proxy settings, and that the preset and mirror URLs are correct.",
+            ),
+            ErrorKind::Chroot => Some(
+                "Re-run with -v for the full command output, or inspect the target with 'alma chroot'.",
+            ),
+            ErrorKind::Bootloader => Some(
+                "Check that the boot partition is large enough and that grub and efibootmgr were installed in the image.",
+            ),
+            ErrorKind::Aborted => None,
+        }
+    }
+}
+
+/// Wraps an error with its kind. It displays exactly like the wrapped error, so attaching
+/// a kind does not change the error messages shown to the user.
+#[derive(Debug)]
+struct KindError {
+    kind: ErrorKind,
+    inner: anyhow::Error,
+}
+
+impl fmt::Display for KindError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.inner, f)
+    }
+}
+
+impl StdError for KindError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        AsRef::<dyn StdError>::as_ref(&self.inner).source()
+    }
+}
+
+/// Returns the kind of an error, looking through any context added after the kind was attached
+pub fn kind_of(error: &anyhow::Error) -> Option<ErrorKind> {
+    error.downcast_ref::<KindError>().map(|e| e.kind)
+}
+
+pub trait ErrorKindExt<T> {
+    /// Attaches an error kind, unless the error already has one
+    fn kind(self, kind: ErrorKind) -> anyhow::Result<T>;
+}
+
+impl<T, E: Into<anyhow::Error>> ErrorKindExt<T> for Result<T, E> {
+    fn kind(self, kind: ErrorKind) -> anyhow::Result<T> {
+        self.map_err(|e| {
+            let inner = e.into();
+            if kind_of(&inner).is_some() {
+                inner
+            } else {
+                anyhow::Error::new(KindError { kind, inner })
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{Context, anyhow};
+
+    #[test]
+    fn test_kind_survives_context() {
+        let result: anyhow::Result<()> = Err(anyhow!("Cannot find sgdisk"));
+        let err = result
+            .kind(ErrorKind::Tooling)
+            .context("Partitioning error")
+            .unwrap_err();
+
+        assert_eq!(kind_of(&err), Some(ErrorKind::Tooling));
+        let messages: Vec<String> = err.chain().map(|e| e.to_string()).collect();
+        assert_eq!(messages, vec!["Partitioning error", "Cannot find sgdisk"]);
+    }
+
+    #[test]
+    fn test_innermost_kind_wins() {
+        let result: anyhow::Result<()> = Err(anyhow!("Bad exit code"));
+        let err = result
+            .kind(ErrorKind::Chroot)
+            .kind(ErrorKind::Bootloader)
+            .unwrap_err();
+        assert_eq!(kind_of(&err), Some(ErrorKind::Chroot));
+    }
+}
@@ -0,0 +1,82 @@
+//! `--fail-at`: a hidden developer flag which makes `alma create` fail at a chosen point, so
+//! `alma cleanup`, resuming with `--only`/`--skip` and the recovery prompts can be exercised
+//! deterministically in tests.
+
+use anyhow::anyhow;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set once the failure has been injected, so retrying the step from the recovery prompt succeeds
+static INJECTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailPoint {
+    /// Once the partitions and filesystems are created, before they are mounted
+    PostPartition,
+    /// Once pacstrap has installed the packages, before the bootstrap phase configures them
+    MidPacstrap,
+    /// Instead of the given preset script, counted from 1 as in the progress messages
+    Preset(usize),
+}
+
+impl FromStr for FailPoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "post-partition" => Ok(FailPoint::PostPartition),
+            "mid-pacstrap" => Ok(FailPoint::MidPacstrap),
+            _ => s
+                .strip_prefix("preset-")
+                .and_then(|n| n.parse().ok())
+                .filter(|&n| n > 0)
+                .map(FailPoint::Preset)
+                .ok_or_else(|| {
+                    format!(
+                        "invalid failure point '{s}', expected post-partition, mid-pacstrap or preset-N"
+                    )
+                }),
+        }
+    }
+}
+
+impl fmt::Display for FailPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FailPoint::PostPartition => write!(f, "post-partition"),
+            FailPoint::MidPacstrap => write!(f, "mid-pacstrap"),
+            FailPoint::Preset(n) => write!(f, "preset-{n}"),
+        }
+    }
+}
+
+/// Fails the first time `point` is reached, if it is the one given to --fail-at
+pub fn inject(fail_at: Option<FailPoint>, point: FailPoint) -> anyhow::Result<()> {
+    if fail_at == Some(point) && !INJECTED.swap(true, Ordering::SeqCst) {
+        return Err(anyhow!("Injected failure at {point} (--fail-at)"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("mid-pacstrap".parse(), Ok(FailPoint::MidPacstrap));
+        assert_eq!("preset-2".parse(), Ok(FailPoint::Preset(2)));
+        assert!("preset-0".parse::<FailPoint>().is_err());
+        assert!("bootloader".parse::<FailPoint>().is_err());
+        assert_eq!(FailPoint::Preset(3).to_string(), "preset-3");
+    }
+
+    #[test]
+    fn test_inject_once() {
+        inject(None, FailPoint::PostPartition).unwrap();
+        inject(Some(FailPoint::Preset(1)), FailPoint::Preset(2)).unwrap();
+        assert!(inject(Some(FailPoint::Preset(1)), FailPoint::Preset(1)).is_err());
+        inject(Some(FailPoint::Preset(1)), FailPoint::Preset(1)).unwrap();
+    }
+}
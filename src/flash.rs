@@ -0,0 +1,149 @@
+//! `alma flash`: writes an image file to a block device, then reads the device back and compares
+//! its checksum with the image's, in place of `dd` followed by `cmp`.
+
+use crate::args::FlashCommand;
+use crate::error::{ErrorKind, ErrorKindExt};
+use crate::storage::{BlockDevice, StorageDevice};
+use anyhow::{Context, anyhow};
+use byte_unit::Byte;
+use dialoguer::{Confirm, theme::ColorfulTheme};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::info;
+use nix::fcntl::{PosixFadviseAdvice, posix_fadvise};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+
+/// Size of the reads and writes, large enough for the device to sustain its sequential speed
+const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+pub fn flash(command: FlashCommand) -> anyhow::Result<()> {
+    let image_size = fs::metadata(&command.image)
+        .with_context(|| format!("Cannot access {}", command.image.display()))
+        .kind(ErrorKind::Usage)?
+        .len();
+    let mut device = StorageDevice::from_path(&command.device, command.allow_non_removable, false)?;
+    if Byte::from_u64(image_size) > device.size() {
+        return Err(anyhow!(
+            "{device} ({}) is smaller than the image ({})",
+            device.size(),
+            Byte::from_u64(image_size)
+        ))
+        .kind(ErrorKind::DeviceSafety);
+    }
+    if !command.noconfirm {
+        let confirmed = Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt(format!("All data on {device} will be erased. Continue?"))
+            .default(false)
+            .interact()?;
+        if !confirmed {
+            return Err(anyhow!("User aborted operation.")).kind(ErrorKind::Aborted);
+        }
+    }
+    device.umount_if_needed();
+
+    let mut image = File::open(&command.image)
+        .with_context(|| format!("Failed to open {}", command.image.display()))?;
+    let mut target = OpenOptions::new()
+        .write(true)
+        .open(device.path())
+        .with_context(|| format!("Failed to open {device} for writing"))?;
+
+    info!("Writing {} to {device}", command.image.display());
+    let progress = progress_bar(image_size, "Writing");
+    let image_checksum = copy_hashed(&mut image, Some(&mut target), &progress)
+        .with_context(|| format!("Failed to write {} to {device}", command.image.display()))?;
+    progress.set_message("Syncing");
+    target
+        .sync_all()
+        .with_context(|| format!("Failed to flush the writes to {device}"))?;
+    progress.finish_and_clear();
+
+    if command.no_verify {
+        info!("Wrote {device}, sha256 {image_checksum}");
+        return Ok(());
+    }
+
+    // Drops the written pages from the page cache, so they are read back from the device itself
+    posix_fadvise(&target, 0, 0, PosixFadviseAdvice::POSIX_FADV_DONTNEED)
+        .with_context(|| format!("Failed to drop the cached pages of {device}"))?;
+    drop(target);
+
+    info!("Verifying {device}");
+    let mut source = File::open(device.path())
+        .with_context(|| format!("Failed to open {device} for reading"))?
+        .take(image_size);
+    let progress = progress_bar(image_size, "Verifying");
+    let device_checksum = copy_hashed(&mut source, None, &progress)
+        .with_context(|| format!("Failed to read {device}"))?;
+    progress.finish_and_clear();
+
+    if device_checksum != image_checksum {
+        return Err(anyhow!(
+            "Verification failed: {device} reads back with sha256 {device_checksum}, the image has {image_checksum}. The device may be failing or counterfeit"
+        ))
+        .kind(ErrorKind::DeviceSafety);
+    }
+    info!("Verified {device}, sha256 {image_checksum}");
+    Ok(())
+}
+
+/// Reads `source` to the end, writing it to `target` if given, and returns its SHA-256 in hex
+fn copy_hashed(
+    source: &mut dyn Read,
+    mut target: Option<&mut dyn Write>,
+    progress: &ProgressBar,
+) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let read = source.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        if let Some(target) = target.as_mut() {
+            target.write_all(&buffer[..read])?;
+        }
+        progress.inc(read as u64);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn progress_bar(length: u64, message: &'static str) -> ProgressBar {
+    ProgressBar::new(length)
+        .with_style(
+            ProgressStyle::with_template(
+                "{msg} [{bar:40}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
+            )
+            .expect("Invalid progress bar template")
+            .progress_chars("=> "),
+        )
+        .with_message(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_copy_hashed() {
+        let data = vec![0xa5; CHUNK_SIZE + 1];
+        let mut target = Vec::new();
+        let checksum = copy_hashed(
+            &mut data.as_slice(),
+            Some(&mut target),
+            &ProgressBar::hidden(),
+        )
+        .unwrap();
+        assert_eq!(target, data);
+        assert_eq!(
+            copy_hashed(&mut data.as_slice(), None, &ProgressBar::hidden()).unwrap(),
+            checksum
+        );
+        assert_eq!(
+            copy_hashed(&mut b"".as_slice(), None, &ProgressBar::hidden()).unwrap(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+}
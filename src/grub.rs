@@ -0,0 +1,403 @@
+//! Helpers for editing /etc/default/grub, which is a shell fragment of KEY=value assignments,
+//! and for reading the generated grub.cfg.
+
+use crate::arch::Arch;
+use crate::args::GrubTargets;
+use anyhow::Context;
+use sha2::Sha512;
+use std::fmt::Write;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// Environment variable the --grub-password is read from instead of prompting for it
+pub const PASSWORD_ENV: &str = "ALMA_GRUB_PASSWORD";
+
+/// Script run by grub-mkconfig before the menu entries, which sets the superuser of
+/// --grub-password
+pub const PASSWORD_SCRIPT: &str = "etc/grub.d/01_alma_password";
+
+/// Script generating the Linux menu entries, whose entries are marked as unrestricted
+pub const LINUX_SCRIPT: &str = "etc/grub.d/10_linux";
+
+/// Iterations of PBKDF2, the default of grub-mkpasswd-pbkdf2
+const PBKDF2_ROUNDS: u32 = 10000;
+
+/// Sets `key` to `value` (double quoted), replacing an existing (possibly commented out)
+/// assignment or appending a new one.
+pub fn set_option(conf: &mut String, key: &str, value: &str) {
+    let line = format!("{key}=\"{value}\"");
+    let mut replaced = false;
+    let lines: Vec<String> = conf
+        .lines()
+        .filter_map(|l| {
+            if is_assignment(l, key) {
+                if replaced {
+                    // Drop duplicate assignments, the last one would win anyway
+                    return None;
+                }
+                replaced = true;
+                Some(line.clone())
+            } else {
+                Some(l.to_string())
+            }
+        })
+        .collect();
+
+    *conf = lines.join("\n");
+    if !replaced {
+        if !conf.is_empty() && !conf.ends_with('\n') {
+            conf.push('\n');
+        }
+        conf.push_str(&line);
+    }
+    conf.push('\n');
+}
+
+/// Returns the unquoted value of `key`, if it is set (and not commented out).
+pub fn get_option(conf: &str, key: &str) -> Option<String> {
+    conf.lines()
+        .filter(|l| !l.trim_start().starts_with('#'))
+        .filter_map(|l| l.trim().strip_prefix(key)?.strip_prefix('='))
+        .next_back()
+        .map(|v| v.trim_matches(|c| c == '"' || c == '\'').to_string())
+}
+
+/// Appends kernel parameters to a GRUB_CMDLINE_* option, keeping existing parameters. Parameters
+/// which are already there are skipped, so a retried bootloader step does not repeat them.
+pub fn append_cmdline(conf: &mut String, key: &str, params: &str) {
+    let existing = get_option(conf, key).unwrap_or_default();
+    let mut merged: Vec<&str> = existing.split_whitespace().collect();
+    for param in params.split_whitespace() {
+        if !merged.contains(&param) {
+            merged.push(param);
+        }
+    }
+    set_option(conf, key, &merged.join(" "));
+}
+
+/// The kernel, initramfs images and command line of a grub.cfg menu entry. Paths are relative
+/// to the boot partition.
+#[derive(Debug, PartialEq, Eq)]
+pub struct BootEntry {
+    pub kernel: String,
+    pub initrds: Vec<String>,
+    pub cmdline: String,
+}
+
+/// Returns the first (default) menu entry of a grub.cfg generated by grub-mkconfig
+pub fn default_entry(grub_cfg: &str) -> Option<BootEntry> {
+    let mut lines = grub_cfg
+        .lines()
+        .map(str::trim)
+        .skip_while(|l| !l.starts_with("menuentry "));
+    lines.next()?;
+
+    let mut kernel = None;
+    let mut cmdline = String::new();
+    let mut initrds = Vec::new();
+    for line in lines {
+        if line == "}" {
+            break;
+        }
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("linux") => {
+                kernel = words.next().map(str::to_owned);
+                cmdline = words.collect::<Vec<_>>().join(" ");
+            }
+            Some("initrd") => initrds.extend(words.map(str::to_owned)),
+            _ => (),
+        }
+    }
+
+    Some(BootEntry {
+        kernel: kernel?,
+        initrds,
+        cmdline,
+    })
+}
+
+/// Replaces `root=` parameters naming a device path with `root_spec` (e.g. `UUID=...`).
+/// grub-mkconfig falls back to the path of the root device when udev has not yet linked it in
+/// /dev/disk/by-uuid, and that path only exists on the build host.
+pub fn pin_root_device(grub_cfg: &str, root_spec: &str) -> String {
+    let mut pinned = String::with_capacity(grub_cfg.len());
+    for line in grub_cfg.split_inclusive('\n') {
+        if !line.trim_start().starts_with("linux") {
+            pinned.push_str(line);
+            continue;
+        }
+        let mut rest = line;
+        while let Some(index) = rest.find("root=/dev/") {
+            // Only whole parameters, not e.g. cryptroot=
+            let starts_parameter = rest[..index].ends_with(char::is_whitespace);
+            pinned.push_str(&rest[..index]);
+            rest = &rest[index..];
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            if starts_parameter {
+                pinned.push_str("root=");
+                pinned.push_str(root_spec);
+            } else {
+                pinned.push_str(&rest[..end]);
+            }
+            rest = &rest[end..];
+        }
+        pinned.push_str(rest);
+    }
+    pinned
+}
+
+/// Hashes a password for password_pbkdf2, in the format of grub-mkpasswd-pbkdf2
+pub fn hash_password(password: &str, salt: &[u8]) -> String {
+    let mut hash = [0; 64];
+    pbkdf2::pbkdf2_hmac::<Sha512>(password.as_bytes(), salt, PBKDF2_ROUNDS, &mut hash);
+    format!(
+        "grub.pbkdf2.sha512.{PBKDF2_ROUNDS}.{}.{}",
+        hex_upper(salt),
+        hex_upper(&hash)
+    )
+}
+
+/// A salt for [hash_password], of the length grub-mkpasswd-pbkdf2 uses
+pub fn random_salt() -> anyhow::Result<[u8; 64]> {
+    let mut salt = [0; 64];
+    File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut salt))
+        .context("Failed to read /dev/urandom")?;
+    Ok(salt)
+}
+
+fn hex_upper(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .fold(String::with_capacity(bytes.len() * 2), |mut hex, byte| {
+            write!(hex, "{byte:02X}").unwrap();
+            hex
+        })
+}
+
+/// The /etc/grub.d script making "root" the only GRUB user. Once a superuser is set, only
+/// unrestricted entries boot without the password, and only superusers may edit them.
+pub fn password_script(hash: &str) -> String {
+    format!(
+        "#!/bin/sh
+# Generated by ALMA for --grub-password
+cat <<'EOF'
+set superusers=\"root\"
+password_pbkdf2 root {hash}
+EOF
+"
+    )
+}
+
+/// Adds --unrestricted to the menu entry options of /etc/grub.d/10_linux, so the entries boot
+/// without the password. Upgrades of grub restore the script, so the pacman hook of
+/// --grub-password edits it again.
+pub fn unrestrict_entries(script: &str) -> String {
+    let mut output: String = script
+        .lines()
+        .map(|line| match line.strip_prefix("CLASS=\"") {
+            Some(rest) if !line.contains("--unrestricted") && rest.ends_with('"') => {
+                format!("CLASS=\"{} --unrestricted\"", &rest[..rest.len() - 1])
+            }
+            _ => line.to_owned(),
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+    if script.ends_with('\n') {
+        output.push('\n');
+    }
+    output
+}
+
+/// The sed expression doing what [unrestrict_entries] does
+const UNRESTRICT_SED: &str = r#"/^CLASS="/{/--unrestricted/!s/"$/ --unrestricted"/}"#;
+
+/// File name of the pacman hook of --grub-password in /etc/pacman.d/hooks
+pub const UNRESTRICT_HOOK: &str = "95-alma-grub-unrestricted.hook";
+
+/// Pacman hook keeping the entries unrestricted when grub is upgraded
+pub fn unrestrict_hook() -> String {
+    format!(
+        "[Trigger]
+Type = Package
+Operation = Install
+Operation = Upgrade
+Target = grub
+
+[Action]
+Description = Keeping the GRUB menu entries bootable without the password (ALMA)...
+When = PostTransaction
+Exec = /usr/bin/sed -i '{UNRESTRICT_SED}' /{LINUX_SCRIPT}
+"
+    )
+}
+
+/// The shell commands installing GRUB on `disk` for `targets`, then generating grub.cfg
+pub fn install_script(targets: GrubTargets, disk: &Path) -> String {
+    let mut commands = Vec::new();
+    if targets.bios() {
+        commands.push(format!(
+            "grub-install --target=i386-pc --boot-directory /boot {}",
+            disk.display()
+        ));
+    }
+    if targets.efi() {
+        commands.push(format!(
+            "grub-install --target={} --efi-directory /boot --boot-directory /boot --removable {}",
+            Arch::target().grub_efi_target(),
+            disk.display()
+        ));
+    }
+    commands.push(String::from("grub-mkconfig -o /boot/grub/grub.cfg"));
+    commands.join(" && ")
+}
+
+fn is_assignment(line: &str, key: &str) -> bool {
+    line.trim_start()
+        .trim_start_matches('#')
+        .trim_start()
+        .strip_prefix(key)
+        .is_some_and(|rest| rest.starts_with('='))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_install_script() {
+        let disk = Path::new("/dev/loop0");
+        let both = install_script(GrubTargets::Both, disk);
+        assert!(both.starts_with("grub-install --target=i386-pc"));
+        let efi_target = format!("--target={}", Arch::target().grub_efi_target());
+        assert!(both.contains(&efi_target));
+        let efi = install_script(GrubTargets::Efi, disk);
+        assert!(!efi.contains("i386-pc"));
+        assert!(efi.ends_with("--removable /dev/loop0 && grub-mkconfig -o /boot/grub/grub.cfg"));
+        assert!(!install_script(GrubTargets::Bios, disk).contains(&efi_target));
+    }
+
+    #[test]
+    fn test_hash_password() {
+        let hash = hash_password("alma", &[0xab; 4]);
+        let fields: Vec<&str> = hash.split('.').collect();
+        assert_eq!(
+            fields[..5],
+            ["grub", "pbkdf2", "sha512", "10000", "ABABABAB"]
+        );
+        assert_eq!(fields[5].len(), 128);
+        assert_eq!(hash, hash_password("alma", &[0xab; 4]));
+        assert_ne!(hash, hash_password("alma", &[0xac; 4]));
+    }
+
+    #[test]
+    fn test_unrestrict_entries() {
+        let script = "prefix=\"/usr\"\nCLASS=\"--class gnu-linux --class gnu --class os\"\n";
+        let unrestricted = unrestrict_entries(script);
+        assert_eq!(
+            unrestricted,
+            "prefix=\"/usr\"\nCLASS=\"--class gnu-linux --class gnu --class os --unrestricted\"\n"
+        );
+        assert_eq!(unrestrict_entries(&unrestricted), unrestricted);
+    }
+
+    const DEFAULT_GRUB: &str = "GRUB_DEFAULT=0
+GRUB_TIMEOUT=5
+GRUB_CMDLINE_LINUX_DEFAULT=\"loglevel=3 quiet\"
+GRUB_CMDLINE_LINUX=\"\"
+#GRUB_DISABLE_OS_PROBER=false
+";
+
+    #[test]
+    fn test_set_option_replaces_existing() {
+        let mut conf = DEFAULT_GRUB.to_string();
+        set_option(&mut conf, "GRUB_TIMEOUT", "1");
+        assert!(conf.contains("GRUB_TIMEOUT=\"1\"\n"));
+        assert!(!conf.contains("GRUB_TIMEOUT=5"));
+        assert_eq!(conf.lines().count(), DEFAULT_GRUB.lines().count());
+    }
+
+    #[test]
+    fn test_set_option_uncomments_and_appends() {
+        let mut conf = DEFAULT_GRUB.to_string();
+        set_option(&mut conf, "GRUB_DISABLE_OS_PROBER", "false");
+        assert!(conf.contains("\nGRUB_DISABLE_OS_PROBER=\"false\"\n"));
+
+        set_option(&mut conf, "GRUB_SAVEDEFAULT", "true");
+        assert!(conf.ends_with("GRUB_SAVEDEFAULT=\"true\"\n"));
+    }
+
+    #[test]
+    fn test_append_cmdline() {
+        let mut conf = DEFAULT_GRUB.to_string();
+        append_cmdline(&mut conf, "GRUB_CMDLINE_LINUX_DEFAULT", "splash");
+        append_cmdline(
+            &mut conf,
+            "GRUB_CMDLINE_LINUX",
+            "cryptdevice=UUID=abc:luks_root",
+        );
+        assert_eq!(
+            get_option(&conf, "GRUB_CMDLINE_LINUX_DEFAULT").as_deref(),
+            Some("loglevel=3 quiet splash")
+        );
+        assert_eq!(
+            get_option(&conf, "GRUB_CMDLINE_LINUX").as_deref(),
+            Some("cryptdevice=UUID=abc:luks_root")
+        );
+
+        append_cmdline(&mut conf, "GRUB_CMDLINE_LINUX_DEFAULT", "splash nomodeset");
+        assert_eq!(
+            get_option(&conf, "GRUB_CMDLINE_LINUX_DEFAULT").as_deref(),
+            Some("loglevel=3 quiet splash nomodeset")
+        );
+    }
+
+    #[test]
+    fn test_default_entry() {
+        let grub_cfg = "### BEGIN /etc/grub.d/10_linux ###
+menuentry 'Arch Linux' --class arch --class gnu-linux $menuentry_id_option 'gnulinux-simple-abc' {
+	load_video
+	set gfxpayload=keep
+	echo	'Loading Linux linux ...'
+	linux	/vmlinuz-linux root=UUID=abc rw  loglevel=3 quiet
+	echo	'Loading initial ramdisk ...'
+	initrd	/intel-ucode.img /initramfs-linux.img
+}
+menuentry 'Arch Linux, fallback' {
+	linux	/vmlinuz-linux root=UUID=abc rw
+	initrd	/initramfs-linux-fallback.img
+}
+";
+        assert_eq!(
+            default_entry(grub_cfg),
+            Some(BootEntry {
+                kernel: String::from("/vmlinuz-linux"),
+                initrds: vec![
+                    String::from("/intel-ucode.img"),
+                    String::from("/initramfs-linux.img")
+                ],
+                cmdline: String::from("root=UUID=abc rw loglevel=3 quiet"),
+            })
+        );
+        assert_eq!(default_entry("set timeout=5\n"), None);
+    }
+
+    #[test]
+    fn test_pin_root_device() {
+        let grub_cfg = "\
+menuentry 'Arch Linux' {
+\tlinux\t/vmlinuz-linux root=/dev/mapper/alma_root_4242 rw cryptroot=/dev/sdb3 quiet
+\tinitrd\t/initramfs-linux.img
+}
+# root=/dev/sdb3 in a comment
+";
+        let pinned = pin_root_device(grub_cfg, "UUID=abc");
+        assert!(
+            pinned.contains("\tlinux\t/vmlinuz-linux root=UUID=abc rw cryptroot=/dev/sdb3 quiet\n")
+        );
+        assert!(pinned.contains("# root=/dev/sdb3 in a comment\n"));
+        assert_eq!(pin_root_device(&pinned, "UUID=abc"), pinned);
+    }
+}
@@ -0,0 +1,177 @@
+//! `alma info`: shows how a system was built. Mounts it read-only like `alma chroot --read-only`
+//! and summarizes the manifest `alma create` wrote to it.
+
+use crate::args::{InfoCommand, Manifest};
+use crate::baked;
+use crate::error::{ErrorKind, ErrorKindExt};
+use crate::install;
+use crate::tool::{MountOptions, with_mounted_system};
+use anyhow::{Context, anyhow};
+use serde::Serialize;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// The parts of the manifest worth showing
+#[derive(Debug, Serialize)]
+struct Summary {
+    alma_version: String,
+    system_variant: String,
+    filesystem: String,
+    encrypted_root: bool,
+    kernels: Vec<String>,
+    /// Where the presets baked into the system came from
+    presets: Vec<String>,
+    original_command: String,
+    baked_sources: Vec<BakedSource>,
+}
+
+/// A source baked into the system, without a size once `alma gc` removed it
+#[derive(Debug, Serialize)]
+struct BakedSource {
+    path: PathBuf,
+    size_bytes: Option<u64>,
+}
+
+impl Summary {
+    fn new(manifest: &Manifest, mount_path: &Path) -> Self {
+        let baked_sources = baked::source_sizes(mount_path, &manifest.sources)
+            .into_iter()
+            .map(|(path, size_bytes)| BakedSource { path, size_bytes })
+            .collect();
+        Self {
+            baked_sources,
+            ..Self::from(manifest)
+        }
+    }
+}
+
+impl From<&Manifest> for Summary {
+    fn from(manifest: &Manifest) -> Self {
+        Self {
+            alma_version: manifest.alma_version.clone(),
+            system_variant: manifest.system_variant.to_string(),
+            filesystem: manifest.filesystem.to_string(),
+            encrypted_root: manifest.encrypted_root,
+            kernels: manifest.kernels.iter().map(|k| k.to_string()).collect(),
+            presets: manifest
+                .sources
+                .iter()
+                .filter(|source| source.r#type == "preset")
+                .map(|source| source.origin.clone())
+                .collect(),
+            original_command: manifest.original_command.clone(),
+            baked_sources: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "ALMA version:  {}", self.alma_version)?;
+        writeln!(f, "Variant:       {}", self.system_variant)?;
+        writeln!(f, "Filesystem:    {}", self.filesystem)?;
+        writeln!(
+            f,
+            "Encryption:    {}",
+            if self.encrypted_root { "LUKS" } else { "none" }
+        )?;
+        writeln!(f, "Kernels:       {}", self.kernels.join(", "))?;
+        if self.presets.is_empty() {
+            writeln!(f, "Presets:       none")?;
+        } else {
+            for (i, preset) in self.presets.iter().enumerate() {
+                let label = if i == 0 { "Presets:" } else { "" };
+                writeln!(f, "{label:<15}{preset}")?;
+            }
+        }
+        for (i, source) in self.baked_sources.iter().enumerate() {
+            let size = source
+                .size_bytes
+                .map_or_else(|| String::from("removed"), baked::human_size);
+            let label = if i == 0 { "Baked:" } else { "" };
+            writeln!(f, "{label:<15}{} ({size})", source.path.display())?;
+        }
+        write!(f, "Command:       {}", self.original_command)
+    }
+}
+
+pub fn info(command: InfoCommand) -> anyhow::Result<()> {
+    let options = MountOptions {
+        allow_non_removable: command.allow_non_removable,
+        read_only: true,
+        ..MountOptions::default()
+    };
+
+    let mut summary = None;
+    with_mounted_system(&command.block_device, &options, |system| {
+        let mount_path = system.mount_point.path();
+        summary =
+            install::read_manifest(mount_path).map(|manifest| Summary::new(&manifest, mount_path));
+        Ok(())
+    })?;
+    let summary = summary
+        .ok_or_else(|| {
+            anyhow!(
+                "{} has no readable ALMA manifest at {}, it was not built by alma create",
+                command.block_device.display(),
+                install::MANIFEST_PATH
+            )
+        })
+        .kind(ErrorKind::Usage)?;
+
+    if command.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&summary).context("Failed to serialize the summary")?
+        );
+    } else {
+        println!("{summary}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let manifest: Manifest = serde_json::from_str(
+            r#"{"alma_version": "0.9.0", "system_variant": "arch", "filesystem": "btrfs",
+                "encrypted_root": true, "kernels": ["linux", "linux-lts"], "aur_helper": "paru",
+                "original_command": "alma create --filesystem btrfs --encrypted-root /dev/sdb",
+                "sources": [
+                    {"type": "preset", "origin": "presets/user.toml", "baked_path": "/usr/share/alma/presets/0"},
+                    {"type": "preset", "origin": "https://example.com/dev.toml", "baked_path": "/usr/share/alma/presets/1"},
+                    {"type": "system", "origin": "https://github.com/basecamp/omarchy", "baked_path": "/usr/share/omarchy"}
+                ]}"#,
+        )
+        .unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let preset = root.path().join("usr/share/alma/presets/0");
+        std::fs::create_dir_all(&preset).unwrap();
+        std::fs::write(preset.join("preset.toml"), [b'#'; 2048]).unwrap();
+        let summary = Summary::new(&manifest, root.path());
+        assert_eq!(
+            summary.presets,
+            ["presets/user.toml", "https://example.com/dev.toml"]
+        );
+        assert_eq!(
+            summary.to_string(),
+            "ALMA version:  0.9.0
+Variant:       arch
+Filesystem:    btrfs
+Encryption:    LUKS
+Kernels:       linux, linux-lts
+Presets:       presets/user.toml
+               https://example.com/dev.toml
+Baked:         /usr/share/alma/presets/0 (2.0 KiB)
+               /usr/share/alma/presets/1 (removed)
+               /usr/share/omarchy (removed)
+Command:       alma create --filesystem btrfs --encrypted-root /dev/sdb"
+        );
+        let json: serde_json::Value = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json["filesystem"], "btrfs");
+        assert_eq!(json["encrypted_root"], true);
+    }
+}
@@ -1,37 +1,370 @@
-use std::fmt::Write;
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Write};
+use std::str::FromStr;
+
+const COMPRESSION_ALGORITHMS: [&str; 7] = ["zstd", "lz4", "gzip", "xz", "lzma", "bzip2", "lzop"];
+
+/// An mkinitcpio compressor, optionally with a compression level, written as "zstd" or "zstd:19"
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Compression {
+    algorithm: String,
+    level: Option<u32>,
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (algorithm, level) = match s.split_once(':') {
+            Some((algorithm, level)) => (
+                algorithm,
+                Some(
+                    level
+                        .parse()
+                        .map_err(|_| anyhow!("Invalid compression level: {level}"))?,
+                ),
+            ),
+            None => (s, None),
+        };
+
+        if !COMPRESSION_ALGORITHMS.contains(&algorithm) {
+            return Err(anyhow!(
+                "Unsupported compression: {algorithm}. Supported: {}",
+                COMPRESSION_ALGORITHMS.join(", ")
+            ));
+        }
+
+        Ok(Self {
+            algorithm: algorithm.to_string(),
+            level,
+        })
+    }
+}
+
+impl TryFrom<String> for Compression {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> anyhow::Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<Compression> for String {
+    fn from(c: Compression) -> Self {
+        c.to_string()
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.level {
+            Some(level) => write!(f, "{}:{level}", self.algorithm),
+            None => write!(f, "{}", self.algorithm),
+        }
+    }
+}
+
+/// Additional mkinitcpio.conf entries from presets and the command line, merged into the
+/// defaults generated by ALMA
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InitcpioOptions {
+    pub modules: Vec<String>,
+    pub binaries: Vec<String>,
+    pub files: Vec<String>,
+    /// Inserted after the block device hooks and before filesystems
+    pub hooks: Vec<String>,
+    pub compression: Option<Compression>,
+}
+
+impl InitcpioOptions {
+    /// Adds the entries of `other`, whose compression takes precedence if set
+    pub fn merge(&mut self, other: &InitcpioOptions) {
+        for (ours, theirs) in [
+            (&mut self.modules, &other.modules),
+            (&mut self.binaries, &other.binaries),
+            (&mut self.files, &other.files),
+            (&mut self.hooks, &other.hooks),
+        ] {
+            for entry in theirs {
+                if !ours.contains(entry) {
+                    ours.push(entry.clone());
+                }
+            }
+        }
+
+        if other.compression.is_some() {
+            self.compression = other.compression.clone();
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
 
 pub struct Initcpio {
     encrypted: bool,
     plymouth: bool,
+    autodetect: bool,
+    systemd: bool,
+    resume: bool,
+    raid: bool,
+    volatile_root: bool,
+    options: InitcpioOptions,
 }
 
 impl Initcpio {
-    pub fn new(encrypted: bool, plymouth: bool) -> Self {
+    pub fn new(encrypted: bool, plymouth: bool, autodetect: bool) -> Self {
         Self {
             encrypted,
             plymouth,
+            autodetect,
+            systemd: false,
+            resume: false,
+            raid: false,
+            volatile_root: false,
+            options: InitcpioOptions::default(),
         }
     }
 
+    /// Uses the systemd based hooks (systemd, sd-vconsole, sd-encrypt) instead of the busybox
+    /// based ones. These are required for unlocking the root with a TPM or FIDO2 token.
+    pub fn with_systemd(mut self, systemd: bool) -> Self {
+        self.systemd = systemd;
+        self
+    }
+
+    /// Resumes from hibernation. The systemd hook does this on its own, so this only adds the
+    /// resume hook to the busybox based hooks.
+    pub fn with_resume(mut self, resume: bool) -> Self {
+        self.resume = resume;
+        self
+    }
+
+    /// Assembles mdadm RAID arrays, which the root (or its LUKS container) is on
+    pub fn with_raid(mut self, raid: bool) -> Self {
+        self.raid = raid;
+        self
+    }
+
+    /// Includes the overlay module, which systemd.volatile=overlay mounts the root with
+    pub fn with_volatile_root(mut self, volatile_root: bool) -> Self {
+        self.volatile_root = volatile_root;
+        self
+    }
+
+    pub fn with_options(mut self, options: InitcpioOptions) -> Self {
+        self.options = options;
+        self
+    }
+
     pub fn to_config(&self) -> anyhow::Result<String> {
-        // Note we do not use autodetect as for USB drives we will boot on different hardware than the image was built on!
-        let mut output = String::from(
-            "MODULES=()
-BINARIES=()
-FILES=()
-HOOKS=(base udev keyboard microcode modconf keymap consolefont block ",
-        );
+        let mut output = String::new();
+        let mut modules = self.options.modules.clone();
+        if self.volatile_root && !modules.iter().any(|m| m == "overlay") {
+            modules.push(String::from("overlay"));
+        }
+        writeln!(output, "MODULES=({})", modules.join(" "))?;
+        writeln!(output, "BINARIES=({})", self.options.binaries.join(" "))?;
+        writeln!(output, "FILES=({})", self.options.files.join(" "))?;
+        output.write_str(if self.systemd {
+            "HOOKS=(base systemd "
+        } else {
+            "HOOKS=(base udev "
+        })?;
 
-        if self.encrypted {
-            output.write_str("encrypt ")?;
+        // By default we do not use autodetect as for USB drives we will boot on different hardware than the image was built on!
+        if self.autodetect {
+            output.write_str("autodetect ")?;
         }
 
-        if self.plymouth {
-            output.write_str("kms plymouth")?;
+        if self.systemd {
+            output.write_str("microcode modconf kms keyboard sd-vconsole block ")?;
+
+            if self.raid {
+                output.write_str("mdadm_udev ")?;
+            }
+
+            // Plymouth has to come before sd-encrypt to show the passphrase prompt
+            if self.plymouth {
+                output.write_str("plymouth ")?;
+            }
+
+            if self.encrypted {
+                output.write_str("sd-encrypt ")?;
+            }
+        } else {
+            output.write_str("keyboard microcode modconf keymap consolefont block ")?;
+
+            // The array has to be assembled before the container on it can be unlocked
+            if self.raid {
+                output.write_str("mdadm_udev ")?;
+            }
+
+            if self.encrypted {
+                output.write_str("encrypt ")?;
+            }
+
+            if self.plymouth {
+                output.write_str("kms plymouth ")?;
+            }
+
+            if self.resume {
+                output.write_str("resume ")?;
+            }
+        }
+
+        for hook in &self.options.hooks {
+            write!(output, "{hook} ")?;
         }
 
         output.write_str("filesystems fsck)\n")?;
 
+        if let Some(compression) = &self.options.compression {
+            writeln!(output, "COMPRESSION=\"{}\"", compression.algorithm)?;
+            if let Some(level) = compression.level {
+                writeln!(output, "COMPRESSION_OPTIONS=(-{level})")?;
+            }
+        }
+
         Ok(output)
     }
 }
+
+/// Generates /etc/crypttab.initramfs, which sd-encrypt uses to unlock the root partition
+pub fn crypttab(luks_uuid: &str) -> String {
+    format!("luks_root UUID={luks_uuid} none luks\n")
+}
+
+/// Generates the mkinitcpio preset for a kernel package, always including a fallback image
+/// built without autodetect so the system still boots if the default image is broken.
+pub fn preset(kernel: &str) -> String {
+    format!(
+        "# mkinitcpio preset file for the '{kernel}' package, generated by ALMA
+
+ALL_kver=\"/boot/vmlinuz-{kernel}\"
+
+PRESETS=('default' 'fallback')
+
+default_image=\"/boot/initramfs-{kernel}.img\"
+
+fallback_image=\"/boot/initramfs-{kernel}-fallback.img\"
+fallback_options=\"-S autodetect\"
+"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_autodetect_by_default() {
+        let config = Initcpio::new(false, false, false).to_config().unwrap();
+        assert!(config.contains(
+            "HOOKS=(base udev keyboard microcode modconf keymap consolefont block filesystems fsck)"
+        ));
+    }
+
+    #[test]
+    fn test_autodetect_and_encrypt() {
+        let config = Initcpio::new(true, false, true).to_config().unwrap();
+        assert!(config.contains(
+            "HOOKS=(base udev autodetect keyboard microcode modconf keymap consolefont block encrypt filesystems fsck)"
+        ));
+    }
+
+    #[test]
+    fn test_systemd_hooks() {
+        let config = Initcpio::new(true, true, false)
+            .with_systemd(true)
+            .to_config()
+            .unwrap();
+        assert!(config.contains(
+            "HOOKS=(base systemd microcode modconf kms keyboard sd-vconsole block plymouth sd-encrypt filesystems fsck)"
+        ));
+    }
+
+    #[test]
+    fn test_raid_hook() {
+        let config = Initcpio::new(true, false, false)
+            .with_raid(true)
+            .to_config()
+            .unwrap();
+        assert!(config.contains("block mdadm_udev encrypt filesystems fsck)"));
+    }
+
+    #[test]
+    fn test_volatile_root_module() {
+        let config = Initcpio::new(false, false, false)
+            .with_systemd(true)
+            .with_volatile_root(true)
+            .to_config()
+            .unwrap();
+        assert!(config.starts_with("MODULES=(overlay)\n"));
+    }
+
+    #[test]
+    fn test_resume_hook() {
+        let config = Initcpio::new(true, false, false)
+            .with_resume(true)
+            .to_config()
+            .unwrap();
+        assert!(config.contains("block encrypt resume filesystems fsck)"));
+
+        let config = Initcpio::new(true, false, false)
+            .with_systemd(true)
+            .with_resume(true)
+            .to_config()
+            .unwrap();
+        assert!(!config.contains("resume"));
+    }
+
+    #[test]
+    fn test_plymouth_hooks() {
+        let config = Initcpio::new(false, true, false).to_config().unwrap();
+        assert!(config.contains("block kms plymouth filesystems fsck)"));
+    }
+
+    #[test]
+    fn test_options_merged_into_defaults() {
+        let mut options = InitcpioOptions {
+            modules: vec!["i915".to_string()],
+            hooks: vec!["lvm2".to_string()],
+            compression: Some("zstd:19".parse().unwrap()),
+            ..Default::default()
+        };
+        options.merge(&InitcpioOptions {
+            modules: vec!["i915".to_string(), "nvme".to_string()],
+            compression: Some("lz4".parse().unwrap()),
+            ..Default::default()
+        });
+
+        let config = Initcpio::new(true, false, false)
+            .with_options(options)
+            .to_config()
+            .unwrap();
+        assert!(config.starts_with("MODULES=(i915 nvme)\nBINARIES=()\nFILES=()\n"));
+        assert!(config.contains("block encrypt lvm2 filesystems fsck)"));
+        assert!(config.contains("COMPRESSION=\"lz4\"\n"));
+        assert!(!config.contains("COMPRESSION_OPTIONS"));
+    }
+
+    #[test]
+    fn test_compression_parsing() {
+        let compression: Compression = "zstd:19".parse().unwrap();
+        assert_eq!(compression.to_string(), "zstd:19");
+        assert!("zstd:high".parse::<Compression>().is_err());
+        assert!("brotli".parse::<Compression>().is_err());
+    }
+
+    #[test]
+    fn test_preset_has_fallback() {
+        let preset = preset("linux-lts");
+        assert!(preset.contains("PRESETS=('default' 'fallback')"));
+        assert!(preset.contains("fallback_image=\"/boot/initramfs-linux-lts-fallback.img\""));
+    }
+}
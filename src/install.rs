@@ -1,9 +1,14 @@
-use crate::args::{CreateCommand, InstallCommand, Manifest};
+use crate::args::{CreateCommand, InstallCommand, Manifest, PlanFormat, Preallocation};
 use crate::create;
+use crate::error::{ErrorKind, ErrorKindExt};
+use crate::interactive::UserSettings;
 use crate::process::CommandExt;
+use crate::seed;
+use crate::state;
 use crate::storage::{self, BlockDevice, MountStack};
 use crate::tool::Tool;
 use anyhow::anyhow;
+use byte_unit::Byte;
 use console::style;
 use dialoguer::{Confirm, Select, theme::ColorfulTheme};
 use log::{info, warn};
@@ -11,7 +16,13 @@ use nix::mount::MsFlags;
 use std::fs;
 use std::path::{Path, PathBuf};
 
-const MANIFEST_PATH: &str = "/usr/share/alma/manifest.json";
+pub const MANIFEST_PATH: &str = "/usr/share/alma/manifest.json";
+
+/// The manifest of the system mounted at `mount_path`, if it was built by ALMA
+pub fn read_manifest(mount_path: &Path) -> Option<Manifest> {
+    let json = fs::read_to_string(mount_path.join(MANIFEST_PATH.trim_start_matches('/'))).ok()?;
+    serde_json::from_str(&json).ok()
+}
 
 pub fn install(command: InstallCommand) -> anyhow::Result<()> {
     // 1. Check if we are on a valid ALMA system by finding the manifest
@@ -25,6 +36,16 @@ pub fn install(command: InstallCommand) -> anyhow::Result<()> {
     }
     let manifest: Manifest = serde_json::from_str(&fs::read_to_string(manifest_file)?)?;
     info!("Found manifest for a '{}' system.", manifest.system_variant);
+    if let Some(source) = manifest
+        .sources
+        .iter()
+        .find(|s| s.r#type == "preset" && !s.baked_path.exists())
+    {
+        return Err(anyhow!(
+            "The baked preset {} is missing, it was removed by 'alma gc'. This system can no longer be reinstalled.",
+            source.baked_path.display()
+        ));
+    }
 
     // 2. Determine target device/partitions
     // This logic is now mutually exclusive thanks to clap's `conflicts_with_all`
@@ -49,7 +70,9 @@ pub fn install(command: InstallCommand) -> anyhow::Result<()> {
             || root_partition.as_ref().unwrap().display().to_string(),
             |p| p.display().to_string(),
         );
-        let warning = if target_path.is_some() {
+        let warning = if command.image.is_some() {
+            "CREATE AN IMAGE FILE"
+        } else if target_path.is_some() {
             "WIPE ALL DATA"
         } else {
             "REFORMAT THE PARTITION"
@@ -65,10 +88,27 @@ pub fn install(command: InstallCommand) -> anyhow::Result<()> {
             .default(false)
             .interact()?;
         if !confirmed {
-            return Err(anyhow!("User aborted installation."));
+            return Err(anyhow!("User aborted installation.")).kind(ErrorKind::Aborted);
         }
     }
 
+    // The password is not recorded, so it has to be entered again
+    let user_settings = manifest
+        .user_settings
+        .clone()
+        .map(|mut settings| -> anyhow::Result<UserSettings> {
+            if command.noconfirm {
+                warn!(
+                    "No password is set for user '{}' with --noconfirm, set one with 'alma chroot' before logging in",
+                    settings.username
+                );
+            } else {
+                settings.user_password = Some(UserSettings::prompt_password(&settings.username)?);
+            }
+            Ok(settings)
+        })
+        .transpose()?;
+
     // 4. Reconstruct the CreateCommand
     let reconstructed_cmd = CreateCommand {
         path: target_path,
@@ -76,9 +116,60 @@ pub fn install(command: InstallCommand) -> anyhow::Result<()> {
         boot_partition,
         system: manifest.system_variant,
         filesystem: manifest.filesystem,
+        omarchy_no_patch: false,
+        omarchy_patches: None,
         encrypted_root: manifest.encrypted_root,
+        keymap: manifest.keymap.clone(),
+        locales: manifest.locales.clone(),
+        kernels: manifest.kernels.clone(),
         aur_helper: manifest.aur_helper.parse()?,
+        no_shim: manifest.no_shim,
+        pacman_hooks: manifest.pacman_hooks,
+        grub_timeout: manifest.grub_timeout,
+        grub_default: manifest.grub_default.clone(),
+        grub_password: manifest.grub_password,
+        grub_password_hash: None,
+        grub_targets: manifest.grub_targets,
+        kernel_cmdline: manifest.kernel_cmdline.clone(),
+        board: manifest.board.clone(),
+        initramfs_autodetect: manifest.initramfs_autodetect,
+        initramfs: manifest.initramfs,
+        systemd_initramfs: manifest.systemd_initramfs,
+        hibernate: manifest.hibernate,
+        swapfile: manifest
+            .swap_size_mb
+            .map(|mb| Byte::from_u64(mb * 1024 * 1024)),
+        nodatacow: manifest.nodatacow.clone(),
+        tmpfs: manifest
+            .tmpfs_size_mb
+            .map(|mb| Byte::from_u64(mb * 1024 * 1024)),
+        tmpfs_var_tmp: manifest.tmpfs_var_tmp,
+        mount_options: manifest.mount_options.clone(),
+        sudo_nopasswd: manifest.sudo.nopasswd_commands.clone(),
+        sudo_timeout: manifest.sudo.timeout,
+        sudo_lecture: manifest.sudo.lecture,
+        sudo_rs: manifest.sudo.sudo_rs,
+        motd: manifest.motd.clone(),
+        issue: manifest.issue.clone(),
+        image_name: manifest.image_name.clone(),
+        root_label: manifest.root_label.clone(),
+        boot_label: manifest.boot_label.clone(),
+        fstab_labels: manifest.fstab_labels,
+        bake_shallow: manifest.bake_shallow,
+        initramfs_modules: manifest.initcpio.modules.clone(),
+        initramfs_binaries: manifest.initcpio.binaries.clone(),
+        initramfs_files: manifest.initcpio.files.clone(),
+        initramfs_hooks: manifest.initcpio.hooks.clone(),
+        initramfs_compression: manifest.initcpio.compression.clone(),
         noconfirm: true,
+        ignore_tool_versions: false,
+        keep_mounts_on_error: false,
+        notify_cmd: None,
+        notify_desktop: false,
+        reproducible: None,
+        skip: vec![],
+        only: vec![],
+        resume: false,
         allow_non_removable: command.allow_non_removable,
         presets: manifest
             .sources
@@ -88,12 +179,48 @@ pub fn install(command: InstallCommand) -> anyhow::Result<()> {
             .collect(),
         extra_packages: vec![],
         aur_packages: vec![],
+        continue_on_aur_failure: false,
+        aur_helper_prebuilt: false,
         boot_size: None,
+        leave_free: (manifest.leave_free_mb > 0)
+            .then(|| Byte::from_u64(manifest.leave_free_mb * 1024 * 1024)),
+        raid1: None,
+        partitioner: manifest.partitioner,
+        volatile_root: manifest.volatile_root,
+        iso: None,
+        cloud_init: manifest.cloud_init,
+        cloud_init_seed: None,
+        data_partition: manifest
+            .data_partition_mb
+            .map(|mb| Byte::from_u64(mb * 1024 * 1024)),
         interactive: false,
-        image: None,
-        overwrite: true,
+        image: command.image,
+        from_image: None,
+        preallocate: Preallocation::None,
+        fail_at: None,
+        convert: None,
+        remove_raw: false,
+        compress: None,
+        // An existing file at the --image path is refused rather than replaced
+        overwrite: command.image.is_none(),
         dryrun: false,
+        dryrun_script: None,
+        answers: None,
+        compiler_cache: None,
+        username: None,
+        hostname: None,
+        timezone: None,
+        password_file: None,
+        output: PlanFormat::Shell,
+        backup_table: None,
         pacman_conf: None,
+        no_host_mirrorlist: false,
+        mirrors_country: Vec::new(),
+        no_host_keyring: false,
+        fresh_keyring: false,
+        config: None,
+        refresh: manifest.refresh,
+        user_settings,
     };
 
     // 5. Run the create command logic
@@ -136,9 +263,16 @@ fn migrate_system_data(target_device_path: &Path) -> anyhow::Result<()> {
     let rsync = Tool::find("rsync", false)?;
     let arch_chroot = Tool::find("arch-chroot", false)?;
 
-    let storage_device = storage::StorageDevice::from_path(target_device_path, true, false)?;
+    let loop_device = seed::attach_if_file(target_device_path)?;
+    let storage_device = storage::StorageDevice::from_path(
+        loop_device
+            .as_ref()
+            .map_or(target_device_path, |l| l.path()),
+        true,
+        false,
+    )?;
     let root_partition = storage_device.get_partition(crate::constants::ROOT_PARTITION_INDEX)?;
-    let mount_point = tempfile::tempdir()?;
+    let mount_point = state::temp_mount_point()?;
     let mut mount_stack = MountStack::new(false);
     // Since this is a simple mount, we pass empty flags and no specific data.
     mount_stack.mount_single(
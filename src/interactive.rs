@@ -1,21 +1,105 @@
-use crate::constants::{FONT_PACKAGES, VIDEO_PACKAGES};
-use dialoguer::{Confirm, Input, MultiSelect, Password, theme::ColorfulTheme};
+use crate::constants::{APPLICATION_CATEGORIES, FONT_PACKAGES, PackageChoice, VIDEO_PACKAGES};
+use crate::error::{ErrorKind, ErrorKindExt};
+use crate::keymap;
+use crate::locale;
+use crate::timezone;
+use anyhow::{Context, anyhow};
+use dialoguer::{Confirm, FuzzySelect, Input, MultiSelect, Password, theme::ColorfulTheme};
 use log::info;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::Path;
 
-// Struct to hold all collected user settings
-#[derive(Debug, Clone)]
+// Struct to hold all collected user settings. They are recorded in the manifest, except for the
+// password.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct UserSettings {
     pub username: String,
     pub hostname: String,
+    #[serde(skip)]
     pub user_password: Option<String>,
+    /// Whether `user_password` is already hashed, as given in an answers file
+    #[serde(skip)]
+    pub password_is_hash: bool,
     pub passwordless_sudo: bool,
     pub timezone: String,
+    /// The locales of the system, the first of which is its LANG. en_US.UTF-8 if empty.
+    #[serde(default)]
+    pub locales: Vec<String>,
+    /// The console keymap, unless `--keymap` sets one
+    #[serde(default)]
+    pub keymap: Option<String>,
     pub graphics_packages: Vec<String>,
     pub font_packages: Vec<String>,
+    #[serde(default)]
+    pub application_packages: Vec<String>,
+    /// The name and email Omarchy configures git with, asked for during its installation unless
+    /// given in an answers file
+    #[serde(skip)]
+    pub git_identity: Option<(String, String)>,
+}
+
+/// The settings of an unattended build, read from the TOML file of `--answers` in place of the
+/// interactive setup
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Answers {
+    username: String,
+    #[serde(default = "default_hostname")]
+    hostname: String,
+    /// A crypt(5) hash like `openssl passwd -6` prints, so the answers file holds no password
+    password_hash: Option<String>,
+    #[serde(default)]
+    passwordless_sudo: bool,
+    #[serde(default = "default_timezone")]
+    timezone: String,
+    #[serde(default)]
+    locales: Vec<String>,
+    keymap: Option<String>,
+    #[serde(default)]
+    graphics_packages: Vec<String>,
+    #[serde(default)]
+    font_packages: Vec<String>,
+    #[serde(default)]
+    application_packages: Vec<String>,
+    full_name: Option<String>,
+    email: Option<String>,
+}
+
+fn default_hostname() -> String {
+    String::from("alma-linux")
+}
+
+fn default_timezone() -> String {
+    String::from(timezone::DEFAULT_TIMEZONE)
+}
+
+// The password is redacted, so the settings can be logged
+impl fmt::Debug for UserSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UserSettings")
+            .field("username", &self.username)
+            .field("hostname", &self.hostname)
+            .field(
+                "user_password",
+                &self.user_password.as_ref().map(|_| "********"),
+            )
+            .field("password_is_hash", &self.password_is_hash)
+            .field("passwordless_sudo", &self.passwordless_sudo)
+            .field("timezone", &self.timezone)
+            .field("locales", &self.locales)
+            .field("keymap", &self.keymap)
+            .field("graphics_packages", &self.graphics_packages)
+            .field("font_packages", &self.font_packages)
+            .field("application_packages", &self.application_packages)
+            .field("git_identity", &self.git_identity)
+            .finish()
+    }
 }
 
 impl UserSettings {
-    /// Prompts the user interactively for all settings. This is the sole entry point.
+    /// Prompts the user interactively for all settings
     pub fn prompt() -> anyhow::Result<Self> {
         info!("Starting interactive setup...");
 
@@ -37,36 +121,184 @@ impl UserSettings {
             })
             .interact_text()?;
 
-        let user_password = Some(
-            Password::with_theme(&ColorfulTheme::default())
-                .with_prompt(format!("Enter password for user '{username}'"))
-                .with_confirmation("Confirm password", "Passwords do not match.")
-                .interact()?,
-        );
+        let user_password = Some(Self::prompt_password(&username)?);
 
         let passwordless_sudo = Confirm::with_theme(&ColorfulTheme::default())
             .with_prompt("Enable passwordless sudo for this user?")
             .default(false)
             .interact()?;
 
-        let timezone = Input::with_theme(&ColorfulTheme::default())
-            .with_prompt("Enter timezone (e.g., Europe/London, America/New_York, or UTC)")
-            .default("UTC".to_string())
+        let timezone = Self::prompt_timezone()?;
+
+        let locales = Self::prompt_locales()?;
+
+        // Also the keymap the passphrase of an encrypted root is typed with at boot
+        let keymap = Input::with_theme(&ColorfulTheme::default())
+            .with_prompt("Enter console keymap (e.g., us, uk, de-latin1, fr)")
+            .default(keymap::host_keymap())
+            .validate_with(|s: &String| keymap::validate(s).map_err(|e| e.to_string()))
             .interact_text()?;
 
         let (graphics_packages, font_packages) = Self::prompt_package_selections()?;
+        let application_packages = Self::prompt_applications()?;
 
         Ok(Self {
             username,
             hostname,
             user_password,
+            password_is_hash: false,
             passwordless_sudo,
             timezone,
+            locales,
+            keymap: Some(keymap),
             graphics_packages,
             font_packages,
+            application_packages,
+            git_identity: None,
+        })
+    }
+
+    /// The settings of a user given on the command line, with the defaults of the interactive
+    /// setup for the rest
+    pub fn for_user(username: String) -> anyhow::Result<Self> {
+        validate_username(&username)
+            .map_err(|e| anyhow!(e))
+            .kind(ErrorKind::Usage)?;
+        Ok(Self {
+            username,
+            hostname: default_hostname(),
+            user_password: None,
+            password_is_hash: false,
+            passwordless_sudo: false,
+            timezone: default_timezone(),
+            locales: Vec::new(),
+            keymap: None,
+            graphics_packages: Vec::new(),
+            font_packages: Vec::new(),
+            application_packages: Vec::new(),
+            git_identity: None,
+        })
+    }
+
+    /// Reads a password from the first line of the file, so it never appears on a command line
+    pub fn read_password_file(path: &Path) -> anyhow::Result<String> {
+        let data = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read the password from {}", path.display()))
+            .kind(ErrorKind::Usage)?;
+        let password = data.lines().next().unwrap_or_default();
+        if password.is_empty() {
+            return Err(anyhow!("{} holds no password", path.display())).kind(ErrorKind::Usage);
+        }
+        Ok(password.to_owned())
+    }
+
+    /// Reads the settings from an answers file instead of prompting for them
+    pub fn from_answers(path: &Path) -> anyhow::Result<Self> {
+        let data = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read the answers file {}", path.display()))
+            .kind(ErrorKind::Usage)?;
+        Self::parse_answers(&data)
+            .with_context(|| format!("Invalid answers file {}", path.display()))
+            .kind(ErrorKind::Usage)
+    }
+
+    fn parse_answers(data: &str) -> anyhow::Result<Self> {
+        let answers: Answers = toml::from_str(data)?;
+        validate_username(&answers.username).map_err(|e| anyhow!(e))?;
+        if answers.hostname.is_empty() {
+            return Err(anyhow!("Hostname cannot be empty"));
+        }
+        if let Some(hash) = &answers.password_hash
+            && !hash.starts_with('$')
+        {
+            return Err(anyhow!(
+                "password_hash must be a crypt hash like `openssl passwd -6` prints, not the password itself"
+            ));
+        }
+        for locale in &answers.locales {
+            locale::validate(locale)?;
+        }
+        if let Some(keymap) = &answers.keymap {
+            keymap::validate(keymap)?;
+        }
+        timezone::validate(&answers.timezone)?;
+        let git_identity = match (answers.full_name, answers.email) {
+            (None, None) => None,
+            (name, email) => Some((
+                name.unwrap_or_else(|| answers.username.clone()),
+                email.unwrap_or_default(),
+            )),
+        };
+        Ok(Self {
+            username: answers.username,
+            hostname: answers.hostname,
+            password_is_hash: answers.password_hash.is_some(),
+            user_password: answers.password_hash,
+            passwordless_sudo: answers.passwordless_sudo,
+            timezone: answers.timezone,
+            locales: answers.locales,
+            keymap: answers.keymap,
+            graphics_packages: answers.graphics_packages,
+            font_packages: answers.font_packages,
+            application_packages: answers.application_packages,
+            git_identity,
         })
     }
 
+    pub fn prompt_password(username: &str) -> anyhow::Result<String> {
+        Ok(Password::with_theme(&ColorfulTheme::default())
+            .with_prompt(format!("Enter password for user '{username}'"))
+            .with_confirmation("Confirm password", "Passwords do not match.")
+            .interact()?)
+    }
+
+    /// The stdin of chpasswd setting the user's password, if one was given
+    pub fn chpasswd_input(&self) -> Option<String> {
+        self.user_password
+            .as_ref()
+            .map(|password| format!("{}:{}\n", self.username, password))
+    }
+
+    /// Offers the timezones of tzdata to search through, preselecting the host's
+    fn prompt_timezone() -> anyhow::Result<String> {
+        let timezones = timezone::timezones();
+        if timezones.is_empty() {
+            return Ok(Input::with_theme(&ColorfulTheme::default())
+                .with_prompt("Enter timezone (e.g., Europe/London, America/New_York, or UTC)")
+                .default(default_timezone())
+                .interact_text()?);
+        }
+        let current = timezone::host_timezone().unwrap_or_else(default_timezone);
+        let selection = FuzzySelect::with_theme(&ColorfulTheme::default())
+            .with_prompt("Select timezone (type to search)")
+            .items(&timezones)
+            .default(
+                timezones
+                    .iter()
+                    .position(|zone| *zone == current)
+                    .unwrap_or(0),
+            )
+            .interact()?;
+        Ok(timezones[selection].clone())
+    }
+
+    /// Asks for the locales to generate, checked against the locales glibc supports
+    fn prompt_locales() -> anyhow::Result<Vec<String>> {
+        let input: String = Input::with_theme(&ColorfulTheme::default())
+            .with_prompt("Enter locales separated by spaces, the first sets the language")
+            .default(locale::DEFAULT_LOCALE.to_string())
+            .validate_with(|s: &String| -> Result<(), String> {
+                if s.split_whitespace().next().is_none() {
+                    return Err(String::from("Enter at least one locale"));
+                }
+                s.split_whitespace()
+                    .try_for_each(locale::validate)
+                    .map_err(|e| e.to_string())
+            })
+            .interact_text()?;
+        Ok(input.split_whitespace().map(String::from).collect())
+    }
+
     fn prompt_package_selections() -> anyhow::Result<(Vec<String>, Vec<String>)> {
         // Graphics drivers
         let video_items: Vec<&str> = VIDEO_PACKAGES.iter().map(|(name, _)| *name).collect();
@@ -99,21 +331,49 @@ impl UserSettings {
             .defaults(&font_defaults)
             .interact()?;
 
-        let selected_fonts = font_selections
-            .into_iter()
-            .flat_map(|i| FONT_PACKAGES[i].1.iter().map(|s| s.to_string()))
-            .collect();
+        let selected_fonts = packages_of(FONT_PACKAGES, &font_selections);
 
         Ok((selected_video, selected_fonts))
     }
 
-    /// Generates a bash script to perform user setup based on the collected settings.
+    /// Offers the curated applications, first asking which categories to pick from so users who
+    /// want none of them only answer one prompt
+    fn prompt_applications() -> anyhow::Result<Vec<String>> {
+        let category_items: Vec<&str> = APPLICATION_CATEGORIES
+            .iter()
+            .map(|(name, _)| *name)
+            .collect();
+        let categories = MultiSelect::with_theme(&ColorfulTheme::default())
+            .with_prompt("Select application categories to choose from (optional)")
+            .items(&category_items)
+            .interact()?;
+
+        let mut packages = Vec::new();
+        for category in categories {
+            let (name, applications) = APPLICATION_CATEGORIES[category];
+            let items: Vec<&str> = applications.iter().map(|(name, _)| *name).collect();
+            // The first application of each category is preselected
+            let defaults: Vec<bool> = (0..items.len()).map(|i| i == 0).collect();
+            let selections = MultiSelect::with_theme(&ColorfulTheme::default())
+                .with_prompt(format!("Select {}", name.to_lowercase()))
+                .items(&items)
+                .defaults(&defaults)
+                .interact()?;
+            packages.extend(packages_of(applications, &selections));
+        }
+        Ok(packages)
+    }
+
+    /// Generates a bash script to perform user setup based on the collected settings. The
+    /// password is left out, as the script is written to the image and traced with `set -x`: it
+    /// is set separately by feeding `chpasswd_input` to chpasswd.
     pub fn generate_setup_script(&self) -> anyhow::Result<String> {
         let mut script = String::new();
         script.push_str("set -eux\n");
         script.push_str(&format!("echo {} > /etc/hostname\n", self.hostname));
+        // A missing zone would leave a dangling /etc/localtime
         script.push_str(&format!(
-            "ln -sf /usr/share/zoneinfo/{} /etc/localtime\n",
+            "test -f /usr/share/zoneinfo/{0}\nln -sf /usr/share/zoneinfo/{0} /etc/localtime\n",
             self.timezone
         ));
         script.push_str(&format!(
@@ -121,19 +381,6 @@ impl UserSettings {
             self.username, self.username
         ));
 
-        if let Some(password) = &self.user_password {
-            script.push_str(&format!(
-                "echo \"{}:{}\" | chpasswd\n",
-                self.username, password
-            ));
-        }
-
-        if self.passwordless_sudo {
-            script.push_str("echo '%wheel ALL=(ALL) NOPASSWD: ALL' > /etc/sudoers.d/wheel\n");
-        } else {
-            script.push_str("echo '%wheel ALL=(ALL) ALL' > /etc/sudoers.d/wheel\n");
-        }
-
         script.push_str(&format!(
             "sudo -u {} xdg-user-dirs-update || true\n",
             self.username
@@ -142,6 +389,14 @@ impl UserSettings {
     }
 }
 
+/// The packages of the selected options
+fn packages_of(options: &[PackageChoice], selections: &[usize]) -> Vec<String> {
+    selections
+        .iter()
+        .flat_map(|i| options[*i].1.iter().map(|s| s.to_string()))
+        .collect()
+}
+
 #[allow(clippy::ptr_arg)]
 fn validate_username(input: &String) -> Result<(), String> {
     if input == "root" {
@@ -160,3 +415,123 @@ fn validate_username(input: &String) -> Result<(), String> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_password_not_recorded() {
+        let settings = UserSettings {
+            username: String::from("archie"),
+            hostname: String::from("alma-linux"),
+            user_password: Some(String::from("hunter2")),
+            password_is_hash: false,
+            passwordless_sudo: false,
+            timezone: String::from("UTC"),
+            locales: Vec::new(),
+            keymap: None,
+            graphics_packages: vec![String::from("mesa")],
+            font_packages: vec![],
+            application_packages: vec![String::from("firefox")],
+            git_identity: None,
+        };
+        assert!(
+            !settings
+                .generate_setup_script()
+                .unwrap()
+                .contains("hunter2")
+        );
+        assert_eq!(
+            settings.chpasswd_input().as_deref(),
+            Some("archie:hunter2\n")
+        );
+
+        let json = serde_json::to_string(&settings).unwrap();
+        assert!(!json.contains("hunter2"));
+
+        let recorded: UserSettings = serde_json::from_str(&json).unwrap();
+        assert_eq!(recorded.username, "archie");
+        assert_eq!(recorded.user_password, None);
+        assert_eq!(recorded.application_packages, ["firefox"]);
+    }
+
+    #[test]
+    fn test_application_packages() {
+        let (_, development) = APPLICATION_CATEGORIES
+            .iter()
+            .find(|(name, _)| *name == "Development")
+            .unwrap();
+        assert_eq!(
+            packages_of(development, &[0, 3]),
+            ["base-devel", "git", "docker", "docker-compose"]
+        );
+
+        // Manifests written before the categories existed
+        let recorded: UserSettings = serde_json::from_str(
+            r#"{"username": "archie", "hostname": "alma-linux", "passwordless_sudo": false,
+                "timezone": "UTC", "graphics_packages": ["mesa"], "font_packages": []}"#,
+        )
+        .unwrap();
+        assert!(recorded.application_packages.is_empty());
+    }
+
+    #[test]
+    fn test_password_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("password");
+        fs::write(&path, "correct horse\nsecond line\n").unwrap();
+        assert_eq!(
+            UserSettings::read_password_file(&path).unwrap(),
+            "correct horse"
+        );
+        fs::write(&path, "\n").unwrap();
+        assert!(UserSettings::read_password_file(&path).is_err());
+
+        assert!(UserSettings::for_user(String::from("root")).is_err());
+        let settings = UserSettings::for_user(String::from("archie")).unwrap();
+        assert_eq!(settings.hostname, "alma-linux");
+        assert_eq!(settings.chpasswd_input(), None);
+    }
+
+    #[test]
+    fn test_answers() {
+        let settings = UserSettings::parse_answers(
+            r#"
+            username = "archie"
+            password_hash = "$6$salt$hash"
+            locales = ["de_DE.UTF-8"]
+            keymap = "de-latin1"
+            graphics_packages = ["mesa"]
+            full_name = "Archie Linux"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(settings.hostname, "alma-linux");
+        assert_eq!(settings.timezone, "UTC");
+        assert_eq!(settings.locales, ["de_DE.UTF-8"]);
+        assert_eq!(settings.keymap.as_deref(), Some("de-latin1"));
+        assert!(settings.password_is_hash);
+        assert_eq!(
+            settings.chpasswd_input().as_deref(),
+            Some("archie:$6$salt$hash\n")
+        );
+        assert_eq!(
+            settings.git_identity,
+            Some((String::from("Archie Linux"), String::new()))
+        );
+        assert!(!serde_json::to_string(&settings).unwrap().contains("$6$"));
+
+        assert!(UserSettings::parse_answers(r#"username = "root""#).is_err());
+        assert!(
+            UserSettings::parse_answers("username = \"archie\"\nlocales = [\"xx_XX\"]").is_err()
+        );
+        assert!(
+            UserSettings::parse_answers("username = \"archie\"\npassword = \"hunter2\"").is_err()
+        );
+        assert!(
+            UserSettings::parse_answers("username = \"archie\"\npassword_hash = \"hunter2\"")
+                .is_err()
+        );
+    }
+}
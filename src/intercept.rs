@@ -0,0 +1,146 @@
+//! Temporarily replaces binaries of the installation with wrapper scripts, for commands which
+//! misbehave inside a chroot (starting services, reloading firewalls, rebooting the host...).
+
+use crate::effects;
+use anyhow::{Context, anyhow};
+use log::{debug, warn};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// A binary to replace with a wrapper script, as declared by `[[intercept]]` in a preset
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Interception {
+    /// Name of the binary in /usr/bin
+    pub binary: String,
+    /// Script run instead of the binary. The real binary is available as /usr/bin/<binary>.real
+    pub script: String,
+}
+
+impl Interception {
+    pub fn new(binary: &str, script: impl Into<String>) -> Self {
+        Self {
+            binary: binary.to_owned(),
+            script: script.into(),
+        }
+    }
+}
+
+/// Wrapped binaries of an installation, restored in reverse order when the stack is dropped
+pub struct InterceptStack {
+    bin_dir: PathBuf,
+    wrapped: Vec<String>,
+    dryrun: bool,
+}
+
+impl InterceptStack {
+    pub fn new(mount_path: &Path, dryrun: bool) -> Self {
+        Self {
+            bin_dir: mount_path.join("usr/bin"),
+            wrapped: Vec::new(),
+            dryrun,
+        }
+    }
+
+    fn paths(&self, binary: &str) -> (PathBuf, PathBuf) {
+        (
+            self.bin_dir.join(binary),
+            self.bin_dir.join(format!("{binary}.real")),
+        )
+    }
+
+    /// Moves the binary to <binary>.real and puts the wrapper script in its place. Binaries
+    /// which are not installed are left alone.
+    pub fn wrap(&mut self, interception: &Interception) -> anyhow::Result<()> {
+        let binary = &interception.binary;
+        if binary.is_empty() || binary.contains('/') {
+            return Err(anyhow!("Cannot intercept '{binary}': not a binary name"));
+        }
+        let (path, real_path) = self.paths(binary);
+
+        // Nothing is installed in dry runs, the plan wraps every binary
+        if !self.dryrun {
+            if !path.exists() {
+                debug!("{binary} is not installed, not intercepting it");
+                return Ok(());
+            }
+            if real_path.exists() {
+                return Err(anyhow!("{binary} is already intercepted"));
+            }
+        }
+
+        debug!("Wrapping {binary}");
+        effects::rename(&path, &real_path, self.dryrun)
+            .with_context(|| format!("Failed to move real {binary}"))?;
+        self.wrapped.push(binary.clone());
+        effects::write(&path, &interception.script, self.dryrun)
+            .with_context(|| format!("Failed to write {binary} wrapper script"))?;
+        effects::set_mode(&path, 0o755, self.dryrun)
+            .with_context(|| format!("Failed to make the {binary} wrapper executable"))?;
+        Ok(())
+    }
+
+    fn _restore(&mut self) -> anyhow::Result<()> {
+        let mut result = Ok(());
+
+        while let Some(binary) = self.wrapped.pop() {
+            let (path, real_path) = self.paths(&binary);
+            debug!("Restoring {binary}");
+
+            if let Err(e) = effects::rename(&real_path, &path, self.dryrun) {
+                warn!("Unable to restore {}: {}", path.display(), e);
+                result = Err(anyhow!("Failed restoring {}: {}", path.display(), e));
+            }
+        }
+
+        result
+    }
+
+    pub fn restore(mut self) -> anyhow::Result<()> {
+        self._restore()
+    }
+}
+
+impl Drop for InterceptStack {
+    fn drop(&mut self) {
+        self._restore().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_wrap_and_restore() {
+        let root = tempfile::tempdir().unwrap();
+        let bin_dir = root.path().join("usr/bin");
+        fs::create_dir_all(&bin_dir).unwrap();
+        fs::write(bin_dir.join("reboot"), "real reboot").unwrap();
+
+        {
+            let mut stack = InterceptStack::new(root.path(), false);
+            stack
+                .wrap(&Interception::new("reboot", "#!/bin/sh\nexit 0\n"))
+                .unwrap();
+            // Not installed, so silently skipped
+            stack
+                .wrap(&Interception::new("ufw", "#!/bin/sh\n"))
+                .unwrap();
+            assert!(stack.wrap(&Interception::new("reboot", "")).is_err());
+
+            assert_eq!(
+                fs::read_to_string(bin_dir.join("reboot")).unwrap(),
+                "#!/bin/sh\nexit 0\n"
+            );
+            assert!(!bin_dir.join("ufw").exists());
+        }
+
+        assert_eq!(
+            fs::read_to_string(bin_dir.join("reboot")).unwrap(),
+            "real reboot"
+        );
+        assert!(!bin_dir.join("reboot.real").exists());
+    }
+}
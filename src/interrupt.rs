@@ -0,0 +1,76 @@
+//! Ctrl+C and SIGTERM handling. The handler only records the signal: the command then fails at
+//! the external command it is running (which got Ctrl+C too) or the next one, and returns through
+//! the usual error path, so the MountStack, EncryptedDevice and LoopDevice are released as they
+//! are dropped. A second signal exits immediately, leaving the rest to `alma cleanup`.
+
+use crate::cleanup;
+use crate::error::{ErrorKind, ErrorKindExt};
+use anyhow::{Context, anyhow};
+use nix::libc;
+use nix::sys::signal::{SaFlags, SigAction, SigHandler, SigSet, Signal, sigaction};
+use std::sync::Once;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+/// The signal received, 0 until then
+static SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+/// The PID of the external command being run, 0 if none
+static CHILD: AtomicI32 = AtomicI32::new(0);
+
+static STOP_CHROOT_PROCESSES: Once = Once::new();
+
+extern "C" fn handle(signal: libc::c_int) {
+    if SIGNAL.swap(signal, Ordering::SeqCst) != 0 {
+        // SAFETY: _exit is async-signal-safe
+        unsafe { libc::_exit(128 + signal) };
+    }
+    // Ctrl+C reaches the whole foreground process group, SIGTERM only ALMA
+    let child = CHILD.load(Ordering::SeqCst);
+    if signal == libc::SIGTERM && child > 0 {
+        // SAFETY: kill is async-signal-safe
+        unsafe { libc::kill(child, libc::SIGTERM) };
+    }
+}
+
+pub fn install_handler() -> anyhow::Result<()> {
+    // Without SA_RESTART, so blocking reads like prompts return
+    let action = SigAction::new(
+        SigHandler::Handler(handle),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+    for signal in [Signal::SIGINT, Signal::SIGTERM] {
+        // SAFETY: the handler only uses atomics and async-signal-safe functions
+        unsafe { sigaction(signal, &action) }
+            .with_context(|| format!("Failed to handle {signal}"))?;
+    }
+    Ok(())
+}
+
+/// The exit code of a process killed by the signal received, if any
+pub fn exit_code() -> Option<i32> {
+    match SIGNAL.load(Ordering::SeqCst) {
+        0 => None,
+        signal => Some(128 + signal),
+    }
+}
+
+pub fn interrupted() -> bool {
+    exit_code().is_some()
+}
+
+/// Fails once a signal was received. The first time, the processes still running in a chroot
+/// of this run are stopped, as they would keep its filesystems busy while they are unmounted.
+pub fn check() -> anyhow::Result<()> {
+    let Ok(signal) = Signal::try_from(SIGNAL.load(Ordering::SeqCst)) else {
+        return Ok(());
+    };
+    STOP_CHROOT_PROCESSES.call_once(cleanup::stop_chroot_processes);
+    Err(anyhow!("Interrupted by {}", signal.as_str())).kind(ErrorKind::Aborted)
+}
+
+/// Records the external command being run, so SIGTERM is forwarded to it
+pub fn set_child(pid: Option<u32>) {
+    let pid = pid.and_then(|pid| i32::try_from(pid).ok()).unwrap_or(0);
+    CHILD.store(pid, Ordering::SeqCst);
+}
@@ -0,0 +1,191 @@
+//! `alma create --iso`: a live ISO of the installed system. The system is built into a temporary
+//! image as usual, then its root filesystem is packed into a squashfs which the archiso hooks
+//! boot with a tmpfs overlay, and grub-mkrescue wraps it in a hybrid BIOS/UEFI ISO.
+
+use crate::args::CreateCommand;
+use crate::effects;
+use crate::process::CommandExt;
+use crate::reproducible::Reproducible;
+use crate::tool::Tools;
+use anyhow::{Context, anyhow};
+use byte_unit::Byte;
+use log::info;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Volume label of the ISO, which the archiso hook looks for
+pub const LABEL: &str = "ALMA_LIVE";
+
+/// Size of the sparse image the system is built in, only the used space is written
+const BUILD_IMAGE_GIB: u64 = 32;
+
+/// The package providing the archiso initramfs hooks
+pub const HOOKS_PACKAGE: &str = "mkinitcpio-archiso";
+
+/// Where the archiso hook expects the squashfs, below archisobasedir
+const SQUASHFS_PATH: &str = "arch/x86_64/airootfs.sfs";
+const KERNEL_DIR: &str = "arch/boot/x86_64";
+
+/// mkinitcpio configuration of the live initramfs, which finds the ISO by its label and mounts
+/// the squashfs under a tmpfs overlay
+const LIVE_MKINITCPIO_CONF: &str = "etc/mkinitcpio-alma-live.conf";
+const LIVE_HOOKS: &str = "MODULES=(loop squashfs overlay isofs)
+BINARIES=()
+FILES=()
+HOOKS=(base udev microcode modconf kms keyboard keymap consolefont block archiso filesystems)
+";
+
+/// `<iso>.rootfs.img`, the image the system is built in before it is packed
+pub fn build_image_path(iso: &Path) -> PathBuf {
+    let mut name = iso
+        .file_name()
+        .map_or_else(OsString::new, |n| n.to_os_string());
+    name.push(".rootfs.img");
+    iso.with_file_name(name)
+}
+
+/// Points the command at the temporary build image of the ISO. An existing file there, e.g. the
+/// build image of a failed run, is only replaced with --overwrite.
+pub fn prepare_command(command: &mut CreateCommand, iso: &Path) {
+    command.path = Some(build_image_path(iso));
+    command.image = Some(Byte::from_u64(BUILD_IMAGE_GIB * 1024 * 1024 * 1024));
+}
+
+/// Packs the system mounted at `mount_path` into the ISO. This changes the system, so it must
+/// be the last step.
+pub fn build(
+    command: &CreateCommand,
+    tools: &Tools,
+    mount_path: &Path,
+    iso: &Path,
+    reproducible: Option<&Reproducible>,
+) -> anyhow::Result<()> {
+    let dryrun = command.dryrun;
+    let kernel = command
+        .kernels
+        .first()
+        .ok_or_else(|| anyhow!("No kernel to boot the live system with"))?;
+    let kernel_image = format!("vmlinuz-{kernel}");
+    let initramfs_image = format!("initramfs-{kernel}-live.img");
+
+    info!("Generating the live initramfs");
+    let conf_path = mount_path.join(LIVE_MKINITCPIO_CONF);
+    effects::write(&conf_path, LIVE_HOOKS, dryrun)?;
+    tools
+        .arch_chroot
+        .execute()
+        .arg(mount_path)
+        .args(["mkinitcpio", "-c"])
+        .arg(Path::new("/").join(LIVE_MKINITCPIO_CONF))
+        .arg("-k")
+        .arg(format!("/boot/{kernel_image}"))
+        .arg("-g")
+        .arg(format!("/boot/{initramfs_image}"))
+        .run(dryrun)
+        .context("Failed to generate the live initramfs")?;
+
+    // The root is the squashfs under an overlay, and the partitions of the build image do not
+    // exist on the ISO
+    effects::remove_file(&conf_path, dryrun)?;
+    effects::write(
+        &mount_path.join("etc/fstab"),
+        "# Live system, / is an overlay of the squashfs on the ISO\n",
+        dryrun,
+    )?;
+
+    // Next to the ISO rather than in /tmp, which is often a small tmpfs
+    let iso_dir = iso.parent().filter(|p| !p.as_os_str().is_empty());
+    let tree = tempfile::Builder::new()
+        .prefix(".alma-iso-")
+        .tempdir_in(iso_dir.unwrap_or(Path::new(".")))
+        .context("Error creating a temporary directory")?;
+
+    info!("Packing the root filesystem into a squashfs");
+    let squashfs = tree.path().join(SQUASHFS_PATH);
+    let kernel_dir = tree.path().join(KERNEL_DIR);
+    let grub_dir = tree.path().join("boot/grub");
+    for dir in [squashfs.parent().unwrap(), &kernel_dir, &grub_dir] {
+        effects::create_dir_all(dir, dryrun)?;
+    }
+    let mut mksquashfs = tools
+        .mksquashfs
+        .as_ref()
+        .expect("No tool for mksquashfs")
+        .execute();
+    mksquashfs
+        .arg(mount_path)
+        .arg(&squashfs)
+        // Leaves out the boot partition mounted at /boot
+        .args(["-one-file-system", "-noappend", "-comp", "zstd"]);
+    if let Some(reproducible) = reproducible {
+        let epoch = reproducible.epoch().to_string();
+        mksquashfs.args(["-mkfs-time", &epoch, "-all-time", &epoch]);
+    }
+    mksquashfs
+        .run(dryrun)
+        .context("Failed to create the squashfs")?;
+
+    for image in [&kernel_image, &initramfs_image] {
+        effects::copy(
+            &mount_path.join("boot").join(image),
+            &kernel_dir.join(image),
+            dryrun,
+        )?;
+    }
+
+    effects::write(
+        &grub_dir.join("grub.cfg"),
+        grub_cfg(&kernel_image, &initramfs_image, command),
+        dryrun,
+    )?;
+
+    info!("Writing {}", iso.display());
+    tools
+        .grub_mkrescue
+        .as_ref()
+        .expect("No tool for grub-mkrescue")
+        .execute()
+        .arg("-o")
+        .arg(iso)
+        .arg(tree.path())
+        .args(["--", "-volid", LABEL])
+        .run(dryrun)
+        .context("Failed to create the ISO")
+}
+
+fn grub_cfg(kernel_image: &str, initramfs_image: &str, command: &CreateCommand) -> String {
+    let mut cmdline = format!("archisobasedir=arch archisolabel={LABEL}");
+    if let Some(extra) = &command.kernel_cmdline {
+        cmdline.push(' ');
+        cmdline.push_str(extra);
+    }
+    let timeout = command.grub_timeout.unwrap_or(5);
+    format!(
+        "set timeout={timeout}
+set default=0
+
+menuentry \"ALMA live system\" {{
+    linux /{KERNEL_DIR}/{kernel_image} {cmdline}
+    initrd /{KERNEL_DIR}/{initramfs_image}
+}}
+
+menuentry \"ALMA live system (copied to RAM)\" {{
+    linux /{KERNEL_DIR}/{kernel_image} {cmdline} copytoram=y
+    initrd /{KERNEL_DIR}/{initramfs_image}
+}}
+"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_image_path() {
+        assert_eq!(
+            build_image_path(Path::new("/srv/out/alma.iso")),
+            PathBuf::from("/srv/out/alma.iso.rootfs.img")
+        );
+    }
+}
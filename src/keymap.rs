@@ -0,0 +1,105 @@
+//! `--keymap`: the console keymap of the installed system, written to /etc/vconsole.conf before
+//! the initramfs is generated, so the keymap (busybox) and sd-vconsole (systemd) hooks of
+//! mkinitcpio and the i18n module of dracut embed it and the LUKS passphrase is typed with it.
+
+use anyhow::anyhow;
+use log::warn;
+use std::fs;
+use std::path::Path;
+
+pub const VCONSOLE_CONF: &str = "etc/vconsole.conf";
+
+/// The keymap of the console when vconsole.conf does not set one
+pub const DEFAULT_KEYMAP: &str = "us";
+
+/// Where kbd installs the keymaps, as `<layout family>/.../<name>.map.gz`
+const KEYMAPS_DIR: &str = "usr/share/kbd/keymaps";
+
+pub fn vconsole_conf(keymap: &str) -> String {
+    format!("KEYMAP={keymap}\n")
+}
+
+/// The KEYMAP of a vconsole.conf, which is an environment-like file whose values may be quoted
+fn parse_keymap(vconsole_conf: &str) -> Option<String> {
+    vconsole_conf
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("KEYMAP="))
+        .map(|value| {
+            value
+                .trim()
+                .trim_matches(|c| c == '"' || c == '\'')
+                .to_string()
+        })
+        .rfind(|value| !value.is_empty())
+}
+
+/// The console keymap of the host running ALMA, which the LUKS passphrase is typed with
+pub fn host_keymap() -> String {
+    fs::read_to_string(Path::new("/").join(VCONSOLE_CONF))
+        .ok()
+        .as_deref()
+        .and_then(parse_keymap)
+        .unwrap_or_else(|| String::from(DEFAULT_KEYMAP))
+}
+
+/// Checks that kbd on the host ships `keymap`. Skipped on hosts without kbd, the keymap is then
+/// only checked when loadkeys runs in the initramfs.
+pub fn validate(keymap: &str) -> anyhow::Result<()> {
+    let keymaps_dir = Path::new("/").join(KEYMAPS_DIR);
+    if !keymaps_dir.is_dir() || contains_keymap(&keymaps_dir, keymap) {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "Unknown keymap '{keymap}', `localectl list-keymaps` lists the available ones"
+    ))
+}
+
+fn contains_keymap(dir: &Path, keymap: &str) -> bool {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return false;
+    };
+    let file_name = format!("{keymap}.map.gz");
+    entries.flatten().any(|entry| {
+        let path = entry.path();
+        if path.is_dir() {
+            contains_keymap(&path, keymap)
+        } else {
+            entry.file_name().to_str() == Some(file_name.as_str())
+        }
+    })
+}
+
+/// Warns before the passphrase prompt of cryptsetup if the host types it with another layout
+/// than the one the initramfs will read it with, as a passphrase with characters placed
+/// differently in the two layouts would then not unlock the system
+pub fn warn_on_passphrase_mismatch(target_keymap: Option<&str>) {
+    let target = target_keymap.unwrap_or(DEFAULT_KEYMAP);
+    let host = host_keymap();
+    if host != target {
+        warn!(
+            "The passphrase is typed with the '{host}' keymap of this host, but the '{target}' keymap unlocks the root partition at boot. Use characters which are on the same keys in both layouts, or pass --keymap {host}"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_keymap() {
+        assert_eq!(
+            parse_keymap("FONT=ter-v16n\nKEYMAP=\"de-latin1\"\n").as_deref(),
+            Some("de-latin1")
+        );
+        assert_eq!(
+            parse_keymap("KEYMAP=uk\nKEYMAP=fr\n").as_deref(),
+            Some("fr")
+        );
+        assert_eq!(parse_keymap("FONT=lat2-16\nKEYMAP=\n"), None);
+        assert_eq!(
+            parse_keymap(&vconsole_conf("dvorak")).as_deref(),
+            Some("dvorak")
+        );
+    }
+}
@@ -0,0 +1,69 @@
+//! `alma list-devices`: the disks `alma create` offers when no device is given, for scripts which
+//! choose a target themselves.
+
+use crate::args::ListDevicesCommand;
+use crate::storage::{self, Device};
+use anyhow::Context;
+
+pub fn list_devices(command: ListDevicesCommand) -> anyhow::Result<()> {
+    let devices = storage::get_storage_devices(command.all)?;
+    if command.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&devices).context("Failed to serialize the devices")?
+        );
+    } else {
+        print!("{}", table(&devices));
+    }
+    Ok(())
+}
+
+/// One line per device, the model last as it may contain spaces
+fn table(devices: &[Device]) -> String {
+    let mut table = format!(
+        "{:<12} {:>10} {:<9} {:<9} {}\n",
+        "NAME", "SIZE", "TRANSPORT", "REMOVABLE", "MODEL"
+    );
+    for device in devices {
+        let size = format!(
+            "{:.1}",
+            device
+                .size
+                .get_appropriate_unit(byte_unit::UnitType::Binary)
+        );
+        let model = format!("{} {}", device.vendor, device.model);
+        table.push_str(&format!(
+            "{:<12} {size:>10} {:<9} {:<9} {}\n",
+            device.name,
+            device.transport.unwrap_or("-"),
+            if device.removable { "yes" } else { "no" },
+            model.trim()
+        ));
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byte_unit::Byte;
+
+    #[test]
+    fn test_table() {
+        let devices = [Device {
+            name: String::from("sdb"),
+            size: Byte::from_u64(32 * 1024 * 1024 * 1024),
+            vendor: String::from("SanDisk"),
+            model: String::from("Ultra Fit"),
+            transport: Some("usb"),
+            removable: true,
+            id_link: None,
+        }];
+        assert_eq!(
+            table(&devices),
+            "NAME               SIZE TRANSPORT REMOVABLE MODEL
+sdb            32.0 GiB usb       yes       SanDisk Ultra Fit
+"
+        );
+    }
+}
@@ -0,0 +1,99 @@
+//! `--locale`: the locales generated in the installed system, the first of which is its LANG.
+//! glibc lists the locales it can generate in /usr/share/i18n/SUPPORTED, as the lines locale.gen
+//! expects, e.g. `de_DE.UTF-8 UTF-8` or `de_DE@euro ISO-8859-15`.
+
+use anyhow::anyhow;
+use std::fs;
+use std::path::Path;
+
+pub const DEFAULT_LOCALE: &str = "en_US.UTF-8";
+
+const SUPPORTED: &str = "usr/share/i18n/SUPPORTED";
+
+/// The locales of glibc in the system at `root`, unless it has no glibc i18n files
+pub fn supported(root: &Path) -> Option<String> {
+    fs::read_to_string(root.join(SUPPORTED)).ok()
+}
+
+/// The charset of `locale` in SUPPORTED
+fn charset<'a>(supported: &'a str, locale: &str) -> Option<&'a str> {
+    supported.lines().find_map(|line| {
+        let (name, charset) = line.split_once(' ')?;
+        (name == locale).then(|| charset.trim())
+    })
+}
+
+/// The line of locale.gen generating `locale`. Without SUPPORTED, only UTF-8 locales, whose
+/// charset is known from their name, can be generated.
+fn locale_gen_line(supported: Option<&str>, locale: &str) -> anyhow::Result<String> {
+    let charset = match supported {
+        Some(supported) => charset(supported, locale).ok_or_else(|| {
+            anyhow!(
+                "Unknown locale '{locale}', /usr/share/i18n/SUPPORTED lists the available ones, e.g. de_DE.UTF-8"
+            )
+        })?,
+        None if locale.ends_with(".UTF-8") => "UTF-8",
+        None => {
+            return Err(anyhow!(
+                "Unknown locale '{locale}', give a UTF-8 locale like de_DE.UTF-8"
+            ));
+        }
+    };
+    Ok(format!("{locale} {charset}\n"))
+}
+
+/// Checks that glibc on the host can generate `locale`
+pub fn validate(locale: &str) -> anyhow::Result<()> {
+    locale_gen_line(supported(Path::new("/")).as_deref(), locale).map(|_| ())
+}
+
+/// The lines appended to locale.gen. en_US.UTF-8 is always generated, as some programs expect it.
+pub fn locale_gen(supported: Option<&str>, locales: &[String]) -> anyhow::Result<String> {
+    let mut names: Vec<&str> = locales.iter().map(String::as_str).collect();
+    names.push(DEFAULT_LOCALE);
+    let mut locale_gen = String::new();
+    for (i, locale) in names.iter().enumerate() {
+        if !names[..i].contains(locale) {
+            locale_gen.push_str(&locale_gen_line(supported, locale)?);
+        }
+    }
+    Ok(locale_gen)
+}
+
+pub fn locale_conf(locales: &[String]) -> String {
+    format!(
+        "LANG={}\n",
+        locales.first().map_or(DEFAULT_LOCALE, String::as_str)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locale_gen() {
+        let supported = "de_DE.UTF-8 UTF-8\nde_DE@euro ISO-8859-15\nen_US.UTF-8 UTF-8\n";
+        let locales = |names: &[&str]| names.iter().map(|s| String::from(*s)).collect::<Vec<_>>();
+        assert_eq!(
+            locale_gen(Some(supported), &locales(&["de_DE.UTF-8", "de_DE@euro"])).unwrap(),
+            "de_DE.UTF-8 UTF-8\nde_DE@euro ISO-8859-15\nen_US.UTF-8 UTF-8\n"
+        );
+        assert_eq!(
+            locale_gen(Some(supported), &locales(&["en_US.UTF-8"])).unwrap(),
+            "en_US.UTF-8 UTF-8\n"
+        );
+        assert!(locale_gen(Some(supported), &locales(&["de_DE"])).is_err());
+        assert_eq!(
+            locale_gen(None, &locales(&["fr_FR.UTF-8"])).unwrap(),
+            "fr_FR.UTF-8 UTF-8\nen_US.UTF-8 UTF-8\n"
+        );
+        assert!(locale_gen(None, &locales(&["de_DE@euro"])).is_err());
+
+        assert_eq!(locale_conf(&[]), "LANG=en_US.UTF-8\n");
+        assert_eq!(
+            locale_conf(&locales(&["de_DE.UTF-8", "en_GB.UTF-8"])),
+            "LANG=de_DE.UTF-8\n"
+        );
+    }
+}
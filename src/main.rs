@@ -1,22 +1,83 @@
+mod arch;
 mod args;
+mod artifact;
 mod aur;
+mod backup;
+mod baked;
+mod board;
+mod boot_report;
+mod checkpoint;
+mod cleanup;
+mod clone;
+mod cloud_init;
+mod compiler_cache;
+mod conflicts;
 mod constants;
 mod create;
+mod dkms;
+mod doctor;
+mod dracut;
+mod effects;
+mod error;
+mod fault;
+mod flash;
+mod grub;
+mod info;
 mod initcpio;
 mod install;
 mod interactive;
+mod intercept;
+mod interrupt;
+mod iso;
+mod keymap;
+mod list_devices;
+mod locale;
+mod mirrors;
+mod motd;
+mod notify;
+mod omarchy_patch;
+mod pacman_hooks;
+mod plan;
 mod presets;
 mod process;
+mod repart;
+mod reproducible;
+mod resize;
+mod schedule;
+mod seed;
+mod state;
 mod storage;
+mod sudo;
+mod swap;
+mod table_backup;
+mod timezone;
 mod tool;
+mod update;
+mod vagrant;
 
 use anyhow::Result;
 use args::Command;
-use clap::Parser;
+use error::{ErrorKind, ErrorKindExt};
 use log::LevelFilter;
 
-fn main() -> Result<()> {
-    let app = args::App::parse();
+fn main() {
+    if let Err(err) = run() {
+        if interrupt::interrupted() {
+            cleanup::release_current_run();
+        }
+        let kind = error::kind_of(&err);
+        eprintln!("Error: {err:?}");
+        if let Some(hint) = kind.and_then(|k| k.hint()) {
+            eprintln!("\nHint: {hint}");
+        }
+        let exit_code = interrupt::exit_code().unwrap_or(kind.map_or(1, |k| k.exit_code()));
+        std::process::exit(exit_code);
+    }
+}
+
+fn run() -> Result<()> {
+    let app = args::App::parse_with_config().kind(ErrorKind::Usage)?;
+    interrupt::install_handler()?;
 
     let mut builder = pretty_env_logger::formatted_timed_builder();
     let log_level = if app.verbose {
@@ -32,5 +93,23 @@ fn main() -> Result<()> {
         Command::Install(command) => install::install(command),
         Command::Chroot(command) => tool::chroot(command),
         Command::Qemu(command) => tool::qemu(command),
+        Command::Doctor(command) => doctor::doctor(command),
+        Command::ListDevices(command) => list_devices::list_devices(command),
+        Command::Resize(command) => resize::resize(command),
+        Command::FixGpt(command) => resize::fix_gpt(command),
+        Command::RestoreGpt(command) => table_backup::restore(command),
+        Command::Cleanup(command) => cleanup::cleanup(command),
+        Command::Gc(command) => baked::gc(command),
+        Command::Schedule(command) => schedule::schedule(command),
+        Command::Seed(command) => seed::seed(command),
+        Command::Sprout(command) => seed::sprout(command),
+        Command::Clone(command) => clone::clone(command),
+        Command::Update(command) => update::update(command),
+        Command::Info(command) => info::info(command),
+        Command::Backup(command) => backup::backup(command),
+        Command::Flash(command) => flash::flash(command),
+        Command::Export(command) => match command.format {
+            args::ExportFormat::Vagrant(command) => vagrant::export(command),
+        },
     }
 }
@@ -0,0 +1,189 @@
+//! `--mirrors-country`: ranks the mirrors of the given countries by the Arch Linux mirror status,
+//! the data reflector uses, and bootstraps from them and installs them as the mirrorlist of the
+//! image, instead of whatever mirrorlist the host has.
+
+use crate::effects;
+use crate::error::{ErrorKind, ErrorKindExt};
+use anyhow::{Context, anyhow};
+use log::{info, warn};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+const STATUS_URL: &str = "https://archlinux.org/mirrors/status/json/";
+
+/// How many of the best mirrors are listed
+const MIRROR_COUNT: usize = 10;
+
+/// The mirrorlist which the standard pacman.conf includes for every repository
+const MIRRORLIST: &str = "/etc/pacman.d/mirrorlist";
+
+#[derive(Deserialize)]
+struct Status {
+    urls: Vec<Mirror>,
+}
+
+#[derive(Deserialize)]
+struct Mirror {
+    url: String,
+    protocol: String,
+    country_code: String,
+    active: bool,
+    /// The share of the last checks which found the mirror up to date
+    completion_pct: Option<f64>,
+    /// Lower is better, none if the mirror was not reachable lately
+    score: Option<f64>,
+}
+
+/// Checks a country code like "DE", as the mirror status gives them
+pub fn parse_country(code: &str) -> anyhow::Result<String> {
+    if code.len() != 2 || !code.bytes().all(|b| b.is_ascii_alphabetic()) {
+        return Err(anyhow!(
+            "Invalid country code '{code}', give two letter codes like DE or FR"
+        ));
+    }
+    Ok(code.to_ascii_uppercase())
+}
+
+/// The mirrorlist of the best HTTPS mirrors in `countries` which are fully synced
+fn rank(status: Status, countries: &[String]) -> anyhow::Result<String> {
+    let mut mirrors: Vec<(f64, String)> = status
+        .urls
+        .into_iter()
+        .filter(|m| {
+            m.active
+                && m.protocol == "https"
+                && m.completion_pct.is_some_and(|pct| pct >= 1.0)
+                && countries.contains(&m.country_code)
+        })
+        .filter_map(|m| Some((m.score?, m.url)))
+        .collect();
+    if mirrors.is_empty() {
+        return Err(anyhow!(
+            "The mirror status lists no up to date HTTPS mirrors in {}",
+            countries.join(", ")
+        ));
+    }
+    mirrors.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let mut mirrorlist = format!(
+        "## Generated by ALMA from {STATUS_URL}\n## The best mirrors in {}\n\n",
+        countries.join(", ")
+    );
+    for (_, url) in mirrors.into_iter().take(MIRROR_COUNT) {
+        let separator = if url.ends_with('/') { "" } else { "/" };
+        mirrorlist.push_str(&format!("Server = {url}{separator}$repo/os/$arch\n"));
+    }
+    Ok(mirrorlist)
+}
+
+/// Points the `Include`s of the standard mirrorlist in `pacman_conf` at `mirrorlist`, returning
+/// the new configuration and how many repositories now use it
+fn redirect_includes(pacman_conf: &str, mirrorlist: &Path) -> (String, usize) {
+    let mut redirected = 0;
+    let conf = pacman_conf
+        .lines()
+        .map(|line| match line.split_once('=') {
+            Some((key, value)) if key.trim() == "Include" && value.trim() == MIRRORLIST => {
+                redirected += 1;
+                format!("Include = {}\n", mirrorlist.display())
+            }
+            _ => format!("{line}\n"),
+        })
+        .collect();
+    (conf, redirected)
+}
+
+/// The ranked mirrorlist, and a copy of the pacman.conf using it for pacstrap, so the host's own
+/// configuration is left alone
+pub struct Mirrorlist {
+    pub contents: String,
+    dir: TempDir,
+}
+
+impl Mirrorlist {
+    /// Downloads the mirror status, also in dry runs, so the plan lists the mirrors
+    pub fn generate(
+        countries: &[String],
+        pacman_conf: &Path,
+        dryrun: bool,
+    ) -> anyhow::Result<Self> {
+        info!("Ranking the mirrors in {}", countries.join(", "));
+        let status = reqwest::blocking::get(STATUS_URL)
+            .and_then(|r| r.error_for_status())
+            .and_then(|r| r.text())
+            .with_context(|| format!("Failed to download the mirror status from {STATUS_URL}"))
+            .kind(ErrorKind::Network)?;
+        let status: Status =
+            serde_json::from_str(&status).context("Failed to parse the mirror status")?;
+        let contents = rank(status, countries).kind(ErrorKind::Network)?;
+
+        let dir = tempfile::tempdir().context("Error creating a temporary directory")?;
+        let mirrorlist = dir.path().join("mirrorlist");
+        let conf = std::fs::read_to_string(pacman_conf)
+            .with_context(|| format!("Failed to read {}", pacman_conf.display()))?;
+        let (conf, redirected) = redirect_includes(&conf, &mirrorlist);
+        if redirected == 0 {
+            warn!(
+                "{} does not include {MIRRORLIST}, so the ranked mirrors are only used by the installed system",
+                pacman_conf.display()
+            );
+        }
+        effects::write(&mirrorlist, &contents, dryrun)?;
+        effects::write(&dir.path().join("pacman.conf"), conf, dryrun)?;
+        Ok(Self { contents, dir })
+    }
+
+    /// The pacman.conf to bootstrap with
+    pub fn pacman_conf(&self) -> PathBuf {
+        self.dir.path().join("pacman.conf")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rank() {
+        let status: Status = serde_json::from_str(
+            r#"{"urls": [
+                {"url": "https://slow.example.de/archlinux/", "protocol": "https", "country_code": "DE", "active": true, "completion_pct": 1.0, "score": 5.2},
+                {"url": "https://fast.example.fr/arch", "protocol": "https", "country_code": "FR", "active": true, "completion_pct": 1.0, "score": 0.8},
+                {"url": "http://plain.example.de/archlinux/", "protocol": "http", "country_code": "DE", "active": true, "completion_pct": 1.0, "score": 0.5},
+                {"url": "https://behind.example.de/archlinux/", "protocol": "https", "country_code": "DE", "active": true, "completion_pct": 0.5, "score": 0.5},
+                {"url": "https://down.example.de/archlinux/", "protocol": "https", "country_code": "DE", "active": true, "completion_pct": null, "score": null},
+                {"url": "https://example.se/archlinux/", "protocol": "https", "country_code": "SE", "active": true, "completion_pct": 1.0, "score": 0.1}
+            ]}"#,
+        )
+        .unwrap();
+        let mirrorlist = rank(status, &[String::from("DE"), String::from("FR")]).unwrap();
+        let servers: Vec<&str> = mirrorlist
+            .lines()
+            .filter(|line| line.starts_with("Server"))
+            .collect();
+        assert_eq!(
+            servers,
+            [
+                "Server = https://fast.example.fr/arch/$repo/os/$arch",
+                "Server = https://slow.example.de/archlinux/$repo/os/$arch",
+            ]
+        );
+        assert!(rank(Status { urls: Vec::new() }, &[String::from("DE")]).is_err());
+
+        assert_eq!(parse_country("de").unwrap(), "DE");
+        assert!(parse_country("DEU").is_err());
+    }
+
+    #[test]
+    fn test_redirect_includes() {
+        let (conf, redirected) = redirect_includes(
+            "[options]\nHoldPkg = pacman glibc\n\n[core]\nInclude = /etc/pacman.d/mirrorlist\n\n[extra]\nInclude=/etc/pacman.d/mirrorlist\n\n[custom]\nInclude = /etc/pacman.d/custom\n",
+            Path::new("/tmp/alma/mirrorlist"),
+        );
+        assert_eq!(redirected, 2);
+        assert!(conf.contains("[core]\nInclude = /tmp/alma/mirrorlist\n"));
+        assert!(conf.contains("[extra]\nInclude = /tmp/alma/mirrorlist\n"));
+        assert!(conf.contains("Include = /etc/pacman.d/custom\n"));
+    }
+}
@@ -0,0 +1,101 @@
+//! Templating of /etc/motd and /etc/issue, so deployed systems identify the image they come from.
+
+use anyhow::Context;
+use std::fs;
+use std::path::Path;
+
+/// Facts about the build available to the templates as {{name}}
+#[derive(Debug, Clone)]
+pub struct BuildInfo {
+    pub image_name: String,
+    /// Seconds since the Unix epoch
+    pub build_time: u64,
+    /// SHA-256 of manifest.json
+    pub manifest_hash: String,
+}
+
+/// Reads the template from a file if the argument names one, otherwise the argument is the
+/// template itself
+pub fn load_template(arg: &str) -> anyhow::Result<String> {
+    let path = Path::new(arg);
+    if path.is_file() {
+        return fs::read_to_string(path)
+            .with_context(|| format!("Failed to read the template {}", path.display()));
+    }
+    Ok(arg.to_owned())
+}
+
+/// Replaces the {{image_name}}, {{build_date}}, {{manifest_hash}}, {{manifest_hash_short}} and
+/// {{alma_version}} placeholders. Anything else, like the agetty escapes of /etc/issue, is kept.
+pub fn render(template: &str, info: &BuildInfo) -> String {
+    let mut rendered = template
+        .replace("{{image_name}}", &info.image_name)
+        .replace("{{build_date}}", &format_date(info.build_time))
+        .replace("{{manifest_hash}}", &info.manifest_hash)
+        .replace(
+            "{{manifest_hash_short}}",
+            &info.manifest_hash[..info.manifest_hash.len().min(12)],
+        )
+        .replace("{{alma_version}}", env!("CARGO_PKG_VERSION"));
+    if !rendered.ends_with('\n') {
+        rendered.push('\n');
+    }
+    rendered
+}
+
+/// Formats a Unix timestamp as "YYYY-MM-DD HH:MM UTC"
+fn format_date(timestamp: u64) -> String {
+    let (year, month, day) = civil_date(timestamp);
+    let seconds = timestamp % 86400;
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02} UTC",
+        seconds / 3600,
+        seconds % 3600 / 60
+    )
+}
+
+/// The (year, month, day) of a Unix timestamp, in UTC
+pub fn civil_date(timestamp: u64) -> (i64, i64, i64) {
+    let days = (timestamp / 86400) as i64;
+
+    // Civil date from days since 1970-01-01, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_date() {
+        assert_eq!(format_date(0), "1970-01-01 00:00 UTC");
+        assert_eq!(format_date(315_532_800), "1980-01-01 00:00 UTC");
+        assert_eq!(format_date(1_709_210_096), "2024-02-29 12:34 UTC");
+    }
+
+    #[test]
+    fn test_render() {
+        let info = BuildInfo {
+            image_name: String::from("kiosk"),
+            build_time: 0,
+            manifest_hash: String::from("0123456789abcdef0123"),
+        };
+        assert_eq!(
+            render(
+                "\\S{PRETTY_NAME} \\l\n{{image_name}} built {{build_date}} ({{manifest_hash_short}})",
+                &info
+            ),
+            "\\S{PRETTY_NAME} \\l\nkiosk built 1970-01-01 00:00 UTC (0123456789ab)\n"
+        );
+    }
+}
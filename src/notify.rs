@@ -0,0 +1,144 @@
+//! Notifications about the progress of `alma create`, for long unattended builds.
+
+use crate::args::Phase;
+use crate::plan::{self, Action};
+use clap::ValueEnum;
+use log::{debug, warn};
+use std::env;
+use std::process::Command;
+
+/// What happened, passed to --notify-cmd as $ALMA_EVENT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Phase,
+    Success,
+    Failure,
+}
+
+impl Event {
+    fn name(self) -> &'static str {
+        match self {
+            Event::Phase => "phase",
+            Event::Success => "success",
+            Event::Failure => "failure",
+        }
+    }
+}
+
+pub struct Notifier {
+    command: Option<String>,
+    desktop: bool,
+    target: String,
+    dryrun: bool,
+}
+
+impl Notifier {
+    pub fn new(command: Option<String>, desktop: bool, target: String, dryrun: bool) -> Self {
+        Self {
+            command,
+            desktop,
+            target,
+            dryrun,
+        }
+    }
+
+    /// A phase of the installation starts
+    pub fn phase(&self, phase: Phase) {
+        let name = phase_name(phase);
+        self.notify(
+            Event::Phase,
+            Some(&name),
+            &format!("{}: running the {name} phase", self.target),
+        );
+    }
+
+    pub fn success(&self) {
+        self.notify(
+            Event::Success,
+            None,
+            &format!("{}: installation complete", self.target),
+        );
+    }
+
+    pub fn failure(&self, err: &anyhow::Error) {
+        self.notify(
+            Event::Failure,
+            None,
+            &format!("{}: installation failed: {err}", self.target),
+        );
+    }
+
+    fn notify(&self, event: Event, phase: Option<&str>, message: &str) {
+        if let Some(notify_cmd) = &self.command {
+            let mut command = Command::new("sh");
+            command
+                .arg("-c")
+                .arg(notify_cmd)
+                .env("ALMA_EVENT", event.name())
+                .env("ALMA_PHASE", phase.unwrap_or(""))
+                .env("ALMA_MESSAGE", message)
+                .env("ALMA_TARGET", &self.target);
+            self.run(command);
+        }
+
+        // Phase transitions would flood the desktop
+        if self.desktop && event != Event::Phase {
+            let urgency = if event == Event::Failure {
+                "critical"
+            } else {
+                "normal"
+            };
+            self.run(desktop_notification(urgency, message));
+        }
+    }
+
+    /// Notifications are best effort, they never interrupt the installation
+    fn run(&self, mut command: Command) {
+        debug!("Running notification command: {command:?}");
+        if self.dryrun {
+            plan::print(Action::command(&command));
+            return;
+        }
+        match command.status() {
+            Ok(status) if status.success() => (),
+            Ok(status) => warn!("Notification command failed: {status}"),
+            Err(e) => warn!("Unable to run the notification command: {e}"),
+        }
+    }
+}
+
+fn phase_name(phase: Phase) -> String {
+    phase
+        .to_possible_value()
+        .map_or_else(String::new, |v| v.get_name().to_owned())
+}
+
+/// ALMA runs as root, so the notification is sent to the session of the user who ran sudo
+fn desktop_notification(urgency: &str, message: &str) -> Command {
+    let mut command = match (env::var("SUDO_USER"), env::var("SUDO_UID")) {
+        (Ok(user), Ok(uid)) => {
+            let mut command = Command::new("sudo");
+            command
+                .args(["-u", &user, "env"])
+                .arg(format!(
+                    "DBUS_SESSION_BUS_ADDRESS=unix:path=/run/user/{uid}/bus"
+                ))
+                .arg("notify-send");
+            command
+        }
+        _ => Command::new("notify-send"),
+    };
+    command.args(["--app-name=ALMA", "--urgency", urgency, "ALMA", message]);
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phase_name() {
+        assert_eq!(phase_name(Phase::Bootloader), "bootloader");
+        assert_eq!(Event::Failure.name(), "failure");
+    }
+}
@@ -0,0 +1,280 @@
+//! Declarative patching of the Omarchy install scripts, see omarchy_patches.toml.
+
+use crate::tool::Version;
+use anyhow::{Context, anyhow};
+use regex::Regex;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const BUILTIN_RULES: &str = include_str!("omarchy_patches.toml");
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct RuleFile {
+    rule: Vec<RawRule>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct RawRule {
+    name: String,
+    #[serde(default)]
+    description: Option<String>,
+    files: String,
+    pattern: String,
+    #[serde(default)]
+    replacement: String,
+    #[serde(default)]
+    delete_line: bool,
+    min_version: Option<String>,
+    max_version: Option<String>,
+    aur_helper: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct PatchRule {
+    pub name: String,
+    pub description: Option<String>,
+    files: String,
+    pattern: Regex,
+    replacement: String,
+    delete_line: bool,
+    min_version: Option<Version>,
+    max_version: Option<Version>,
+    aur_helper: Option<String>,
+}
+
+impl TryFrom<RawRule> for PatchRule {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawRule) -> anyhow::Result<Self> {
+        let context = || format!("Invalid Omarchy patch rule '{}'", raw.name);
+        Ok(Self {
+            pattern: Regex::new(&raw.pattern).with_context(context)?,
+            min_version: raw
+                .min_version
+                .as_deref()
+                .map(str::parse)
+                .transpose()
+                .with_context(context)?,
+            max_version: raw
+                .max_version
+                .as_deref()
+                .map(str::parse)
+                .transpose()
+                .with_context(context)?,
+            name: raw.name,
+            description: raw.description,
+            files: raw.files,
+            replacement: raw.replacement,
+            delete_line: raw.delete_line,
+            aur_helper: raw.aur_helper,
+        })
+    }
+}
+
+impl PatchRule {
+    /// Whether the rule applies to this Omarchy release and AUR helper. Version ranges are
+    /// ignored if the release is unknown.
+    fn applies_to(&self, version: Option<&Version>, aur_helper: &str) -> bool {
+        if let Some(helper) = &self.aur_helper
+            && helper != aur_helper
+        {
+            return false;
+        }
+
+        let Some(version) = version else {
+            return true;
+        };
+        self.min_version.as_ref().is_none_or(|min| version >= min)
+            && self.max_version.as_ref().is_none_or(|max| version < max)
+    }
+
+    fn matches_file(&self, relative_path: &Path) -> bool {
+        match self.files.strip_prefix('*') {
+            Some(suffix) => relative_path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().ends_with(suffix)),
+            None => relative_path == Path::new(&self.files),
+        }
+    }
+
+    /// Patches the contents of a file, returning the number of changed lines
+    fn patch(&self, contents: &mut String) -> usize {
+        let mut changed = 0;
+        let mut output = String::with_capacity(contents.len());
+        for line in contents.split_inclusive('\n') {
+            let (text, newline) = match line.strip_suffix('\n') {
+                Some(text) => (text, "\n"),
+                None => (line, ""),
+            };
+            if !self.pattern.is_match(text) {
+                output.push_str(line);
+                continue;
+            }
+
+            changed += 1;
+            if !self.delete_line {
+                output.push_str(&self.pattern.replace_all(text, self.replacement.as_str()));
+                output.push_str(newline);
+            }
+        }
+        *contents = output;
+        changed
+    }
+}
+
+/// Loads the rules shipped with ALMA, followed by the rules of an optional extra file
+pub fn load_rules(extra: Option<&Path>) -> anyhow::Result<Vec<PatchRule>> {
+    let mut raw = toml::from_str::<RuleFile>(BUILTIN_RULES)
+        .context("Invalid built-in Omarchy patch rules")?
+        .rule;
+    if let Some(path) = extra {
+        let data = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        raw.extend(
+            toml::from_str::<RuleFile>(&data)
+                .with_context(|| format!("Invalid Omarchy patch rules in {}", path.display()))?
+                .rule,
+        );
+    }
+
+    raw.into_iter().map(PatchRule::try_from).collect()
+}
+
+/// Reads the release of an Omarchy checkout from its version file
+pub fn omarchy_version(omarchy_dir: &Path) -> Option<Version> {
+    fs::read_to_string(omarchy_dir.join("version"))
+        .ok()?
+        .trim()
+        .trim_start_matches('v')
+        .parse()
+        .ok()
+}
+
+/// How often a rule fired
+#[derive(Debug, PartialEq, Eq)]
+pub struct PatchReport {
+    pub rule: String,
+    pub files: usize,
+    pub lines: usize,
+}
+
+/// Applies the rules to every file below `omarchy_dir`
+pub fn apply(
+    rules: &[PatchRule],
+    omarchy_dir: &Path,
+    version: Option<&Version>,
+    aur_helper: &str,
+) -> anyhow::Result<Vec<PatchReport>> {
+    let rules: Vec<&PatchRule> = rules
+        .iter()
+        .filter(|r| r.applies_to(version, aur_helper))
+        .collect();
+    let mut reports: Vec<PatchReport> = rules
+        .iter()
+        .map(|r| PatchReport {
+            rule: r.name.clone(),
+            files: 0,
+            lines: 0,
+        })
+        .collect();
+
+    let mut files = Vec::new();
+    collect_files(omarchy_dir, &mut files)
+        .with_context(|| format!("Failed to list {}", omarchy_dir.display()))?;
+    files.sort();
+
+    for path in files {
+        let relative = path
+            .strip_prefix(omarchy_dir)
+            .map_err(|_| anyhow!("{} is outside of the Omarchy directory", path.display()))?;
+        let matching: Vec<usize> = (0..rules.len())
+            .filter(|&i| rules[i].matches_file(relative))
+            .collect();
+        if matching.is_empty() {
+            continue;
+        }
+
+        let Ok(mut contents) = fs::read_to_string(&path) else {
+            continue;
+        };
+        let mut modified = false;
+        for i in matching {
+            let lines = rules[i].patch(&mut contents);
+            if lines > 0 {
+                reports[i].files += 1;
+                reports[i].lines += lines;
+                modified = true;
+            }
+        }
+        if modified {
+            fs::write(&path, contents)
+                .with_context(|| format!("Failed to patch {}", path.display()))?;
+        }
+    }
+
+    Ok(reports)
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(name: &str) -> PatchRule {
+        load_rules(None)
+            .unwrap()
+            .into_iter()
+            .find(|r| r.name == name)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_builtin_rules() {
+        let mut script = String::from("sudo systemctl enable --now bluetooth\nreboot\n");
+        assert_eq!(rule("systemctl-no-now").patch(&mut script), 1);
+        assert_eq!(rule("no-reboot").patch(&mut script), 1);
+        assert_eq!(
+            script,
+            "sudo systemctl enable bluetooth\n# reboot (disabled in chroot)\n"
+        );
+    }
+
+    #[test]
+    fn test_delete_line_and_conditions() {
+        let skip_yay = rule("skip-yay");
+        let mut packages = String::from("yay -S --needed \\\n  yay \\\n  zoxide\n");
+        assert_eq!(skip_yay.patch(&mut packages), 1);
+        assert_eq!(packages, "yay -S --needed \\\n  zoxide\n");
+
+        assert!(skip_yay.applies_to(None, "yay"));
+        assert!(!skip_yay.applies_to(None, "paru"));
+        assert!(skip_yay.matches_file(Path::new("install/packages.sh")));
+        assert!(!skip_yay.matches_file(Path::new("install/other.sh")));
+    }
+
+    #[test]
+    fn test_version_range() {
+        let mut rule = rule("no-reboot");
+        rule.min_version = Some("2.0".parse().unwrap());
+        rule.max_version = Some("3.0".parse().unwrap());
+        let v = |s: &str| s.parse::<Version>().unwrap();
+        assert!(rule.applies_to(Some(&v("2.1.0")), "paru"));
+        assert!(!rule.applies_to(Some(&v("3.0")), "paru"));
+        assert!(!rule.applies_to(Some(&v("1.9")), "paru"));
+        assert!(rule.applies_to(None, "paru"));
+    }
+}
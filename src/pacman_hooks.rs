@@ -0,0 +1,169 @@
+use crate::arch::Arch;
+use crate::args::GrubTargets;
+
+/// Pacman hooks which keep an ALMA system bootable on any hardware across upgrades.
+pub struct PacmanHooks {
+    targets: GrubTargets,
+    shim: bool,
+    mkinitcpio: bool,
+}
+
+pub const HOOKS_DIR: &str = "etc/pacman.d/hooks";
+pub const SCRIPTS_DIR: &str = "usr/share/alma/hooks";
+/// Reference copy of the mkinitcpio.conf generated by ALMA
+pub const MKINITCPIO_CONF_COPY: &str = "usr/share/alma/mkinitcpio.conf";
+
+impl PacmanHooks {
+    /// The initramfs hook only applies to mkinitcpio, dracut installs get their own hook
+    /// regardless of --pacman-hooks
+    pub fn new(targets: GrubTargets, shim: bool, mkinitcpio: bool) -> Self {
+        Self {
+            targets,
+            shim,
+            mkinitcpio,
+        }
+    }
+
+    /// Returns the (file name, contents) pairs of the hooks to place in /etc/pacman.d/hooks
+    pub fn hooks(&self) -> Vec<(&'static str, String)> {
+        let mut bootloader_targets = String::from("Target = grub\n");
+        if self.shim {
+            bootloader_targets.push_str("Target = shim-signed\n");
+        }
+
+        let mut hooks = vec![(
+            "95-alma-bootloader.hook",
+            format!(
+                "[Trigger]
+Type = Package
+Operation = Upgrade
+{bootloader_targets}
+[Action]
+Description = Refreshing removable GRUB installation (ALMA)...
+When = PostTransaction
+Exec = /{SCRIPTS_DIR}/bootloader.sh
+"
+            ),
+        )];
+
+        if self.mkinitcpio {
+            hooks.push((
+                // Runs after 90-mkinitcpio-install.hook
+                "95-alma-initramfs.hook",
+                format!(
+                    "[Trigger]
+Type = Package
+Operation = Install
+Operation = Upgrade
+Target = mkinitcpio
+Target = linux*
+
+[Action]
+Description = Ensuring initramfs images are built without autodetect (ALMA)...
+When = PostTransaction
+Exec = /{SCRIPTS_DIR}/initramfs.sh
+"
+                ),
+            ));
+        }
+
+        hooks
+    }
+
+    /// Returns the (file name, contents) pairs of the scripts executed by the hooks
+    pub fn scripts(&self) -> Vec<(&'static str, String)> {
+        let initramfs = format!(
+            r#"#!/bin/sh
+# Restores ALMA's mkinitcpio.conf if a merged .pacnew reintroduced the autodetect hook,
+# since autodetect only includes modules for the hardware the image was last updated on.
+reference=/{MKINITCPIO_CONF_COPY}
+[ -f "$reference" ] || exit 0
+if grep -q '^HOOKS=.*autodetect' /etc/mkinitcpio.conf && ! grep -q '^HOOKS=.*autodetect' "$reference"; then
+  echo "==> WARNING: autodetect found in /etc/mkinitcpio.conf, restoring ALMA configuration"
+  cp /etc/mkinitcpio.conf /etc/mkinitcpio.conf.alma-bak
+  cp "$reference" /etc/mkinitcpio.conf
+  mkinitcpio -P
+fi
+"#
+        );
+
+        let mut bootloader = String::from("#!/bin/sh\nset -e\n");
+        if self.targets.bios() {
+            bootloader.push_str(
+                r#"disk="/dev/$(lsblk -no PKNAME "$(findmnt -no SOURCE /boot)")"
+grub-install --target=i386-pc --boot-directory /boot "$disk" || echo "==> WARNING: BIOS GRUB installation failed"
+"#,
+            );
+        }
+        if self.targets.efi() {
+            bootloader.push_str(&format!(
+                "grub-install --target={} --efi-directory /boot --boot-directory /boot --removable\n",
+                Arch::target().grub_efi_target()
+            ));
+        }
+        if self.shim {
+            bootloader.push_str(
+                "mv /boot/EFI/BOOT/BOOTX64.efi /boot/EFI/BOOT/grubx64.efi
+cp /usr/share/shim-signed/mmx64.efi /boot/EFI/BOOT/mmx64.efi
+cp /usr/share/shim-signed/shimx64.efi /boot/EFI/BOOT/BOOTX64.efi
+",
+            );
+        }
+        bootloader.push_str("grub-mkconfig -o /boot/grub/grub.cfg\n");
+
+        let mut scripts = vec![("bootloader.sh", bootloader)];
+        if self.mkinitcpio {
+            scripts.push(("initramfs.sh", initramfs));
+        }
+        scripts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hooks() {
+        let hooks = PacmanHooks::new(GrubTargets::Both, true, true);
+        let rendered = hooks.hooks();
+        assert_eq!(rendered[0].0, "95-alma-bootloader.hook");
+        assert_eq!(
+            rendered[0].1,
+            "[Trigger]
+Type = Package
+Operation = Upgrade
+Target = grub
+Target = shim-signed
+
+[Action]
+Description = Refreshing removable GRUB installation (ALMA)...
+When = PostTransaction
+Exec = /usr/share/alma/hooks/bootloader.sh
+"
+        );
+        assert_eq!(rendered[1].0, "95-alma-initramfs.hook");
+        assert!(
+            rendered[1]
+                .1
+                .ends_with("Exec = /usr/share/alma/hooks/initramfs.sh\n")
+        );
+
+        let scripts = hooks.scripts();
+        assert_eq!(scripts.len(), 2);
+        let bootloader = &scripts[0].1;
+        assert!(bootloader.contains("grub-install --target=i386-pc"));
+        assert!(bootloader.contains("mv /boot/EFI/BOOT/BOOTX64.efi /boot/EFI/BOOT/grubx64.efi\n"));
+        assert!(bootloader.ends_with("grub-mkconfig -o /boot/grub/grub.cfg\n"));
+
+        // Without shim only grub triggers a reinstall, and dracut installs skip the initramfs hook
+        let hooks = PacmanHooks::new(GrubTargets::Efi, false, false);
+        let rendered = hooks.hooks();
+        assert_eq!(rendered.len(), 1);
+        assert!(!rendered[0].1.contains("shim-signed"));
+        let scripts = hooks.scripts();
+        assert_eq!(scripts.len(), 1);
+        assert!(!scripts[0].1.contains("i386-pc"));
+        assert!(!scripts[0].1.contains("BOOTX64"));
+    }
+}
@@ -0,0 +1,362 @@
+//! The plan of a dry run: each command ALMA would run and each change it would make, in order.
+//! They are printed as shell commands, or as JSON objects with `--output json` so wrappers can
+//! diff plans, and `--dryrun-script` also writes them to a shell script, which is why every word
+//! is quoted for the shell.
+
+use anyhow::Context;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const SCRIPT_HEADER: &str = "#!/bin/bash
+# Written by alma create --dryrun-script. Values only known while building, such as the loop
+# device (/dev/loop1337) and the UUIDs (00000000-0000-0000-0000-000000000000), are placeholders.
+set -euxo pipefail
+";
+
+/// A step of a dry run
+#[derive(Debug, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    Command {
+        argv: Vec<String>,
+        /// The environment variables set for the command
+        #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+        env: BTreeMap<String, String>,
+        /// The file its output is written to
+        #[serde(skip_serializing_if = "Option::is_none")]
+        stdout: Option<PathBuf>,
+    },
+    Write {
+        path: PathBuf,
+        append: bool,
+        /// The contents, unless they are binary
+        contents: Option<String>,
+        sha256: String,
+        #[serde(skip)]
+        data: Vec<u8>,
+    },
+    Copy {
+        from: PathBuf,
+        to: PathBuf,
+        recursive: bool,
+    },
+    Mkdir {
+        path: PathBuf,
+    },
+    Remove {
+        path: PathBuf,
+        recursive: bool,
+    },
+    Rmdir {
+        path: PathBuf,
+    },
+    Rename {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    Chmod {
+        path: PathBuf,
+        /// In octal
+        mode: String,
+    },
+    Mount {
+        source: PathBuf,
+        target: PathBuf,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        fstype: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        options: Option<String>,
+        bind: bool,
+    },
+    Umount {
+        target: PathBuf,
+    },
+    /// A step which has no command, like a download
+    Note {
+        text: String,
+    },
+}
+
+impl Action {
+    pub fn command(command: &Command) -> Self {
+        Self::Command {
+            argv: std::iter::once(command.get_program())
+                .chain(command.get_args())
+                .map(|word| word.to_string_lossy().into_owned())
+                .collect(),
+            env: command
+                .get_envs()
+                .filter_map(|(name, value)| {
+                    Some((
+                        name.to_string_lossy().into_owned(),
+                        value?.to_string_lossy().into_owned(),
+                    ))
+                })
+                .collect(),
+            stdout: None,
+        }
+    }
+
+    pub fn write(path: &Path, data: &[u8], append: bool) -> Self {
+        Self::Write {
+            path: path.to_path_buf(),
+            append,
+            contents: std::str::from_utf8(data).ok().map(String::from),
+            sha256: format!("{:x}", Sha256::digest(data)),
+            data: data.to_vec(),
+        }
+    }
+
+    /// The shell command taking the step
+    pub fn to_shell(&self) -> String {
+        match self {
+            Self::Command { argv, env, stdout } => {
+                let mut words: Vec<String> = env
+                    .iter()
+                    .map(|(name, value)| format!("{name}={}", quote(value)))
+                    .chain(argv.iter().map(|word| quote(word).into_owned()))
+                    .collect();
+                if let Some(stdout) = stdout {
+                    words.extend([String::from(">"), quote_path(stdout).into_owned()]);
+                }
+                words.join(" ")
+            }
+            Self::Write {
+                path, append, data, ..
+            } => heredoc(if *append { ">>" } else { ">" }, path, data),
+            Self::Copy {
+                from,
+                to,
+                recursive,
+            } => format!(
+                "cp {}{} {}",
+                if *recursive { "-r " } else { "" },
+                quote_path(from),
+                quote_path(to)
+            ),
+            Self::Mkdir { path } => format!("mkdir -p {}", quote_path(path)),
+            Self::Remove { path, recursive } => format!(
+                "rm {}{}",
+                if *recursive { "-r " } else { "" },
+                quote_path(path)
+            ),
+            Self::Rmdir { path } => format!("rmdir {}", quote_path(path)),
+            Self::Rename { from, to } => format!("mv {} {}", quote_path(from), quote_path(to)),
+            Self::Chmod { path, mode } => format!("chmod {mode} {}", quote_path(path)),
+            Self::Mount {
+                source,
+                target,
+                fstype,
+                options,
+                bind,
+            } => {
+                let mut words = vec![String::from("mount")];
+                if *bind {
+                    words.push(String::from("--bind"));
+                }
+                if let Some(fstype) = fstype {
+                    words.extend([String::from("-t"), quote(fstype).into_owned()]);
+                }
+                if let Some(options) = options {
+                    words.extend([String::from("-o"), quote(options).into_owned()]);
+                }
+                words.extend([
+                    quote_path(source).into_owned(),
+                    quote_path(target).into_owned(),
+                ]);
+                words.join(" ")
+            }
+            Self::Umount { target } => format!("umount {}", quote_path(target)),
+            Self::Note { text } => format!("# {text}"),
+        }
+    }
+}
+
+struct Script {
+    path: PathBuf,
+    /// Unbuffered, so the script is complete up to the failure when the build fails early
+    file: fs::File,
+    /// The first failed write, reported when the script is finished
+    error: Option<io::Error>,
+}
+
+static SCRIPT: Mutex<Option<Script>> = Mutex::new(None);
+
+/// Whether the steps are printed as JSON
+static JSON: AtomicBool = AtomicBool::new(false);
+
+pub fn print_json(json: bool) {
+    JSON.store(json, Ordering::Relaxed);
+}
+
+/// Starts writing the steps of the dry run to an executable script at `path`
+pub fn start_script(path: &Path) -> anyhow::Result<()> {
+    let mut file =
+        fs::File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    file.write_all(SCRIPT_HEADER.as_bytes())
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))
+        .with_context(|| format!("Failed to make {} executable", path.display()))?;
+    *SCRIPT.lock().unwrap_or_else(|e| e.into_inner()) = Some(Script {
+        path: path.to_path_buf(),
+        file,
+        error: None,
+    });
+    Ok(())
+}
+
+/// Closes the script, returning its path if one was written
+pub fn finish_script() -> anyhow::Result<Option<PathBuf>> {
+    let Some(script) = SCRIPT.lock().unwrap_or_else(|e| e.into_inner()).take() else {
+        return Ok(None);
+    };
+    if let Some(error) = script.error {
+        return Err(error).with_context(|| format!("Failed to write {}", script.path.display()));
+    }
+    Ok(Some(script.path))
+}
+
+/// Prints a step of the dry run and adds it to the script
+pub fn print(action: Action) {
+    let shell = action.to_shell();
+    if JSON.load(Ordering::Relaxed) {
+        println!(
+            "{}",
+            serde_json::to_string(&action).expect("Actions serialize to JSON")
+        );
+    } else {
+        println!("{shell}");
+    }
+    if let Some(script) = SCRIPT.lock().unwrap_or_else(|e| e.into_inner()).as_mut()
+        && script.error.is_none()
+        && let Err(error) = writeln!(script.file, "{shell}")
+    {
+        script.error = Some(error);
+    }
+}
+
+/// Quotes a word for the shell, unless it is made of characters which never need it
+pub fn quote(word: &str) -> Cow<'_, str> {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "_-+=%@:,./".contains(c);
+    if !word.is_empty() && word.chars().all(plain) {
+        Cow::Borrowed(word)
+    } else {
+        Cow::Owned(format!("'{}'", word.replace('\'', r"'\''")))
+    }
+}
+
+pub fn quote_path(path: &Path) -> Cow<'_, str> {
+    match path.to_string_lossy() {
+        Cow::Borrowed(path) => quote(path),
+        Cow::Owned(path) => Cow::Owned(quote(&path).into_owned()),
+    }
+}
+
+/// `cat` writing `contents` to the file from a here-document, or `printf` for binary contents
+fn heredoc(redirect: &str, path: &Path, contents: &[u8]) -> String {
+    let path = quote_path(path);
+    let Ok(text) = std::str::from_utf8(contents) else {
+        let escaped: String = contents.iter().map(|b| format!("\\{b:03o}")).collect();
+        return format!("printf '{escaped}' {redirect} {path}");
+    };
+    let newline = if text.is_empty() || text.ends_with('\n') {
+        ""
+    } else {
+        "\n"
+    };
+    // A delimiter which is not a line of the text
+    let delimiter = (0..)
+        .map(|i| match i {
+            0 => String::from("EOF"),
+            i => format!("EOF{i}"),
+        })
+        .find(|delimiter| !text.lines().any(|line| line == delimiter))
+        .expect("Some delimiter is free");
+    format!("cat {redirect} {path} <<'{delimiter}'\n{text}{newline}{delimiter}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heredoc() {
+        let path = Path::new("/mnt/etc/locale.conf");
+        assert_eq!(
+            heredoc(">", path, b"LANG=en_US.UTF-8"),
+            "cat > /mnt/etc/locale.conf <<'EOF'\nLANG=en_US.UTF-8\nEOF"
+        );
+        assert_eq!(
+            heredoc(">>", path, b"a\nb\n"),
+            "cat >> /mnt/etc/locale.conf <<'EOF'\na\nb\nEOF"
+        );
+        assert_eq!(
+            heredoc(">", path, &[0xff, 0x00]),
+            r"printf '\377\000' > /mnt/etc/locale.conf"
+        );
+        assert_eq!(
+            heredoc(">", Path::new("/mnt/root/my notes"), b"EOF\n"),
+            "cat > '/mnt/root/my notes' <<'EOF1'\nEOF\nEOF1"
+        );
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("/dev/loop0p2"), "/dev/loop0p2");
+        assert_eq!(quote("noatime,compress=zstd"), "noatime,compress=zstd");
+        assert_eq!(quote(""), "''");
+        assert_eq!(
+            quote("cd /home/aur && makepkg"),
+            "'cd /home/aur && makepkg'"
+        );
+        assert_eq!(quote("it's"), r"'it'\''s'");
+    }
+
+    #[test]
+    fn test_actions() {
+        let mut command = Command::new("mkfs.ext4");
+        command
+            .args(["-L", "ALMA Root", "/dev/loop1337p3"])
+            .env("E2FSPROGS_FAKE_TIME", "0");
+        let action = Action::command(&command);
+        assert_eq!(
+            action.to_shell(),
+            "E2FSPROGS_FAKE_TIME=0 mkfs.ext4 -L 'ALMA Root' /dev/loop1337p3"
+        );
+        assert_eq!(
+            serde_json::to_value(&action).unwrap(),
+            serde_json::json!({
+                "action": "command",
+                "argv": ["mkfs.ext4", "-L", "ALMA Root", "/dev/loop1337p3"],
+                "env": {"E2FSPROGS_FAKE_TIME": "0"}
+            })
+        );
+
+        let action = Action::write(Path::new("/mnt/etc/hostname"), b"alma\n", false);
+        let json = serde_json::to_value(&action).unwrap();
+        assert_eq!(json["action"], "write");
+        assert_eq!(json["contents"], "alma\n");
+        assert!(json.get("data").is_none());
+
+        let action = Action::Mount {
+            source: PathBuf::from("/dev/mapper/alma_root"),
+            target: PathBuf::from("/tmp/alma-x1Yz"),
+            fstype: Some(String::from("btrfs")),
+            options: Some(String::from("noatime,subvol=@")),
+            bind: false,
+        };
+        assert_eq!(
+            action.to_shell(),
+            "mount -t btrfs -o noatime,subvol=@ /dev/mapper/alma_root /tmp/alma-x1Yz"
+        );
+    }
+}
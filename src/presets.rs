@@ -1,6 +1,11 @@
+use crate::aur::AurBatch;
+use crate::error::{ErrorKind, ErrorKindExt};
+use crate::initcpio::InitcpioOptions;
+use crate::intercept::Interception;
 use anyhow::{Context, anyhow};
 use either::Either;
 use flate2::read::GzDecoder;
+use log::warn;
 use reqwest::Url;
 use serde::Deserialize;
 use std::collections::HashSet;
@@ -91,8 +96,11 @@ impl PresetsPath {
             }
             // If url archive then download with reqwest and extract to tmpfile dir
             PresetsPath::UrlArchive(u, archive_type) => {
-                let resp = reqwest::blocking::Client::new().get(u).send()?;
-                let bytes = resp.bytes()?;
+                let resp = reqwest::blocking::Client::new()
+                    .get(u)
+                    .send()
+                    .kind(ErrorKind::Network)?;
+                let bytes = resp.bytes().kind(ErrorKind::Network)?;
                 let tmpdir = tempfile::tempdir()?;
 
                 archive_type.extract_to_dir(Either::Right(bytes), tmpdir.path())?;
@@ -101,7 +109,9 @@ impl PresetsPath {
             // If git then clone to tmpfile dir
             PresetsPath::GitHttp(u) => {
                 let tmpdir = tempfile::tempdir()?;
-                git2::Repository::clone(u.as_str(), tmpdir.path())?;
+                shallow_clone(git2::FetchOptions::new())
+                    .clone(u.as_str(), tmpdir.path())
+                    .kind(ErrorKind::Network)?;
                 Ok(PathWrapper::Tmp(tmpdir))
             }
             PresetsPath::GitSSH(u) => {
@@ -174,13 +184,11 @@ impl PresetsPath {
                 let mut fo = git2::FetchOptions::new();
                 fo.remote_callbacks(callbacks);
 
-                // Prepare builder.
-                let mut builder = git2::build::RepoBuilder::new();
-                builder.fetch_options(fo);
-
                 let tmpdir = tempfile::tempdir()?;
                 // Clone the project.
-                builder.clone(u.as_str(), tmpdir.path())?;
+                shallow_clone(fo)
+                    .clone(u.as_str(), tmpdir.path())
+                    .kind(ErrorKind::Network)?;
 
                 Ok(PathWrapper::Tmp(tmpdir))
             }
@@ -188,6 +196,22 @@ impl PresetsPath {
     }
 }
 
+/// A builder cloning only the latest commit, as the presets are read once and never updated.
+/// libgit2 supports neither single branch clones nor partial clone filters.
+fn shallow_clone(mut fetch_options: git2::FetchOptions<'_>) -> git2::build::RepoBuilder<'_> {
+    fetch_options.depth(1);
+    let mut builder = git2::build::RepoBuilder::new();
+    builder.fetch_options(fetch_options);
+    builder
+}
+
+/// The commit checked out in the git repository at `path`, if it is one
+pub fn git_commit(path: &Path) -> Option<String> {
+    let repository = git2::Repository::open(path).ok()?;
+    let commit = repository.head().ok()?.peel_to_commit().ok()?;
+    Some(commit.id().to_string())
+}
+
 impl std::str::FromStr for PresetsPath {
     type Err = String;
 
@@ -252,6 +276,10 @@ struct Preset {
     environment_variables: Option<Vec<String>>,
     shared_directories: Option<Vec<PathBuf>>,
     aur_packages: Option<Vec<String>>,
+    mkinitcpio: Option<InitcpioOptions>,
+    intercept: Option<Vec<Interception>>,
+    nodatacow: Option<Vec<PathBuf>>,
+    mount_options: Option<String>,
 }
 
 fn visit_dirs(dir: &Path, filevec: &mut Vec<PathBuf>) -> Result<(), io::Error> {
@@ -275,20 +303,67 @@ impl Preset {
         toml::from_str(&data).with_context(|| format!("{}", path.display()))
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn process(
         &self,
         packages: &mut HashSet<String>,
         scripts: &mut Vec<Script>,
         environment_variables: &mut HashSet<String>,
         path: &Path,
-        aur_packages: &mut HashSet<String>,
+        aur_packages: &mut Vec<AurBatch>,
+        initcpio: &mut InitcpioOptions,
+        intercepts: &mut Vec<Interception>,
+        nodatacow: &mut Vec<PathBuf>,
+        mount_options: &mut Option<String>,
     ) -> anyhow::Result<()> {
         if let Some(preset_packages) = &self.packages {
-            packages.extend(preset_packages.clone());
+            for package in preset_packages {
+                if !packages.insert(package.clone()) {
+                    warn!(
+                        "Preset: {} - {package} is already installed by an earlier preset",
+                        path.display()
+                    );
+                }
+            }
+        }
+
+        if let Some(preset_initcpio) = &self.mkinitcpio {
+            initcpio.merge(preset_initcpio);
+        }
+
+        if let Some(preset_intercepts) = &self.intercept {
+            for interception in preset_intercepts {
+                if !intercepts.contains(interception) {
+                    intercepts.push(interception.clone());
+                }
+            }
+        }
+
+        if let Some(preset_nodatacow) = &self.nodatacow {
+            for dir in preset_nodatacow {
+                if !dir.is_absolute() {
+                    return Err(anyhow!(
+                        "Preset: {} - nodatacow directory: {} is not an absolute path",
+                        path.display(),
+                        dir.display()
+                    ));
+                }
+                if !nodatacow.contains(dir) {
+                    nodatacow.push(dir.clone());
+                }
+            }
+        }
+
+        // Later presets override the mount options of earlier ones
+        if let Some(preset_mount_options) = &self.mount_options {
+            *mount_options = Some(preset_mount_options.clone());
         }
 
         if let Some(preset_aur_packages) = &self.aur_packages {
-            aur_packages.extend(preset_aur_packages.clone());
+            aur_packages.push(AurBatch::new(
+                path.display().to_string(),
+                preset_aur_packages.clone(),
+            ));
         }
 
         if let Some(preset_environment_variables) = &self.environment_variables {
@@ -305,9 +380,8 @@ impl Preset {
                         // Convert directories to absolute paths
                         // If any shared directory is not a directory then throw an error
                         x.iter()
-                            .cloned()
                             .map(|y| {
-                                let full_path = path.parent().expect("Path has no parent").join(&y);
+                                let full_path = path.parent().expect("Path has no parent").join(y);
                                 if full_path.is_dir() {
                                     Ok(full_path)
                                 } else {
@@ -334,16 +408,25 @@ pub struct Script {
 
 pub struct PresetsCollection {
     pub packages: HashSet<String>,
-    pub aur_packages: HashSet<String>,
+    /// The AUR packages of each preset, in the order the presets were loaded
+    pub aur_packages: Vec<AurBatch>,
     pub scripts: Vec<Script>,
+    pub initcpio: InitcpioOptions,
+    pub intercepts: Vec<Interception>,
+    pub nodatacow: Vec<PathBuf>,
+    pub mount_options: Option<String>,
 }
 
 impl PresetsCollection {
     pub fn load(list: &[&Path]) -> anyhow::Result<Self> {
         let mut packages = HashSet::new();
-        let mut aur_packages = HashSet::new();
+        let mut aur_packages = Vec::new();
         let mut scripts: Vec<Script> = Vec::new();
         let mut environment_variables = HashSet::new();
+        let mut initcpio = InitcpioOptions::default();
+        let mut intercepts = Vec::new();
+        let mut nodatacow = Vec::new();
+        let mut mount_options = None;
 
         for preset in list {
             if preset.is_dir() {
@@ -365,6 +448,10 @@ impl PresetsCollection {
                         &mut environment_variables,
                         &path,
                         &mut aur_packages,
+                        &mut initcpio,
+                        &mut intercepts,
+                        &mut nodatacow,
+                        &mut mount_options,
                     )?;
                 }
             } else {
@@ -374,6 +461,10 @@ impl PresetsCollection {
                     &mut environment_variables,
                     preset,
                     &mut aur_packages,
+                    &mut initcpio,
+                    &mut intercepts,
+                    &mut nodatacow,
+                    &mut mount_options,
                 )?;
             }
         }
@@ -393,6 +484,10 @@ impl PresetsCollection {
             packages,
             aur_packages,
             scripts,
+            initcpio,
+            intercepts,
+            nodatacow,
+            mount_options,
         })
     }
 }
@@ -418,4 +513,20 @@ mod tests {
             pp.into_path_wrapper(false).unwrap().to_path()
         );
     }
+
+    #[test]
+    fn test_git_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(git_commit(dir.path()), None);
+
+        let repository = git2::Repository::init(dir.path()).unwrap();
+        let signature = git2::Signature::now("ALMA", "alma@example.com").unwrap();
+        let tree = repository
+            .find_tree(repository.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        let commit = repository
+            .commit(Some("HEAD"), &signature, &signature, "Presets", &tree, &[])
+            .unwrap();
+        assert_eq!(git_commit(dir.path()), Some(commit.to_string()));
+    }
 }
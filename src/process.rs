@@ -1,3 +1,5 @@
+use crate::interrupt;
+use crate::plan::{self, Action};
 use anyhow::anyhow;
 use log::{debug, error};
 use std::process::Command;
@@ -10,22 +12,22 @@ pub trait CommandExt {
 
 impl CommandExt for Command {
     fn run(&mut self, dryrun: bool) -> anyhow::Result<()> {
-        let command_string = format!(
-            "{} {}",
-            self.get_program().to_string_lossy(),
-            self.get_args()
-                .map(|x| x.to_string_lossy().to_string())
-                .collect::<Vec<String>>()
-                .join(" ")
-        );
-        debug!("Running command: {command_string}");
+        let action = Action::command(self);
+        debug!("Running command: {}", action.to_shell());
 
         if dryrun {
-            println!("{command_string}");
+            plan::print(action);
             return Ok(());
         }
 
-        let exit_status = self.spawn()?.wait()?;
+        interrupt::check()?;
+        let mut child = self.spawn()?;
+        interrupt::set_child(Some(child.id()));
+        let exit_status = child.wait();
+        interrupt::set_child(None);
+        // The command most likely failed because it got the signal too
+        interrupt::check()?;
+        let exit_status = exit_status?;
 
         if !exit_status.success() {
             return Err(anyhow!("Bad exit code: {}", exit_status));
@@ -35,22 +37,17 @@ impl CommandExt for Command {
     }
 
     fn run_text_output(&mut self, dryrun: bool) -> anyhow::Result<String> {
-        let command_string = format!(
-            "{} {}",
-            self.get_program().to_string_lossy(),
-            self.get_args()
-                .map(|x| x.to_string_lossy().to_string())
-                .collect::<Vec<String>>()
-                .join(" ")
-        );
-        debug!("Running command: {command_string}");
+        let action = Action::command(self);
+        debug!("Running command: {}", action.to_shell());
 
         if dryrun {
-            println!("{command_string}");
+            plan::print(action);
             return Ok(String::from(""));
         }
 
+        interrupt::check()?;
         let output = self.output()?;
+        interrupt::check()?;
 
         if !output.status.success() {
             let error = str::from_utf8(&output.stderr).unwrap_or("[INVALID UTF8]");
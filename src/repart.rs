@@ -0,0 +1,198 @@
+//! systemd-repart definitions of the partition layout, used to partition the disk with
+//! `--partitioner repart` and installed to /etc/repart.d, where systemd-repart picks them up on
+//! every boot.
+
+use crate::arch::Arch;
+use crate::constants::{
+    BIOS_BOOT_PARTITION_INDEX, BIOS_BOOT_PARTITION_TYPE, BOOT_PARTITION_INDEX,
+    DATA_PARTITION_INDEX, DATA_PARTITION_TYPE, ESP_PARTITION_TYPE, RAID_PARTITION_TYPE,
+    ROOT_PARTITION_INDEX,
+};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Where the definitions are installed in the new system
+pub const DEFINITIONS_DIR: &str = "etc/repart.d";
+
+/// The same layout sgdisk creates: ESP, BIOS boot partition, root and an optional data
+/// partition, followed by the free space left at the end of the disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    pub boot_size_mb: u32,
+    pub leave_free_mb: u64,
+    pub data_partition_mb: Option<u64>,
+    /// The root partition is a member of a RAID array
+    pub raid: bool,
+    /// Where the boot partition starts, when the space before it is left to the firmware of a
+    /// --board. systemd-repart cannot leave such space.
+    pub start_mb: Option<u64>,
+    /// The architecture whose root partition type is used
+    pub arch: Arch,
+}
+
+impl Layout {
+    /// The GPT type GUID of each partition, by partition number
+    pub fn partition_types(&self) -> BTreeMap<u8, &'static str> {
+        let mut types = BTreeMap::from([
+            (BOOT_PARTITION_INDEX, ESP_PARTITION_TYPE),
+            (BIOS_BOOT_PARTITION_INDEX, BIOS_BOOT_PARTITION_TYPE),
+            (
+                ROOT_PARTITION_INDEX,
+                if self.raid {
+                    RAID_PARTITION_TYPE
+                } else {
+                    self.arch.root_partition_type()
+                },
+            ),
+        ]);
+        if self.data_partition_mb.is_some() {
+            types.insert(DATA_PARTITION_INDEX, DATA_PARTITION_TYPE);
+        }
+        types
+    }
+
+    /// The definition files as (file name, contents), in partition order. The root partition
+    /// has no maximum size, so it takes the rest of the disk and, when it is the last partition,
+    /// grows on the first boot from a larger disk.
+    pub fn definitions(&self) -> Vec<(String, String)> {
+        let last_partition = if self.data_partition_mb.is_some() {
+            4
+        } else {
+            3
+        };
+        let padding = |partition: u32| {
+            (partition == last_partition && self.leave_free_mb > 0).then_some(self.leave_free_mb)
+        };
+
+        let types = self.partition_types();
+        let mut definitions = vec![
+            (
+                String::from("10-esp.conf"),
+                definition(
+                    types[&BOOT_PARTITION_INDEX],
+                    Some(u64::from(self.boot_size_mb)),
+                    None,
+                ),
+            ),
+            (
+                String::from("20-bios-boot.conf"),
+                definition(types[&BIOS_BOOT_PARTITION_INDEX], Some(1), None),
+            ),
+            (
+                String::from("30-root.conf"),
+                definition(types[&ROOT_PARTITION_INDEX], None, padding(3)),
+            ),
+        ];
+        if let Some(data_partition_mb) = self.data_partition_mb {
+            definitions.push((
+                String::from("40-data.conf"),
+                definition(
+                    types[&DATA_PARTITION_INDEX],
+                    Some(data_partition_mb),
+                    padding(4),
+                ),
+            ));
+        }
+        definitions
+    }
+}
+
+/// A partition of exactly `size_mb`, or of at least 1MiB taking the free space when `None`,
+/// followed by `padding_mb` of unallocated space
+fn definition(partition_type: &str, size_mb: Option<u64>, padding_mb: Option<u64>) -> String {
+    let mut output = String::from("# Generated by ALMA\n[Partition]\n");
+    writeln!(output, "Type={partition_type}").unwrap();
+    match size_mb {
+        Some(size_mb) => {
+            writeln!(output, "SizeMinBytes={size_mb}M").unwrap();
+            writeln!(output, "SizeMaxBytes={size_mb}M").unwrap();
+        }
+        None => writeln!(output, "SizeMinBytes=1M").unwrap(),
+    }
+    if let Some(padding_mb) = padding_mb {
+        writeln!(output, "PaddingMinBytes={padding_mb}M").unwrap();
+        writeln!(output, "PaddingMaxBytes={padding_mb}M").unwrap();
+    }
+    output
+}
+
+/// Adds x-systemd.growfs to the root filesystem entry of fstab, so the filesystem follows when
+/// systemd-repart grows its partition
+pub fn grow_root_filesystem(fstab: &str) -> String {
+    fstab
+        .lines()
+        .map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if line.starts_with('#')
+                || fields.len() < 4
+                || fields[1] != "/"
+                || fields[3].split(',').any(|o| o == "x-systemd.growfs")
+            {
+                return line.to_owned();
+            }
+            let mut fields: Vec<String> = fields.into_iter().map(String::from).collect();
+            fields[3].push_str(",x-systemd.growfs");
+            fields.join("\t")
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_definitions() {
+        let layout = Layout {
+            boot_size_mb: 300,
+            leave_free_mb: 0,
+            data_partition_mb: None,
+            raid: false,
+            start_mb: None,
+            arch: Arch::X86_64,
+        };
+        let definitions = layout.definitions();
+        assert_eq!(definitions.len(), 3);
+        assert_eq!(
+            definitions[0].1,
+            "# Generated by ALMA\n[Partition]\nType=c12a7328-f81f-11d2-ba4b-00a0c93ec93b\nSizeMinBytes=300M\nSizeMaxBytes=300M\n"
+        );
+        assert_eq!(
+            definitions[2].1,
+            "# Generated by ALMA\n[Partition]\nType=4f68bce3-e8cd-4db1-96e7-fbcaf984b709\nSizeMinBytes=1M\n"
+        );
+
+        let layout = Layout {
+            leave_free_mb: 1024,
+            data_partition_mb: Some(4096),
+            ..layout
+        };
+        let definitions = layout.definitions();
+        assert_eq!(definitions.len(), 4);
+        assert!(!definitions[2].1.contains("Padding"));
+        assert!(
+            definitions[3]
+                .1
+                .contains("Type=ebd0a0a2-b9e5-4433-87c0-68b6b72699c7\n")
+        );
+        assert!(
+            definitions[3]
+                .1
+                .ends_with("SizeMaxBytes=4096M\nPaddingMinBytes=1024M\nPaddingMaxBytes=1024M\n")
+        );
+    }
+
+    #[test]
+    fn test_grow_root_filesystem() {
+        let fstab = "# /dev/sdb3\nUUID=abc\t/\text4\trw,noatime\t0 1\nUUID=def /boot vfat rw 0 2";
+        assert_eq!(
+            grow_root_filesystem(fstab),
+            "# /dev/sdb3\nUUID=abc\t/\text4\trw,noatime,x-systemd.growfs\t0\t1\nUUID=def /boot vfat rw 0 2"
+        );
+        assert_eq!(
+            grow_root_filesystem(&grow_root_filesystem(fstab)),
+            grow_root_filesystem(fstab)
+        );
+    }
+}
@@ -0,0 +1,108 @@
+//! Helpers for --reproducible builds, which derive every identifier ALMA controls from a seed.
+
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fmt::Write;
+
+/// Environment variable holding the timestamp applied to every file, see
+/// https://reproducible-builds.org/specs/source-date-epoch/
+pub const EPOCH_ENV: &str = "SOURCE_DATE_EPOCH";
+
+/// Used when SOURCE_DATE_EPOCH is not set. FAT cannot store timestamps before 1980.
+pub const DEFAULT_EPOCH: u64 = 315_532_800;
+
+#[derive(Debug, Clone)]
+pub struct Reproducible {
+    seed: String,
+    epoch: u64,
+}
+
+impl Reproducible {
+    pub fn new(seed: &str) -> anyhow::Result<Self> {
+        let epoch = match env::var(EPOCH_ENV) {
+            Ok(value) => value
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid {EPOCH_ENV}: {value}"))?,
+            Err(_) => DEFAULT_EPOCH,
+        };
+        Ok(Self {
+            seed: seed.to_owned(),
+            epoch,
+        })
+    }
+
+    /// Another generator, for a second set of the same identifiers, like those of a mirror disk
+    pub fn derive(&self, name: &str) -> Self {
+        Self {
+            seed: format!("{}/{name}", self.seed),
+            epoch: self.epoch,
+        }
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    fn digest(&self, label: &str) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(self.seed.as_bytes());
+        hasher.update([0]);
+        hasher.update(label.as_bytes());
+        hasher.finalize().into()
+    }
+
+    /// Bytes which only depend on the seed and the label, e.g. for a salt
+    pub fn bytes(&self, label: &str) -> [u8; 32] {
+        self.digest(label)
+    }
+
+    /// A random-looking (version 4) UUID, which only depends on the seed and the label
+    pub fn uuid(&self, label: &str) -> String {
+        let mut bytes = self.digest(label);
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+        let mut uuid = String::with_capacity(36);
+        for (i, byte) in bytes[..16].iter().enumerate() {
+            if matches!(i, 4 | 6 | 8 | 10) {
+                uuid.push('-');
+            }
+            write!(uuid, "{byte:02x}").unwrap();
+        }
+        uuid
+    }
+
+    /// A FAT volume ID (32 bits in hexadecimal), as taken by mkfs.fat -i
+    pub fn volume_id(&self, label: &str) -> String {
+        self.digest(label)[..4]
+            .iter()
+            .fold(String::with_capacity(8), |mut id, byte| {
+                write!(id, "{byte:02x}").unwrap();
+                id
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uuid_derivation() {
+        let reproducible = Reproducible {
+            seed: String::from("seed"),
+            epoch: DEFAULT_EPOCH,
+        };
+        let uuid = reproducible.uuid("root");
+
+        assert_eq!(uuid, reproducible.uuid("root"));
+        assert_ne!(uuid, reproducible.uuid("boot"));
+        assert_eq!(uuid.len(), 36);
+        assert_eq!(&uuid[14..15], "4");
+        assert!(matches!(&uuid[19..20], "8" | "9" | "a" | "b"));
+        assert_eq!(reproducible.volume_id("boot").len(), 8);
+        assert_ne!(reproducible.derive("mirror").uuid("root"), uuid);
+    }
+}
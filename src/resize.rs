@@ -0,0 +1,334 @@
+//! Growing ALMA systems after the fact: image files (`alma resize`) and images written to a
+//! larger device (`alma fix-gpt`). `alma backup --shrink` uses the same steps in reverse.
+
+use crate::arch::Arch;
+use crate::args::{FixGptCommand, ResizeCommand};
+use crate::constants::{RAID_PARTITION_TYPE, ROOT_PARTITION_INDEX};
+use crate::error::{ErrorKind, ErrorKindExt};
+use crate::process::CommandExt;
+use crate::state;
+use crate::storage::filesystem::FilesystemType;
+use crate::storage::{
+    BlockDevice, EncryptedDevice, LoopDevice, MountStack, StorageDevice, is_encrypted_device,
+};
+use crate::tool::Tool;
+use anyhow::{Context, anyhow};
+use log::info;
+use nix::mount::MsFlags;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::Stdio;
+
+/// Grows an image file, then its root partition, LUKS container and filesystem to fill it
+pub fn resize(command: ResizeCommand) -> anyhow::Result<()> {
+    let sgdisk = Tool::find("sgdisk", false)?;
+    let sfdisk = Tool::find("sfdisk", false)?;
+
+    let metadata = fs::metadata(&command.image)
+        .with_context(|| format!("Cannot access {}", command.image.display()))
+        .kind(ErrorKind::Usage)?;
+    if !metadata.is_file() {
+        return Err(anyhow!(
+            "{} is not an image file, only image files can be resized",
+            command.image.display()
+        ))
+        .kind(ErrorKind::Usage);
+    }
+    if !root_is_last_partition(&command.image)? {
+        return Err(anyhow!(
+            "The root partition of {} is followed by another partition, so it cannot grow",
+            command.image.display()
+        ))
+        .kind(ErrorKind::Usage);
+    }
+    let new_size = command.size.as_u64();
+    if new_size <= metadata.len() {
+        return Err(anyhow!(
+            "The new size ({new_size} bytes) must be larger than the current size ({} bytes)",
+            metadata.len()
+        ))
+        .kind(ErrorKind::Usage);
+    }
+
+    info!("Growing {} to {new_size} bytes", command.image.display());
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&command.image)
+        .and_then(|file| file.set_len(new_size))
+        .context("Error growing the image file")?;
+
+    {
+        let loop_device = LoopDevice::create(&command.image, false)?;
+        relocate_backup_header(&sgdisk, loop_device.path())?;
+        grow_root_partition(&sfdisk, loop_device.path())?;
+    }
+
+    // A new loop device picks up the new partition table
+    let loop_device = LoopDevice::create(&command.image, false)?;
+    grow_root_filesystem(loop_device.path())?;
+
+    info!("Resized {}", command.image.display());
+    Ok(())
+}
+
+/// Moves the backup GPT header of an image written to a larger device to the end of the device,
+/// and grows the root partition with --expand
+pub fn fix_gpt(command: FixGptCommand) -> anyhow::Result<()> {
+    let sgdisk = Tool::find("sgdisk", false)?;
+
+    let metadata = fs::metadata(&command.device)
+        .with_context(|| format!("Cannot access {}", command.device.display()))
+        .kind(ErrorKind::Usage)?;
+    let loop_device = if metadata.is_file() {
+        Some(LoopDevice::create(&command.device, false)?)
+    } else {
+        None
+    };
+    let device_path = loop_device
+        .as_ref()
+        .map_or(command.device.as_path(), |l| l.path());
+
+    {
+        // Validates that the device may be touched, and unmounts any of its partitions
+        let mut storage_device = StorageDevice::from_path(
+            device_path,
+            command.allow_non_removable || loop_device.is_some(),
+            false,
+        )?;
+        storage_device.umount_if_needed();
+    }
+
+    if command.expand && !root_is_last_partition(device_path)? {
+        return Err(anyhow!(
+            "The root partition of {} is followed by another partition, so --expand cannot grow it",
+            command.device.display()
+        ))
+        .kind(ErrorKind::Usage);
+    }
+    relocate_backup_header(&sgdisk, device_path)?;
+    if command.expand {
+        let sfdisk = Tool::find("sfdisk", false)?;
+        grow_root_partition(&sfdisk, device_path)?;
+        // Makes sure the kernel sees the new size before the filesystem is grown
+        Tool::find("partx", false)?
+            .execute()
+            .arg("-u")
+            .arg(device_path)
+            .run(false)
+            .context("Failed to update the kernel partition table")?;
+        grow_root_filesystem(device_path)?;
+    }
+
+    info!(
+        "Repaired the partition table of {}",
+        command.device.display()
+    );
+    Ok(())
+}
+
+/// Whether the root partition of the image is its last partition, which can grow into the free
+/// space after it
+pub fn root_is_last_partition(image: &Path) -> anyhow::Result<bool> {
+    let partitions = parse_partitions(&read_partition_table(image)?)?;
+    Ok(partitions.last().map(|(number, _)| *number) == Some(root_partition_index(&partitions)))
+}
+
+/// The number of the root partition of `device`
+pub fn find_root_partition(device: &Path) -> anyhow::Result<u8> {
+    Ok(root_partition_index(&parse_partitions(
+        &read_partition_table(device)?,
+    )?))
+}
+
+fn read_partition_table(device: &Path) -> anyhow::Result<String> {
+    Tool::find("sfdisk", false)?
+        .execute()
+        .arg("--json")
+        .arg(device)
+        .run_text_output(false)
+        .with_context(|| format!("Failed to read the partition table of {}", device.display()))
+}
+
+/// The partitions in the output of `sfdisk --json`, as their numbers and lowercase type GUIDs
+fn parse_partitions(table: &str) -> anyhow::Result<Vec<(u8, String)>> {
+    let table: serde_json::Value = serde_json::from_str(table).context("Invalid sfdisk output")?;
+    let Some(partitions) = table["partitiontable"]["partitions"].as_array() else {
+        return Ok(Vec::new());
+    };
+    partitions
+        .iter()
+        .map(|partition| {
+            let node = partition["node"].as_str().unwrap_or_default();
+            let digits = node.len() - node.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+            let number = node[node.len() - digits..]
+                .parse()
+                .with_context(|| format!("Invalid partition {node} in the sfdisk output"))?;
+            let partition_type = partition["type"].as_str().unwrap_or_default();
+            Ok((number, partition_type.to_ascii_lowercase()))
+        })
+        .collect()
+}
+
+/// The root partition is found by its type, as the layout may have a data partition after it.
+/// Images from before ALMA set the partition types have it at the usual index.
+fn root_partition_index(partitions: &[(u8, String)]) -> u8 {
+    partitions
+        .iter()
+        .find(|(_, partition_type)| {
+            *partition_type == RAID_PARTITION_TYPE
+                || Arch::ALL
+                    .iter()
+                    .any(|arch| *partition_type == arch.root_partition_type())
+        })
+        .map_or(ROOT_PARTITION_INDEX, |(number, _)| *number)
+}
+
+pub fn relocate_backup_header(sgdisk: &Tool, device: &Path) -> anyhow::Result<()> {
+    info!("Moving the backup GPT header to the end of the disk");
+    sgdisk
+        .execute()
+        .arg("-e")
+        .arg(device)
+        .run(false)
+        .context("Failed to relocate the backup GPT header")
+}
+
+/// sfdisk keeps the start, type and GUID of the partition and only changes its size
+pub fn grow_root_partition(sfdisk: &Tool, device: &Path) -> anyhow::Result<()> {
+    info!("Growing the root partition");
+    resize_root_partition(sfdisk, device, "+")
+}
+
+/// Shrinks the root partition to `size` bytes, rounded up to whole MiB to keep the alignment
+pub fn shrink_root_partition(sfdisk: &Tool, device: &Path, size: u64) -> anyhow::Result<()> {
+    const MIB: u64 = 1024 * 1024;
+    info!("Shrinking the root partition");
+    let sectors = size.div_ceil(MIB) * MIB / 512;
+    resize_root_partition(sfdisk, device, &sectors.to_string())
+}
+
+/// `size` is a size in sectors, or + for all the free space after the partition
+fn resize_root_partition(sfdisk: &Tool, device: &Path, size: &str) -> anyhow::Result<()> {
+    let mut resize = sfdisk
+        .execute()
+        .args(["--no-reread", "-N"])
+        .arg(find_root_partition(device)?.to_string())
+        .arg(device)
+        .stdin(Stdio::piped())
+        .spawn()
+        .context("Failed to run sfdisk")?;
+    resize
+        .stdin
+        .take()
+        .expect("sfdisk stdin is piped")
+        .write_all(format!(", {size}\n").as_bytes())?;
+    if !resize.wait()?.success() {
+        return Err(anyhow!("Failed to resize the root partition"));
+    }
+    Ok(())
+}
+
+/// Grows the LUKS container (if any) and the filesystem of the root partition to fill it. The
+/// device must have been validated by the caller.
+fn grow_root_filesystem(device: &Path) -> anyhow::Result<()> {
+    let storage_device = StorageDevice::from_path(device, true, false)?;
+    let root_partition = storage_device.get_partition(find_root_partition(device)?)?;
+
+    let cryptsetup;
+    let encrypted_root = if is_encrypted_device(&root_partition)? {
+        cryptsetup = Tool::find("cryptsetup", false)?;
+        let device =
+            EncryptedDevice::open(&cryptsetup, &root_partition, state::root_mapper_name())?;
+        grow_luks(&cryptsetup, &device)?;
+        Some(device)
+    } else {
+        None
+    };
+    let root_device: &dyn BlockDevice = encrypted_root
+        .as_ref()
+        .map_or(&root_partition, |e| e as &dyn BlockDevice);
+    grow_filesystem(root_device)
+}
+
+/// Grows the opened LUKS container to fill its partition
+pub fn grow_luks(cryptsetup: &Tool, device: &EncryptedDevice) -> anyhow::Result<()> {
+    info!("Growing the LUKS container");
+    cryptsetup
+        .execute()
+        .arg("resize")
+        .arg(device.name())
+        .run(false)
+        .context("Failed to grow the LUKS container")
+}
+
+/// Grows the unmounted filesystem on `root_device` to fill it
+pub fn grow_filesystem(root_device: &dyn BlockDevice) -> anyhow::Result<()> {
+    let blkid = Tool::find("blkid", false)?;
+    let fs_type = blkid
+        .execute()
+        .args(["-s", "TYPE", "-o", "value"])
+        .arg(root_device.path())
+        .run_text_output(false)?;
+    let ops = FilesystemType::from_mount_type(fs_type.trim())
+        .ok_or_else(|| {
+            anyhow!(
+                "Unsupported filesystem type '{}' on the root partition",
+                fs_type.trim()
+            )
+        })?
+        .ops();
+
+    info!("Growing the {} filesystem", ops.mount_type());
+    let mount_point = state::temp_mount_point()?;
+    let mut mount_stack = MountStack::new(false);
+    let path = if ops.resizes_online() {
+        mount_stack.mount_single(
+            root_device.path(),
+            mount_point.path(),
+            Some(ops.mount_type()),
+            MsFlags::MS_NOATIME,
+            None,
+        )?;
+        mount_point.path()
+    } else {
+        root_device.path()
+    };
+    for step in ops.resize(path, None)? {
+        step.run(&Tool::find(step.tool, false)?)
+            .context("Failed to grow the root filesystem")?;
+    }
+    mount_stack.umount()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_root_partition_index() {
+        let table = r#"{"partitiontable": {"label": "gpt", "partitions": [
+            {"node": "/dev/loop0p1", "start": 2048, "size": 614400, "type": "C12A7328-F81F-11D2-BA4B-00A0C93EC93B"},
+            {"node": "/dev/loop0p2", "start": 616448, "size": 2048, "type": "21686148-6453-6F6E-744E-656564454649"},
+            {"node": "/dev/loop0p3", "start": 618496, "size": 2097152, "type": "4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709"},
+            {"node": "/dev/loop0p4", "start": 2715648, "size": 1048576, "type": "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7"}]}}"#;
+        let partitions = parse_partitions(table).unwrap();
+        assert_eq!(partitions.len(), 4);
+        assert_eq!(root_partition_index(&partitions), 3);
+        assert_eq!(partitions.last().unwrap().0, 4);
+
+        // Before ALMA set partition types, the root partition was a Linux filesystem
+        let old = r#"{"partitiontable": {"label": "gpt", "partitions": [
+            {"node": "alma.img1", "type": "C12A7328-F81F-11D2-BA4B-00A0C93EC93B"},
+            {"node": "alma.img2", "type": "21686148-6453-6F6E-744E-656564454649"},
+            {"node": "alma.img3", "type": "0FC63DAF-8483-4772-8E79-3D69D8477DE4"}]}}"#;
+        assert_eq!(root_partition_index(&parse_partitions(old).unwrap()), 3);
+        assert!(
+            parse_partitions(r#"{"partitiontable": {"label": "gpt"}}"#)
+                .unwrap()
+                .is_empty()
+        );
+        assert!(parse_partitions("sfdisk: cannot open").is_err());
+    }
+}
@@ -0,0 +1,302 @@
+//! `alma schedule`: installs a systemd timer which rebuilds an image from a saved configuration,
+//! keeping the newest few images around.
+
+use crate::args::{App, Command, Config, ScheduleCommand};
+use crate::artifact;
+use crate::create;
+use crate::effects;
+use crate::motd;
+use crate::process::CommandExt;
+use crate::tool::Tool;
+use anyhow::{Context, anyhow};
+use log::info;
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const UNIT_DIR: &str = "/etc/systemd/system";
+
+pub fn schedule(command: ScheduleCommand) -> anyhow::Result<()> {
+    validate(&command)?;
+    if command.run {
+        run(&command)
+    } else {
+        install(&command)
+    }
+}
+
+fn validate(command: &ScheduleCommand) -> anyhow::Result<()> {
+    if command.name.is_empty()
+        || !command
+            .name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(anyhow!(
+            "The schedule name may only contain letters, digits, '-' and '_', got '{}'",
+            command.name
+        ));
+    }
+    if command.keep == 0 {
+        return Err(anyhow!("--keep must be at least 1"));
+    }
+    // Parsed now, so a broken configuration fails here rather than at the first scheduled build
+    Config::load(Some(&command.config))?;
+    Ok(())
+}
+
+/// Writes the service and timer units, then enables the timer
+fn install(command: &ScheduleCommand) -> anyhow::Result<()> {
+    // The units run from another working directory, so every path has to be absolute
+    let config = fs::canonicalize(&command.config)
+        .with_context(|| format!("Cannot access {}", command.config.display()))?;
+    effects::create_dir_all(&command.output_dir, command.dryrun)?;
+    let output_dir = if command.dryrun {
+        env::current_dir()?.join(&command.output_dir)
+    } else {
+        fs::canonicalize(&command.output_dir)?
+    };
+    let alma = env::current_exe().context("Cannot find the path of the alma executable")?;
+
+    let unit = format!("alma-{}", command.name);
+    let exec_start = [
+        alma.to_string_lossy().into_owned(),
+        String::from("schedule"),
+        String::from("--run"),
+        String::from("--config"),
+        config.to_string_lossy().into_owned(),
+        String::from("--image"),
+        format!("{}MiB", command.image.as_u64() / (1024 * 1024)),
+        String::from("--output-dir"),
+        output_dir.to_string_lossy().into_owned(),
+        String::from("--keep"),
+        command.keep.to_string(),
+        command.name.clone(),
+    ];
+    let service = service_unit(&command.name, &exec_start);
+    let timer = timer_unit(&command.name, &command.on_calendar);
+
+    let service_path = Path::new(UNIT_DIR).join(format!("{unit}.service"));
+    let timer_path = Path::new(UNIT_DIR).join(format!("{unit}.timer"));
+    for (path, contents) in [(&service_path, &service), (&timer_path, &timer)] {
+        info!("Writing {}", path.display());
+        effects::write(path, contents, command.dryrun)?;
+    }
+
+    let systemctl = Tool::find("systemctl", command.dryrun)?;
+    systemctl
+        .execute()
+        .arg("daemon-reload")
+        .run(command.dryrun)
+        .context("Failed to reload systemd")?;
+    systemctl
+        .execute()
+        .args(["enable", "--now"])
+        .arg(format!("{unit}.timer"))
+        .run(command.dryrun)
+        .context("Failed to enable the timer")?;
+
+    info!(
+        "Scheduled {unit} ({}). Run `systemctl start {unit}.service` to build an image now.",
+        command.on_calendar
+    );
+    Ok(())
+}
+
+/// Builds a new timestamped image, then removes the oldest ones beyond --keep
+fn run(command: &ScheduleCommand) -> anyhow::Result<()> {
+    effects::create_dir_all(&command.output_dir, command.dryrun)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("The system clock is set before 1970")?
+        .as_secs();
+    let image = command.output_dir.join(image_name(&command.name, now));
+
+    let mut args = vec![
+        String::from("alma"),
+        String::from("create"),
+        String::from("--noconfirm"),
+        String::from("--config"),
+        command.config.to_string_lossy().into_owned(),
+        String::from("--image"),
+        format!("{}MiB", command.image.as_u64() / (1024 * 1024)),
+    ];
+    if command.dryrun {
+        args.push(String::from("--dryrun"));
+    }
+    args.push(image.to_string_lossy().into_owned());
+    let app = App::try_parse_with_config(args).context("Invalid scheduled create command")?;
+    let Command::Create(create_command) = app.cmd else {
+        unreachable!("The arguments start with the create subcommand");
+    };
+
+    info!("Building {}", image.display());
+    create::create(create_command)?;
+    rotate(command, &image)
+}
+
+fn rotate(command: &ScheduleCommand, latest: &Path) -> anyhow::Result<()> {
+    let mut names = Vec::new();
+    if command.output_dir.exists() {
+        for entry in fs::read_dir(&command.output_dir)
+            .with_context(|| format!("Failed to read {}", command.output_dir.display()))?
+        {
+            names.push(entry?.file_name().to_string_lossy().into_owned());
+        }
+    }
+    // In a dry run the latest image was not actually created
+    if let Some(latest) = latest.file_name() {
+        let latest = latest.to_string_lossy().into_owned();
+        if !names.contains(&latest) {
+            names.push(latest);
+        }
+    }
+
+    for name in expired_images(&names, &command.name, command.keep) {
+        let image = command.output_dir.join(name);
+        info!("Removing the old image {}", image.display());
+        for path in [artifact::metadata_path(&image), image] {
+            if command.dryrun || path.exists() {
+                effects::remove_file(&path, command.dryrun)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `<name>-YYYYMMDD-HHMM.img`, which sorts chronologically
+fn image_name(name: &str, timestamp: u64) -> String {
+    let (year, month, day) = motd::civil_date(timestamp);
+    let seconds = timestamp % 86400;
+    format!(
+        "{name}-{year:04}{month:02}{day:02}-{:02}{:02}.img",
+        seconds / 3600,
+        seconds % 3600 / 60
+    )
+}
+
+/// The images of the schedule beyond the newest `keep`, oldest first
+fn expired_images<'a>(names: &'a [String], schedule: &str, keep: usize) -> Vec<&'a str> {
+    let prefix = format!("{schedule}-");
+    let mut images: Vec<&str> = names
+        .iter()
+        .map(String::as_str)
+        .filter(|name| {
+            name.strip_prefix(&prefix)
+                .and_then(|rest| rest.strip_suffix(".img"))
+                .is_some_and(|stamp| {
+                    stamp.len() == 13 && stamp.chars().all(|c| c.is_ascii_digit() || c == '-')
+                })
+        })
+        .collect();
+    images.sort_unstable();
+    let expired = images.len().saturating_sub(keep);
+    images.truncate(expired);
+    images
+}
+
+fn service_unit(name: &str, exec_start: &[String]) -> String {
+    let mut exec = String::new();
+    for arg in exec_start {
+        if !exec.is_empty() {
+            exec.push(' ');
+        }
+        exec.push_str(&quote_arg(arg));
+    }
+
+    let mut unit = String::new();
+    writeln!(unit, "[Unit]").unwrap();
+    writeln!(unit, "Description=Rebuild the ALMA image {name}").unwrap();
+    writeln!(unit, "Wants=network-online.target").unwrap();
+    writeln!(unit, "After=network-online.target").unwrap();
+    writeln!(unit).unwrap();
+    writeln!(unit, "[Service]").unwrap();
+    writeln!(unit, "Type=oneshot").unwrap();
+    writeln!(unit, "ExecStart={exec}").unwrap();
+    // Builds are long and heavy on I/O, they should not slow down the rest of the machine
+    writeln!(unit, "Nice=10").unwrap();
+    writeln!(unit, "IOSchedulingClass=idle").unwrap();
+    unit
+}
+
+fn timer_unit(name: &str, on_calendar: &str) -> String {
+    let mut unit = String::new();
+    writeln!(unit, "[Unit]").unwrap();
+    writeln!(
+        unit,
+        "Description=Scheduled rebuild of the ALMA image {name}"
+    )
+    .unwrap();
+    writeln!(unit).unwrap();
+    writeln!(unit, "[Timer]").unwrap();
+    writeln!(unit, "OnCalendar={on_calendar}").unwrap();
+    // Catch up on a build missed while the machine was off
+    writeln!(unit, "Persistent=true").unwrap();
+    writeln!(unit, "RandomizedDelaySec=30min").unwrap();
+    writeln!(unit).unwrap();
+    writeln!(unit, "[Install]").unwrap();
+    writeln!(unit, "WantedBy=timers.target").unwrap();
+    unit
+}
+
+/// Quotes an argument of ExecStart, where `%` starts a specifier and whitespace splits arguments
+fn quote_arg(arg: &str) -> String {
+    let escaped = arg.replace('%', "%%");
+    if !escaped.is_empty()
+        && !escaped
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '"' | '\'' | '\\' | ';' | '$'))
+    {
+        return escaped;
+    }
+    let mut quoted = String::from("\"");
+    for c in escaped.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            // $ would be expanded as an environment variable
+            '$' => quoted.push_str("$$"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expired_images() {
+        let names: Vec<String> = [
+            "lab-20240115-0300.img",
+            "lab-20240108-0300.img",
+            "lab-20240108-0300.img.json",
+            "lab-20240122-0300.img",
+            "lab2-20240101-0300.img",
+            "notes.txt",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        assert_eq!(
+            expired_images(&names, "lab", 2),
+            vec!["lab-20240108-0300.img"]
+        );
+        assert!(expired_images(&names, "lab", 3).is_empty());
+        assert_eq!(image_name("lab", 1_709_210_096), "lab-20240229-1234.img");
+    }
+
+    #[test]
+    fn test_quote_arg() {
+        assert_eq!(quote_arg("/srv/images"), "/srv/images");
+        assert_eq!(quote_arg("/srv/my images"), "\"/srv/my images\"");
+        assert_eq!(quote_arg("100%"), "100%%");
+        assert_eq!(quote_arg("a\"$b"), "\"a\\\"$$b\"");
+    }
+}
@@ -0,0 +1,330 @@
+//! Replicating a golden image with btrfs seed devices: `alma seed` turns the root filesystem of
+//! a master image into a read-only seed, and `alma sprout` creates a writable copy on another
+//! device by adding it to the seed filesystem and removing the seed, which moves the data over
+//! at the speed of the disks.
+
+use crate::args::{SeedCommand, SproutCommand};
+use crate::constants::{BIOS_BOOT_PARTITION_INDEX, BOOT_PARTITION_INDEX, ROOT_PARTITION_INDEX};
+use crate::error::{ErrorKind, ErrorKindExt};
+use crate::process::CommandExt;
+use crate::resize;
+use crate::state;
+use crate::storage::{
+    BlockDevice, LoopDevice, MountStack, StorageDevice, ids, is_encrypted_device,
+};
+use crate::tool::Tool;
+use anyhow::{Context, anyhow};
+use dialoguer::{Confirm, theme::ColorfulTheme};
+use log::info;
+use nix::mount::{MsFlags, mount};
+use std::fs;
+use std::path::Path;
+
+/// Files of the root subvolume which refer to the root filesystem by UUID
+const UUID_FILES: [&str; 2] = ["@/etc/fstab", "@/etc/default/grub"];
+
+/// Marks the root filesystem of a master image as a btrfs seed
+pub fn seed(command: SeedCommand) -> anyhow::Result<()> {
+    let loop_device = attach_if_file(&command.master)?;
+    let device_path = loop_device
+        .as_ref()
+        .map_or(command.master.as_path(), |l| l.path());
+    let mut storage_device = StorageDevice::from_path(
+        device_path,
+        command.allow_non_removable || loop_device.is_some(),
+        false,
+    )?;
+    storage_device.umount_if_needed();
+    let root_partition = storage_device.get_partition(ROOT_PARTITION_INDEX)?;
+    check_btrfs_root(&root_partition)?;
+
+    info!(
+        "Marking {} as a seed device",
+        root_partition.path().display()
+    );
+    Tool::find("btrfstune", false)?
+        .execute()
+        .args(["-S", "1"])
+        .arg(root_partition.path())
+        .run(false)
+        .context("Failed to mark the root filesystem as a seed")?;
+
+    info!(
+        "{} is now read-only, create writable copies of it with `alma sprout {} DEVICE`",
+        command.master.display(),
+        command.master.display()
+    );
+    Ok(())
+}
+
+/// Creates a writable copy of a seed image on another device
+pub fn sprout(command: SproutCommand) -> anyhow::Result<()> {
+    let sgdisk = Tool::find("sgdisk", false)?;
+    let btrfs = Tool::find("btrfs", false)?;
+    let blkid = Tool::find("blkid", false)?;
+
+    let loop_device = attach_if_file(&command.seed)?;
+    let seed_path = loop_device
+        .as_ref()
+        .map_or(command.seed.as_path(), |l| l.path());
+    let mut seed_device = StorageDevice::from_path_read_only(seed_path, true)?;
+    seed_device.umount_if_needed();
+    let seed_root = seed_device.get_partition(ROOT_PARTITION_INDEX)?;
+    check_btrfs_root(&seed_root)?;
+    let dump_super = btrfs
+        .execute()
+        .args(["inspect-internal", "dump-super"])
+        .arg(seed_root.path())
+        .run_text_output(false)
+        .context("Failed to read the btrfs superblock")?;
+    if !is_seeding(&dump_super) {
+        return Err(anyhow!(
+            "The root filesystem of {} is not a seed, run `alma seed` on it first",
+            command.seed.display()
+        ))
+        .kind(ErrorKind::Usage);
+    }
+    let seed_uuid = ids::uuid(&blkid, &seed_root)?;
+
+    let mut target = StorageDevice::from_path(&command.device, command.allow_non_removable, false)?;
+    if target.size() < seed_device.size() {
+        return Err(anyhow!(
+            "{target} ({}) is smaller than the seed ({})",
+            target.size(),
+            seed_device.size()
+        ))
+        .kind(ErrorKind::DeviceSafety);
+    }
+    if !command.noconfirm {
+        let confirmed = Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt(format!("All data on {target} will be erased. Continue?"))
+            .default(false)
+            .interact()?;
+        if !confirmed {
+            return Err(anyhow!("User aborted operation.")).kind(ErrorKind::Aborted);
+        }
+    }
+    target.umount_if_needed();
+
+    copy_partition_table(&sgdisk, seed_device.path(), target.path(), true)?;
+    // GRUB's BIOS boot code and its ESP are copied as they are: the partitions start at the same
+    // sectors, and the ESP keeps its volume ID
+    copy_bytes(seed_device.path(), target.path(), 440)?;
+    for index in [BOOT_PARTITION_INDEX, BIOS_BOOT_PARTITION_INDEX] {
+        copy_partition(
+            &seed_device.get_partition(index)?,
+            &target.get_partition(index)?,
+        )?;
+    }
+
+    let target_root = target.get_partition(ROOT_PARTITION_INDEX)?;
+    let mount_point = state::temp_mount_point()?;
+    let mut mount_stack = MountStack::new(false);
+    mount_stack
+        .mount_single(
+            seed_root.path(),
+            mount_point.path(),
+            Some("btrfs"),
+            MsFlags::MS_RDONLY | MsFlags::MS_NOATIME,
+            None,
+        )
+        .context("Failed to mount the seed")?;
+
+    info!(
+        "Adding {} to the seed filesystem",
+        target_root.path().display()
+    );
+    btrfs
+        .execute()
+        .args(["device", "add", "-f"])
+        .arg(target_root.path())
+        .arg(mount_point.path())
+        .run(false)
+        .context("Failed to add the device to the seed filesystem")?;
+    mount(
+        None::<&str>,
+        mount_point.path(),
+        None::<&str>,
+        MsFlags::MS_REMOUNT | MsFlags::MS_NOATIME,
+        None::<&str>,
+    )
+    .context("Failed to remount the sprouted filesystem writable")?;
+    info!("Moving the data off the seed");
+    btrfs
+        .execute()
+        .args(["device", "remove"])
+        .arg(seed_root.path())
+        .arg(mount_point.path())
+        .run(false)
+        .context("Failed to remove the seed from the sprouted filesystem")?;
+
+    // Sprouting gives the filesystem a new UUID
+    let target_uuid = ids::uuid(&blkid, &target_root)?;
+    info!("Updating the root filesystem UUID to {target_uuid}");
+    for file in UUID_FILES {
+        replace_in_file(&mount_point.path().join(file), &seed_uuid, &target_uuid)?;
+    }
+    let target_boot = target.get_partition(BOOT_PARTITION_INDEX)?;
+    let boot_mount_point = state::temp_mount_point()?;
+    mount_stack
+        .mount_single(
+            target_boot.path(),
+            boot_mount_point.path(),
+            Some("vfat"),
+            MsFlags::MS_NOATIME,
+            None,
+        )
+        .context("Failed to mount the boot partition")?;
+    replace_in_file(
+        &boot_mount_point.path().join("grub/grub.cfg"),
+        &seed_uuid,
+        &target_uuid,
+    )?;
+    mount_stack.umount()?;
+
+    info!(
+        "Sprouted {} from {}",
+        command.device.display(),
+        command.seed.display()
+    );
+    Ok(())
+}
+
+pub fn attach_if_file(path: &Path) -> anyhow::Result<Option<LoopDevice>> {
+    let metadata = fs::metadata(path)
+        .with_context(|| format!("Cannot access {}", path.display()))
+        .kind(ErrorKind::Usage)?;
+    if metadata.is_file() {
+        Ok(Some(LoopDevice::create(path, false)?))
+    } else {
+        Ok(None)
+    }
+}
+
+fn check_btrfs_root(root_partition: &dyn BlockDevice) -> anyhow::Result<()> {
+    if is_encrypted_device(root_partition)? {
+        return Err(anyhow!(
+            "Encrypted root filesystems cannot be used as seeds"
+        ))
+        .kind(ErrorKind::Usage);
+    }
+    let fs_type = Tool::find("blkid", false)?
+        .execute()
+        .args(["-s", "TYPE", "-o", "value"])
+        .arg(root_partition.path())
+        .run_text_output(false)?;
+    if fs_type.trim() != "btrfs" {
+        return Err(anyhow!(
+            "Only btrfs root filesystems can be used as seeds, {} is '{}'",
+            root_partition.path().display(),
+            fs_type.trim()
+        ))
+        .kind(ErrorKind::Usage);
+    }
+    Ok(())
+}
+
+/// Replicates the partition table with new GUIDs, then grows the root partition to the end of
+/// the larger target if `grow_root` is set
+pub fn copy_partition_table(
+    sgdisk: &Tool,
+    seed: &Path,
+    target: &Path,
+    grow_root: bool,
+) -> anyhow::Result<()> {
+    info!("Copying the partition table to {}", target.display());
+    sgdisk
+        .execute()
+        .arg(format!("--replicate={}", target.display()))
+        .arg(seed)
+        .run(false)
+        .context("Failed to copy the partition table")?;
+    sgdisk
+        .execute()
+        .arg("--randomize-guids")
+        .arg(target)
+        .run(false)
+        .context("Failed to randomize the partition GUIDs")?;
+    resize::relocate_backup_header(sgdisk, target)?;
+    if grow_root {
+        resize::grow_root_partition(&Tool::find("sfdisk", false)?, target)?;
+    }
+    Tool::find("partx", false)?
+        .execute()
+        .arg("-u")
+        .arg(target)
+        .run(false)
+        .context("Failed to update the kernel partition table")?;
+    std::thread::sleep(std::time::Duration::from_millis(1000));
+    Ok(())
+}
+
+/// Copies a partition to one at least as large
+pub fn copy_partition(source: &dyn BlockDevice, target: &dyn BlockDevice) -> anyhow::Result<()> {
+    info!("Copying {}", source.path().display());
+    Tool::find("dd", false)?
+        .execute()
+        .arg(format!("if={}", source.path().display()))
+        .arg(format!("of={}", target.path().display()))
+        .args(["bs=4M", "conv=fsync"])
+        .run(false)
+        .context("Failed to copy the partition")
+}
+
+/// Copies the first `count` bytes, without touching the rest of the target
+fn copy_bytes(source: &Path, target: &Path, count: usize) -> anyhow::Result<()> {
+    Tool::find("dd", false)?
+        .execute()
+        .arg(format!("if={}", source.display()))
+        .arg(format!("of={}", target.display()))
+        .arg(format!("bs={count}"))
+        .args(["count=1", "conv=notrunc,fsync"])
+        .run(false)
+        .context("Failed to copy the boot code")
+}
+
+pub fn replace_in_file(path: &Path, from: &str, to: &str) -> anyhow::Result<()> {
+    let Ok(contents) = fs::read_to_string(path) else {
+        return Ok(());
+    };
+    if contents.contains(from) {
+        fs::write(path, contents.replace(from, to))
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// BTRFS_SUPER_FLAG_SEEDING
+const SEEDING_FLAG: u64 = 1 << 32;
+
+/// Whether the superblock flags shown by `btrfs inspect-internal dump-super` include seeding.
+/// The flag names are spread over several lines, so the hexadecimal value is checked instead.
+fn is_seeding(dump_super: &str) -> bool {
+    dump_super
+        .lines()
+        .filter_map(|line| line.strip_prefix("flags"))
+        .filter_map(|rest| rest.split_whitespace().next()?.strip_prefix("0x"))
+        .filter_map(|hex| u64::from_str_radix(hex, 16).ok())
+        .any(|flags| flags & SEEDING_FLAG != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_seeding() {
+        let dump_super = "\
+superblock: bytenr=65536, device=/dev/loop0p3
+csum_type\t\t0 (crc32c)
+flags\t\t\t0x100000001
+\t\t\t( WRITTEN |
+\t\t\t  SEEDING )
+incompat_flags\t\t0x361
+";
+        assert!(is_seeding(dump_super));
+        assert!(!is_seeding(
+            "flags\t\t\t0x1\n\t\t\t( WRITTEN )\nincompat_flags\t\t0x100000000\n"
+        ));
+    }
+}
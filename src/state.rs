@@ -0,0 +1,260 @@
+//! Registry of the mounts, LVM volume groups, LUKS mappers, RAID arrays and loop devices held by
+//! each ALMA process, so `alma cleanup` can tear down what a crashed run left behind.
+
+use anyhow::Context;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+use tempfile::TempDir;
+
+/// One file per process, named after its PID. /run is a tmpfs, so the registry does not outlive
+/// a reboot, and neither do the resources listed in it.
+pub const STATE_DIR: &str = "/run/alma";
+
+/// Prefix of the temporary directories filesystems are mounted on
+pub const MOUNT_POINT_PREFIX: &str = "alma-";
+
+/// Prefix of the device mapper names of opened LUKS containers
+const MAPPER_PREFIX: &str = "alma_root";
+
+/// Prefix of the /dev/md/ nodes of assembled RAID arrays
+const RAID_PREFIX: &str = "alma_md";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LoopEntry {
+    pub device: PathBuf,
+    /// The image file attached to the loop device
+    pub file: PathBuf,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RunState {
+    pub pid: u32,
+    /// Start time of the process in clock ticks since boot, which tells a dead run apart from an
+    /// unrelated process that got the same PID
+    pub start_time: Option<u64>,
+    pub command: String,
+    /// In mount order
+    #[serde(default)]
+    pub mounts: Vec<PathBuf>,
+    /// Activated by `alma chroot`
+    #[serde(default)]
+    pub volume_groups: Vec<String>,
+    #[serde(default)]
+    pub mappers: Vec<String>,
+    #[serde(default)]
+    pub arrays: Vec<PathBuf>,
+    #[serde(default)]
+    pub loop_devices: Vec<LoopEntry>,
+}
+
+impl RunState {
+    fn current() -> Self {
+        let pid = process::id();
+        Self {
+            pid,
+            start_time: process_start_time(pid),
+            command: std::env::args().collect::<Vec<String>>().join(" "),
+            ..Self::default()
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.mounts.is_empty()
+            && self.volume_groups.is_empty()
+            && self.mappers.is_empty()
+            && self.arrays.is_empty()
+            && self.loop_devices.is_empty()
+    }
+
+    /// Whether the process which registered these resources is still running
+    pub fn is_alive(&self) -> bool {
+        self.start_time.is_some() && process_start_time(self.pid) == self.start_time
+    }
+
+    /// Writes the state, or removes the file once nothing is held anymore
+    fn save(&self) -> anyhow::Result<()> {
+        let path = Path::new(STATE_DIR).join(format!("{}.json", self.pid));
+        if self.is_empty() {
+            return match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    Err(e).with_context(|| format!("Failed to remove {}", path.display()))
+                }
+                _ => Ok(()),
+            };
+        }
+
+        fs::create_dir_all(STATE_DIR).with_context(|| format!("Failed to create {STATE_DIR}"))?;
+        // Renamed into place, so `alma cleanup` never reads a partial file
+        let partial = path.with_extension("json.partial");
+        fs::write(&partial, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write {}", partial.display()))?;
+        fs::rename(&partial, &path)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(())
+    }
+}
+
+/// The registered runs, with the path of their state file
+pub fn load_runs() -> anyhow::Result<Vec<(PathBuf, RunState)>> {
+    let entries = match fs::read_dir(STATE_DIR) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {STATE_DIR}")),
+    };
+
+    let mut runs = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        let state = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        match serde_json::from_str::<RunState>(&state) {
+            Ok(state) => runs.push((path, state)),
+            Err(e) => warn!("Ignoring the invalid state file {}: {e}", path.display()),
+        }
+    }
+    runs.sort_by_key(|(_, state)| state.pid);
+    Ok(runs)
+}
+
+static CURRENT: Mutex<Option<RunState>> = Mutex::new(None);
+
+/// The registry is best effort: failing to update it never interrupts the command
+fn update(change: impl FnOnce(&mut RunState)) {
+    let mut current = CURRENT.lock().unwrap_or_else(|e| e.into_inner());
+    let state = current.get_or_insert_with(RunState::current);
+    change(state);
+    if let Err(e) = state.save() {
+        warn!("Unable to update the ALMA state registry: {e:#}");
+    }
+}
+
+/// What this process holds, if it registered anything
+pub fn current_run() -> Option<RunState> {
+    let current = CURRENT.lock().unwrap_or_else(|e| e.into_inner());
+    current.clone().filter(|state| !state.is_empty())
+}
+
+/// Empties the registry of this process, once everything in it was released
+pub fn forget_current_run() {
+    update(|state| *state = RunState::current());
+}
+
+pub fn register_mount(target: &Path) {
+    update(|state| state.mounts.push(target.to_path_buf()));
+}
+
+pub fn release_mount(target: &Path) {
+    update(|state| {
+        if let Some(index) = state.mounts.iter().rposition(|t| t == target) {
+            state.mounts.remove(index);
+        }
+    });
+}
+
+pub fn register_volume_group(name: &str) {
+    update(|state| state.volume_groups.push(name.to_owned()));
+}
+
+pub fn release_volume_group(name: &str) {
+    update(|state| state.volume_groups.retain(|n| n != name));
+}
+
+pub fn register_mapper(name: &str) {
+    update(|state| state.mappers.push(name.to_owned()));
+}
+
+pub fn release_mapper(name: &str) {
+    update(|state| state.mappers.retain(|n| n != name));
+}
+
+pub fn register_array(path: &Path) {
+    update(|state| state.arrays.push(path.to_path_buf()));
+}
+
+pub fn release_array(path: &Path) {
+    update(|state| state.arrays.retain(|p| p != path));
+}
+
+pub fn register_loop(device: &Path, file: &Path) {
+    let file = fs::canonicalize(file).unwrap_or_else(|_| file.to_path_buf());
+    update(|state| {
+        state.loop_devices.push(LoopEntry {
+            device: device.to_path_buf(),
+            file,
+        })
+    });
+}
+
+pub fn release_loop(device: &Path) {
+    update(|state| state.loop_devices.retain(|l| l.device != device));
+}
+
+/// A temporary directory to mount filesystems on, named so leftovers can be recognised
+pub fn temp_mount_point() -> anyhow::Result<TempDir> {
+    tempfile::Builder::new()
+        .prefix(MOUNT_POINT_PREFIX)
+        .tempdir()
+        .context("Error creating a temporary directory")
+}
+
+/// Whether the directory is one of the temporary mount points of ALMA
+pub fn is_temp_mount_point(path: &Path) -> bool {
+    path.parent() == Some(std::env::temp_dir().as_path())
+        && path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with(MOUNT_POINT_PREFIX))
+}
+
+/// Device mapper name for the root LUKS container. It includes the PID, so concurrent runs do
+/// not collide.
+pub fn root_mapper_name() -> String {
+    format!("{MAPPER_PREFIX}_{}", process::id())
+}
+
+/// Name of the /dev/md/ node of the root RAID array while ALMA works on it, unique like
+/// [`root_mapper_name`]
+pub fn raid_node_name() -> String {
+    format!("{RAID_PREFIX}_{}", process::id())
+}
+
+fn process_start_time(pid: u32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{pid}/stat"))
+        .map_err(|e| debug!("Cannot read the status of process {pid}: {e}"))
+        .ok()?;
+    parse_start_time(&stat)
+}
+
+/// The 22nd field of /proc/<pid>/stat. The second field is the command name in parentheses,
+/// which may itself contain spaces and parentheses.
+fn parse_start_time(stat: &str) -> Option<u64> {
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_start_time() {
+        let stat = "4242 (alma (x)) S 1 4242 4242 0 -1 4194560 1000 0 0 0 12 3 0 0 20 0 1 0 987654 12345678 300";
+        assert_eq!(parse_start_time(stat), Some(987_654));
+        assert_eq!(parse_start_time("4242 (alma) S 1"), None);
+    }
+
+    #[test]
+    fn test_is_alive() {
+        let mut state = RunState::current();
+        assert!(state.is_alive());
+        state.start_time = state.start_time.map(|t| t + 1);
+        assert!(!state.is_alive());
+    }
+}
@@ -1,5 +1,6 @@
 use super::markers::BlockDevice;
 use crate::process::CommandExt;
+use crate::state;
 use crate::tool::Tool;
 use anyhow::Context;
 use log::{debug, warn};
@@ -20,12 +21,19 @@ pub struct EncryptedDevice<'t, 'o> {
 }
 
 impl<'t, 'o> EncryptedDevice<'t, 'o> {
-    pub fn prepare(cryptsetup: &Tool, device: &dyn BlockDevice) -> anyhow::Result<()> {
+    /// Formats the device as LUKS, with the given header UUID instead of a random one
+    pub fn prepare(
+        cryptsetup: &Tool,
+        device: &dyn BlockDevice,
+        uuid: Option<&str>,
+    ) -> anyhow::Result<()> {
         debug!("Preparing encrypted device in {}", device.path().display());
-        cryptsetup
-            .execute()
-            .arg("luksFormat")
-            .arg("-q")
+        let mut command = cryptsetup.execute();
+        command.arg("luksFormat").arg("-q");
+        if let Some(uuid) = uuid {
+            command.arg("--uuid").arg(uuid);
+        }
+        command
             .arg(device.path())
             .run(cryptsetup.dryrun)
             .context("Error setting up an encrypted device")?;
@@ -37,19 +45,43 @@ impl<'t, 'o> EncryptedDevice<'t, 'o> {
         cryptsetup: &'t Tool,
         device: &'o dyn BlockDevice,
         name: String,
+    ) -> anyhow::Result<EncryptedDevice<'t, 'o>> {
+        Self::activate(cryptsetup, device, name, false)
+    }
+
+    /// Opens the device with a read-only mapping, which cannot be written to
+    pub fn open_read_only(
+        cryptsetup: &'t Tool,
+        device: &'o dyn BlockDevice,
+        name: String,
+    ) -> anyhow::Result<EncryptedDevice<'t, 'o>> {
+        Self::activate(cryptsetup, device, name, true)
+    }
+
+    fn activate(
+        cryptsetup: &'t Tool,
+        device: &'o dyn BlockDevice,
+        name: String,
+        read_only: bool,
     ) -> anyhow::Result<EncryptedDevice<'t, 'o>> {
         debug!(
             "Opening encrypted device {} as {}",
             device.path().display(),
             name
         );
-        cryptsetup
-            .execute()
-            .arg("open")
+        let mut command = cryptsetup.execute();
+        command.arg("open");
+        if read_only {
+            command.arg("--readonly");
+        }
+        command
             .arg(device.path())
             .arg(&name)
             .run(cryptsetup.dryrun)
             .context("Error opening the encrypted device")?;
+        if !cryptsetup.dryrun {
+            state::register_mapper(&name);
+        }
 
         let path = PathBuf::from("/dev/mapper").join(&name);
         Ok(Self {
@@ -60,6 +92,10 @@ impl<'t, 'o> EncryptedDevice<'t, 'o> {
         })
     }
 
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     fn _close(&mut self) -> anyhow::Result<()> {
         debug!("Closing encrypted device {}", self.name);
         self.cryptsetup
@@ -68,6 +104,9 @@ impl<'t, 'o> EncryptedDevice<'t, 'o> {
             .arg(&self.name)
             .run(self.cryptsetup.dryrun)
             .context("Error closing the encrypted device")?;
+        if !self.cryptsetup.dryrun {
+            state::release_mapper(&self.name);
+        }
 
         Ok(())
     }
@@ -1,12 +1,17 @@
 use super::markers::BlockDevice;
-use crate::{args::RootFilesystemType, process::CommandExt, tool::Tool};
-use anyhow::Context;
+use crate::{
+    args::RootFilesystemType, process::CommandExt, reproducible::Reproducible, tool::Tool,
+};
+use anyhow::{Context, anyhow};
+use std::ffi::OsString;
+use std::path::Path;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FilesystemType {
     Ext4,
     Btrfs,
     Vfat,
+    Exfat,
 }
 
 impl From<RootFilesystemType> for FilesystemType {
@@ -19,12 +24,366 @@ impl From<RootFilesystemType> for FilesystemType {
 }
 
 impl FilesystemType {
-    pub fn to_mount_type(self) -> &'static str {
+    /// The operations of this filesystem type
+    pub fn ops(self) -> &'static dyn FilesystemOps {
         match self {
-            FilesystemType::Ext4 => "ext4",
-            FilesystemType::Btrfs => "btrfs",
-            FilesystemType::Vfat => "vfat",
+            FilesystemType::Ext4 => &Ext4,
+            FilesystemType::Btrfs => &Btrfs,
+            FilesystemType::Vfat => &Vfat,
+            FilesystemType::Exfat => &Exfat,
+        }
+    }
+
+    /// The filesystem type of a mount(8) or blkid TYPE name
+    pub fn from_mount_type(name: &str) -> Option<Self> {
+        [
+            FilesystemType::Ext4,
+            FilesystemType::Btrfs,
+            FilesystemType::Vfat,
+            FilesystemType::Exfat,
+        ]
+        .into_iter()
+        .find(|fs_type| fs_type.to_mount_type() == name)
+    }
+
+    pub fn to_mount_type(self) -> &'static str {
+        self.ops().mount_type()
+    }
+
+    /// Checks that the filesystem can store the label
+    pub fn validate_label(self, label: &str) -> anyhow::Result<()> {
+        let ops = self.ops();
+        let max_len = ops.max_label_len();
+        if label.is_empty() || label.len() > max_len {
+            return Err(anyhow!(
+                "Invalid {} label '{label}': it must be 1 to {max_len} bytes long",
+                ops.mount_type()
+            ));
+        }
+        if ops.ascii_label() && !label.is_ascii() {
+            return Err(anyhow!(
+                "Invalid {} label '{label}': it must be ASCII",
+                ops.mount_type()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A run of an external tool, planned without running it so the arguments can be checked
+/// without a device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invocation {
+    pub tool: &'static str,
+    pub args: Vec<OsString>,
+    pub env: Vec<(&'static str, String)>,
+}
+
+impl Invocation {
+    fn new(tool: &'static str) -> Self {
+        Self {
+            tool,
+            args: Vec::new(),
+            env: Vec::new(),
+        }
+    }
+
+    fn arg(mut self, arg: impl Into<OsString>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    fn env(mut self, key: &'static str, value: String) -> Self {
+        self.env.push((key, value));
+        self
+    }
+
+    /// Runs the invocation with `tool`, which must be the tool it names
+    pub fn run(&self, tool: &Tool) -> anyhow::Result<()> {
+        tool.execute()
+            .args(&self.args)
+            .envs(self.env.iter().map(|(key, value)| (key, value)))
+            .run(tool.dryrun)
+            .with_context(|| format!("Error running {}", self.tool))
+    }
+}
+
+/// What ALMA needs to know about a filesystem type: how to create it, mount it and record it
+/// in fstab, and how to resize it. Adding a filesystem means implementing this trait and adding
+/// a variant to FilesystemType.
+pub trait FilesystemOps: Sync {
+    /// The type name of mount(8), fstab and blkid
+    fn mount_type(&self) -> &'static str;
+
+    fn max_label_len(&self) -> usize;
+
+    /// Whether labels are restricted to ASCII
+    fn ascii_label(&self) -> bool {
+        false
+    }
+
+    /// The mkfs invocation, which formats the device given as its last argument. With
+    /// `reproducible`, the identifiers and timestamps written by mkfs are derived from the seed
+    /// instead of being random.
+    fn mkfs(&self, label: Option<&str>, reproducible: Option<&Reproducible>) -> Invocation;
+
+    /// Mount options used when --mount-options is not given. noatime reduces disk writes by not
+    /// recording file access times.
+    fn default_mount_options(&self) -> &'static str {
+        "noatime"
+    }
+
+    /// Mount options which leave the device untouched, including the journal replay a read-only
+    /// mount otherwise still performs
+    fn read_only_mount_options(&self) -> &'static str {
+        "ro"
+    }
+
+    /// The fsck pass of the fstab entry, 0 for filesystems which are not checked at boot
+    fn fsck_pass(&self, mount_point: &str) -> u8 {
+        if mount_point == "/" { 1 } else { 2 }
+    }
+
+    /// The fstab line mounting the filesystem identified by `spec`
+    fn fstab_entry(&self, spec: &str, mount_point: &str, options: &str) -> String {
+        format!(
+            "{spec} {mount_point} {} {options} 0 {}\n",
+            self.mount_type(),
+            self.fsck_pass(mount_point)
+        )
+    }
+
+    /// Whether the filesystem is resized while mounted, in which case `resize` is given the
+    /// mount point rather than the device
+    fn resizes_online(&self) -> bool {
+        false
+    }
+
+    /// The invocations growing the filesystem at `path` to fill its device, or shrinking it to
+    /// `size` bytes
+    fn resize(&self, path: &Path, size: Option<u64>) -> anyhow::Result<Vec<Invocation>> {
+        let _ = (path, size);
+        Err(anyhow!(
+            "{} filesystems cannot be resized",
+            self.mount_type()
+        ))
+    }
+
+    /// The invocations shrinking the unmounted filesystem on `device` to its minimum size
+    fn shrink(&self, device: &Path) -> anyhow::Result<Vec<Invocation>> {
+        let _ = device;
+        Err(anyhow!(
+            "{} filesystems cannot be shrunk",
+            self.mount_type()
+        ))
+    }
+
+    /// The invocations giving the unmounted filesystem on `device` a new UUID derived from
+    /// `reproducible`, so a copy of it is not mistaken for the original
+    fn set_uuid(
+        &self,
+        device: &Path,
+        reproducible: &Reproducible,
+    ) -> anyhow::Result<Vec<Invocation>> {
+        let _ = (device, reproducible);
+        Err(anyhow!(
+            "The UUID of {} filesystems cannot be changed",
+            self.mount_type()
+        ))
+    }
+}
+
+struct Ext4;
+struct Btrfs;
+struct Vfat;
+struct Exfat;
+
+impl FilesystemOps for Ext4 {
+    fn mount_type(&self) -> &'static str {
+        "ext4"
+    }
+
+    fn max_label_len(&self) -> usize {
+        16
+    }
+
+    fn mkfs(&self, label: Option<&str>, reproducible: Option<&Reproducible>) -> Invocation {
+        let mut mkfs = Invocation::new("mkfs.ext4").arg("-F");
+        if let Some(label) = label {
+            mkfs = mkfs.arg("-L").arg(label);
+        }
+        if let Some(reproducible) = reproducible {
+            mkfs = mkfs
+                .arg("-U")
+                .arg(reproducible.uuid("ext4"))
+                .arg("-E")
+                .arg(format!("hash_seed={}", reproducible.uuid("ext4-hash")))
+                .env("E2FSPROGS_FAKE_TIME", reproducible.epoch().to_string());
+        }
+        mkfs
+    }
+
+    fn read_only_mount_options(&self) -> &'static str {
+        "ro,noload"
+    }
+
+    fn resize(&self, path: &Path, size: Option<u64>) -> anyhow::Result<Vec<Invocation>> {
+        // resize2fs refuses to work on a filesystem which was not checked recently
+        let check = Invocation::new("e2fsck").arg("-f").arg("-p").arg(path);
+        let mut resize = Invocation::new("resize2fs").arg(path);
+        if let Some(size) = size {
+            resize = resize.arg(format!("{}K", size / 1024));
+        }
+        Ok(vec![check, resize])
+    }
+
+    fn shrink(&self, device: &Path) -> anyhow::Result<Vec<Invocation>> {
+        let check = Invocation::new("e2fsck").arg("-f").arg("-p").arg(device);
+        let shrink = Invocation::new("resize2fs").arg("-M").arg(device);
+        Ok(vec![check, shrink])
+    }
+
+    fn set_uuid(
+        &self,
+        device: &Path,
+        reproducible: &Reproducible,
+    ) -> anyhow::Result<Vec<Invocation>> {
+        // tune2fs also wants a freshly checked filesystem when metadata_csum is enabled
+        let check = Invocation::new("e2fsck").arg("-f").arg("-p").arg(device);
+        let tune = Invocation::new("tune2fs")
+            .arg("-U")
+            .arg(reproducible.uuid("ext4"))
+            .arg(device);
+        Ok(vec![check, tune])
+    }
+}
+
+impl FilesystemOps for Btrfs {
+    fn mount_type(&self) -> &'static str {
+        "btrfs"
+    }
+
+    fn max_label_len(&self) -> usize {
+        255
+    }
+
+    fn mkfs(&self, label: Option<&str>, reproducible: Option<&Reproducible>) -> Invocation {
+        let mut mkfs = Invocation::new("mkfs.btrfs").arg("-f");
+        if let Some(label) = label {
+            mkfs = mkfs.arg("-L").arg(label);
+        }
+        if let Some(reproducible) = reproducible {
+            mkfs = mkfs.arg("-U").arg(reproducible.uuid("btrfs"));
         }
+        mkfs
+    }
+
+    fn default_mount_options(&self) -> &'static str {
+        "noatime,compress=zstd:3"
+    }
+
+    fn read_only_mount_options(&self) -> &'static str {
+        "ro,rescue=nologreplay"
+    }
+
+    /// btrfs checks itself when mounted, fsck.btrfs does nothing
+    fn fsck_pass(&self, _mount_point: &str) -> u8 {
+        0
+    }
+
+    fn resizes_online(&self) -> bool {
+        true
+    }
+
+    fn resize(&self, path: &Path, size: Option<u64>) -> anyhow::Result<Vec<Invocation>> {
+        let size = size.map_or_else(|| String::from("max"), |size| size.to_string());
+        Ok(vec![
+            Invocation::new("btrfs")
+                .arg("filesystem")
+                .arg("resize")
+                .arg(size)
+                .arg(path),
+        ])
+    }
+
+    /// Rewrites every metadata block, which takes a while on large filesystems
+    fn set_uuid(
+        &self,
+        device: &Path,
+        reproducible: &Reproducible,
+    ) -> anyhow::Result<Vec<Invocation>> {
+        Ok(vec![
+            Invocation::new("btrfstune")
+                .arg("-U")
+                .arg(reproducible.uuid("btrfs"))
+                .arg(device),
+        ])
+    }
+}
+
+impl FilesystemOps for Vfat {
+    fn mount_type(&self) -> &'static str {
+        "vfat"
+    }
+
+    fn max_label_len(&self) -> usize {
+        11
+    }
+
+    fn ascii_label(&self) -> bool {
+        true
+    }
+
+    fn mkfs(&self, label: Option<&str>, reproducible: Option<&Reproducible>) -> Invocation {
+        let mut mkfs = Invocation::new("mkfs.fat").arg("-F32");
+        if let Some(label) = label {
+            mkfs = mkfs.arg("-n").arg(label);
+        }
+        if let Some(reproducible) = reproducible {
+            mkfs = mkfs
+                .arg("--invariant")
+                .arg("-i")
+                .arg(reproducible.volume_id("vfat"));
+        }
+        mkfs
+    }
+
+    fn set_uuid(
+        &self,
+        device: &Path,
+        reproducible: &Reproducible,
+    ) -> anyhow::Result<Vec<Invocation>> {
+        Ok(vec![
+            Invocation::new("fatlabel")
+                .arg("-i")
+                .arg(device)
+                .arg(reproducible.volume_id("vfat")),
+        ])
+    }
+}
+
+impl FilesystemOps for Exfat {
+    fn mount_type(&self) -> &'static str {
+        "exfat"
+    }
+
+    fn max_label_len(&self) -> usize {
+        11
+    }
+
+    // The data partition is not part of the system, so its identifiers stay random even with
+    // `reproducible`
+    fn mkfs(&self, label: Option<&str>, _reproducible: Option<&Reproducible>) -> Invocation {
+        let mut mkfs = Invocation::new("mkfs.exfat");
+        if let Some(label) = label {
+            mkfs = mkfs.arg("-L").arg(label);
+        }
+        mkfs
+    }
+
+    /// The data partition may be missing or shared with other systems, it is never checked
+    fn fsck_pass(&self, _mount_point: &str) -> u8 {
+        0
     }
 }
 
@@ -35,25 +394,20 @@ pub struct Filesystem<'a> {
 }
 
 impl<'a> Filesystem<'a> {
+    /// Formats the block device, see FilesystemOps::mkfs
     pub fn format(
         block: &'a dyn BlockDevice,
         fs_type: FilesystemType,
         mkfs: &Tool,
+        label: Option<&str>,
+        reproducible: Option<&Reproducible>,
     ) -> anyhow::Result<Self> {
-        let mut command = mkfs.execute();
-        match fs_type {
-            FilesystemType::Ext4 => command.arg("-F").arg(block.path()),
-            FilesystemType::Btrfs => command.arg("-f").arg(block.path()),
-            FilesystemType::Vfat => command.arg("-F32").arg(block.path()),
-        };
-
-        command.run(mkfs.dryrun).with_context(|| {
-            format!(
-                "Error formatting {:?} with {}",
-                fs_type,
-                mkfs.exec.display()
-            )
-        })?;
+        fs_type
+            .ops()
+            .mkfs(label, reproducible)
+            .arg(block.path())
+            .run(mkfs)
+            .with_context(|| format!("Error formatting {}", block.path().display()))?;
 
         Ok(Self { fs_type, block })
     }
@@ -70,3 +424,145 @@ impl<'a> Filesystem<'a> {
         self.fs_type
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_label() {
+        assert!(FilesystemType::Ext4.validate_label("alma-root").is_ok());
+        assert!(
+            FilesystemType::Ext4
+                .validate_label("a-very-long-label")
+                .is_err()
+        );
+        assert!(FilesystemType::Vfat.validate_label("ALMA-BOOT").is_ok());
+        assert!(
+            FilesystemType::Vfat
+                .validate_label("ALMA-BOOT-EFI")
+                .is_err()
+        );
+        assert!(FilesystemType::Vfat.validate_label("BOÖT").is_err());
+        assert!(FilesystemType::Btrfs.validate_label("").is_err());
+    }
+
+    #[test]
+    fn test_from_mount_type() {
+        assert_eq!(
+            FilesystemType::from_mount_type("btrfs"),
+            Some(FilesystemType::Btrfs)
+        );
+        assert_eq!(FilesystemType::from_mount_type("xfs"), None);
+    }
+
+    fn args(invocation: &Invocation) -> Vec<&str> {
+        invocation
+            .args
+            .iter()
+            .map(|a| a.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_mkfs() {
+        let mkfs = FilesystemType::Ext4.ops().mkfs(Some("alma-root"), None);
+        assert_eq!(mkfs.tool, "mkfs.ext4");
+        assert_eq!(args(&mkfs), ["-F", "-L", "alma-root"]);
+        assert!(mkfs.env.is_empty());
+
+        let reproducible = Reproducible::new("seed").unwrap();
+        let mkfs = FilesystemType::Ext4.ops().mkfs(None, Some(&reproducible));
+        assert_eq!(args(&mkfs)[..2], ["-F", "-U"]);
+        assert_eq!(
+            mkfs.env,
+            [("E2FSPROGS_FAKE_TIME", reproducible.epoch().to_string())]
+        );
+
+        let mkfs = FilesystemType::Vfat
+            .ops()
+            .mkfs(Some("ALMA-BOOT"), Some(&reproducible));
+        assert_eq!(args(&mkfs)[..4], ["-F32", "-n", "ALMA-BOOT", "--invariant"]);
+
+        let mkfs = FilesystemType::Exfat
+            .ops()
+            .mkfs(Some("ALMA-DATA"), Some(&reproducible));
+        assert_eq!(args(&mkfs), ["-L", "ALMA-DATA"]);
+    }
+
+    #[test]
+    fn test_fstab_entry() {
+        assert_eq!(
+            FilesystemType::Ext4
+                .ops()
+                .fstab_entry("UUID=abcd", "/", "noatime"),
+            "UUID=abcd / ext4 noatime 0 1\n"
+        );
+        assert_eq!(
+            FilesystemType::Btrfs
+                .ops()
+                .fstab_entry("UUID=abcd", "/home", "subvol=@home"),
+            "UUID=abcd /home btrfs subvol=@home 0 0\n"
+        );
+        assert_eq!(
+            FilesystemType::Vfat
+                .ops()
+                .fstab_entry("UUID=AB-CD", "/boot", "rw"),
+            "UUID=AB-CD /boot vfat rw 0 2\n"
+        );
+    }
+
+    #[test]
+    fn test_resize() {
+        let device = Path::new("/dev/mapper/alma_root");
+        let steps = FilesystemType::Ext4.ops().resize(device, None).unwrap();
+        assert_eq!(
+            steps.iter().map(|s| s.tool).collect::<Vec<_>>(),
+            ["e2fsck", "resize2fs"]
+        );
+        let steps = FilesystemType::Ext4
+            .ops()
+            .resize(device, Some(8 * 1024 * 1024 * 1024))
+            .unwrap();
+        assert_eq!(args(&steps[1]), ["/dev/mapper/alma_root", "8388608K"]);
+
+        let btrfs = FilesystemType::Btrfs.ops();
+        assert!(btrfs.resizes_online());
+        let steps = btrfs.resize(Path::new("/mnt"), None).unwrap();
+        assert_eq!(args(&steps[0]), ["filesystem", "resize", "max", "/mnt"]);
+
+        assert!(FilesystemType::Vfat.ops().resize(device, None).is_err());
+
+        let steps = FilesystemType::Ext4.ops().shrink(device).unwrap();
+        assert_eq!(args(&steps[1]), ["-M", "/dev/mapper/alma_root"]);
+        assert!(FilesystemType::Btrfs.ops().shrink(device).is_err());
+    }
+
+    #[test]
+    fn test_set_uuid() {
+        let reproducible = Reproducible::new("seed").unwrap();
+        let device = Path::new("/dev/sdb3");
+        let steps = FilesystemType::Ext4
+            .ops()
+            .set_uuid(device, &reproducible)
+            .unwrap();
+        assert_eq!(
+            steps.iter().map(|s| s.tool).collect::<Vec<_>>(),
+            ["e2fsck", "tune2fs"]
+        );
+        let uuid = reproducible.uuid("ext4");
+        assert_eq!(args(&steps[1]), ["-U", uuid.as_str(), "/dev/sdb3"]);
+
+        let steps = FilesystemType::Vfat
+            .ops()
+            .set_uuid(Path::new("/dev/sdb1"), &reproducible)
+            .unwrap();
+        assert_eq!(args(&steps[0])[..2], ["-i", "/dev/sdb1"]);
+        assert!(
+            FilesystemType::Exfat
+                .ops()
+                .set_uuid(device, &reproducible)
+                .is_err()
+        );
+    }
+}
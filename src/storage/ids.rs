@@ -0,0 +1,121 @@
+//! Stable identifiers of block devices, for configuration read on other machines where the
+//! device paths differ.
+
+use super::markers::BlockDevice;
+use crate::process::CommandExt;
+use crate::tool::Tool;
+use anyhow::{Context, anyhow};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Where udev links disks under names made of their bus, model and serial number
+const BY_ID_DIR: &str = "/dev/disk/by-id";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Identifier {
+    /// UUID of the filesystem, or of the LUKS header or swap area
+    Uuid,
+    Label,
+}
+
+impl Identifier {
+    /// The blkid tag, which is also the prefix understood by fstab, crypttab and the kernel
+    fn tag(self) -> &'static str {
+        match self {
+            Self::Uuid => "UUID",
+            Self::Label => "LABEL",
+        }
+    }
+
+    /// `TAG=value`, as used in fstab, crypttab and kernel parameters
+    pub fn spec(self, value: &str) -> String {
+        format!("{}={value}", self.tag())
+    }
+}
+
+impl fmt::Display for Identifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.tag())
+    }
+}
+
+/// Reads an identifier of the device with blkid. Dry runs get a placeholder, since the device
+/// was not actually formatted.
+pub fn read(blkid: &Tool, device: &dyn BlockDevice, id: Identifier) -> anyhow::Result<String> {
+    let output = blkid
+        .execute()
+        .args(["-o", "value", "-s", id.tag()])
+        .arg(device.path())
+        .run_text_output(blkid.dryrun)
+        .context("Failed to run blkid")?;
+    if blkid.dryrun {
+        return Ok(String::from(match id {
+            Identifier::Uuid => "00000000-0000-0000-0000-000000000000",
+            Identifier::Label => "ALMA",
+        }));
+    }
+
+    let value = output.trim();
+    if value.is_empty() {
+        return Err(anyhow!("{} has no {id}", device.path().display()));
+    }
+    Ok(value.to_owned())
+}
+
+pub fn uuid(blkid: &Tool, device: &dyn BlockDevice) -> anyhow::Result<String> {
+    read(blkid, device, Identifier::Uuid)
+}
+
+/// The /dev/disk/by-id link of the device at the canonical path `device`, which unlike /dev/sdX
+/// names the same disk across reboots. Loop devices have none.
+pub fn by_id_link(device: &Path) -> Option<PathBuf> {
+    let names = fs::read_dir(BY_ID_DIR)
+        .ok()?
+        .filter_map(Result::ok)
+        .filter(|entry| fs::canonicalize(entry.path()).is_ok_and(|target| target == device))
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect();
+    preferred_link(names).map(|name| Path::new(BY_ID_DIR).join(name))
+}
+
+/// Prefers the links made of the model and serial number (ata-, nvme-, usb-, mmc-) to the WWN
+/// and EUI ones, which mean nothing to a person, and the shortest of those, since NVMe disks
+/// also have one with the namespace appended
+fn preferred_link(names: Vec<String>) -> Option<String> {
+    names.into_iter().min_by_key(|name| {
+        let opaque = ["wwn-", "nvme-eui.", "nvme-nvme."]
+            .iter()
+            .any(|prefix| name.starts_with(prefix));
+        (opaque, name.len(), name.clone())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spec() {
+        assert_eq!(Identifier::Uuid.spec("abcd"), "UUID=abcd");
+        assert_eq!(Identifier::Label.spec("ALMA-DATA"), "LABEL=ALMA-DATA");
+    }
+
+    #[test]
+    fn test_preferred_link() {
+        let names = [
+            "nvme-eui.0025388b91b3c4d1",
+            "nvme-Samsung_SSD_970_EVO_1TB_S467NX0M123456_1",
+            "nvme-Samsung_SSD_970_EVO_1TB_S467NX0M123456",
+        ];
+        assert_eq!(
+            preferred_link(names.map(String::from).to_vec()).as_deref(),
+            Some("nvme-Samsung_SSD_970_EVO_1TB_S467NX0M123456")
+        );
+        assert_eq!(
+            preferred_link(vec![String::from("wwn-0x5002538e40a1b2c3")]).as_deref(),
+            Some("wwn-0x5002538e40a1b2c3")
+        );
+        assert_eq!(preferred_link(vec![]), None);
+    }
+}
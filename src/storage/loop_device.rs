@@ -1,4 +1,4 @@
-use crate::{process::CommandExt, tool::Tool};
+use crate::{process::CommandExt, state, tool::Tool};
 use anyhow::Context;
 use log::info;
 use std::path::{Path, PathBuf};
@@ -12,10 +12,22 @@ pub struct LoopDevice {
 
 impl LoopDevice {
     pub fn create(file: &Path, dryrun: bool) -> anyhow::Result<Self> {
+        Self::attach(file, false, dryrun)
+    }
+
+    /// Attaches the file as a read-only loop device
+    pub fn create_read_only(file: &Path) -> anyhow::Result<Self> {
+        Self::attach(file, true, false)
+    }
+
+    fn attach(file: &Path, read_only: bool, dryrun: bool) -> anyhow::Result<Self> {
         let losetup = Tool::find("losetup", dryrun)?;
-        let output = losetup
-            .execute()
-            .args(["--find", "-P", "--show"])
+        let mut command = losetup.execute();
+        command.args(["--find", "-P", "--show"]);
+        if read_only {
+            command.arg("--read-only");
+        }
+        let output = command
             .arg(file)
             .run_text_output(dryrun)
             .context("Error creating the image")?;
@@ -26,6 +38,9 @@ impl LoopDevice {
             PathBuf::from(output.trim())
         };
         info!("Mounted {} to {}", file.display(), path.display());
+        if !dryrun {
+            state::register_loop(&path, file);
+        }
 
         Ok(Self {
             path,
@@ -42,11 +57,15 @@ impl LoopDevice {
 impl Drop for LoopDevice {
     fn drop(&mut self) {
         info!("Detaching loop device {}", self.path.display());
-        self.losetup
+        let detached = self
+            .losetup
             .execute()
             .arg("-d")
             .arg(&self.path)
             .run(self.dryrun)
-            .ok();
+            .is_ok();
+        if detached && !self.dryrun {
+            state::release_loop(&self.path);
+        }
     }
 }
@@ -0,0 +1,121 @@
+use crate::process::CommandExt;
+use crate::state;
+use crate::tool::Tool;
+use anyhow::Context;
+use log::{debug, warn};
+use std::path::{Path, PathBuf};
+
+/// The volume group the LVM physical volume belongs to, if any
+pub fn volume_group_of(physical_volume: &Path) -> anyhow::Result<Option<String>> {
+    let output = Tool::find("pvs", false)?
+        .execute()
+        .args(["--noheadings", "-o", "vg_name"])
+        .arg(physical_volume)
+        .run_text_output(false)
+        .with_context(|| {
+            format!(
+                "Error reading the LVM metadata of {}",
+                physical_volume.display()
+            )
+        })?;
+    Ok(parse_fields(&output).next().map(String::from))
+}
+
+/// An activated LVM volume group, deactivated when dropped
+#[derive(Debug)]
+pub struct VolumeGroup {
+    vgchange: Tool,
+    lvs: Tool,
+    name: String,
+    /// Whether the group was activated here, rather than already in use on the host
+    activated: bool,
+}
+
+impl VolumeGroup {
+    /// Activates the logical volumes of the group. Its physical volumes may be spread over
+    /// several disks, which all have to be connected.
+    pub fn activate(name: &str) -> anyhow::Result<Self> {
+        let vgchange = Tool::find("vgchange", false)?;
+        let lvs = Tool::find("lvs", false)?;
+        // /dev/<volume group> only exists while some of its logical volumes are active
+        let activated = !Path::new("/dev").join(name).exists();
+        if activated {
+            debug!("Activating the LVM volume group {name}");
+            vgchange
+                .execute()
+                .arg("-ay")
+                .arg(name)
+                .run(false)
+                .with_context(|| format!("Error activating the LVM volume group {name}"))?;
+            state::register_volume_group(name);
+        }
+
+        Ok(Self {
+            vgchange,
+            lvs,
+            name: name.to_owned(),
+            activated,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The device paths of the logical volumes, e.g. /dev/vg0/root
+    pub fn logical_volumes(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let output = self
+            .lvs
+            .execute()
+            .args(["--noheadings", "-o", "lv_path"])
+            .arg(&self.name)
+            .run_text_output(false)
+            .with_context(|| format!("Error listing the logical volumes of {}", self.name))?;
+        Ok(parse_fields(&output).map(PathBuf::from).collect())
+    }
+
+    fn _deactivate(&mut self) -> anyhow::Result<()> {
+        if !self.activated {
+            return Ok(());
+        }
+        debug!("Deactivating the LVM volume group {}", self.name);
+        self.vgchange
+            .execute()
+            .arg("-an")
+            .arg(&self.name)
+            .run(false)
+            .context("Error deactivating the LVM volume group")?;
+        state::release_volume_group(&self.name);
+
+        Ok(())
+    }
+}
+
+impl Drop for VolumeGroup {
+    fn drop(&mut self) {
+        if self._deactivate().is_err() {
+            warn!("Error deactivating {}", self.name);
+        }
+    }
+}
+
+/// The values of a single column report of pvs or lvs, which are indented and may be empty
+fn parse_fields(output: &str) -> impl Iterator<Item = &str> {
+    output.lines().map(str::trim).filter(|s| !s.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_fields() {
+        let lvs = "  /dev/vg0/root\n  /dev/vg0/swap\n";
+        assert_eq!(
+            parse_fields(lvs).collect::<Vec<_>>(),
+            ["/dev/vg0/root", "/dev/vg0/swap"]
+        );
+        // A physical volume which is not in a group yet
+        assert_eq!(parse_fields("    \n").next(), None);
+    }
+}
@@ -1,16 +1,23 @@
 mod crypt;
 pub mod filesystem;
+pub mod ids;
 mod loop_device;
+mod lvm;
 mod markers;
 mod mount_stack;
 pub mod partition;
+pub mod raid;
+mod reconnect;
 mod removeable_devices;
 mod storage_device;
 
 pub use crypt::{EncryptedDevice, is_encrypted_device};
 pub use filesystem::Filesystem;
 pub use loop_device::LoopDevice;
+pub use lvm::{VolumeGroup, volume_group_of};
 pub use markers::BlockDevice;
 pub use mount_stack::MountStack;
-pub use removeable_devices::get_storage_devices;
+pub use raid::RaidArray;
+pub use reconnect::DeviceWatch;
+pub use removeable_devices::{Device, get_storage_devices};
 pub use storage_device::StorageDevice;
@@ -1,12 +1,39 @@
+use super::reconnect;
+use crate::plan::{self, Action};
+use crate::process::CommandExt;
+use crate::state;
 use crate::storage::filesystem::Filesystem;
-use anyhow::anyhow;
+use anyhow::{Context, anyhow};
 use log::{debug, warn};
-use nix::mount::{MsFlags, mount, umount};
+use nix::mount::{MntFlags, MsFlags, mount, umount, umount2};
 use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// A mount made by the stack, kept so it can be made again from another device
+struct Mounted {
+    source: PathBuf,
+    target: PathBuf,
+    fstype: Option<String>,
+    flags: MsFlags,
+    data: Option<String>,
+    /// The `X-mount.idmap` option of an idmapped bind mount
+    idmap: Option<String>,
+}
+
+/// Makes an idmapped bind mount with mount(8), since nix has no mount_setattr
+fn mount_idmapped(source: &Path, target: &Path, idmap: &str) -> anyhow::Result<()> {
+    Command::new("mount")
+        .arg("--bind")
+        .arg("-o")
+        .arg(format!("X-mount.idmap={idmap}"))
+        .arg(source)
+        .arg(target)
+        .run(false)
+}
 
 pub struct MountStack<'a> {
-    targets: Vec<PathBuf>,
+    mounts: Vec<Mounted>,
     _lifetime: PhantomData<&'a ()>, // Changed to a generic lifetime
     dryrun: bool,
 }
@@ -14,7 +41,7 @@ pub struct MountStack<'a> {
 impl<'a> MountStack<'a> {
     pub fn new(dryrun: bool) -> Self {
         MountStack {
-            targets: Vec::new(),
+            mounts: Vec::new(),
             _lifetime: PhantomData,
             dryrun,
         }
@@ -39,24 +66,31 @@ impl<'a> MountStack<'a> {
         );
         if !self.dryrun {
             mount(Some(source), target, fstype, flags, data)?;
+            state::register_mount(target);
         } else {
-            let type_str = fstype.map_or(String::new(), |t| format!("-t {t}"));
             // In dryrun, we lump flags and data into a single -o for simplicity.
-            let opts_str = match (flags.contains(MsFlags::MS_NOATIME), data) {
-                (true, Some(d)) => format!("-o noatime,{d}"),
-                (true, None) => "-o noatime".to_string(),
-                (false, Some(d)) => format!("-o {d}"),
-                (false, None) => String::new(),
+            let options = match (flags.contains(MsFlags::MS_NOATIME), data) {
+                (true, Some(d)) => Some(format!("noatime,{d}")),
+                (true, None) => Some(String::from("noatime")),
+                (false, Some(d)) => Some(d.to_owned()),
+                (false, None) => None,
             };
-            println!(
-                "mount {} {} {} {}",
-                type_str,
-                opts_str,
-                source.display(),
-                target.display()
-            );
+            plan::print(Action::Mount {
+                source: source.to_path_buf(),
+                target: target.to_path_buf(),
+                fstype: fstype.map(String::from),
+                options,
+                bind: false,
+            });
         }
-        self.targets.push(target.to_path_buf());
+        self.mounts.push(Mounted {
+            source: source.to_path_buf(),
+            target: target.to_path_buf(),
+            fstype: fstype.map(String::from),
+            flags,
+            data: data.map(String::from),
+            idmap: None,
+        });
         Ok(())
     }
 
@@ -83,39 +117,136 @@ impl<'a> MountStack<'a> {
         options: Option<&str>,
     ) -> nix::Result<()> {
         debug!("Mounting {source:?} to {target:?}");
+        let flags = MsFlags::MS_BIND | MsFlags::MS_NOATIME; // Read-only flag has no effect for bind mounts
         if !self.dryrun {
-            mount::<_, _, str, _>(
-                Some(&source),
-                &target,
-                None,
-                MsFlags::MS_BIND | MsFlags::MS_NOATIME, // Read-only flag has no effect for bind mounts
-                options,
-            )?;
+            mount::<_, _, str, _>(Some(&source), &target, None, flags, options)?;
+            state::register_mount(&target);
         } else {
             // TODO: Add flags, etc.
-            println!("mount --bind {} {}", source.display(), target.display());
+            plan::print(Action::Mount {
+                source: source.clone(),
+                target: target.clone(),
+                fstype: None,
+                options: options.map(String::from),
+                bind: true,
+            });
         }
-        self.targets.push(target);
+        self.mounts.push(Mounted {
+            source,
+            target,
+            fstype: None,
+            flags,
+            data: options.map(String::from),
+            idmap: None,
+        });
         Ok(())
     }
 
+    /// Bind mounts `source` with the owners of its files mapped by `idmap`, e.g.
+    /// `u:1000:1001:1`, so they differ at `target` without changing them in `source`
+    pub fn bind_mount_idmapped(
+        &mut self,
+        source: PathBuf,
+        target: PathBuf,
+        idmap: &str,
+    ) -> anyhow::Result<()> {
+        debug!("Mounting {source:?} to {target:?} with the IDs mapped by {idmap}");
+        if !self.dryrun {
+            mount_idmapped(&source, &target, idmap)?;
+            state::register_mount(&target);
+        } else {
+            plan::print(Action::Mount {
+                source: source.clone(),
+                target: target.clone(),
+                fstype: None,
+                options: Some(format!("X-mount.idmap={idmap}")),
+                bind: true,
+            });
+        }
+        self.mounts.push(Mounted {
+            source,
+            target,
+            fstype: None,
+            flags: MsFlags::MS_BIND,
+            data: None,
+            idmap: Some(String::from(idmap)),
+        });
+        Ok(())
+    }
+
+    /// Mounts everything again after the disk at `old_device` came back as `new_device`, taking
+    /// the partitions from the new device. The old mounts are detached, since their device is
+    /// gone. Returns the number of mounts of partitions which moved.
+    pub fn remount_moved(&mut self, old_device: &Path, new_device: &Path) -> anyhow::Result<usize> {
+        let moved = self
+            .mounts
+            .iter()
+            .filter(|m| reconnect::moved_partition(&m.source, old_device, new_device).is_some())
+            .count();
+        if moved == 0 || self.dryrun {
+            return Ok(moved);
+        }
+        // The mounts further up the stack are on top of the moved ones, so all of them are redone
+        for mounted in self.mounts.iter().rev() {
+            if let Err(e) = umount2(&mounted.target, MntFlags::MNT_DETACH) {
+                warn!("Unable to detach {}: {e}", mounted.target.display());
+            }
+        }
+        for mounted in &mut self.mounts {
+            if let Some(source) =
+                reconnect::moved_partition(&mounted.source, old_device, new_device)
+            {
+                mounted.source = source;
+            }
+            debug!(
+                "Mounting {} to {} again",
+                mounted.source.display(),
+                mounted.target.display()
+            );
+            let mounted_again = match &mounted.idmap {
+                Some(idmap) => mount_idmapped(&mounted.source, &mounted.target, idmap),
+                None => mount(
+                    Some(&mounted.source),
+                    &mounted.target,
+                    mounted.fstype.as_deref(),
+                    mounted.flags,
+                    mounted.data.as_deref(),
+                )
+                .map_err(anyhow::Error::from),
+            };
+            mounted_again.with_context(|| {
+                format!(
+                    "Failed to mount {} to {} again",
+                    mounted.source.display(),
+                    mounted.target.display()
+                )
+            })?;
+        }
+        Ok(moved)
+    }
+
     fn _umount(&mut self) -> anyhow::Result<()> {
         let mut result = Ok(());
 
-        while let Some(target) = self.targets.pop() {
+        while let Some(Mounted { target, .. }) = self.mounts.pop() {
             debug!("Unmounting {}", target.display());
 
             if !self.dryrun {
-                if let Err(e) = umount(&target) {
-                    warn!("Unable to umount {}: {}", target.display(), e);
-                    result = Err(anyhow!(
-                        "Failed unmounting filesystem: {}, {}",
-                        target.display(),
-                        e
-                    ));
+                match umount(&target) {
+                    Ok(()) => state::release_mount(&target),
+                    Err(e) => {
+                        warn!("Unable to umount {}: {}", target.display(), e);
+                        result = Err(anyhow!(
+                            "Failed unmounting filesystem: {}, {}",
+                            target.display(),
+                            e
+                        ));
+                    }
                 };
             } else {
-                println!("umount {}", target.display());
+                plan::print(Action::Umount {
+                    target: target.clone(),
+                });
             }
         }
 
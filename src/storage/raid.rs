@@ -0,0 +1,131 @@
+use super::markers::BlockDevice;
+use crate::process::CommandExt;
+use crate::state;
+use crate::tool::Tool;
+use anyhow::{Context, anyhow};
+use log::{debug, warn};
+use std::path::{Path, PathBuf};
+
+/// Name of the array in its superblock, and so its /dev/md/ name on the installed system
+pub const ARRAY_NAME: &str = "alma_root";
+
+/// An mdadm RAID-1 array, stopped when dropped
+#[derive(Debug)]
+pub struct RaidArray<'t> {
+    mdadm: &'t Tool,
+    path: PathBuf,
+}
+
+impl<'t> RaidArray<'t> {
+    /// Creates a RAID-1 array mirroring the members, with the given array UUID instead of a
+    /// random one
+    pub fn create(
+        mdadm: &'t Tool,
+        members: [&dyn BlockDevice; 2],
+        node: &str,
+        uuid: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let path = Path::new("/dev/md").join(node);
+        debug!("Creating the RAID-1 array {}", path.display());
+        let mut command = mdadm.execute();
+        command
+            .arg("--create")
+            .arg(&path)
+            .args([
+                "--run",
+                "--level=1",
+                "--raid-devices=2",
+                "--metadata=1.2",
+                "--homehost=any",
+            ])
+            .arg(format!("--name={ARRAY_NAME}"));
+        if let Some(uuid) = uuid {
+            command.arg(format!("--uuid={uuid}"));
+        }
+        command
+            .args(members.map(|m| m.path()))
+            .run(mdadm.dryrun)
+            .context("Error creating the RAID array")?;
+
+        Ok(Self::opened(mdadm, path))
+    }
+
+    /// Assembles the array created by an earlier run
+    pub fn assemble(
+        mdadm: &'t Tool,
+        members: [&dyn BlockDevice; 2],
+        node: &str,
+    ) -> anyhow::Result<Self> {
+        let path = Path::new("/dev/md").join(node);
+        debug!("Assembling the RAID-1 array {}", path.display());
+        mdadm
+            .execute()
+            .arg("--assemble")
+            .arg(&path)
+            .args(members.map(|m| m.path()))
+            .run(mdadm.dryrun)
+            .context("Error assembling the RAID array")?;
+
+        Ok(Self::opened(mdadm, path))
+    }
+
+    fn opened(mdadm: &'t Tool, path: PathBuf) -> Self {
+        if !mdadm.dryrun {
+            state::register_array(&path);
+        }
+        Self { mdadm, path }
+    }
+
+    /// The UUID of the array, in the format of mdadm.conf
+    pub fn uuid(&self) -> anyhow::Result<String> {
+        let output = self
+            .mdadm
+            .execute()
+            .args(["--detail", "--export"])
+            .arg(&self.path)
+            .run_text_output(self.mdadm.dryrun)
+            .context("Error querying the RAID array")?;
+        if self.mdadm.dryrun {
+            return Ok(String::from("00000000:00000000:00000000:00000000"));
+        }
+        output
+            .lines()
+            .find_map(|line| line.strip_prefix("MD_UUID="))
+            .map(String::from)
+            .ok_or_else(|| anyhow!("mdadm did not report the UUID of {}", self.path.display()))
+    }
+
+    fn _stop(&mut self) -> anyhow::Result<()> {
+        debug!("Stopping the RAID array {}", self.path.display());
+        self.mdadm
+            .execute()
+            .arg("--stop")
+            .arg(&self.path)
+            .run(self.mdadm.dryrun)
+            .context("Error stopping the RAID array")?;
+        if !self.mdadm.dryrun {
+            state::release_array(&self.path);
+        }
+
+        Ok(())
+    }
+}
+
+impl<'t> Drop for RaidArray<'t> {
+    fn drop(&mut self) {
+        if self._stop().is_err() {
+            warn!("Error stopping {}", self.path.display());
+        }
+    }
+}
+
+impl<'t> BlockDevice for RaidArray<'t> {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// The line of /etc/mdadm.conf which assembles the array on the installed system
+pub fn mdadm_conf_entry(uuid: &str) -> String {
+    format!("ARRAY /dev/md/{ARRAY_NAME} metadata=1.2 UUID={uuid}\n")
+}
@@ -0,0 +1,159 @@
+//! USB disks on flaky hubs or cables can drop off the bus during a long build and come back as
+//! another node, e.g. /dev/sdb as /dev/sdc, which leaves the mounted filesystems without their
+//! device. The target is followed by its /dev/disk/by-id link, and its filesystems are mounted
+//! again from the new node before the next phase, instead of the phase failing deep inside
+//! pacstrap or a chroot.
+
+use super::markers::BlockDevice;
+use super::{MountStack, StorageDevice};
+use crate::error::{ErrorKind, ErrorKindExt};
+use anyhow::anyhow;
+use log::{info, warn};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a disconnected device has to come back
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The partition of `new_device` taking the place of `partition`, if it is a partition of
+/// `old_device`
+pub fn moved_partition(partition: &Path, old_device: &Path, new_device: &Path) -> Option<PathBuf> {
+    let suffix = partition.to_str()?.strip_prefix(old_device.to_str()?)?;
+    let number = suffix.strip_prefix('p').unwrap_or(suffix);
+    if number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some(PathBuf::from(format!("{}{suffix}", new_device.display())))
+}
+
+/// The sequence number the kernel gives each disk it attaches, which changes when the disk comes
+/// back, even under the same name. Older kernels have none.
+fn diskseq(device: &Path) -> Option<u64> {
+    let name = device.file_name()?.to_str()?;
+    fs::read_to_string(format!("/sys/class/block/{name}/diskseq"))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// The node the by-id link points to, and its sequence number
+fn resolve(id_link: &Path) -> Option<(PathBuf, Option<u64>)> {
+    let node = fs::canonicalize(id_link).ok()?;
+    let seq = diskseq(&node);
+    Some((node, seq))
+}
+
+pub struct DeviceWatch {
+    id_link: Option<PathBuf>,
+    node: PathBuf,
+    seq: Option<u64>,
+    /// An encrypted or RAID root sits on device mapper or md devices, which cannot be moved to
+    /// another disk while the build runs
+    stacked_root: bool,
+}
+
+impl DeviceWatch {
+    /// Watches the target, if it has a by-id link. Loop devices of images have none and do not
+    /// disconnect.
+    pub fn new(device: &StorageDevice, stacked_root: bool) -> Self {
+        Self {
+            id_link: device.id_link().map(Path::to_path_buf),
+            node: device.path().to_path_buf(),
+            seq: diskseq(device.path()),
+            stacked_root,
+        }
+    }
+
+    /// Whether the target is no longer the disk it was when the build started
+    fn moved(&self) -> Option<Option<(PathBuf, Option<u64>)>> {
+        let id_link = self.id_link.as_ref()?;
+        match resolve(id_link) {
+            Some((node, seq)) if node == self.node && seq == self.seq => None,
+            current => Some(current),
+        }
+    }
+
+    /// Before the next phase, mounts the filesystems of the target again if it was disconnected
+    /// and came back, waiting for it if it is still gone
+    pub fn check(&mut self, mount_stack: &mut MountStack) -> anyhow::Result<()> {
+        let (Some(id_link), Some(mut current)) = (self.id_link.clone(), self.moved()) else {
+            return Ok(());
+        };
+        warn!(
+            "{} was disconnected during the build, waiting for it to come back",
+            id_link.display()
+        );
+        let start = Instant::now();
+        while current.is_none() && start.elapsed() < RECONNECT_TIMEOUT {
+            thread::sleep(Duration::from_secs(1));
+            current = resolve(&id_link);
+        }
+        let resume_hint = format!(
+            "Once it is back, continue with the same command and --resume {}",
+            id_link.display()
+        );
+        let Some((node, seq)) = current else {
+            return Err(anyhow!(
+                "{} did not come back within {} seconds. {resume_hint}",
+                id_link.display(),
+                RECONNECT_TIMEOUT.as_secs()
+            ))
+            .kind(ErrorKind::DeviceSafety);
+        };
+        if self.stacked_root {
+            return Err(anyhow!(
+                "{} came back as {}, but an encrypted or RAID root cannot be moved to it during the build. {resume_hint}",
+                id_link.display(),
+                node.display()
+            ))
+            .kind(ErrorKind::DeviceSafety);
+        }
+
+        let moved = mount_stack.remount_moved(&self.node, &node)?;
+        info!(
+            "{} came back as {}, mounted {moved} filesystems from it again",
+            id_link.display(),
+            node.display()
+        );
+        self.node = node;
+        self.seq = seq;
+        Ok(())
+    }
+
+    /// Explains a failed build by the target having been disconnected, if it was
+    pub fn warn_if_disconnected(&self) {
+        if let (Some(id_link), Some(_)) = (&self.id_link, self.moved()) {
+            warn!(
+                "{} was disconnected during the build, which is likely why it failed. Once it is back, continue with the same command and --resume {}",
+                id_link.display(),
+                id_link.display()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_moved_partition() {
+        let moved = |partition, old, new| {
+            moved_partition(Path::new(partition), Path::new(old), Path::new(new))
+        };
+        assert_eq!(
+            moved("/dev/sdb3", "/dev/sdb", "/dev/sdc"),
+            Some(PathBuf::from("/dev/sdc3"))
+        );
+        assert_eq!(
+            moved("/dev/mmcblk0p2", "/dev/mmcblk0", "/dev/mmcblk1"),
+            Some(PathBuf::from("/dev/mmcblk1p2"))
+        );
+        assert_eq!(moved("/dev/sdb", "/dev/sdb", "/dev/sdc"), None);
+        assert_eq!(moved("/dev/sdba1", "/dev/sdb", "/dev/sdc"), None);
+        assert_eq!(moved("/home/aur", "/dev/sdb", "/dev/sdc"), None);
+    }
+}
@@ -1,24 +1,40 @@
+use super::ids;
 use anyhow::Context;
 use byte_unit::Byte;
+use serde::{Serialize, Serializer};
+use std::path::{Path, PathBuf};
 use std::{fmt, fs};
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct Device {
-    model: String,
-    vendor: String,
-    size: Byte,
     pub name: String,
+    #[serde(rename = "size_bytes", serialize_with = "serialize_size")]
+    pub size: Byte,
+    pub vendor: String,
+    pub model: String,
+    /// The bus the disk is attached with, e.g. usb or nvme
+    pub transport: Option<&'static str>,
+    pub removable: bool,
+    pub id_link: Option<PathBuf>,
+}
+
+fn serialize_size<S: Serializer>(size: &Byte, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u128(size.as_u128())
 }
 
 impl fmt::Display for Device {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "{} {} ({})",
+            "{} {} ({}), ",
             self.vendor,
             self.model,
             self.size.get_appropriate_unit(byte_unit::UnitType::Binary)
-        )
+        )?;
+        match &self.id_link {
+            Some(link) => write!(f, "{}", link.display()),
+            None => write!(f, "/dev/{}", self.name),
+        }
     }
 }
 
@@ -26,40 +42,76 @@ fn trimmed(source: String) -> String {
     String::from(source.trim_end())
 }
 
+/// The transport of a disk, from the components of its canonical sysfs path, e.g.
+/// /sys/devices/pci0000:00/0000:00:14.0/usb2/2-1/2-1:1.0/host6/target6:0:0/6:0:0:0/block/sdb
+fn transport_of(sys_path: &Path) -> Option<&'static str> {
+    let components: Vec<String> = sys_path
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    let has = |prefix: &str| components.iter().any(|c| c.starts_with(prefix));
+    // A USB to SATA or NVMe adapter shows both, the disk is attached with USB
+    if has("usb") {
+        Some("usb")
+    } else if has("nvme") {
+        Some("nvme")
+    } else if has("mmc") {
+        Some("mmc")
+    } else if has("virtio") {
+        Some("virtio")
+    } else if has("ata") {
+        Some("sata")
+    } else {
+        None
+    }
+}
+
 pub fn get_storage_devices(allow_non_removable: bool) -> anyhow::Result<Vec<Device>> {
     let mut result = Vec::new();
 
     for entry in fs::read_dir("/sys/block").context("Error querying storage devices")? {
         let entry = entry.context("Error querying storage devices")?;
 
-        let removable = allow_non_removable
-            || fs::read_to_string(entry.path().join("removable"))
-                .map(|v| v == "1\n")
-                .context("Error querying storage devices")?;
+        // Loop, device mapper, RAID and zram devices are not disks
+        if !entry.path().join("device").exists() {
+            continue;
+        }
+
+        let removable = fs::read_to_string(entry.path().join("removable"))
+            .map(|v| v == "1\n")
+            .context("Error querying storage devices")?;
 
-        if !removable {
+        if !removable && !allow_non_removable {
             continue;
         }
 
+        // SD cards and some NVMe drives have no model or vendor
         let model = fs::read_to_string(entry.path().join("device/model"))
             .map(trimmed)
-            .context("Error querying storage devices")?;
+            .unwrap_or_default();
 
         if model == "CD-ROM" {
             continue;
         }
 
+        let name = entry
+            .path()
+            .file_name()
+            .expect("Could not get file name for dir entry /sys/block")
+            .to_string_lossy()
+            .into_owned();
         result.push(Device {
-            name: entry
-                .path()
-                .file_name()
-                .expect("Could not get file name for dir entry /sys/block")
-                .to_string_lossy()
-                .into_owned(),
+            id_link: ids::by_id_link(&Path::new("/dev").join(&name)),
+            name,
             model,
             vendor: fs::read_to_string(entry.path().join("device/vendor"))
                 .map(trimmed)
-                .context("Error querying storage devices")?,
+                .unwrap_or_default(),
+            transport: fs::canonicalize(entry.path())
+                .ok()
+                .as_deref()
+                .and_then(transport_of),
+            removable,
             size: Byte::from_u128(
                 fs::read_to_string(entry.path().join("size"))
                     .context("Error querying storage devices")?
@@ -84,4 +136,30 @@ mod tests {
         let devices = get_storage_devices(false).expect("No devices");
         println!("{devices:?}");
     }
+
+    #[test]
+    fn test_transport_of() {
+        assert_eq!(
+            transport_of(Path::new(
+                "/sys/devices/pci0000:00/0000:00:14.0/usb2/2-1/2-1:1.0/host6/target6:0:0/6:0:0:0/block/sdb"
+            )),
+            Some("usb")
+        );
+        assert_eq!(
+            transport_of(Path::new(
+                "/sys/devices/pci0000:00/0000:00:1d.0/0000:3d:00.0/nvme/nvme0/nvme0n1"
+            )),
+            Some("nvme")
+        );
+        assert_eq!(
+            transport_of(Path::new(
+                "/sys/devices/pci0000:00/0000:00:17.0/ata1/host0/target0:0:0/0:0:0:0/block/sda"
+            )),
+            Some("sata")
+        );
+        assert_eq!(
+            transport_of(Path::new("/sys/devices/virtual/block/loop0")),
+            None
+        );
+    }
 }
@@ -1,9 +1,13 @@
 // src/storage/storage_device.rs
+use super::ids;
 use super::markers::{BlockDevice, Origin};
 use super::partition::Partition;
+use crate::error::{ErrorKind, ErrorKindExt};
+use crate::plan::{self, Action};
 use anyhow::{Context, anyhow};
 use byte_unit::Byte;
 use log::debug;
+use std::fmt;
 use std::fs::read_to_string;
 use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
@@ -12,6 +16,8 @@ use std::path::{Path, PathBuf};
 pub struct StorageDevice<'a> {
     name: String,
     path: PathBuf,
+    /// The stable /dev/disk/by-id link of the device, if it has one
+    id_link: Option<PathBuf>,
     size: Byte,
     origin: PhantomData<&'a dyn Origin>,
     mount_config: Vec<MountConfig>,
@@ -28,6 +34,21 @@ impl<'a> StorageDevice<'a> {
         path: &'a Path,
         allow_non_removable: bool,
         dryrun: bool,
+    ) -> anyhow::Result<Self> {
+        Self::open(path, allow_non_removable, dryrun, true)
+    }
+
+    /// Like [`StorageDevice::from_path`], for devices which are only read, so write-protected
+    /// devices are accepted
+    pub fn from_path_read_only(path: &'a Path, allow_non_removable: bool) -> anyhow::Result<Self> {
+        Self::open(path, allow_non_removable, false, false)
+    }
+
+    fn open(
+        path: &'a Path,
+        allow_non_removable: bool,
+        dryrun: bool,
+        writable: bool,
     ) -> anyhow::Result<Self> {
         debug!("path: {path:?}");
 
@@ -57,10 +78,12 @@ impl<'a> StorageDevice<'a> {
 
         let path_as_str = path.to_str().context("Unable to get the path as &str ")?;
         let mount_config = Self::get_mount_point(path_as_str)?;
+        let id_link = if dryrun { None } else { ids::by_id_link(&path) };
 
         let _self = Self {
             name: device_name,
             path,
+            id_link,
             size,
             origin: PhantomData,
             mount_config,
@@ -75,9 +98,18 @@ impl<'a> StorageDevice<'a> {
             || dryrun)
         {
             return Err(anyhow!(
-                "The given block device is neither removable nor a loop device: {}",
-                _self.name
-            ));
+                "The given block device is neither removable nor a loop device: {_self}"
+            ))
+            .kind(ErrorKind::DeviceSafety);
+        }
+
+        // Fails here rather than with an obscure error from sgdisk or mkfs
+        if writable && _self.is_read_only() {
+            return Err(anyhow!(
+                "{_self} is read-only. If it is an SD card, slide the lock switch on its side away from LOCK and insert it again, otherwise make it writable with `blockdev --setrw {}`",
+                _self.path.display()
+            ))
+            .kind(ErrorKind::DeviceSafety);
         }
 
         Ok(_self)
@@ -101,6 +133,14 @@ impl<'a> StorageDevice<'a> {
         Ok(result == "1\n")
     }
 
+    /// Whether the kernel refuses writes to the device, which is how the write-protect switch of
+    /// an SD card shows up
+    fn is_read_only(&self) -> bool {
+        let mut path = self.sys_path();
+        path.push("ro");
+        read_to_string(&path).is_ok_and(|ro| ro.trim() == "1")
+    }
+
     fn is_loop_device(&self) -> bool {
         let mut path = self.sys_path();
         path.push("loop");
@@ -136,7 +176,9 @@ impl<'a> StorageDevice<'a> {
                 // Ignore result, as we're just trying to clean up
                 let _ = nix::mount::umount(&config.mount_point);
             } else {
-                println!("umount {}", config.mount_point.display());
+                plan::print(Action::Umount {
+                    target: config.mount_point.clone(),
+                });
             }
         }
         self.mount_config = vec![]
@@ -150,6 +192,19 @@ impl<'a> StorageDevice<'a> {
         self.size
     }
 
+    pub fn id_link(&self) -> Option<&Path> {
+        self.id_link.as_deref()
+    }
+
+    /// The node the device has now, which differs from its path if it was disconnected during
+    /// the build and came back under another name
+    pub fn current_path(&self) -> PathBuf {
+        self.id_link
+            .as_ref()
+            .and_then(|link| link.canonicalize().ok())
+            .unwrap_or_else(|| self.path.clone())
+    }
+
     // Code from @assapir - can we do this without manually reading mounts file?
     /// Reads mount points for StorageDevice - note there can be multiple mounts
     fn get_mount_point(path: &str) -> anyhow::Result<Vec<MountConfig>> {
@@ -175,3 +230,14 @@ impl<'a> BlockDevice for StorageDevice<'a> {
 }
 
 impl<'a> Origin for StorageDevice<'a> {}
+
+/// The by-id link followed by the kernel name, e.g. `/dev/disk/by-id/usb-SanDisk_Ultra_4C53 (sdb)`,
+/// so prompts and logs name the disk unambiguously
+impl fmt::Display for StorageDevice<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.id_link {
+            Some(link) => write!(f, "{} ({})", link.display(), self.name),
+            None => write!(f, "{}", self.path.display()),
+        }
+    }
+}
@@ -0,0 +1,148 @@
+//! Declarative sudo policy for the wheel group, written to /etc/sudoers.d/wheel.
+
+use anyhow::anyhow;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+
+/// Path of the generated policy inside the installation
+pub const SUDOERS_PATH: &str = "etc/sudoers.d/wheel";
+
+/// When sudo shows its lecture about using root privileges responsibly
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SudoLecture {
+    Always,
+    Once,
+    Never,
+}
+
+impl SudoLecture {
+    fn sudoers_value(self) -> &'static str {
+        match self {
+            SudoLecture::Always => "always",
+            SudoLecture::Once => "once",
+            SudoLecture::Never => "never",
+        }
+    }
+}
+
+/// The sudo settings given on the command line
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SudoOptions {
+    #[serde(default)]
+    pub nopasswd_commands: Vec<String>,
+    #[serde(default)]
+    pub timeout: Option<u32>,
+    #[serde(default)]
+    pub lecture: Option<SudoLecture>,
+    #[serde(default)]
+    pub sudo_rs: bool,
+}
+
+impl SudoOptions {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for command in &self.nopasswd_commands {
+            if !command.starts_with('/') {
+                return Err(anyhow!(
+                    "--sudo-nopasswd takes commands with their full path, got '{command}'"
+                ));
+            }
+            if command.contains(['\n', '\r']) {
+                return Err(anyhow!("--sudo-nopasswd command contains a line break"));
+            }
+        }
+        Ok(())
+    }
+
+    /// The sudoers policy of the wheel group. `passwordless` lets wheel run everything without a
+    /// password, otherwise only the --sudo-nopasswd commands are.
+    pub fn sudoers(&self, passwordless: bool) -> String {
+        let mut sudoers = String::from("# Generated by ALMA\n");
+        if let Some(timeout) = self.timeout {
+            writeln!(sudoers, "Defaults timestamp_timeout={timeout}").unwrap();
+        }
+        if let Some(lecture) = self.lecture {
+            writeln!(sudoers, "Defaults lecture={}", lecture.sudoers_value()).unwrap();
+        }
+
+        if passwordless {
+            sudoers.push_str("%wheel ALL=(ALL:ALL) NOPASSWD: ALL\n");
+        } else {
+            sudoers.push_str("%wheel ALL=(ALL:ALL) ALL\n");
+            // The last matching entry wins, so these come after the general rule
+            if !self.nopasswd_commands.is_empty() {
+                let commands: Vec<String> = self
+                    .nopasswd_commands
+                    .iter()
+                    .map(|c| escape_command(c))
+                    .collect();
+                writeln!(
+                    sudoers,
+                    "%wheel ALL=(ALL:ALL) NOPASSWD: {}",
+                    commands.join(", ")
+                )
+                .unwrap();
+            }
+        }
+        sudoers
+    }
+}
+
+/// Escapes the characters which are special in a sudoers command specification
+fn escape_command(command: &str) -> String {
+    let mut escaped = String::with_capacity(command.len());
+    for c in command.chars() {
+        if matches!(c, ',' | ':' | '=' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sudoers() {
+        let options = SudoOptions {
+            nopasswd_commands: vec![
+                String::from("/usr/bin/pacman -Syu"),
+                String::from("/usr/bin/systemctl restart sshd,nginx"),
+            ],
+            timeout: Some(5),
+            lecture: Some(SudoLecture::Never),
+            sudo_rs: false,
+        };
+        assert_eq!(
+            options.sudoers(false),
+            "# Generated by ALMA\n\
+             Defaults timestamp_timeout=5\n\
+             Defaults lecture=never\n\
+             %wheel ALL=(ALL:ALL) ALL\n\
+             %wheel ALL=(ALL:ALL) NOPASSWD: /usr/bin/pacman -Syu, /usr/bin/systemctl restart sshd\\,nginx\n"
+        );
+        assert!(
+            SudoOptions::default()
+                .sudoers(true)
+                .ends_with("%wheel ALL=(ALL:ALL) NOPASSWD: ALL\n")
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut options = SudoOptions {
+            nopasswd_commands: vec![String::from("pacman")],
+            ..SudoOptions::default()
+        };
+        assert!(options.validate().is_err());
+        options.nopasswd_commands = vec![String::from("/usr/bin/pacman")];
+        assert!(options.validate().is_ok());
+    }
+}
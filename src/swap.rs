@@ -0,0 +1,76 @@
+//! Helpers for the swap file created by --swapfile and --hibernate.
+
+use crate::args::RootFilesystemType;
+
+/// Subvolume holding the swap file on btrfs, so it is excluded from snapshots of @
+pub const SWAP_SUBVOLUME: &str = "@swap";
+
+/// Mount point of the swap subvolume, relative to the root of the installation
+pub const SWAP_DIR: &str = "swap";
+
+/// Location of the swap file, relative to the root of the installation
+pub fn swap_file(filesystem: RootFilesystemType) -> &'static str {
+    match filesystem {
+        RootFilesystemType::Ext4 => "swapfile",
+        RootFilesystemType::Btrfs => "swap/swapfile",
+    }
+}
+
+/// Appended to the generated fstab, which does not end with a newline
+pub fn fstab_entry(filesystem: RootFilesystemType) -> String {
+    format!("\n/{} none swap defaults 0 0\n", swap_file(filesystem))
+}
+
+/// Whether an fstab line mounts a swap area, as opposed to e.g. the swap subvolume
+pub fn is_swap_entry(line: &str) -> bool {
+    line.split_whitespace().nth(2) == Some("swap")
+}
+
+/// Extracts the physical offset of the first extent from `filefrag -v` output, which is the
+/// resume_offset of a swap file on ext4.
+pub fn parse_filefrag_offset(output: &str) -> Option<u64> {
+    output
+        .lines()
+        .map(str::trim)
+        .find(|l| l.starts_with("0:"))?
+        .split(':')
+        .nth(2)?
+        .split("..")
+        .next()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_filefrag_offset() {
+        let output = "Filesystem type is: ef53
+File size of /mnt/swapfile is 4294967296 (1048576 blocks of 4096 bytes)
+ ext:     logical_offset:        physical_offset: length:   expected: flags:
+   0:        0..   32767:      34816..     67583:  32768:
+   1:    32768..   65535:      67584..    100351:  32768:
+/mnt/swapfile: 2 extents found
+";
+        assert_eq!(parse_filefrag_offset(output), Some(34816));
+        assert_eq!(
+            parse_filefrag_offset("/mnt/swapfile: 0 extents found"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_swap_entries() {
+        assert_eq!(
+            fstab_entry(RootFilesystemType::Btrfs),
+            "\n/swap/swapfile none swap defaults 0 0\n"
+        );
+        assert!(is_swap_entry("/dev/sda2 none swap defaults 0 0"));
+        assert!(!is_swap_entry(
+            "UUID=abcd /swap btrfs rw,noatime,subvol=/@swap 0 0"
+        ));
+    }
+}
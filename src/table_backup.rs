@@ -0,0 +1,169 @@
+//! `alma create --backup-table DIR`: before a device is wiped, saves its `lsblk` listing and a
+//! backup of its partition table, so wiping the wrong disk leaves what is needed to restore the
+//! partitions with `alma restore-gpt`.
+
+use crate::args::RestoreGptCommand;
+use crate::effects;
+use crate::error::{ErrorKind, ErrorKindExt};
+use crate::motd;
+use crate::process::CommandExt;
+use crate::storage::{BlockDevice, StorageDevice};
+use crate::tool::Tool;
+use anyhow::{Context, anyhow};
+use console::style;
+use dialoguer::{Confirm, theme::ColorfulTheme};
+use log::{info, warn};
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The columns identifying what was on the device and where it was mounted
+const LSBLK_COLUMNS: &str = "NAME,SIZE,TYPE,FSTYPE,LABEL,UUID,PARTLABEL,PARTUUID,MOUNTPOINTS";
+
+/// `<device>-YYYYMMDD-HHMMSS`, the name of the files saved for a device
+fn backup_name(device: &str, timestamp: u64) -> String {
+    let (year, month, day) = motd::civil_date(timestamp);
+    let seconds = timestamp % 86400;
+    format!(
+        "{device}-{year:04}{month:02}{day:02}-{:02}{:02}{:02}",
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+/// The device a backup was saved from, if its file is named like the ones `save` writes
+fn backed_up_device(backup: &Path) -> Option<&str> {
+    let name = backup.file_stem()?.to_str()?;
+    let mut parts = name.rsplitn(3, '-');
+    let time = parts.next()?;
+    let date = parts.next()?;
+    let device = parts.next()?;
+    let digits = |s: &str, len| s.len() == len && s.bytes().all(|b| b.is_ascii_digit());
+    (digits(time, 6) && digits(date, 8) && !device.is_empty()).then_some(device)
+}
+
+pub fn save(sgdisk: &Tool, device: &StorageDevice, dir: &Path, dryrun: bool) -> anyhow::Result<()> {
+    effects::create_dir_all(dir, dryrun)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("The system clock is set before 1970")?
+        .as_secs();
+    let device_name = device.path().file_name().map_or_else(
+        || String::from("device"),
+        |n| n.to_string_lossy().into_owned(),
+    );
+    let name = backup_name(&device_name, now);
+
+    let listing = Tool::find("lsblk", dryrun)?
+        .execute()
+        .args(["-o", LSBLK_COLUMNS])
+        .arg(device.path())
+        .run_text_output(dryrun)
+        .with_context(|| format!("Failed to list the partitions of {device}"))?;
+    let listing_path = dir.join(format!("{name}.lsblk"));
+    effects::write(&listing_path, listing, dryrun)?;
+
+    // A blank device has no partition table to save, which must not stop its installation
+    let table_path = dir.join(format!("{name}.sgdisk"));
+    let backup = sgdisk
+        .execute()
+        .arg(format!("--backup={}", table_path.display()))
+        .arg(device.path())
+        .run(dryrun);
+    match backup {
+        Ok(()) => info!(
+            "Saved the partition table of {device} to {}, restore it with `sgdisk --load-backup={} {}`",
+            table_path.display(),
+            table_path.display(),
+            device.path().display()
+        ),
+        Err(e) => warn!("Could not back up the partition table of {device}: {e:#}"),
+    }
+    info!(
+        "Saved the partitions of {device} to {}",
+        listing_path.display()
+    );
+    Ok(())
+}
+
+/// Loads a partition table saved with `save` back onto a device, once the user has seen what it
+/// held when it was saved
+pub fn restore(command: RestoreGptCommand) -> anyhow::Result<()> {
+    let sgdisk = Tool::find("sgdisk", false)?;
+    if !command.backup.is_file() {
+        return Err(anyhow!(
+            "{} is not a partition table backup",
+            command.backup.display()
+        ))
+        .kind(ErrorKind::Usage);
+    }
+    let device = StorageDevice::from_path(&command.device, command.allow_non_removable, false)?;
+    // The table would change under the mounted filesystems
+    if device.is_mounted() {
+        return Err(anyhow!(
+            "{device} has mounted partitions, unmount them before restoring its partition table"
+        ))
+        .kind(ErrorKind::DeviceSafety);
+    }
+
+    let device_name = device.path().file_name().and_then(|n| n.to_str());
+    let mut prompt = format!(
+        "The partition table of {device} will be replaced with {}. Continue?",
+        command.backup.display()
+    );
+    if let Some(backed_up) = backed_up_device(&command.backup)
+        && device_name != Some(backed_up)
+    {
+        prompt = format!(
+            "{} The backup was saved from {backed_up}, not {}. {prompt}",
+            style("WARNING:").red().bold(),
+            device_name.unwrap_or("this device")
+        );
+    }
+    // The listing saved next to the backup shows what the table held
+    let listing = command.backup.with_extension("lsblk");
+    if let Ok(listing) = fs::read_to_string(&listing) {
+        info!("When the backup was saved, the device held:\n{listing}");
+    }
+    if !command.noconfirm {
+        let confirmed = Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt(prompt)
+            .default(false)
+            .interact()?;
+        if !confirmed {
+            return Err(anyhow!("User aborted operation.")).kind(ErrorKind::Aborted);
+        }
+    }
+
+    // sgdisk has the kernel re-read the table once it is written
+    sgdisk
+        .execute()
+        .arg(format!("--load-backup={}", command.backup.display()))
+        .arg(device.path())
+        .run(false)
+        .with_context(|| format!("Failed to restore the partition table of {device}"))?;
+    info!(
+        "Restored the partition table of {device}. Filesystems on partitions which were not written to since are intact"
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backup_name() {
+        assert_eq!(backup_name("sdb", 1_709_210_096), "sdb-20240229-123456");
+        assert_eq!(
+            backed_up_device(Path::new("/root/backups/nvme0n1-20240229-123456.sgdisk")),
+            Some("nvme0n1")
+        );
+        assert_eq!(
+            backed_up_device(Path::new("md-alma-20240229-123456.sgdisk")),
+            Some("md-alma")
+        );
+        assert_eq!(backed_up_device(Path::new("sdb-old.sgdisk")), None);
+    }
+}
@@ -0,0 +1,88 @@
+//! The timezone of the installed system, linked to /etc/localtime. tzdata lists its zones and
+//! their aliases in tzdata.zi, the source `timedatectl list-timezones` reads too.
+
+use anyhow::anyhow;
+use std::fs;
+use std::path::Path;
+
+pub const DEFAULT_TIMEZONE: &str = "UTC";
+
+const ZONEINFO: &str = "usr/share/zoneinfo";
+
+/// The zones (`Z name ...`) and links (`L target name`) of a tzdata.zi
+fn parse_tzdata(tzdata: &str) -> Vec<String> {
+    let mut zones: Vec<String> = tzdata
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            match fields.next()? {
+                "Z" => fields.next(),
+                "L" => fields.nth(1),
+                _ => None,
+            }
+        })
+        .map(String::from)
+        .collect();
+    zones.sort();
+    zones.dedup();
+    zones
+}
+
+/// The timezones of tzdata on the host, none if it is not installed
+pub fn timezones() -> Vec<String> {
+    fs::read_to_string(Path::new("/").join(ZONEINFO).join("tzdata.zi"))
+        .map(|tzdata| parse_tzdata(&tzdata))
+        .unwrap_or_default()
+}
+
+/// The timezone of the host, from its /etc/localtime link
+pub fn host_timezone() -> Option<String> {
+    let target = fs::read_link("/etc/localtime").ok()?;
+    let zone = target
+        .to_str()?
+        .split_once(&format!("{ZONEINFO}/"))?
+        .1
+        .to_owned();
+    Some(zone)
+}
+
+fn validate_in(timezones: &[String], timezone: &str) -> anyhow::Result<()> {
+    if timezones.is_empty() || timezones.iter().any(|zone| zone == timezone) {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "Unknown timezone '{timezone}', `timedatectl list-timezones` lists the available ones, e.g. Europe/London"
+    ))
+}
+
+/// Checks that tzdata on the host has `timezone`. Skipped on hosts without tzdata, a typo then
+/// fails the setup script, which checks the zone exists before linking it.
+pub fn validate(timezone: &str) -> anyhow::Result<()> {
+    validate_in(&timezones(), timezone)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tzdata() {
+        let timezones = parse_tzdata(
+            "# version 2025b
+R d 1916 o - Jun 14 23s 1 S
+Z Europe/Berlin 0:53:28 - LMT 1893 Ap
+1 c CE%sT
+Z Etc/UTC 0 - UTC
+L Etc/UTC UTC
+L Europe/Kyiv Europe/Kiev
+",
+        );
+        assert_eq!(
+            timezones,
+            ["Etc/UTC", "Europe/Berlin", "Europe/Kiev", "UTC"]
+        );
+        assert!(validate_in(&timezones, "Europe/Berlin").is_ok());
+        assert!(validate_in(&timezones, "Europe/Berln").is_err());
+        assert!(validate_in(&[], "Europe/Berln").is_ok());
+    }
+}
@@ -1,48 +1,252 @@
 use super::Tool;
 use super::mount;
 use crate::args;
+use crate::error::{ErrorKind, ErrorKindExt};
 use crate::process::CommandExt;
+use crate::state;
 use crate::storage;
 use crate::storage::filesystem::FilesystemType;
-use crate::storage::{BlockDevice, Filesystem, LoopDevice, partition::Partition};
-use crate::storage::{EncryptedDevice, is_encrypted_device};
+use crate::storage::{
+    BlockDevice, EncryptedDevice, Filesystem, LoopDevice, VolumeGroup, partition::Partition,
+    volume_group_of,
+};
 use anyhow::{Context, anyhow};
+use dialoguer::{Select, theme::ColorfulTheme};
 use log::info;
-use std::path::PathBuf;
+use std::fmt;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
 
-use tempfile::tempdir;
+/// What blkid found on a partition or logical volume
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Content {
+    /// An ext4 or btrfs filesystem, which may be the root filesystem
+    Root(FilesystemType),
+    /// A LUKS container, which may hold the root filesystem
+    Luks,
+    /// A FAT filesystem, which may be the boot partition
+    Boot,
+    Swap,
+    /// An LVM physical volume
+    Lvm,
+    Other,
+}
+
+impl Content {
+    fn from_type(fs_type: &str) -> Self {
+        match fs_type {
+            "crypto_LUKS" => Content::Luks,
+            "swap" => Content::Swap,
+            "LVM2_member" => Content::Lvm,
+            _ => match FilesystemType::from_mount_type(fs_type) {
+                Some(FilesystemType::Vfat) => Content::Boot,
+                Some(fs_type @ (FilesystemType::Ext4 | FilesystemType::Btrfs)) => {
+                    Content::Root(fs_type)
+                }
+                _ => Content::Other,
+            },
+        }
+    }
+
+    fn is_root(self) -> bool {
+        matches!(self, Content::Root(_) | Content::Luks)
+    }
+}
+
+/// A partition or logical volume, described in the prompts by its type and label
+#[derive(Debug, Clone)]
+struct Volume {
+    path: PathBuf,
+    fs_type: String,
+    label: Option<String>,
+    content: Content,
+}
+
+impl Volume {
+    fn probe(blkid: &Tool, path: &Path) -> Self {
+        // blkid fails on devices without a signature it recognises
+        let output = blkid
+            .execute()
+            .args(["-o", "export"])
+            .arg(path)
+            .run_text_output(false)
+            .unwrap_or_default();
+        let (fs_type, label) = parse_blkid_export(&output);
+        Self {
+            path: path.to_path_buf(),
+            content: Content::from_type(&fs_type),
+            fs_type,
+            label,
+        }
+    }
+}
+
+impl fmt::Display for Volume {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fs_type = if self.fs_type.is_empty() {
+            "unknown"
+        } else {
+            &self.fs_type
+        };
+        write!(f, "{} ({fs_type}", self.path.display())?;
+        if let Some(label) = &self.label {
+            write!(f, ", {label}")?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// The TYPE and LABEL of the output of `blkid -o export`
+fn parse_blkid_export(output: &str) -> (String, Option<String>) {
+    let value = |key: &str| {
+        output
+            .lines()
+            .find_map(|line| line.strip_prefix(key))
+            .map(String::from)
+    };
+    (
+        value("TYPE=").unwrap_or_default(),
+        value("LABEL=").filter(|label| !label.is_empty()),
+    )
+}
+
+/// Probes the devices, replacing LVM physical volumes with the logical volumes of their groups.
+/// The groups are activated and added to `volume_groups`, and may span several disks.
+fn expand_volumes(
+    blkid: &Tool,
+    paths: impl IntoIterator<Item = PathBuf>,
+    volume_groups: &mut Vec<VolumeGroup>,
+) -> anyhow::Result<Vec<Volume>> {
+    let mut volumes = Vec::new();
+    for path in paths {
+        let volume = Volume::probe(blkid, &path);
+        if volume.content != Content::Lvm {
+            volumes.push(volume);
+            continue;
+        }
+        let Some(name) = volume_group_of(&path)? else {
+            info!("Ignoring {volume}, which is in no LVM volume group");
+            continue;
+        };
+        // The other physical volumes of a group were already handled
+        if volume_groups.iter().any(|group| group.name() == name) {
+            continue;
+        }
+        info!(
+            "Activating the LVM volume group {name} of {}",
+            path.display()
+        );
+        let group = VolumeGroup::activate(&name)?;
+        for logical_volume in group.logical_volumes()? {
+            volumes.push(Volume::probe(blkid, &logical_volume));
+        }
+        volume_groups.push(group);
+    }
+    Ok(volumes)
+}
+
+/// Picks one of the candidates, asking the user if there are several
+fn choose<'v>(what: &str, candidates: &[&'v Volume]) -> anyhow::Result<&'v Volume> {
+    if let [candidate] = candidates {
+        return Ok(candidate);
+    }
+    let list = candidates
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+    if !io::stdin().is_terminal() {
+        return Err(anyhow!(
+            "Found several candidates for the {what}: {list}. Run alma chroot in a terminal to choose one"
+        ))
+        .kind(ErrorKind::Usage);
+    }
+    info!("Found several candidates for the {what}: {list}");
+    let selection = Select::with_theme(&ColorfulTheme::default())
+        .with_prompt(format!("Select the {what}"))
+        .default(0)
+        .items(candidates)
+        .interact()?;
+    Ok(candidates[selection])
+}
 
 /// Use arch-chroot to chroot to the given device
-/// Also handles encrypted root partitions (detected by checking for the LUKS magic header)
 pub fn chroot(command: args::ChrootCommand) -> anyhow::Result<()> {
     let arch_chroot = Tool::find("arch-chroot", false)?;
+    let options = MountOptions {
+        allow_non_removable: command.allow_non_removable,
+        read_only: command.read_only,
+        mount_options: command.mount_options.as_deref(),
+    };
+    with_mounted_system(&command.block_device, &options, |system| {
+        arch_chroot
+            .execute()
+            .arg(system.mount_point.path())
+            .args(&command.command)
+            .run(false)
+            .with_context(|| {
+                format!(
+                    "Error running command in chroot: {}",
+                    command.command.join(" "),
+                )
+            })
+            .kind(ErrorKind::Chroot)
+    })
+}
+
+/// How `with_mounted_system` opens and mounts a system
+#[derive(Debug, Default)]
+pub struct MountOptions<'a> {
+    pub allow_non_removable: bool,
+    /// Attach, open and mount everything read-only
+    pub read_only: bool,
+    /// Replace the default mount options of the root filesystem
+    pub mount_options: Option<&'a str>,
+}
+
+/// A system mounted by `with_mounted_system`
+pub struct MountedSystem<'a> {
+    pub storage_device: &'a storage::StorageDevice<'a>,
+    pub mount_point: &'a tempfile::TempDir,
+    /// The block device of the root filesystem, which may be a LUKS mapping or a logical volume
+    pub root_device: &'a dyn BlockDevice,
+}
+
+/// Mounts the system on the given device or image file, runs `f` on it, then unmounts it. Finds
+/// the root filesystem (possibly in a LUKS container or an LVM logical volume) and the boot
+/// partition, asking which to use when there are several, and ignores swap.
+pub fn with_mounted_system(
+    block_device: &Path,
+    options: &MountOptions,
+    f: impl FnOnce(&MountedSystem) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
     let blkid = Tool::find("blkid", false)?;
     let sfdisk = Tool::find("sfdisk", false)?;
     let cryptsetup;
 
     let loop_device: Option<LoopDevice>;
-    let storage_device = match storage::StorageDevice::from_path(
-        &command.block_device,
-        command.allow_non_removable,
-        false,
-    ) {
-        Ok(b) => b,
-        Err(_) => {
-            loop_device = Some(LoopDevice::create(&command.block_device, false)?);
-            storage::StorageDevice::from_path(
-                loop_device.as_ref().expect("loop device not found").path(),
-                command.allow_non_removable,
-                false,
-            )?
+    let open = |path| {
+        if options.read_only {
+            storage::StorageDevice::from_path_read_only(path, options.allow_non_removable)
+        } else {
+            storage::StorageDevice::from_path(path, options.allow_non_removable, false)
         }
     };
-    let mount_point = tempdir().context("Error creating a temporary directory")?;
+    // Errors of block devices, e.g. a write-protected SD card, are reported as they are
+    let storage_device = if block_device.is_file() {
+        loop_device = Some(if options.read_only {
+            LoopDevice::create_read_only(block_device)?
+        } else {
+            LoopDevice::create(block_device, false)?
+        });
+        open(loop_device.as_ref().expect("loop device not found").path())?
+    } else {
+        open(block_device)?
+    };
+    let mount_point = state::temp_mount_point()?;
 
     // --- Automatic Partition and Filesystem Detection ---
-    info!(
-        "Discovering partitions on {}",
-        storage_device.path().display()
-    );
+    info!("Discovering partitions on {storage_device}");
     let partition_list_raw = sfdisk
         .execute()
         .args(["-l", "-o", "Device"])
@@ -58,127 +262,134 @@ pub fn chroot(command: args::ChrootCommand) -> anyhow::Result<()> {
         .collect();
 
     if partitions.is_empty() {
-        return Err(anyhow!(
-            "No partitions found on {}",
-            storage_device.path().display()
-        ));
+        return Err(anyhow!("No partitions found on {storage_device}"));
     }
 
-    let mut boot_partition_opt: Option<Partition> = None;
-    let mut root_partition_base_opt: Option<Partition> = None;
-    let mut root_fs_type_opt: Option<FilesystemType> = None;
-
-    for part_path in &partitions {
-        let partition = Partition::new::<storage::StorageDevice>(part_path.clone());
-
-        if is_encrypted_device(&partition)? {
-            if root_partition_base_opt.is_some() {
-                return Err(anyhow!(
-                    "Found multiple potential root partitions (LUKS encrypted). Ambiguous layout."
-                ));
-            }
-            root_partition_base_opt = Some(partition);
-            continue;
-        }
-
-        let fs_type_str = blkid
-            .execute()
-            .args(["-s", "TYPE", "-o", "value"])
-            .arg(part_path)
-            .run_text_output(false)
-            .unwrap_or_default();
-
-        match fs_type_str.trim() {
-            "vfat" => {
-                if boot_partition_opt.is_some() {
-                    return Err(anyhow!(
-                        "Found multiple potential boot partitions (vfat). Ambiguous layout."
-                    ));
-                }
-                boot_partition_opt = Some(partition);
-            }
-            "ext4" => {
-                if root_partition_base_opt.is_some() {
-                    return Err(anyhow!(
-                        "Found multiple potential root partitions (ext4 and previous). Ambiguous layout."
-                    ));
-                }
-                root_partition_base_opt = Some(partition);
-                root_fs_type_opt = Some(FilesystemType::Ext4);
-            }
-            "btrfs" => {
-                if root_partition_base_opt.is_some() {
-                    return Err(anyhow!(
-                        "Found multiple potential root partitions (btrfs and previous). Ambiguous layout."
-                    ));
-                }
-                root_partition_base_opt = Some(partition);
-                root_fs_type_opt = Some(FilesystemType::Btrfs);
-            }
-            _ => {} // Ignore swap, etc.
-        }
+    // Groups in the partitions are deactivated after the root LUKS container is closed, those
+    // inside the container before
+    let mut outer_groups = Vec::new();
+    let volumes = expand_volumes(&blkid, partitions, &mut outer_groups)?;
+    for swap in volumes.iter().filter(|v| v.content == Content::Swap) {
+        info!("Ignoring the swap space {swap}");
     }
 
-    let root_partition_base = root_partition_base_opt.ok_or_else(|| {
-        anyhow!("Could not find a suitable root partition (ext4, btrfs, or LUKS).")
-    })?;
+    let roots: Vec<&Volume> = volumes.iter().filter(|v| v.content.is_root()).collect();
+    if roots.is_empty() {
+        return Err(anyhow!(
+            "Could not find a suitable root partition (ext4, btrfs, or LUKS)."
+        ));
+    }
+    let root = choose("root filesystem", &roots)?;
+    let boots: Vec<&Volume> = volumes
+        .iter()
+        .filter(|v| v.content == Content::Boot)
+        .collect();
+    let boot = if boots.is_empty() {
+        None
+    } else {
+        Some(choose("boot partition", &boots)?)
+    };
 
-    let encrypted_root = if is_encrypted_device(&root_partition_base)? {
+    let root_partition_base = Partition::new::<storage::StorageDevice>(root.path.clone());
+    let encrypted_root = if root.content == Content::Luks {
         cryptsetup = Some(Tool::find("cryptsetup", false)?);
-        Some(EncryptedDevice::open(
+        let open = if options.read_only {
+            EncryptedDevice::open_read_only
+        } else {
+            EncryptedDevice::open
+        };
+        Some(open(
             cryptsetup.as_ref().unwrap(),
             &root_partition_base,
-            "alma_root".into(),
+            state::root_mapper_name(),
         )?)
     } else {
         None
     };
 
-    let root_partition: &dyn BlockDevice = encrypted_root
-        .as_ref()
-        .map_or(&root_partition_base, |e| e as &dyn BlockDevice);
-
-    let root_fs_type = if let Some(fs_type) = root_fs_type_opt {
-        fs_type
-    } else {
-        // We have an encrypted device, so we must check the type on the opened container
-        let fs_type_str = blkid
-            .execute()
-            .args(["-s", "TYPE", "-o", "value"])
-            .arg(root_partition.path())
-            .run_text_output(false)?;
-        match fs_type_str.trim() {
-            "ext4" => FilesystemType::Ext4,
-            "btrfs" => FilesystemType::Btrfs,
-            other => {
+    let mut inner_groups = Vec::new();
+    let root = match &encrypted_root {
+        // The container holds the filesystem itself, or an LVM volume group
+        Some(encrypted_root) => {
+            let inner = expand_volumes(
+                &blkid,
+                [encrypted_root.path().to_path_buf()],
+                &mut inner_groups,
+            )?;
+            let roots: Vec<&Volume> = inner
+                .iter()
+                .filter(|v| matches!(v.content, Content::Root(_)))
+                .collect();
+            if roots.is_empty() {
                 return Err(anyhow!(
-                    "Unsupported filesystem type '{}' on encrypted container.",
-                    other
+                    "Found no ext4 or btrfs filesystem in the encrypted container {root}"
                 ));
             }
+            choose("root filesystem", &roots)?.clone()
         }
+        None => root.clone(),
+    };
+    let Content::Root(root_fs_type) = root.content else {
+        return Err(anyhow!("Unsupported root filesystem {root}"));
     };
-    let root_filesystem = Filesystem::from_partition(root_partition, root_fs_type);
+    let root_partition = Partition::new::<storage::StorageDevice>(root.path.clone());
+    let root_filesystem = Filesystem::from_partition(&root_partition, root_fs_type);
 
-    let boot_sys = boot_partition_opt
+    let boot_partition = boot.map(|b| Partition::new::<storage::StorageDevice>(b.path.clone()));
+    let boot_sys = boot_partition
         .as_ref()
         .map(|p| Filesystem::from_partition(p, FilesystemType::Vfat));
-    let mount_stack = mount(mount_point.path(), &boot_sys, &root_filesystem, false)?;
+    // The read-only options come first, so the given ones cannot make the mount writable
+    let mount_options = [
+        options
+            .read_only
+            .then(|| root_fs_type.ops().read_only_mount_options()),
+        options.mount_options,
+    ]
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>();
+    let mount_options = (!mount_options.is_empty()).then(|| mount_options.join(","));
+    let mount_stack = mount(
+        mount_point.path(),
+        &boot_sys,
+        &root_filesystem,
+        mount_options.as_deref(),
+        false,
+        false,
+    )?;
 
-    arch_chroot
-        .execute()
-        .arg(mount_point.path())
-        .args(&command.command)
-        .run(false)
-        .with_context(|| {
-            format!(
-                "Error running command in chroot: {}",
-                command.command.join(" "),
-            )
-        })?;
+    f(&MountedSystem {
+        storage_device: &storage_device,
+        mount_point: &mount_point,
+        root_device: &root_partition,
+    })?;
 
     info!("Unmounting filesystems");
     mount_stack.umount()?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_blkid_export() {
+        let output = "DEVNAME=/dev/sdb3\nUUID=0d6c\nLABEL=alma-root\nTYPE=ext4\nPARTUUID=9f1e\n";
+        let (fs_type, label) = parse_blkid_export(output);
+        assert_eq!(
+            Content::from_type(&fs_type),
+            Content::Root(FilesystemType::Ext4)
+        );
+        assert_eq!(label.as_deref(), Some("alma-root"));
+
+        let (fs_type, label) = parse_blkid_export("DEVNAME=/dev/sdb2\nTYPE=LVM2_member\n");
+        assert_eq!(Content::from_type(&fs_type), Content::Lvm);
+        assert_eq!(label, None);
+        assert_eq!(Content::from_type("swap"), Content::Swap);
+        assert!(Content::from_type("crypto_LUKS").is_root());
+        assert!(!Content::from_type("").is_root());
+    }
+}
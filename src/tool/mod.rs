@@ -1,16 +1,22 @@
 mod chroot;
 mod mount;
 mod qemu;
+mod version;
 
 use anyhow::{Context, anyhow};
-pub use chroot::chroot;
+pub use chroot::{MountOptions, chroot, with_mounted_system};
+use log::{debug, warn};
 pub use mount::mount;
-pub use qemu::qemu;
+pub use qemu::{UsbId, qemu};
+pub use version::Version;
 
 use std::path::PathBuf;
 use std::process::Command;
 use which::which;
 
+use crate::constants::MIN_TOOL_VERSIONS;
+use crate::error::{ErrorKind, ErrorKindExt};
+
 #[derive(Debug)]
 pub struct Tool {
     pub exec: PathBuf,
@@ -20,7 +26,9 @@ pub struct Tool {
 impl Tool {
     pub fn find(name: &'static str, dryrun: bool) -> anyhow::Result<Self> {
         Ok(Self {
-            exec: which(name).context(format!("Cannot find {name}"))?,
+            exec: which(name)
+                .context(format!("Cannot find {name}"))
+                .kind(ErrorKind::Tooling)?,
             dryrun,
         })
     }
@@ -28,9 +36,77 @@ impl Tool {
     pub fn execute(&self) -> Command {
         Command::new(&self.exec)
     }
+
+    fn name(&self) -> String {
+        self.exec
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+
+    /// Returns the minimum supported version of this tool, if there is one
+    pub fn min_version(&self) -> Option<Version> {
+        let name = self.name();
+        MIN_TOOL_VERSIONS
+            .iter()
+            .find(|(tool, _, _)| *tool == name)
+            .and_then(|(_, _, min)| min.parse().ok())
+    }
+
+    /// Probes the installed version by running the tool with its version argument
+    pub fn version(&self) -> anyhow::Result<Version> {
+        let name = self.name();
+        let version_arg = MIN_TOOL_VERSIONS
+            .iter()
+            .find(|(tool, _, _)| *tool == name)
+            .map_or("--version", |(_, arg, _)| *arg);
+
+        // Some tools (e.g. mke2fs) print their version to stderr
+        let output = self
+            .execute()
+            .arg(version_arg)
+            .output()
+            .with_context(|| format!("Failed to run {name} {version_arg}"))?;
+        let text = format!(
+            "{}\n{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+        Version::from_output(&text)
+            .ok_or_else(|| anyhow!("Could not determine the version of {name}"))
+    }
+
+    /// Fails if the installed version is older than the minimum supported version.
+    /// With `ignore` set, problems are only reported as warnings.
+    pub fn check_min_version(&self, ignore: bool) -> anyhow::Result<()> {
+        let Some(min) = self.min_version() else {
+            return Ok(());
+        };
+        let name = self.name();
+
+        let problem = match self.version() {
+            Ok(version) if version >= min => {
+                debug!("{name} version {version} (minimum {min})");
+                return Ok(());
+            }
+            Ok(version) => format!(
+                "{name} version {version} is older than the minimum supported version {min}. Please upgrade it."
+            ),
+            Err(e) => format!("{e}, expected at least version {min}."),
+        };
+
+        if ignore {
+            warn!("{problem}");
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "{problem} Use --ignore-tool-versions to continue anyway."
+            ))
+        }
+    }
 }
 
-use crate::args::{CreateCommand, RootFilesystemType};
+use crate::args::{CreateCommand, Partitioner, RootFilesystemType};
 
 pub struct Tools {
     pub sgdisk: Tool,
@@ -40,19 +116,25 @@ pub struct Tools {
     pub mkfat: Tool,
     pub mkext4: Option<Tool>,
     pub mkbtrfs: Option<Tool>,
+    pub mkexfat: Option<Tool>,
     pub btrfs: Option<Tool>,
     pub git: Tool,
     pub cryptsetup: Option<Tool>,
-    pub blkid: Option<Tool>,
+    pub blkid: Tool,
+    pub mdadm: Option<Tool>,
+    pub repart: Option<Tool>,
+    pub mksquashfs: Option<Tool>,
+    pub grub_mkrescue: Option<Tool>,
 }
 
 impl Tools {
     pub fn new(command: &CreateCommand) -> anyhow::Result<Self> {
         let dryrun = command.dryrun;
         let encrypted = command.encrypted_root;
+        let raid = command.raid1.is_some();
         let is_btrfs = matches!(command.filesystem, RootFilesystemType::Btrfs);
 
-        Ok(Self {
+        let tools = Self {
             sgdisk: Tool::find("sgdisk", dryrun).map_err(|_| {
                 anyhow!("sgdisk is required for partitioning the disk. Please install the 'gptfdisk' package.")
             })?,
@@ -83,6 +165,13 @@ impl Tools {
             } else {
                 None
             },
+            mkexfat: if command.data_partition.is_some() {
+                Some(Tool::find("mkfs.exfat", dryrun).map_err(|_| {
+                anyhow!("mkfs.exfat is required for creating the data partition. Please install the 'exfatprogs' package.")
+            })?)
+            } else {
+                None
+            },
             btrfs: if is_btrfs {
                 Some(Tool::find("btrfs", dryrun).map_err(|_| {
                 anyhow!("btrfs is required for creating btrfs filesystems. Please install the 'btrfs-progs' package.")
@@ -100,13 +189,56 @@ impl Tools {
             } else {
                 None
             },
-            blkid: if encrypted {
-                Some(Tool::find("blkid", dryrun).map_err(|_| {
-                    anyhow!("blkid is required for setting up encrypted filesystems. Please install the 'util-linux' package.")
+            blkid: Tool::find("blkid", dryrun).map_err(|_| {
+                anyhow!("blkid is required for referring to the partitions by UUID. Please install the 'util-linux' package.")
+            })?,
+            mdadm: if raid {
+                Some(Tool::find("mdadm", dryrun).map_err(|_| {
+                    anyhow!("mdadm is required for creating RAID arrays. Please install the 'mdadm' package.")
                 })?)
             } else {
                 None
             },
-        })
+            repart: if command.partitioner == Partitioner::Repart {
+                Some(Tool::find("systemd-repart", dryrun).map_err(|_| {
+                    anyhow!("systemd-repart is required for --partitioner repart. Please install the 'systemd' package.")
+                })?)
+            } else {
+                None
+            },
+            mksquashfs: if command.iso.is_some() {
+                Some(Tool::find("mksquashfs", dryrun).map_err(|_| {
+                    anyhow!("mksquashfs is required for creating ISOs. Please install the 'squashfs-tools' package.")
+                })?)
+            } else {
+                None
+            },
+            grub_mkrescue: if command.iso.is_some() {
+                // Runs xorriso and mformat, which the grub package does not depend on
+                for (tool, package) in [("xorriso", "libisoburn"), ("mformat", "mtools")] {
+                    Tool::find(tool, dryrun).map_err(|_| {
+                        anyhow!("{tool} is required for creating ISOs. Please install the '{package}' package.")
+                    })?;
+                }
+                Some(Tool::find("grub-mkrescue", dryrun).map_err(|_| {
+                    anyhow!("grub-mkrescue is required for creating ISOs. Please install the 'grub' package.")
+                })?)
+            } else {
+                None
+            },
+        };
+
+        // Catch ancient tools before anything is partitioned
+        let versioned = [
+            Some(&tools.sgdisk),
+            tools.mkext4.as_ref(),
+            tools.mkbtrfs.as_ref(),
+            tools.cryptsetup.as_ref(),
+        ];
+        for tool in versioned.into_iter().flatten() {
+            tool.check_min_version(command.ignore_tool_versions)?;
+        }
+
+        Ok(tools)
     }
 }
@@ -1,89 +1,167 @@
+use crate::effects;
 use crate::storage::filesystem::FilesystemType;
 use crate::storage::{Filesystem, MountStack};
+use crate::swap;
 use anyhow::Context;
 use log::info;
 use nix::mount::MsFlags;
-use std::fs;
 use std::path::Path;
 
+/// Splits mount options into the generic flags understood by mount(2) and the comma separated,
+/// filesystem specific data, as mount(8) does
+pub fn parse_mount_options(options: &str) -> (MsFlags, Vec<&str>) {
+    let mut flags = MsFlags::empty();
+    let mut data = Vec::new();
+    for option in options.split(',').map(str::trim).filter(|o| !o.is_empty()) {
+        match option {
+            "defaults" | "rw" | "atime" => (),
+            "ro" => flags |= MsFlags::MS_RDONLY,
+            "noatime" => flags |= MsFlags::MS_NOATIME,
+            "nodiratime" => flags |= MsFlags::MS_NODIRATIME,
+            "relatime" => flags |= MsFlags::MS_RELATIME,
+            "strictatime" => flags |= MsFlags::MS_STRICTATIME,
+            "lazytime" => flags |= MsFlags::MS_LAZYTIME,
+            "nosuid" => flags |= MsFlags::MS_NOSUID,
+            "nodev" => flags |= MsFlags::MS_NODEV,
+            "noexec" => flags |= MsFlags::MS_NOEXEC,
+            "sync" => flags |= MsFlags::MS_SYNCHRONOUS,
+            "dirsync" => flags |= MsFlags::MS_DIRSYNC,
+            other => data.push(other),
+        }
+    }
+    (flags, data)
+}
+
+/// Whether btrfs mount options pick the subvolume to mount, instead of ALMA's @ layout
+fn selects_subvolume(data: &[&str]) -> bool {
+    data.iter()
+        .any(|option| option.starts_with("subvol=") || option.starts_with("subvolid="))
+}
+
 /// Mounts root filesystem to given mount_path
 /// Mounts boot filesystem to mount_path/boot
-/// Note we mount with noatime to reduce disk writes by not recording file access times
+/// On btrfs, `swap_subvolume` also mounts the @swap subvolume to mount_path/swap
+/// `mount_options` replace the default options of the root filesystem, genfstab then records
+/// them in fstab. The boot filesystem is mounted read-only along with a read-only root.
+/// Options selecting a btrfs subvolume mount only that subvolume, for systems which do not use
+/// ALMA's subvolume layout.
 pub fn mount<'a>(
     mount_path: &Path,
     boot_filesystem: &'a Option<Filesystem>,
     root_filesystem: &'a Filesystem,
+    mount_options: Option<&str>,
+    swap_subvolume: bool,
     dryrun: bool,
 ) -> anyhow::Result<MountStack<'a>> {
     let mut mount_stack = MountStack::new(dryrun);
     let root_device_path = root_filesystem.block().path();
+    let options =
+        mount_options.unwrap_or_else(|| root_filesystem.fs_type().ops().default_mount_options());
+    let (flags, data) = parse_mount_options(options);
+    let mount_context = || format!("Failed to mount the root filesystem with options '{options}'");
     info!("Mounting filesystems to {}", mount_path.display());
 
-    if root_filesystem.fs_type() == FilesystemType::Btrfs {
+    if root_filesystem.fs_type() == FilesystemType::Btrfs && !selects_subvolume(&data) {
         // --- BTRFS Subvolume Mounting Logic ---
         // For Btrfs, we pass subvol options via the `data` parameter.
-        let common_flags = MsFlags::MS_NOATIME;
+        let subvolume_data = |subvolume: &str| {
+            data.iter()
+                .copied()
+                .chain([subvolume])
+                .collect::<Vec<&str>>()
+                .join(",")
+        };
 
-        let root_data = "compress=zstd:3,subvol=@";
-        mount_stack.mount_single(
-            root_device_path,
-            mount_path,
-            Some("btrfs"),
-            common_flags,
-            Some(root_data),
-        )?;
+        mount_stack
+            .mount_single(
+                root_device_path,
+                mount_path,
+                Some("btrfs"),
+                flags,
+                Some(&subvolume_data("subvol=@")),
+            )
+            .with_context(mount_context)?;
 
-        if !dryrun {
-            fs::create_dir_all(mount_path.join("home"))?;
-            fs::create_dir_all(mount_path.join("var/log"))?;
-            fs::create_dir_all(mount_path.join("var/cache/pacman/pkg"))?;
+        for dir in ["home", "var/log", "var/cache/pacman/pkg"] {
+            effects::create_dir_all(&mount_path.join(dir), dryrun)?;
         }
 
-        let home_data = "compress=zstd:3,subvol=@home";
-        mount_stack.mount_single(
-            root_device_path,
-            &mount_path.join("home"),
-            Some("btrfs"),
-            common_flags,
-            Some(home_data),
-        )?;
-
-        let log_data = "compress=zstd:3,subvol=@log";
-        mount_stack.mount_single(
-            root_device_path,
-            &mount_path.join("var/log"),
-            Some("btrfs"),
-            common_flags,
-            Some(log_data),
-        )?;
+        for (subvolume, target) in [
+            ("subvol=@home", "home"),
+            ("subvol=@log", "var/log"),
+            ("subvol=@pkg", "var/cache/pacman/pkg"),
+        ] {
+            mount_stack.mount_single(
+                root_device_path,
+                &mount_path.join(target),
+                Some("btrfs"),
+                flags,
+                Some(&subvolume_data(subvolume)),
+            )?;
+        }
 
-        let pkg_data = "compress=zstd:3,subvol=@pkg";
-        mount_stack.mount_single(
-            root_device_path,
-            &mount_path.join("var/cache/pacman/pkg"),
-            Some("btrfs"),
-            common_flags,
-            Some(pkg_data),
-        )?;
+        if swap_subvolume {
+            // No compression, btrfs does not support swap files on compressed subvolumes
+            let swap_point = mount_path.join(swap::SWAP_DIR);
+            effects::create_dir_all(&swap_point, dryrun)?;
+            let swap_data = format!("subvol={}", swap::SWAP_SUBVOLUME);
+            mount_stack.mount_single(
+                root_device_path,
+                &swap_point,
+                Some("btrfs"),
+                MsFlags::MS_NOATIME,
+                Some(&swap_data),
+            )?;
+        }
     } else {
-        // --- Standard EXT4 Mounting Logic ---
-        // For ext4, we pass `noatime` as a flag, and `data` is None.
-        mount_stack.mount(
-            root_filesystem,
-            mount_path.to_path_buf(),
-            MsFlags::MS_NOATIME,
-        )?;
+        // --- Single Mount: ext4, or a btrfs subvolume given in the options ---
+        let data = (!data.is_empty()).then(|| data.join(","));
+        mount_stack
+            .mount_single(
+                root_device_path,
+                mount_path,
+                Some(root_filesystem.fs_type().to_mount_type()),
+                flags,
+                data.as_deref(),
+            )
+            .with_context(mount_context)?;
     }
 
     // Mount boot partition to /boot
     if let Some(boot_sys) = boot_filesystem {
         let boot_point = mount_path.join("boot");
-        if !dryrun && !boot_point.exists() {
-            fs::create_dir(&boot_point).context("Error creating the boot directory")?;
+        if !boot_point.exists() {
+            effects::create_dir_all(&boot_point, dryrun)
+                .context("Error creating the boot directory")?;
         }
-        // Boot partition has no special flags.
-        mount_stack.mount(boot_sys, boot_point, MsFlags::empty())?;
+        mount_stack.mount(boot_sys, boot_point, flags & MsFlags::MS_RDONLY)?;
     }
 
     Ok(mount_stack)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mount_options() {
+        let (flags, data) = parse_mount_options("noatime, discard=async,commit=120,,lazytime");
+        assert_eq!(flags, MsFlags::MS_NOATIME | MsFlags::MS_LAZYTIME);
+        assert_eq!(data, ["discard=async", "commit=120"]);
+
+        let (flags, data) =
+            parse_mount_options(FilesystemType::Btrfs.ops().default_mount_options());
+        assert_eq!(flags, MsFlags::MS_NOATIME);
+        assert_eq!(data, ["compress=zstd:3"]);
+
+        let (flags, data) =
+            parse_mount_options(FilesystemType::Ext4.ops().read_only_mount_options());
+        assert_eq!(flags, MsFlags::MS_RDONLY);
+        assert_eq!(data, ["noload"]);
+
+        assert!(!selects_subvolume(&data));
+        let (_, data) = parse_mount_options("subvol=@root,compress=zstd:6");
+        assert!(selects_subvolume(&data));
+    }
+}
@@ -1,10 +1,59 @@
 use super::Tool;
+use super::mount::parse_mount_options;
 use crate::args;
+use crate::args::DiskFormat;
+use crate::error::{ErrorKind, ErrorKindExt};
+use crate::grub;
+use crate::process::CommandExt as _;
+use crate::state;
+use crate::storage::{LoopDevice, MountStack};
 use anyhow::{Context, anyhow};
-use log::debug;
+use clap::ValueEnum;
+use log::{debug, info};
+use nix::mount::MsFlags;
+use tempfile::TempDir;
 
+use std::fmt;
+use std::fs;
+use std::io;
 use std::os::unix::process::CommandExt as UnixCommandExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// The vendor and product ID of a USB device, as shown by lsusb
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsbId {
+    vendor: u16,
+    product: u16,
+}
+
+impl FromStr for UsbId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let parse = |id: &str| {
+            (id.len() == 4)
+                .then(|| u16::from_str_radix(id, 16).ok())
+                .flatten()
+        };
+        s.split_once(':')
+            .and_then(|(vendor, product)| {
+                Some(Self {
+                    vendor: parse(vendor)?,
+                    product: parse(product)?,
+                })
+            })
+            .ok_or_else(|| {
+                anyhow!("Invalid USB device '{s}', expected VENDOR:PRODUCT in hex, e.g. 0781:5581")
+            })
+    }
+}
+
+impl fmt::Display for UsbId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04x}:{:04x}", self.vendor, self.product)
+    }
+}
 
 /// Loads given block device in qemu
 /// Uses kvm if it is enabled
@@ -16,6 +65,15 @@ Please install the 'qemu-desktop' 'qemu-system-x86' 'qemu-system-x86-firmware' p
         )
     })?;
 
+    let direct_boot = if command.direct_kernel {
+        Some(extract_boot_files(
+            &command.block_device,
+            command.mount_options.as_deref(),
+        )?)
+    } else {
+        None
+    };
+
     let mut run = qemu.execute();
     run.args([
         "-m",
@@ -33,15 +91,180 @@ Please install the 'qemu-desktop' 'qemu-system-x86' 'qemu-system-x86-firmware' p
     .arg(format!(
         "file={},if=virtio,format=raw",
         command.block_device.display()
-    ))
-    .args(command.args);
+    ));
+
+    for disk in &command.attach_disks {
+        if !disk.exists() {
+            return Err(anyhow!(
+                "Cannot attach {}, it does not exist",
+                disk.display()
+            ))
+            .kind(ErrorKind::Usage);
+        }
+        run.arg("-drive").arg(format!(
+            "file={},if=virtio,format={}",
+            disk.display(),
+            disk_format(disk)
+        ));
+    }
+    for usb in &command.usb_passthrough {
+        debug!("Passing the USB device {usb} through");
+        run.arg("-device").arg(format!(
+            "usb-host,bus=xhci.0,vendorid=0x{:04x},productid=0x{:04x}",
+            usb.vendor, usb.product
+        ));
+    }
+
+    if let Some(boot) = &direct_boot {
+        run.arg("-kernel")
+            .arg(boot.dir.path().join(KERNEL))
+            .arg("-initrd")
+            .arg(boot.dir.path().join(INITRD))
+            .arg("-append")
+            .arg(&boot.cmdline);
+    }
+    run.args(command.args);
 
     if PathBuf::from("/dev/kvm").exists() {
         debug!("KVM is enabled");
         run.args(["-enable-kvm", "-cpu", "host"]);
     }
 
+    if direct_boot.is_some() {
+        // Wait for qemu instead of replacing this process, so the extracted files are removed
+        let status = run.status().context("Failed launching Qemu")?;
+        if !status.success() {
+            return Err(anyhow!("Qemu exited with {status}"));
+        }
+        return Ok(());
+    }
+
     let err = run.exec();
 
     Err(err).context("Failed launching Qemu")?
 }
+
+/// The qemu format of a disk image, guessed from its extension rather than by qemu, which
+/// refuses to write to the first sector of a guessed raw image
+fn disk_format(disk: &Path) -> &'static str {
+    disk.extension()
+        .and_then(|extension| {
+            DiskFormat::value_variants()
+                .iter()
+                .find(|format| extension == format.name())
+        })
+        .map_or("raw", |format| format.name())
+}
+
+const KERNEL: &str = "vmlinuz";
+const INITRD: &str = "initrd.img";
+
+/// Kernel and initramfs copied out of the boot partition
+struct DirectBoot {
+    dir: TempDir,
+    cmdline: String,
+}
+
+/// Copies the kernel and initramfs images of the default GRUB entry from the boot partition.
+/// Several initramfs images (e.g. microcode) are concatenated, which the kernel supports.
+fn extract_boot_files(
+    block_device: &Path,
+    mount_options: Option<&str>,
+) -> anyhow::Result<DirectBoot> {
+    let loop_device = if block_device.is_file() {
+        Some(LoopDevice::create(block_device, false)?)
+    } else {
+        None
+    };
+    let device = loop_device.as_ref().map_or(block_device, |l| l.path());
+    let boot_partition = find_boot_partition(device)?;
+
+    info!(
+        "Reading the kernel and initramfs from {}",
+        boot_partition.display()
+    );
+    let (flags, data) = parse_mount_options(mount_options.unwrap_or_default());
+    let data = (!data.is_empty()).then(|| data.join(","));
+    let mount_point = state::temp_mount_point()?;
+    let mut mount_stack = MountStack::new(false);
+    mount_stack
+        .mount_single(
+            &boot_partition,
+            mount_point.path(),
+            Some("vfat"),
+            flags | MsFlags::MS_RDONLY,
+            data.as_deref(),
+        )
+        .with_context(|| format!("Failed to mount {}", boot_partition.display()))?;
+
+    let grub_cfg = fs::read_to_string(mount_point.path().join("grub/grub.cfg"))
+        .context("Failed to read grub/grub.cfg from the boot partition")?;
+    let entry =
+        grub::default_entry(&grub_cfg).ok_or_else(|| anyhow!("No boot entry found in grub.cfg"))?;
+    debug!("Booting {:?}", entry);
+
+    let boot_file = |path: &str| mount_point.path().join(path.trim_start_matches('/'));
+    let dir = tempfile::tempdir().context("Error creating a temporary directory")?;
+    fs::copy(boot_file(&entry.kernel), dir.path().join(KERNEL))
+        .with_context(|| format!("Failed to copy the kernel {}", entry.kernel))?;
+    let mut initrd =
+        fs::File::create(dir.path().join(INITRD)).context("Failed to create the initramfs")?;
+    for image in &entry.initrds {
+        io::copy(
+            &mut fs::File::open(boot_file(image))
+                .with_context(|| format!("Failed to open the initramfs {image}"))?,
+            &mut initrd,
+        )
+        .with_context(|| format!("Failed to copy the initramfs {image}"))?;
+    }
+
+    mount_stack.umount()?;
+    Ok(DirectBoot {
+        dir,
+        cmdline: entry.cmdline,
+    })
+}
+
+/// Returns the first vfat partition of the device, which ALMA uses as /boot
+fn find_boot_partition(device: &Path) -> anyhow::Result<PathBuf> {
+    let sfdisk = Tool::find("sfdisk", false)?;
+    let blkid = Tool::find("blkid", false)?;
+
+    sfdisk
+        .execute()
+        .args(["-l", "-o", "Device"])
+        .arg(device)
+        .run_text_output(false)?
+        .lines()
+        .skip(1)
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(PathBuf::from)
+        .find(|partition| {
+            blkid
+                .execute()
+                .args(["-s", "TYPE", "-o", "value"])
+                .arg(partition)
+                .run_text_output(false)
+                .is_ok_and(|fs_type| fs_type.trim() == "vfat")
+        })
+        .ok_or_else(|| anyhow!("No boot partition found on {}", device.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usb_id() {
+        let usb: UsbId = "0781:5581".parse().unwrap();
+        assert_eq!(usb.vendor, 0x0781);
+        assert_eq!(usb.to_string(), "0781:5581");
+        assert!("781:5581".parse::<UsbId>().is_err());
+        assert!("0781-5581".parse::<UsbId>().is_err());
+        assert!("0781:55zz".parse::<UsbId>().is_err());
+
+        assert_eq!(disk_format(Path::new("data.qcow2")), "qcow2");
+        assert_eq!(disk_format(Path::new("/dev/sdc")), "raw");
+    }
+}
@@ -0,0 +1,99 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+
+/// A dotted numeric version such as 1.0.10
+#[derive(Debug, Clone)]
+pub struct Version(Vec<u32>);
+
+impl Version {
+    /// Extracts the first version number found in a tool's `--version` output,
+    /// e.g. "mkfs.btrfs, part of btrfs-progs v6.10" or "cryptsetup 2.7.5 flags: ..."
+    pub fn from_output(output: &str) -> Option<Self> {
+        output
+            .split(|c: char| c.is_whitespace() || c == ',' || c == '(' || c == ')')
+            .map(|word| word.trim_start_matches('v'))
+            .filter(|word| word.contains('.'))
+            .find_map(|word| word.parse().ok())
+    }
+}
+
+impl FromStr for Version {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        s.split('.')
+            .map(|part| part.parse::<u32>())
+            .collect::<Result<Vec<u32>, _>>()
+            .map(Self)
+            .map_err(|_| anyhow!("Invalid version: {s}"))
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Missing components count as zero, so 2.1 == 2.1.0
+        let len = self.0.len().max(other.0.len());
+        (0..len)
+            .map(|i| {
+                let a = self.0.get(i).unwrap_or(&0);
+                let b = other.0.get(i).unwrap_or(&0);
+                a.cmp(b)
+            })
+            .find(|o| *o != Ordering::Equal)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts: Vec<String> = self.0.iter().map(|p| p.to_string()).collect();
+        write!(f, "{}", parts.join("."))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_from_output() {
+        let cases = [
+            ("GPT fdisk (sgdisk) version 1.0.10\n", "1.0.10"),
+            ("cryptsetup 2.7.5 flags: UDEV BLKID KEYRING\n", "2.7.5"),
+            ("mkfs.btrfs, part of btrfs-progs v6.10\n", "6.10"),
+            ("mke2fs 1.47.1 (20-May-2024)\n", "1.47.1"),
+        ];
+        for (output, expected) in cases {
+            assert_eq!(
+                Version::from_output(output).unwrap(),
+                expected.parse().unwrap()
+            );
+        }
+        assert_eq!(Version::from_output("no version here"), None);
+    }
+
+    #[test]
+    fn test_version_ordering() {
+        let v = |s: &str| s.parse::<Version>().unwrap();
+        assert!(v("1.0.10") > v("1.0.9"));
+        assert!(v("2.1") == v("2.1.0"));
+        assert!(v("6.1") < v("6.10"));
+    }
+}
@@ -0,0 +1,131 @@
+//! `alma update`: maintains a system after it was created. Mounts it like `alma chroot`, upgrades
+//! the repository and AUR packages, then regenerates the initramfs and reinstalls GRUB so the
+//! system boots with what was upgraded.
+
+use crate::args::{GrubTargets, InitramfsGenerator, UpdateCommand};
+use crate::aur::AurHelper;
+use crate::create;
+use crate::install;
+use crate::process::CommandExt;
+use crate::tool::{MountOptions, Tool, with_mounted_system};
+use anyhow::Context;
+use log::{info, warn};
+use std::path::Path;
+use strum::IntoEnumIterator;
+
+pub fn update(command: UpdateCommand) -> anyhow::Result<()> {
+    let arch_chroot = Tool::find("arch-chroot", false)?;
+    let blkid = Tool::find("blkid", false)?;
+    let options = MountOptions {
+        allow_non_removable: command.allow_non_removable,
+        ..MountOptions::default()
+    };
+
+    with_mounted_system(&command.block_device, &options, |system| {
+        let mount_path = system.mount_point.path();
+        let manifest = install::read_manifest(mount_path);
+        if manifest.is_none() {
+            warn!(
+                "{} has no ALMA manifest, assuming a mkinitcpio initramfs and GRUB for BIOS and UEFI",
+                command.block_device.display()
+            );
+        }
+
+        info!("Upgrading the packages");
+        arch_chroot
+            .execute()
+            .arg(mount_path)
+            .args(["pacman", "-Syu", "--noconfirm"])
+            .run(false)
+            .context("Failed to upgrade the packages")?;
+
+        if !command.no_aur {
+            let preferred = manifest
+                .as_ref()
+                .and_then(|m| m.aur_helper.parse::<AurHelper>().ok());
+            match installed_aur_helper(mount_path, preferred) {
+                Some(helper) => upgrade_aur_packages(&arch_chroot, mount_path, helper)?,
+                None => info!("No AUR helper is installed, skipping the AUR packages"),
+            }
+        }
+
+        let initramfs = manifest
+            .as_ref()
+            .map_or(InitramfsGenerator::Mkinitcpio, |m| m.initramfs);
+        let grub_targets = manifest
+            .as_ref()
+            .map_or(GrubTargets::Both, |m| m.grub_targets);
+        create::regenerate_initramfs(&arch_chroot, mount_path, initramfs)?;
+        create::reinstall_bootloader(
+            system.storage_device,
+            system.mount_point,
+            &arch_chroot,
+            &blkid,
+            system.root_device,
+            grub_targets,
+        )
+    })?;
+
+    info!("Updated {}", command.block_device.display());
+    Ok(())
+}
+
+/// The AUR helper installed in the system, preferring the one it was built with. ALMA only
+/// installs one when there are AUR packages.
+fn installed_aur_helper(mount_path: &Path, preferred: Option<AurHelper>) -> Option<AurHelper> {
+    let installed = |helper: &AurHelper| {
+        mount_path
+            .join("usr/bin")
+            .join(helper.get_binary_name())
+            .exists()
+    };
+    preferred
+        .filter(installed)
+        .or_else(|| AurHelper::iter().find(installed))
+}
+
+/// Runs the AUR helper as a temporary user, as `alma create` does, since makepkg refuses to run
+/// as root
+fn upgrade_aur_packages(
+    arch_chroot: &Tool,
+    mount_path: &Path,
+    helper: AurHelper,
+) -> anyhow::Result<()> {
+    info!("Upgrading the AUR packages with {helper}");
+    create::with_aur_user(arch_chroot, mount_path, false, || {
+        arch_chroot
+            .execute()
+            .arg(mount_path)
+            .args(["sudo", "-u", "aur"])
+            .args(helper.get_upgrade_command())
+            .run(false)
+            .context("Failed to upgrade the AUR packages")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_installed_aur_helper() {
+        let root = tempfile::tempdir().unwrap();
+        assert_eq!(
+            installed_aur_helper(root.path(), Some(AurHelper::Paru)).map(|h| h.to_string()),
+            None
+        );
+
+        fs::create_dir_all(root.path().join("usr/bin")).unwrap();
+        fs::write(root.path().join("usr/bin/yay"), "").unwrap();
+        assert_eq!(
+            installed_aur_helper(root.path(), Some(AurHelper::Paru)).map(|h| h.to_string()),
+            Some(String::from("yay"))
+        );
+        fs::write(root.path().join("usr/bin/paru"), "").unwrap();
+        assert_eq!(
+            installed_aur_helper(root.path(), Some(AurHelper::Paru)).map(|h| h.to_string()),
+            Some(String::from("paru"))
+        );
+    }
+}
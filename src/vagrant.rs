@@ -0,0 +1,297 @@
+//! `alma export vagrant`: packages an image file as a Vagrant box, a tar archive of the disk, a
+//! metadata.json naming the provider and a Vagrantfile with the defaults of the machine.
+
+use crate::args::{VagrantExportCommand, VagrantProvider};
+use crate::effects;
+use crate::error::{ErrorKind, ErrorKindExt};
+use crate::process::CommandExt;
+use crate::tool::Tool;
+use anyhow::{Context, anyhow};
+use log::info;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const GIB: u64 = 1024 * 1024 * 1024;
+
+/// Disk file names the providers expect in the box
+const LIBVIRT_DISK: &str = "box.img";
+const VIRTUALBOX_DISK: &str = "box-disk001.vmdk";
+const VIRTUALBOX_OVF: &str = "box.ovf";
+
+pub fn export(command: VagrantExportCommand) -> anyhow::Result<()> {
+    let dryrun = command.dryrun;
+    let qemu_img = Tool::find("qemu-img", dryrun)
+        .map_err(|_| {
+            anyhow!(
+                "qemu-img is required for exporting boxes. Please install the 'qemu-img' package."
+            )
+        })
+        .kind(ErrorKind::Tooling)?;
+    let tar = Tool::find("tar", dryrun)?;
+
+    let metadata = fs::metadata(&command.image)
+        .with_context(|| format!("Cannot access {}", command.image.display()))
+        .kind(ErrorKind::Usage)?;
+    if !metadata.is_file() {
+        return Err(anyhow!(
+            "{} is not an image file, build one with `alma create --image`",
+            command.image.display()
+        ))
+        .kind(ErrorKind::Usage);
+    }
+    let name = box_name(&command.image);
+    let output = command
+        .output
+        .clone()
+        .unwrap_or_else(|| default_output(&command.image, command.provider));
+
+    // Next to the box rather than in /tmp, which is often a small tmpfs
+    let output_dir = output.parent().filter(|p| !p.as_os_str().is_empty());
+    let staging = tempfile::Builder::new()
+        .prefix(".alma-box-")
+        .tempdir_in(output_dir.unwrap_or(Path::new(".")))
+        .context("Error creating a temporary directory")?;
+    let staging_path = staging.path();
+
+    let mut files = vec!["metadata.json", "Vagrantfile"];
+    info!("Converting {} for {name}", command.image.display());
+    match command.provider {
+        VagrantProvider::Libvirt => {
+            qemu_img
+                .execute()
+                .args(["convert", "-f", "raw", "-O", "qcow2"])
+                .arg(&command.image)
+                .arg(staging_path.join(LIBVIRT_DISK))
+                .run(dryrun)
+                .context("Failed to convert the image to qcow2")?;
+            files.push(LIBVIRT_DISK);
+        }
+        VagrantProvider::Virtualbox => {
+            qemu_img
+                .execute()
+                .args(["convert", "-f", "raw", "-O", "vmdk"])
+                .args(["-o", "subformat=streamOptimized"])
+                .arg(&command.image)
+                .arg(staging_path.join(VIRTUALBOX_DISK))
+                .run(dryrun)
+                .context("Failed to convert the image to VMDK")?;
+            effects::write(
+                &staging_path.join(VIRTUALBOX_OVF),
+                ovf(&name, metadata.len(), command.memory, command.cpus),
+                dryrun,
+            )?;
+            files.extend([VIRTUALBOX_OVF, VIRTUALBOX_DISK]);
+        }
+    }
+    effects::write(
+        &staging_path.join("metadata.json"),
+        metadata_json(command.provider, metadata.len()),
+        dryrun,
+    )?;
+    effects::write(
+        &staging_path.join("Vagrantfile"),
+        vagrantfile(command.provider, command.memory, command.cpus),
+        dryrun,
+    )?;
+
+    info!("Writing {}", output.display());
+    tar.execute()
+        .arg("-czf")
+        .arg(&output)
+        .arg("-C")
+        .arg(staging_path)
+        .args(files)
+        .run(dryrun)
+        .context("Failed to create the box archive")?;
+
+    info!(
+        "Add it to Vagrant with `vagrant box add --name {name} {}`",
+        output.display()
+    );
+    Ok(())
+}
+
+/// The file stem of the image, which names the VirtualBox machine
+fn box_name(image: &Path) -> String {
+    image.file_stem().map_or_else(
+        || String::from("alma"),
+        |s| s.to_string_lossy().into_owned(),
+    )
+}
+
+/// `<image>-<provider>.box`, next to the image
+fn default_output(image: &Path, provider: VagrantProvider) -> PathBuf {
+    image.with_file_name(format!(
+        "{}-{}.box",
+        box_name(image),
+        provider_name(provider)
+    ))
+}
+
+fn provider_name(provider: VagrantProvider) -> &'static str {
+    match provider {
+        VagrantProvider::Libvirt => "libvirt",
+        VagrantProvider::Virtualbox => "virtualbox",
+    }
+}
+
+fn metadata_json(provider: VagrantProvider, image_size: u64) -> String {
+    let metadata = match provider {
+        VagrantProvider::Libvirt => serde_json::json!({
+            "provider": "libvirt",
+            "format": "qcow2",
+            "virtual_size": image_size.div_ceil(GIB),
+        }),
+        VagrantProvider::Virtualbox => serde_json::json!({ "provider": "virtualbox" }),
+    };
+    metadata.to_string()
+}
+
+/// Defaults of machines using the box, which their own Vagrantfile can override. The synced
+/// folder is disabled, since the image may have neither rsync nor an NFS client.
+fn vagrantfile(provider: VagrantProvider, memory: u32, cpus: u32) -> String {
+    let provider_settings = match provider {
+        VagrantProvider::Libvirt => format!(
+            "  config.vm.provider :libvirt do |libvirt|
+    libvirt.driver = \"kvm\"
+    libvirt.memory = {memory}
+    libvirt.cpus = {cpus}
+  end"
+        ),
+        VagrantProvider::Virtualbox => format!(
+            "  config.vm.provider :virtualbox do |vb|
+    vb.memory = {memory}
+    vb.cpus = {cpus}
+  end"
+        ),
+    };
+    format!(
+        "Vagrant.configure(\"2\") do |config|
+  config.vm.synced_folder \".\", \"/vagrant\", disabled: true
+{provider_settings}
+end
+"
+    )
+}
+
+/// The OVF descriptor of the VirtualBox machine: the CPUs, the memory, a SATA disk and a NAT
+/// network adapter
+fn ovf(name: &str, capacity: u64, memory: u32, cpus: u32) -> String {
+    let name = xml_escape(name);
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<Envelope ovf:version="1.0" xml:lang="en-US" xmlns="http://schemas.dmtf.org/ovf/envelope/1" xmlns:ovf="http://schemas.dmtf.org/ovf/envelope/1" xmlns:rasd="http://schemas.dmtf.org/wbem/wscim/1/cim-schema/2/CIM_ResourceAllocationSettingData" xmlns:vssd="http://schemas.dmtf.org/wbem/wscim/1/cim-schema/2/CIM_VirtualSystemSettingData">
+  <References>
+    <File ovf:id="file1" ovf:href="{VIRTUALBOX_DISK}"/>
+  </References>
+  <DiskSection>
+    <Info>List of the virtual disks used in the package</Info>
+    <Disk ovf:capacity="{capacity}" ovf:diskId="vmdisk1" ovf:fileRef="file1" ovf:format="http://www.vmware.com/interfaces/specifications/vmdk.html#streamOptimized"/>
+  </DiskSection>
+  <NetworkSection>
+    <Info>Logical networks used in the package</Info>
+    <Network ovf:name="NAT">
+      <Description>Logical network used by this appliance.</Description>
+    </Network>
+  </NetworkSection>
+  <VirtualSystem ovf:id="{name}">
+    <Info>A virtual machine</Info>
+    <OperatingSystemSection ovf:id="101">
+      <Info>The kind of installed guest operating system</Info>
+      <Description>ArchLinux_64</Description>
+    </OperatingSystemSection>
+    <VirtualHardwareSection>
+      <Info>Virtual hardware requirements for a virtual machine</Info>
+      <System>
+        <vssd:ElementName>Virtual Hardware Family</vssd:ElementName>
+        <vssd:InstanceID>0</vssd:InstanceID>
+        <vssd:VirtualSystemIdentifier>{name}</vssd:VirtualSystemIdentifier>
+        <vssd:VirtualSystemType>virtualbox-2.2</vssd:VirtualSystemType>
+      </System>
+      <Item>
+        <rasd:Caption>{cpus} virtual CPU</rasd:Caption>
+        <rasd:ElementName>{cpus} virtual CPU</rasd:ElementName>
+        <rasd:InstanceID>1</rasd:InstanceID>
+        <rasd:ResourceType>3</rasd:ResourceType>
+        <rasd:VirtualQuantity>{cpus}</rasd:VirtualQuantity>
+      </Item>
+      <Item>
+        <rasd:AllocationUnits>MegaBytes</rasd:AllocationUnits>
+        <rasd:Caption>{memory} MB of memory</rasd:Caption>
+        <rasd:ElementName>{memory} MB of memory</rasd:ElementName>
+        <rasd:InstanceID>2</rasd:InstanceID>
+        <rasd:ResourceType>4</rasd:ResourceType>
+        <rasd:VirtualQuantity>{memory}</rasd:VirtualQuantity>
+      </Item>
+      <Item>
+        <rasd:Address>0</rasd:Address>
+        <rasd:Caption>sataController0</rasd:Caption>
+        <rasd:ElementName>sataController0</rasd:ElementName>
+        <rasd:InstanceID>3</rasd:InstanceID>
+        <rasd:ResourceSubType>AHCI</rasd:ResourceSubType>
+        <rasd:ResourceType>20</rasd:ResourceType>
+      </Item>
+      <Item>
+        <rasd:AutomaticAllocation>true</rasd:AutomaticAllocation>
+        <rasd:Caption>Ethernet adapter on 'NAT'</rasd:Caption>
+        <rasd:Connection>NAT</rasd:Connection>
+        <rasd:ElementName>Ethernet adapter on 'NAT'</rasd:ElementName>
+        <rasd:InstanceID>4</rasd:InstanceID>
+        <rasd:ResourceSubType>E1000</rasd:ResourceSubType>
+        <rasd:ResourceType>10</rasd:ResourceType>
+      </Item>
+      <Item>
+        <rasd:AddressOnParent>0</rasd:AddressOnParent>
+        <rasd:Caption>disk1</rasd:Caption>
+        <rasd:ElementName>disk1</rasd:ElementName>
+        <rasd:HostResource>/disk/vmdisk1</rasd:HostResource>
+        <rasd:InstanceID>5</rasd:InstanceID>
+        <rasd:Parent>3</rasd:Parent>
+        <rasd:ResourceType>17</rasd:ResourceType>
+      </Item>
+    </VirtualHardwareSection>
+  </VirtualSystem>
+</Envelope>
+"#
+    )
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_output() {
+        assert_eq!(
+            default_output(Path::new("/srv/alma.img"), VagrantProvider::Libvirt),
+            PathBuf::from("/srv/alma-libvirt.box")
+        );
+    }
+
+    #[test]
+    fn test_metadata_json() {
+        assert_eq!(
+            metadata_json(VagrantProvider::Libvirt, 10 * GIB + 1),
+            r#"{"format":"qcow2","provider":"libvirt","virtual_size":11}"#
+        );
+        assert_eq!(
+            metadata_json(VagrantProvider::Virtualbox, GIB),
+            r#"{"provider":"virtualbox"}"#
+        );
+    }
+
+    #[test]
+    fn test_ovf() {
+        let ovf = ovf("a<b", 4 * GIB, 2048, 2);
+        assert!(ovf.contains(r#"ovf:capacity="4294967296""#));
+        assert!(ovf.contains("<rasd:VirtualQuantity>2048</rasd:VirtualQuantity>"));
+        assert!(ovf.contains(r#"<VirtualSystem ovf:id="a&lt;b">"#));
+    }
+}
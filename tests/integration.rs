@@ -0,0 +1,298 @@
+//! End-to-end tests: builds an image with `alma create --image`, boots it with `alma qemu` and
+//! logs in over the serial console and ssh. They need root, loop devices, pacstrap and qemu, so
+//! they only run with `cargo test --features integration`, which CI does in a privileged Arch
+//! Linux container.
+//!
+//! `ALMA_TEST_KEYMAP` and `ALMA_TEST_LOCALE` build the image with that console keymap and locale
+//! and check that they apply: the keymap by typing on the VGA console through the QEMU monitor,
+//! the locale in the login shell on the serial console.
+#![cfg(feature = "integration")]
+
+use nix::unistd::geteuid;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Host port forwarded to the ssh server of the guest
+const SSH_PORT: u16 = 2222;
+
+const BOOT_TIMEOUT: Duration = Duration::from_secs(600);
+const SHELL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Installs the test key for root and enables sshd
+const PRESET: &str = r#"packages = ["openssh"]
+environment_variables = ["ALMA_TEST_PUBKEY"]
+script = """
+set -eux
+install -d -m 700 /root/.ssh
+echo "${ALMA_TEST_PUBKEY}" > /root/.ssh/authorized_keys
+chmod 600 /root/.ssh/authorized_keys
+systemctl enable sshd
+"""
+"#;
+
+/// A key whose character differs between keymaps: (keymap, QEMU key name, character typed)
+const KEYMAP_PROBES: &[(&str, &str, &str)] = &[
+    ("us", "y", "y"),
+    ("uk", "y", "y"),
+    ("de", "y", "z"),
+    ("de-latin1", "y", "z"),
+    ("fr", "q", "a"),
+    ("fr-latin1", "q", "a"),
+    ("dvorak", "j", "h"),
+];
+
+fn alma() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_alma"))
+}
+
+/// The qemu process group, killed when dropped so a failed test does not leave the VM running
+struct Vm {
+    child: Child,
+    stdin: ChildStdin,
+    output: Receiver<Vec<u8>>,
+    console: String,
+    /// The socket of the QEMU monitor, which types on the VGA console
+    monitor: PathBuf,
+}
+
+impl Vm {
+    fn boot(image: &Path, monitor: &Path) -> Self {
+        let mut child = alma()
+            .arg("qemu")
+            .arg("--direct-kernel")
+            .arg(image)
+            .args(["--", "-display", "none", "-serial", "stdio", "-netdev"])
+            .arg(format!("user,id=ssh,hostfwd=tcp:127.0.0.1:{SSH_PORT}-:22"))
+            .args(["-device", "virtio-net-pci,netdev=ssh", "-monitor"])
+            .arg(format!("unix:{},server,nowait", monitor.display()))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .process_group(0)
+            .spawn()
+            .expect("Failed to run alma qemu");
+        let stdin = child.stdin.take().unwrap();
+        let mut stdout = child.stdout.take().unwrap();
+
+        // The login prompt does not end with a newline, so the console is read in chunks
+        let (sender, output) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buffer = [0; 4096];
+            while let Ok(n @ 1..) = stdout.read(&mut buffer) {
+                if sender.send(buffer[..n].to_vec()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Self {
+            child,
+            stdin,
+            output,
+            console: String::new(),
+            monitor: monitor.to_path_buf(),
+        }
+    }
+
+    /// Waits until the serial console prints `text`, failing with the console output after
+    /// `timeout`
+    fn expect(&mut self, text: &str, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        while !self.console.contains(text) {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.output.recv_timeout(remaining) {
+                Ok(chunk) => {
+                    let chunk = String::from_utf8_lossy(&chunk);
+                    print!("{chunk}");
+                    self.console.push_str(&chunk);
+                }
+                Err(_) => panic!("Timed out waiting for '{text}' on the serial console"),
+            }
+        }
+        self.console.clear();
+    }
+
+    fn send(&mut self, line: &str) {
+        writeln!(self.stdin, "{line}").expect("Failed to write to the serial console");
+    }
+
+    /// Presses the keys (QEMU key names, e.g. "y" or "ret") on the keyboard of the VM, whose
+    /// keymap turns them into characters on the VGA console
+    fn press_keys(&self, keys: &[&str]) {
+        let mut monitor = UnixStream::connect(&self.monitor).expect("Failed to open the monitor");
+        for key in keys {
+            writeln!(monitor, "sendkey {key}").expect("Failed to write to the monitor");
+            thread::sleep(Duration::from_millis(200));
+        }
+    }
+
+    /// Checks that the login shell on the serial console runs with `locale`, and that it was
+    /// generated
+    fn check_locale(&mut self, locale: &str) {
+        self.send("echo lang-$LANG");
+        self.expect(&format!("lang-{locale}"), SHELL_TIMEOUT);
+        // locale -a lists de_DE.UTF-8 as de_DE.utf8
+        let listed = locale.replace("UTF-8", "utf8");
+        self.send(&format!(
+            "locale -a | grep -qx '{listed}' && echo locale-$(echo generated)"
+        ));
+        self.expect("locale-generated", SHELL_TIMEOUT);
+    }
+
+    /// Types a key whose character depends on the keymap on tty1, and reads the line back from
+    /// the serial console
+    fn check_keymap(&mut self, keymap: &str) {
+        let (_, key, typed) = KEYMAP_PROBES
+            .iter()
+            .find(|(name, _, _)| *name == keymap)
+            .unwrap_or_else(|| panic!("No key to check the '{keymap}' keymap with"));
+        self.send("systemctl stop getty@tty1; chvt 1; stty -F /dev/tty1 sane");
+        self.expect("]#", SHELL_TIMEOUT);
+        self.send("read -r line < /dev/tty1; echo typed-$line");
+        // Give read time to open the terminal
+        thread::sleep(Duration::from_secs(2));
+        self.press_keys(&[key, "ret"]);
+        self.expect(&format!("typed-{typed}"), SHELL_TIMEOUT);
+    }
+}
+
+impl Drop for Vm {
+    fn drop(&mut self) {
+        // alma qemu leads the process group, with qemu in it
+        let _ = Command::new("kill")
+            .args(["-KILL", "--", &format!("-{}", self.child.id())])
+            .status();
+        let _ = self.child.wait();
+    }
+}
+
+fn ssh(key: &Path, command: &str) -> Option<String> {
+    let output = Command::new("ssh")
+        .arg("-i")
+        .arg(key)
+        .args(["-p", &SSH_PORT.to_string()])
+        .args([
+            "-o",
+            "StrictHostKeyChecking=no",
+            "-o",
+            "UserKnownHostsFile=/dev/null",
+            "-o",
+            "ConnectTimeout=10",
+            "-o",
+            "BatchMode=yes",
+            "root@127.0.0.1",
+            command,
+        ])
+        .output()
+        .expect("Failed to run ssh");
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[test]
+fn create_and_boot() {
+    assert!(
+        geteuid().is_root(),
+        "The integration tests must run as root"
+    );
+
+    // Not in /tmp, which is often a tmpfs too small for the image
+    let dir = tempfile::tempdir_in("/var/tmp").unwrap();
+    let image = dir.path().join("alma.img");
+    let key = dir.path().join("id_ed25519");
+    let preset = dir.path().join("test.toml");
+    fs::write(&preset, PRESET).unwrap();
+    let keymap = std::env::var("ALMA_TEST_KEYMAP").ok();
+    let locale = std::env::var("ALMA_TEST_LOCALE").ok();
+
+    let status = Command::new("ssh-keygen")
+        .args(["-t", "ed25519", "-N", "", "-q", "-f"])
+        .arg(&key)
+        .status()
+        .expect("Failed to run ssh-keygen");
+    assert!(status.success());
+    let public_key = fs::read_to_string(key.with_extension("pub")).unwrap();
+
+    let mut create = alma();
+    create
+        .args(["create", "--noconfirm", "--image", "4GiB"])
+        .args(["--kernel-cmdline", "console=ttyS0"]);
+    if let Some(keymap) = &keymap {
+        create.args(["--keymap", keymap]);
+    }
+    if let Some(locale) = &locale {
+        create.args(["--locale", locale]);
+    }
+    let status = create
+        .arg("--presets")
+        .arg(&preset)
+        .arg(&image)
+        .env("ALMA_TEST_PUBKEY", public_key.trim())
+        .status()
+        .expect("Failed to run alma create");
+    assert!(status.success(), "alma create failed with {status}");
+    assert!(dir.path().join("alma.img.json").exists());
+
+    let mut vm = Vm::boot(&image, &dir.path().join("monitor.sock"));
+    vm.expect("login:", BOOT_TIMEOUT);
+    // The root password is empty
+    vm.send("root");
+    vm.expect("]#", SHELL_TIMEOUT);
+    // The echoed command line does not contain the expanded marker
+    vm.send("systemctl is-system-running --wait; echo system-$(echo ready)");
+    vm.expect("system-ready", BOOT_TIMEOUT);
+    if let Some(locale) = &locale {
+        vm.check_locale(locale);
+    }
+    if let Some(keymap) = &keymap {
+        vm.check_keymap(keymap);
+    }
+
+    let mut manifest = None;
+    for _ in 0..10 {
+        manifest = ssh(&key, "cat /usr/share/alma/manifest.json");
+        if manifest.is_some() {
+            break;
+        }
+        thread::sleep(Duration::from_secs(5));
+    }
+    let manifest = manifest.expect("Could not log in over ssh");
+    let manifest: serde_json::Value = serde_json::from_str(&manifest).unwrap();
+    assert_eq!(manifest["alma_version"], env!("CARGO_PKG_VERSION"));
+
+    vm.send("poweroff");
+}
+
+/// A build failing once the image is partitioned leaves no loop device attached to it
+#[test]
+fn fail_after_partitioning() {
+    assert!(
+        geteuid().is_root(),
+        "The integration tests must run as root"
+    );
+
+    let dir = tempfile::tempdir_in("/var/tmp").unwrap();
+    let image = dir.path().join("alma.img");
+    let status = alma()
+        .args(["create", "--noconfirm", "--image", "2GiB"])
+        .args(["--fail-at", "post-partition"])
+        .arg(&image)
+        .status()
+        .expect("Failed to run alma create");
+    assert!(!status.success(), "alma create did not fail");
+
+    let output = Command::new("losetup")
+        .arg("-j")
+        .arg(&image)
+        .output()
+        .expect("Failed to run losetup");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+}